@@ -1,57 +1,136 @@
 //! Block group descriptor for ext4 filesystem.
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{ByteOrder, LittleEndian};
 use std::io::{Read, Seek, SeekFrom};
 use crate::error::Ext4Error;
 
+/// The size of a group descriptor without the 64bit feature.
+pub const EXT4_MIN_DESC_SIZE: u16 = 32;
+
+/// The smallest group descriptor size with the 64bit feature.
+pub const EXT4_MIN_DESC_SIZE_64BIT: u16 = 64;
+
+/// The inode bitmap and inode table of the group are not initialized.
+pub const EXT4_BG_INODE_UNINIT: u16 = 0x0001;
+
+/// The block bitmap of the group is not initialized.
+pub const EXT4_BG_BLOCK_UNINIT: u16 = 0x0002;
+
 /// The block group descriptor of an ext4 filesystem.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct BlockGroup {
     /// Block bitmap block.
-    pub block_bitmap: u32,
+    pub block_bitmap: u64,
     /// Inode bitmap block.
-    pub inode_bitmap: u32,
+    pub inode_bitmap: u64,
     /// Inode table block.
-    pub inode_table: u32,
+    pub inode_table: u64,
     /// Free blocks count.
-    pub free_blocks_count: u16,
+    pub free_blocks_count: u32,
     /// Free inodes count.
-    pub free_inodes_count: u16,
+    pub free_inodes_count: u32,
     /// Directories count.
-    pub used_dirs_count: u16,
-    /// Padding.
-    pub pad: u16,
-    /// Reserved.
-    pub reserved: [u8; 12],
+    pub used_dirs_count: u32,
+    /// Block group flags (uninitialized bitmaps and inode table).
+    pub flags: u16,
+    /// Snapshot exclusion bitmap block.
+    pub exclude_bitmap: u64,
+    /// Block bitmap checksum.
+    pub block_bitmap_csum: u32,
+    /// Inode bitmap checksum.
+    pub inode_bitmap_csum: u32,
+    /// Number of unused inodes at the end of the inode table.
+    pub itable_unused: u32,
+    /// Group descriptor checksum.
+    pub checksum: u16,
+    /// Reserved bytes at the end of a 64-byte descriptor.
+    pub reserved: u32,
 }
 
 impl BlockGroup {
     /// Read a block group descriptor from a reader.
-    pub fn read<R: Read + Seek>(reader: &mut R, group_num: u32, first_data_block: u32, block_size: u32) -> Result<Self, Ext4Error> {
+    pub fn read<R: Read + Seek>(
+        reader: &mut R,
+        group_num: u32,
+        first_data_block: u32,
+        block_size: u32,
+        desc_size: u16,
+    ) -> Result<Self, Ext4Error> {
         // The block group descriptor table starts at the first block after the superblock
-        let offset = (first_data_block + 1) * block_size + group_num * 32;
-        reader.seek(SeekFrom::Start(offset as u64))?;
-
-        let block_bitmap = reader.read_u32::<LittleEndian>()?;
-        let inode_bitmap = reader.read_u32::<LittleEndian>()?;
-        let inode_table = reader.read_u32::<LittleEndian>()?;
-        let free_blocks_count = reader.read_u16::<LittleEndian>()?;
-        let free_inodes_count = reader.read_u16::<LittleEndian>()?;
-        let used_dirs_count = reader.read_u16::<LittleEndian>()?;
-        let pad = reader.read_u16::<LittleEndian>()?;
-        
-        let mut reserved = [0u8; 12];
-        reader.read_exact(&mut reserved)?;
-
-        Ok(BlockGroup {
-            block_bitmap,
-            inode_bitmap,
-            inode_table,
-            free_blocks_count,
-            free_inodes_count,
-            used_dirs_count,
-            pad,
-            reserved,
-        })
+        let offset = (first_data_block as u64 + 1) * block_size as u64
+            + group_num as u64 * desc_size as u64;
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![0u8; desc_size as usize];
+        reader.read_exact(&mut data)?;
+        Ok(Self::parse(&data))
+    }
+
+    /// Parse a descriptor, including the high halves when it is at least 64 bytes long.
+    pub fn parse(data: &[u8]) -> Self {
+        let mut bg = BlockGroup {
+            block_bitmap: LittleEndian::read_u32(&data[0..4]) as u64,
+            inode_bitmap: LittleEndian::read_u32(&data[4..8]) as u64,
+            inode_table: LittleEndian::read_u32(&data[8..12]) as u64,
+            free_blocks_count: LittleEndian::read_u16(&data[12..14]) as u32,
+            free_inodes_count: LittleEndian::read_u16(&data[14..16]) as u32,
+            used_dirs_count: LittleEndian::read_u16(&data[16..18]) as u32,
+            flags: LittleEndian::read_u16(&data[18..20]),
+            exclude_bitmap: LittleEndian::read_u32(&data[20..24]) as u64,
+            block_bitmap_csum: LittleEndian::read_u16(&data[24..26]) as u32,
+            inode_bitmap_csum: LittleEndian::read_u16(&data[26..28]) as u32,
+            itable_unused: LittleEndian::read_u16(&data[28..30]) as u32,
+            checksum: LittleEndian::read_u16(&data[30..32]),
+            reserved: 0,
+        };
+
+        if data.len() >= EXT4_MIN_DESC_SIZE_64BIT as usize {
+            bg.block_bitmap |= (LittleEndian::read_u32(&data[32..36]) as u64) << 32;
+            bg.inode_bitmap |= (LittleEndian::read_u32(&data[36..40]) as u64) << 32;
+            bg.inode_table |= (LittleEndian::read_u32(&data[40..44]) as u64) << 32;
+            bg.free_blocks_count |= (LittleEndian::read_u16(&data[44..46]) as u32) << 16;
+            bg.free_inodes_count |= (LittleEndian::read_u16(&data[46..48]) as u32) << 16;
+            bg.used_dirs_count |= (LittleEndian::read_u16(&data[48..50]) as u32) << 16;
+            bg.itable_unused |= (LittleEndian::read_u16(&data[50..52]) as u32) << 16;
+            bg.exclude_bitmap |= (LittleEndian::read_u32(&data[52..56]) as u64) << 32;
+            bg.block_bitmap_csum |= (LittleEndian::read_u16(&data[56..58]) as u32) << 16;
+            bg.inode_bitmap_csum |= (LittleEndian::read_u16(&data[58..60]) as u32) << 16;
+            bg.reserved = LittleEndian::read_u32(&data[60..64]);
+        }
+
+        bg
     }
-}
\ No newline at end of file
+
+    /// Serialize the descriptor into `desc_size` bytes.
+    pub fn to_bytes(&self, desc_size: u16) -> Vec<u8> {
+        let mut data = vec![0u8; desc_size as usize];
+        LittleEndian::write_u32(&mut data[0..4], self.block_bitmap as u32);
+        LittleEndian::write_u32(&mut data[4..8], self.inode_bitmap as u32);
+        LittleEndian::write_u32(&mut data[8..12], self.inode_table as u32);
+        LittleEndian::write_u16(&mut data[12..14], self.free_blocks_count as u16);
+        LittleEndian::write_u16(&mut data[14..16], self.free_inodes_count as u16);
+        LittleEndian::write_u16(&mut data[16..18], self.used_dirs_count as u16);
+        LittleEndian::write_u16(&mut data[18..20], self.flags);
+        LittleEndian::write_u32(&mut data[20..24], self.exclude_bitmap as u32);
+        LittleEndian::write_u16(&mut data[24..26], self.block_bitmap_csum as u16);
+        LittleEndian::write_u16(&mut data[26..28], self.inode_bitmap_csum as u16);
+        LittleEndian::write_u16(&mut data[28..30], self.itable_unused as u16);
+        LittleEndian::write_u16(&mut data[30..32], self.checksum);
+
+        if desc_size >= EXT4_MIN_DESC_SIZE_64BIT {
+            LittleEndian::write_u32(&mut data[32..36], (self.block_bitmap >> 32) as u32);
+            LittleEndian::write_u32(&mut data[36..40], (self.inode_bitmap >> 32) as u32);
+            LittleEndian::write_u32(&mut data[40..44], (self.inode_table >> 32) as u32);
+            LittleEndian::write_u16(&mut data[44..46], (self.free_blocks_count >> 16) as u16);
+            LittleEndian::write_u16(&mut data[46..48], (self.free_inodes_count >> 16) as u16);
+            LittleEndian::write_u16(&mut data[48..50], (self.used_dirs_count >> 16) as u16);
+            LittleEndian::write_u16(&mut data[50..52], (self.itable_unused >> 16) as u16);
+            LittleEndian::write_u32(&mut data[52..56], (self.exclude_bitmap >> 32) as u32);
+            LittleEndian::write_u16(&mut data[56..58], (self.block_bitmap_csum >> 16) as u16);
+            LittleEndian::write_u16(&mut data[58..60], (self.inode_bitmap_csum >> 16) as u16);
+            LittleEndian::write_u32(&mut data[60..64], self.reserved);
+        }
+
+        data
+    }
+}
@@ -0,0 +1,105 @@
+//! Indirect block maps for ext2/ext3-style inodes.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek, SeekFrom};
+use crate::error::Ext4Error;
+
+/// Number of direct block pointers in `i_block`.
+pub const EXT4_NDIR_BLOCKS: usize = 12;
+
+/// Index of the single indirect block pointer in `i_block`.
+pub const EXT4_IND_BLOCK: usize = 12;
+
+/// Index of the double indirect block pointer in `i_block`.
+pub const EXT4_DIND_BLOCK: usize = 13;
+
+/// Index of the triple indirect block pointer in `i_block`.
+pub const EXT4_TIND_BLOCK: usize = 14;
+
+/// Get the number of block pointers stored in an indirect block.
+pub fn pointers_per_block(block_size: u32) -> u64 {
+    block_size as u64 / 4
+}
+
+/// Get the number of logical blocks reachable through an indirect block of a given level.
+pub fn blocks_per_level(block_size: u32, level: u32) -> u64 {
+    pointers_per_block(block_size).pow(level)
+}
+
+/// Get the highest number of logical blocks a block map can address.
+pub fn max_blocks(block_size: u32) -> u64 {
+    EXT4_NDIR_BLOCKS as u64 + (1..=3).map(|level| blocks_per_level(block_size, level)).sum::<u64>()
+}
+
+/// Locate a logical block in the map.
+///
+/// Returns the `i_block` slot holding the root of the path, the level of indirection
+/// below it (0 for direct blocks) and the offset of the block within that subtree.
+pub fn locate(logical: u64, block_size: u32) -> Option<(usize, u32, u64)> {
+    if logical < EXT4_NDIR_BLOCKS as u64 {
+        return Some((logical as usize, 0, 0));
+    }
+
+    let mut offset = logical - EXT4_NDIR_BLOCKS as u64;
+    for (slot, level) in [(EXT4_IND_BLOCK, 1), (EXT4_DIND_BLOCK, 2), (EXT4_TIND_BLOCK, 3)] {
+        let span = blocks_per_level(block_size, level);
+        if offset < span {
+            return Some((slot, level, offset));
+        }
+        offset -= span;
+    }
+
+    None
+}
+
+/// Read the block pointers stored in an indirect block.
+pub fn read_pointers<R: Read + Seek>(
+    reader: &mut R,
+    block_num: u32,
+    block_size: u32,
+) -> Result<Vec<u32>, Ext4Error> {
+    let mut data = vec![0u8; block_size as usize];
+    reader.seek(SeekFrom::Start(block_num as u64 * block_size as u64))?;
+    reader.read_exact(&mut data)?;
+
+    Ok(data.chunks_exact(4).map(LittleEndian::read_u32).collect())
+}
+
+/// Serialize block pointers into an indirect block.
+pub fn write_pointers(pointers: &[u32], block_size: u32) -> Vec<u8> {
+    let mut data = vec![0u8; block_size as usize];
+    for (chunk, &ptr) in data.chunks_exact_mut(4).zip(pointers) {
+        LittleEndian::write_u32(chunk, ptr);
+    }
+    data
+}
+
+/// Map a logical block through the direct and indirect pointers of `i_block`.
+///
+/// Returns `None` for holes.
+pub fn map_block<R: Read + Seek>(
+    reader: &mut R,
+    block: &[u32; 15],
+    logical: u64,
+    block_size: u32,
+) -> Result<Option<u32>, Ext4Error> {
+    let (slot, level, mut offset) = match locate(logical, block_size) {
+        Some(location) => location,
+        None => return Ok(None),
+    };
+
+    let mut current = block[slot];
+    for depth in (1..=level).rev() {
+        if current == 0 {
+            return Ok(None);
+        }
+
+        // Pick the pointer covering the offset at this level of indirection
+        let span = blocks_per_level(block_size, depth - 1);
+        let pointers = read_pointers(reader, current, block_size)?;
+        current = pointers[(offset / span) as usize];
+        offset %= span;
+    }
+
+    Ok(if current == 0 { None } else { Some(current) })
+}
@@ -0,0 +1,272 @@
+//! CRC32C checksums protecting ext4 metadata (the metadata_csum feature).
+
+use byteorder::{ByteOrder, LittleEndian};
+use crate::error::Ext4Error;
+
+/// The reflected CRC32C (Castagnoli) polynomial.
+const CRC32C_POLY: u32 = 0x82F6_3B78;
+
+/// Lookup table for byte-at-a-time CRC32C.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ CRC32C_POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// Offset of `l_i_checksum_lo` in an inode.
+const INODE_CHECKSUM_LO: usize = 0x7C;
+
+/// Offset of `i_checksum_hi` in an inode.
+const INODE_CHECKSUM_HI: usize = 0x82;
+
+/// Offset of `i_extra_isize` in an inode.
+const INODE_EXTRA_ISIZE: usize = 0x80;
+
+/// Offset of `i_generation` in an inode.
+const INODE_GENERATION: usize = 0x64;
+
+/// Offset of `bg_checksum` in a group descriptor.
+const GROUP_DESC_CHECKSUM: usize = 0x1E;
+
+/// Size of the checksum tail at the end of directory leaf blocks.
+pub const DIRENT_TAIL_SIZE: usize = 12;
+
+/// File type marking the fake directory entry that holds a leaf block checksum.
+pub const DIRENT_TAIL_FILE_TYPE: u8 = 0xDE;
+
+/// How checksum mismatches found while reading metadata are handled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ChecksumPolicy {
+    /// Fail the operation, like the kernel does.
+    #[default]
+    Strict,
+    /// Log a warning and carry on.
+    Warn,
+    /// Do not verify checksums at all.
+    Ignore,
+}
+
+impl ChecksumPolicy {
+    /// Compare a stored checksum against the computed one and apply the policy.
+    pub fn check(self, what: &str, stored: u32, computed: u32) -> Result<(), Ext4Error> {
+        if stored == computed || self == ChecksumPolicy::Ignore {
+            return Ok(());
+        }
+
+        let message = format!(
+            "{}: stored checksum {:#010x}, computed {:#010x}",
+            what, stored, computed
+        );
+        match self {
+            ChecksumPolicy::Strict => Err(Ext4Error::ChecksumMismatch(message)),
+            _ => {
+                log::warn!("Checksum mismatch in {}", message);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Update a CRC32C with more data, without pre- or post-inversion (like the kernel's `crc32c()`).
+pub fn crc32c(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32C_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+/// Compute the superblock checksum over everything before `s_checksum`.
+pub fn superblock_checksum(raw: &[u8]) -> u32 {
+    crc32c(!0, &raw[..raw.len() - 4])
+}
+
+/// Compute the filesystem-wide checksum seed from the volume UUID.
+pub fn uuid_seed(uuid: &[u8; 16]) -> u32 {
+    crc32c(!0, uuid)
+}
+
+/// Compute the checksum of a group descriptor, ignoring its stored `bg_checksum`.
+pub fn group_desc_checksum(seed: u32, group: u32, desc: &[u8]) -> u16 {
+    let crc = crc32c(seed, &group.to_le_bytes());
+    let crc = crc32c(crc, &desc[..GROUP_DESC_CHECKSUM]);
+    let crc = crc32c(crc, &[0, 0]);
+    let crc = crc32c(crc, &desc[GROUP_DESC_CHECKSUM + 2..]);
+    crc as u16
+}
+
+/// Compute the checksum of the used part of a block or inode bitmap.
+pub fn bitmap_checksum(seed: u32, bitmap: &[u8]) -> u32 {
+    crc32c(seed, bitmap)
+}
+
+/// Compute the per-inode seed used for the inode itself and the blocks it owns.
+pub fn inode_seed(seed: u32, inode_num: u32, generation: u32) -> u32 {
+    let crc = crc32c(seed, &inode_num.to_le_bytes());
+    crc32c(crc, &generation.to_le_bytes())
+}
+
+/// Check if a raw inode has room for the upper half of its checksum.
+fn inode_has_checksum_hi(raw: &[u8]) -> bool {
+    raw.len() > INODE_EXTRA_ISIZE + 2
+        && LittleEndian::read_u16(&raw[INODE_EXTRA_ISIZE..INODE_EXTRA_ISIZE + 2]) >= 4
+}
+
+/// Compute the checksum of a full on-disk inode, ignoring its stored checksum fields.
+pub fn inode_checksum(seed: u32, inode_num: u32, raw: &[u8]) -> u32 {
+    let generation = LittleEndian::read_u32(&raw[INODE_GENERATION..INODE_GENERATION + 4]);
+    let crc = inode_seed(seed, inode_num, generation);
+
+    let crc = crc32c(crc, &raw[..INODE_CHECKSUM_LO]);
+    let crc = crc32c(crc, &[0, 0]);
+    if !inode_has_checksum_hi(raw) {
+        return crc32c(crc, &raw[INODE_CHECKSUM_LO + 2..]);
+    }
+    let crc = crc32c(crc, &raw[INODE_CHECKSUM_LO + 2..INODE_CHECKSUM_HI]);
+    let crc = crc32c(crc, &[0, 0]);
+    crc32c(crc, &raw[INODE_CHECKSUM_HI + 2..])
+}
+
+/// Get the stored and computed checksums of a full on-disk inode, masked to the stored width.
+pub fn inode_checksums(seed: u32, inode_num: u32, raw: &[u8]) -> (u32, u32) {
+    let computed = inode_checksum(seed, inode_num, raw);
+    let lo = LittleEndian::read_u16(&raw[INODE_CHECKSUM_LO..INODE_CHECKSUM_LO + 2]) as u32;
+    if inode_has_checksum_hi(raw) {
+        let hi = LittleEndian::read_u16(&raw[INODE_CHECKSUM_HI..INODE_CHECKSUM_HI + 2]) as u32;
+        (hi << 16 | lo, computed)
+    } else {
+        (lo, computed & 0xFFFF)
+    }
+}
+
+/// Store a fresh checksum in a full on-disk inode.
+pub fn set_inode_checksum(seed: u32, inode_num: u32, raw: &mut [u8]) {
+    let crc = inode_checksum(seed, inode_num, raw);
+    LittleEndian::write_u16(&mut raw[INODE_CHECKSUM_LO..INODE_CHECKSUM_LO + 2], crc as u16);
+    if inode_has_checksum_hi(raw) {
+        LittleEndian::write_u16(
+            &mut raw[INODE_CHECKSUM_HI..INODE_CHECKSUM_HI + 2],
+            (crc >> 16) as u16,
+        );
+    }
+}
+
+/// Get the offset of the checksum following an extent tree block, from the node's `eh_max`.
+fn extent_tail_offset(block: &[u8]) -> usize {
+    let offset = 12 * (LittleEndian::read_u16(&block[4..6]) as usize + 1);
+    std::cmp::min(offset, block.len() - 4)
+}
+
+/// Get the stored and computed checksums of an extent tree block.
+pub fn extent_block_checksums(inode_seed: u32, block: &[u8]) -> (u32, u32) {
+    let offset = extent_tail_offset(block);
+    (
+        LittleEndian::read_u32(&block[offset..offset + 4]),
+        crc32c(inode_seed, &block[..offset]),
+    )
+}
+
+/// Store a fresh checksum after the entries of an extent tree block.
+pub fn set_extent_block_checksum(inode_seed: u32, block: &mut [u8]) {
+    let offset = extent_tail_offset(block);
+    let crc = crc32c(inode_seed, &block[..offset]);
+    LittleEndian::write_u32(&mut block[offset..offset + 4], crc);
+}
+
+/// Store a fresh checksum in the header of an extended attribute block, which covers the
+/// block's number and the block with the checksum field zeroed.
+pub fn set_xattr_block_checksum(seed: u32, block_num: u64, block: &mut [u8]) {
+    block[16..20].fill(0);
+    let crc = crc32c(seed, &block_num.to_le_bytes());
+    let crc = crc32c(crc, block);
+    LittleEndian::write_u32(&mut block[16..20], crc);
+}
+
+/// Check if a directory block ends with a checksum tail.
+pub fn has_dirent_tail(block: &[u8]) -> bool {
+    let tail = &block[block.len() - DIRENT_TAIL_SIZE..];
+    LittleEndian::read_u32(&tail[0..4]) == 0
+        && LittleEndian::read_u16(&tail[4..6]) as usize == DIRENT_TAIL_SIZE
+        && tail[6] == 0
+        && tail[7] == DIRENT_TAIL_FILE_TYPE
+}
+
+/// Write an empty checksum tail at the end of a directory block.
+pub fn init_dirent_tail(block: &mut [u8]) {
+    let len = block.len();
+    let tail = &mut block[len - DIRENT_TAIL_SIZE..];
+    tail.fill(0);
+    LittleEndian::write_u16(&mut tail[4..6], DIRENT_TAIL_SIZE as u16);
+    tail[7] = DIRENT_TAIL_FILE_TYPE;
+}
+
+/// Compute the checksum of an htree index block whose count/limit header is at `count_offset`.
+///
+/// Only the entries in use are covered, followed by the tail's reserved field.
+fn dx_block_checksum(inode_seed: u32, block: &[u8], count_offset: usize, tail: usize) -> u32 {
+    let count = LittleEndian::read_u16(&block[count_offset + 2..count_offset + 4]) as usize;
+    let crc = crc32c(inode_seed, &block[..count_offset + count * 8]);
+    let crc = crc32c(crc, &block[tail..tail + 4]);
+    crc32c(crc, &[0, 0, 0, 0])
+}
+
+/// Get the offset of the checksum tail after the `limit` entries of an htree index block.
+///
+/// Returns `None` if there is no room for it, or the node claims more entries than it holds.
+fn dx_tail_offset(block: &[u8], count_offset: usize) -> Option<usize> {
+    let limit = LittleEndian::read_u16(&block[count_offset..count_offset + 2]) as usize;
+    let count = LittleEndian::read_u16(&block[count_offset + 2..count_offset + 4]) as usize;
+    let tail = count_offset + limit * 8;
+    if tail + 8 > block.len() || count > limit {
+        None
+    } else {
+        Some(tail)
+    }
+}
+
+/// Get the stored and computed checksums of an htree index block, or `None` if its
+/// entries leave no room for the tail.
+pub fn dx_block_checksums(
+    inode_seed: u32,
+    block: &[u8],
+    count_offset: usize,
+) -> Option<(u32, u32)> {
+    let tail = dx_tail_offset(block, count_offset)?;
+    Some((
+        LittleEndian::read_u32(&block[tail + 4..tail + 8]),
+        dx_block_checksum(inode_seed, block, count_offset, tail),
+    ))
+}
+
+/// Store a fresh checksum in the tail of an htree index block.
+pub fn set_dx_block_checksum(inode_seed: u32, block: &mut [u8], count_offset: usize) {
+    if let Some(tail) = dx_tail_offset(block, count_offset) {
+        let crc = dx_block_checksum(inode_seed, block, count_offset, tail);
+        LittleEndian::write_u32(&mut block[tail + 4..tail + 8], crc);
+    }
+}
+
+/// Get the stored and computed checksums of a directory leaf block with a checksum tail.
+pub fn dirent_block_checksums(inode_seed: u32, block: &[u8]) -> (u32, u32) {
+    let offset = block.len() - DIRENT_TAIL_SIZE;
+    (
+        LittleEndian::read_u32(&block[offset + 8..offset + 12]),
+        crc32c(inode_seed, &block[..offset]),
+    )
+}
+
+/// Store a fresh checksum in the tail of a directory leaf block.
+pub fn set_dirent_block_checksum(inode_seed: u32, block: &mut [u8]) {
+    let offset = block.len() - DIRENT_TAIL_SIZE;
+    let crc = crc32c(inode_seed, &block[..offset]);
+    LittleEndian::write_u32(&mut block[offset + 8..offset + 12], crc);
+}
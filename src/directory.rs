@@ -1,16 +1,50 @@
 //! Directory entry for ext4 filesystem.
 
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::io::{Read, Seek, SeekFrom};
 use crate::error::Ext4Error;
-use crate::inode::Inode;
-use byteorder::{LittleEndian, WriteBytesExt};
+use crate::inode::{Inode, INODE_BLOCK_SIZE};
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Size of the parent's inode number at the start of an inline directory, which stands in
+/// for "." and "..".
+pub const EXT4_INLINE_DOTDOT_SIZE: usize = 4;
+
+/// The largest `rec_len` that fits in its 16 bits as is; 64 KiB blocks store the whole
+/// block as this value.
+const EXT4_MAX_REC_LEN: usize = 65535;
+
+/// Decode a `rec_len` from a block of `block_len` bytes.
+///
+/// A 64 KiB block has no room for a record spanning all of it, so such records are stored
+/// as `EXT4_MAX_REC_LEN` or 0, and longer lengths keep their high bits in the low two.
+pub fn rec_len_from_disk(rec_len: u16, block_len: usize) -> usize {
+    let rec_len = rec_len as usize;
+    if block_len <= EXT4_MAX_REC_LEN {
+        return rec_len;
+    }
+    if rec_len == EXT4_MAX_REC_LEN || rec_len == 0 {
+        return block_len;
+    }
+    (rec_len & 65532) | ((rec_len & 3) << 16)
+}
+
+/// Encode a `rec_len` for a block of `block_len` bytes, the inverse of `rec_len_from_disk`.
+pub fn rec_len_to_disk(rec_len: usize, block_len: usize) -> u16 {
+    if block_len <= EXT4_MAX_REC_LEN {
+        return rec_len as u16;
+    }
+    if rec_len == block_len {
+        return EXT4_MAX_REC_LEN as u16;
+    }
+    ((rec_len & 65532) | ((rec_len >> 16) & 3)) as u16
+}
 
 /// The directory entry of an ext4 filesystem.
 #[derive(Debug, Clone)]
 pub struct DirectoryEntry {
     /// Inode number.
     pub inode: u32,
-    /// Entry length.
+    /// Entry length, encoded as on disk.
     pub rec_len: u16,
     /// Name length.
     pub name_len: u8,
@@ -29,6 +63,12 @@ pub struct Directory {
     pub entries: Vec<DirectoryEntry>,
 }
 
+impl Default for Directory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Directory {
 
     /// Create a new empty directory
@@ -87,84 +127,37 @@ impl Directory {
         println!("========================================\n");
     }
 
-     /// 将目录项写入到文件中
-     pub fn write<W: Write + Seek>(&self, writer: &mut W, block_size: u32) -> Result<(), Ext4Error> {
-        println!("开始写入目录项，总条目数: {}", self.entries.len());
-        
-        // 确保至少有一个数据块
-        if self.inode.block[0] == 0 {
-            return Err(Ext4Error::InvalidDirectory("目录没有分配数据块".to_string()));
-        }
-
-        // 只使用第一个数据块来存储目录项
-        let block_num = self.inode.block[0];
-        println!("使用数据块 #{}", block_num);
-
-        // 定位到数据块位置
-        writer.seek(SeekFrom::Start((block_num * block_size) as u64))?;
-
-        // 创建一个新的数据块缓冲区
-        let mut block_data = vec![0u8; block_size as usize];
-        let mut offset = 0;
-
-        // 写入所有目录项
-        for (idx, entry) in self.entries.iter().enumerate() {
-            println!("写入第 {} 个目录项: {}", idx + 1, entry.name);
-            
-            if offset + 8 + entry.name.len() > block_size as usize {
-                return Err(Ext4Error::NoSpace("数据块空间不足".to_string()));
-            }
-
-            // 写入目录项头部
-            let mut cursor = std::io::Cursor::new(&mut block_data[offset..]);
-            cursor.write_u32::<LittleEndian>(entry.inode)?;
-            cursor.write_u16::<LittleEndian>(entry.rec_len)?;
-            cursor.write_u8(entry.name_len)?;
-            cursor.write_u8(entry.file_type)?;
-
-            // 写入文件名
-            let name_bytes = entry.name.as_bytes();
-            block_data[offset + 8..offset + 8 + name_bytes.len()].copy_from_slice(name_bytes);
-
-            offset += entry.rec_len as usize;
-        }
-
-        // 一次性写入整个数据块
-        writer.seek(SeekFrom::Start((block_num * block_size) as u64))?;
-        writer.write_all(&block_data[..offset])?;
-        
-        // 如果有剩余空间，用0填充
-        if offset < block_size as usize {
-            let zeros = vec![0u8; block_size as usize - offset];
-            writer.write_all(&zeros)?;
-        }
-
-        println!("目录项写入完成，总写入字节数: {}", offset);
-        Ok(())
-    }
-
     /// Read a directory from a reader.
+    ///
+    /// Inline directories keep part of their entries in an extended attribute, so they are
+    /// read with `read_inline` instead.
     pub fn read<R: Read + Seek>(reader: &mut R, inode: Inode, block_size: u32) -> Result<Self, Ext4Error> {
         if !inode.is_directory() {
             return Err(Ext4Error::InvalidDirectory("Not a directory".to_string()));
         }
+        if inode.has_inline_data() {
+            return Err(Ext4Error::InvalidDirectory(
+                "Inline directory cannot be read from blocks".to_string(),
+            ));
+        }
 
         let mut entries = Vec::new();
         
-        // 遍历目录的所有直接数据块
-        for i in 0..12 {
-            let block_num = inode.block[i];
-            if block_num == 0 {
-                break;
-            }
+        // 遍历目录的所有逻辑块
+        let block_count = inode.get_size().div_ceil(block_size as u64) as u32;
+        for i in 0..block_count {
+            let block_num = match inode.map_block(reader, i, block_size)? {
+                Some(block_num) => block_num,
+                None => continue,
+            };
 
             // 定位到数据块的位置
-            reader.seek(SeekFrom::Start((block_num * block_size) as u64))?;
+            reader.seek(SeekFrom::Start(block_num * block_size as u64))?;
             
             // 读取数据块，处理可能的 EOF 情况
             let mut block_data = vec![0u8; block_size as usize];
             match reader.read(&mut block_data) {
-                Ok(n) if n == 0 => break,  // EOF
+                Ok(0) => break,  // EOF
                 Ok(n) if n < block_size as usize => {
                     block_data.truncate(n);  // 只保留实际读取的数据
                 }
@@ -172,59 +165,427 @@ impl Directory {
                 Err(e) => return Err(Ext4Error::Io(e)),
             }
 
-            // 解析数据块中的目录项
-            let mut offset = 0;
-            while offset < block_data.len() {
-                if offset + 8 > block_data.len() {
-                    break;
-                }
+            // 解析数据块中的目录项；校验和尾部和索引节点都是未使用的记录
+            let block = DirectoryBlock::parse(&block_data)?;
+            entries.extend(block.entries().map(|record| DirectoryEntry {
+                inode: record.inode,
+                rec_len: rec_len_to_disk(record.rec_len, block.block_len()),
+                name_len: record.name.len() as u8,
+                file_type: record.file_type,
+                name: String::from_utf8_lossy(&record.name).to_string(),
+            }));
+        }
 
-                // 读取目录项头部
-                use byteorder::{LittleEndian, ReadBytesExt};
-                let mut cursor = std::io::Cursor::new(&block_data[offset..]);
-                
-                let entry_inode = cursor.read_u32::<LittleEndian>()?;
-                if entry_inode == 0 {
-                    // 跳过已删除的目录项
-                    offset += 8;
-                    continue;
-                }
+        Ok(Directory {
+            inode,
+            entries,
+        })
+    }
 
-                let rec_len = cursor.read_u16::<LittleEndian>()?;
-                let name_len = cursor.read_u8()?;
-                let file_type = cursor.read_u8()?;
+    /// Read an inline directory from its inline data: `i_block` followed by the value of its
+    /// `system.data` attribute.
+    ///
+    /// "." and ".." are not stored, so they are made up from the inode numbers.
+    pub fn read_inline(inode: Inode, inode_num: u32, data: &[u8]) -> Result<Self, Ext4Error> {
+        let inline = InlineDirectory::parse(data)?;
+        let dot = |inode, name: &str| DirectoryEntry {
+            inode,
+            rec_len: Self::entry_len(name.len()) as u16,
+            name_len: name.len() as u8,
+            file_type: 2,
+            name: name.to_string(),
+        };
 
-                // 确保名称长度有效
-                if name_len == 0 || offset + 8 + name_len as usize > block_size as usize {
-                    break;
-                }
+        let mut entries = vec![dot(inode_num, "."), dot(inline.parent, "..")];
+        entries.extend(inline.entries().map(|record| DirectoryEntry {
+            inode: record.inode,
+            rec_len: record.rec_len as u16,
+            name_len: record.name.len() as u8,
+            file_type: record.file_type,
+            name: String::from_utf8_lossy(&record.name).to_string(),
+        }));
+        Ok(Directory {
+            inode,
+            entries,
+        })
+    }
 
-                // 读取文件名
-                let name_bytes = &block_data[offset + 8..offset + 8 + name_len as usize];
-                let name = String::from_utf8_lossy(name_bytes).to_string();
-
-                // 创建目录项
-                entries.push(DirectoryEntry {
-                    inode: entry_inode,
-                    rec_len,
-                    name_len,
-                    file_type,
-                    name,
-                });
-
-                // 移动到下一个目录项
-                offset += rec_len as usize;
-                if offset >= block_size as usize || rec_len == 0 {
-                    break;
-                }
+    /// Find an entry by name.
+    pub fn find_entry(&self, name: &str) -> Option<&DirectoryEntry> {
+        self.entries.iter().find(|entry| entry.name == name)
+    }
+
+    /// Get the space an entry with a name of this length takes, rounded up to 4 bytes.
+    pub fn entry_len(name_len: usize) -> usize {
+        (8 + name_len + 3) & !3
+    }
+}
+
+/// One record of a directory block: an entry in use, or space left unused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirectoryRecord {
+    /// Inode number, 0 if the record is unused.
+    pub inode: u32,
+    /// Space the record takes in the block, including any slack after the name.
+    pub rec_len: usize,
+    /// File type.
+    pub file_type: u8,
+    /// Raw name bytes.
+    pub name: Vec<u8>,
+}
+
+impl DirectoryRecord {
+    /// Create a record for an entry in use, trimmed to the space it needs.
+    pub fn new(inode: u32, name: &[u8], file_type: u8) -> Result<Self, Ext4Error> {
+        if name.is_empty() || name.len() > 255 {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Invalid file name length: {}",
+                name.len()
+            )));
+        }
+        Ok(DirectoryRecord {
+            inode,
+            rec_len: Directory::entry_len(name.len()),
+            file_type,
+            name: name.to_vec(),
+        })
+    }
+
+    /// Check if the record holds an entry.
+    pub fn is_used(&self) -> bool {
+        self.inode != 0
+    }
+
+    /// Get the space the record cannot give up: its header and name, or nothing if unused.
+    fn used_len(&self) -> usize {
+        if self.is_used() {
+            Directory::entry_len(self.name.len())
+        } else {
+            0
+        }
+    }
+}
+
+/// An editor for the records of one directory block.
+///
+/// The records always cover the block exactly, each starting on a 4-byte boundary with a
+/// `rec_len` that holds its name. A checksum tail parses as an unused record, so it must be
+/// left out of blocks that are edited.
+#[derive(Debug, Clone)]
+pub struct DirectoryBlock {
+    /// The records in on-disk order.
+    records: Vec<DirectoryRecord>,
+    /// The length of the block.
+    len: usize,
+}
+
+impl DirectoryBlock {
+    /// Create a block holding a single unused record.
+    pub fn new(len: usize) -> Self {
+        DirectoryBlock {
+            records: vec![DirectoryRecord {
+                inode: 0,
+                rec_len: len,
+                file_type: 0,
+                name: Vec::new(),
+            }],
+            len,
+        }
+    }
+
+    /// Parse a directory block, checking each record like the kernel does.
+    pub fn parse(block_data: &[u8]) -> Result<Self, Ext4Error> {
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while offset < block_data.len() {
+            let invalid = |reason: &str| {
+                Ext4Error::InvalidDirectory(format!(
+                    "Bad directory entry at offset {}: {}",
+                    offset, reason
+                ))
+            };
+            if offset + 8 > block_data.len() {
+                return Err(invalid("header overruns the block"));
+            }
+            let inode = LittleEndian::read_u32(&block_data[offset..offset + 4]);
+            let rec_len = rec_len_from_disk(
+                LittleEndian::read_u16(&block_data[offset + 4..offset + 6]),
+                block_data.len(),
+            );
+            let name_len = block_data[offset + 6] as usize;
+            if rec_len < Directory::entry_len(1) {
+                return Err(invalid("rec_len is smaller than minimal"));
+            }
+            if !rec_len.is_multiple_of(4) {
+                return Err(invalid("rec_len is not a multiple of 4"));
             }
+            if rec_len < Directory::entry_len(name_len) {
+                return Err(invalid("rec_len is too small for name_len"));
+            }
+            if offset + rec_len > block_data.len() {
+                return Err(invalid("entry overruns the block"));
+            }
+
+            records.push(DirectoryRecord {
+                inode,
+                rec_len,
+                file_type: block_data[offset + 7],
+                name: block_data[offset + 8..offset + 8 + name_len].to_vec(),
+            });
+            offset += rec_len;
         }
+        Ok(DirectoryBlock {
+            records,
+            len: block_data.len(),
+        })
+    }
 
-        Ok(Directory { inode, entries })
+    /// Lay records out back to back, with the last one spanning the rest of the block.
+    ///
+    /// Unused records are dropped; without any entries the block gets a single unused record.
+    pub fn pack(len: usize, records: impl IntoIterator<Item = DirectoryRecord>) -> Self {
+        let mut block = DirectoryBlock {
+            records: Vec::new(),
+            len,
+        };
+        for mut record in records.into_iter().filter(DirectoryRecord::is_used) {
+            record.rec_len = record.used_len();
+            block.records.push(record);
+        }
+        let used: usize = block.records.iter().map(|record| record.rec_len).sum();
+        match block.records.last_mut() {
+            Some(last) => last.rec_len += len - used,
+            None => return Self::new(len),
+        }
+        block
     }
 
-    /// Find an entry by name.
-    pub fn find_entry(&self, name: &str) -> Option<&DirectoryEntry> {
-        self.entries.iter().find(|entry| entry.name == name)
+    /// Get the length of the block the records cover.
+    pub fn block_len(&self) -> usize {
+        self.len
+    }
+
+    /// Get the entries in use.
+    pub fn entries(&self) -> impl Iterator<Item = &DirectoryRecord> {
+        self.records.iter().filter(|record| record.is_used())
+    }
+
+    /// Find a name in the block, returning the inode it links to.
+    pub fn find(&self, name: &[u8]) -> Option<u32> {
+        self.entries()
+            .find(|record| record.name == name)
+            .map(|record| record.inode)
+    }
+
+    /// Get the space not taken up by entries in use.
+    pub fn free_space(&self) -> usize {
+        let used: usize = self.records.iter().map(DirectoryRecord::used_len).sum();
+        self.len - used
+    }
+
+    /// Insert an entry into the first record with room for it.
+    ///
+    /// An entry in use gives up the slack after its name; an unused record is taken over
+    /// whole. Returns false if no record has room.
+    pub fn insert(&mut self, record: DirectoryRecord) -> bool {
+        self.try_insert(record).is_ok()
+    }
+
+    /// Insert an entry like `insert`, handing it back if no record has room.
+    fn try_insert(&mut self, record: DirectoryRecord) -> Result<(), DirectoryRecord> {
+        let needed = record.used_len();
+        let found = self
+            .records
+            .iter()
+            .position(|existing| existing.rec_len >= existing.used_len() + needed);
+        let i = match found {
+            Some(i) => i,
+            None => return Err(record),
+        };
+
+        let existing = &mut self.records[i];
+        let used = existing.used_len();
+        let record = DirectoryRecord {
+            rec_len: existing.rec_len - used,
+            ..record
+        };
+        if used == 0 {
+            *existing = record;
+        } else {
+            existing.rec_len = used;
+            self.records.insert(i + 1, record);
+        }
+        Ok(())
+    }
+
+    /// Insert an entry, compacting the block first if its free space is scattered over
+    /// records that are each too small.
+    ///
+    /// Returns false if the block does not have enough free space at all.
+    pub fn insert_or_compact(&mut self, record: DirectoryRecord) -> bool {
+        if self.free_space() < record.used_len() {
+            return false;
+        }
+        if let Err(record) = self.try_insert(record) {
+            self.compact();
+            self.insert(record);
+        }
+        true
+    }
+
+    /// Remove an entry, returning the inode it linked to.
+    ///
+    /// Its space goes to the record before it; the first record of the block is only
+    /// marked unused.
+    pub fn remove(&mut self, name: &[u8]) -> Option<u32> {
+        let i = self
+            .records
+            .iter()
+            .position(|record| record.is_used() && record.name == name)?;
+        let inode = self.records[i].inode;
+        if i == 0 {
+            self.records[0].inode = 0;
+        } else {
+            let record = self.records.remove(i);
+            self.records[i - 1].rec_len += record.rec_len;
+        }
+        Some(inode)
+    }
+
+    /// Move the entries together, gathering the free space after the last one.
+    pub fn compact(&mut self) {
+        let records = std::mem::take(&mut self.records);
+        *self = Self::pack(self.len, records);
+    }
+
+    /// Serialize the records into the start of `block_data`, leaving anything after the
+    /// block's length, such as a checksum tail, alone.
+    pub fn write_to(&self, block_data: &mut [u8]) {
+        let block_data = &mut block_data[..self.len];
+        block_data.fill(0);
+        let mut offset = 0;
+        for record in &self.records {
+            let slot = &mut block_data[offset..offset + record.rec_len];
+            LittleEndian::write_u32(&mut slot[0..4], record.inode);
+            LittleEndian::write_u16(&mut slot[4..6], rec_len_to_disk(record.rec_len, self.len));
+            slot[6] = record.name.len() as u8;
+            slot[7] = record.file_type;
+            slot[8..8 + record.name.len()].copy_from_slice(&record.name);
+            offset += record.rec_len;
+        }
+    }
+}
+
+/// An editor for a directory stored inline in its inode.
+///
+/// `i_block` starts with the parent's inode number, followed by entries; more entries may
+/// follow in the value of the `system.data` attribute.
+#[derive(Debug, Clone)]
+pub struct InlineDirectory {
+    /// The inode number of the parent directory.
+    pub parent: u32,
+    /// The entries in `i_block`.
+    pub block_entries: DirectoryBlock,
+    /// The entries in the `system.data` attribute, if its value is not empty.
+    pub xattr_entries: Option<DirectoryBlock>,
+}
+
+impl InlineDirectory {
+    /// Create an empty inline directory.
+    pub fn new(parent: u32) -> Self {
+        InlineDirectory {
+            parent,
+            block_entries: DirectoryBlock::new(INODE_BLOCK_SIZE - EXT4_INLINE_DOTDOT_SIZE),
+            xattr_entries: None,
+        }
+    }
+
+    /// Parse the inline data of a directory.
+    pub fn parse(data: &[u8]) -> Result<Self, Ext4Error> {
+        if data.len() < INODE_BLOCK_SIZE {
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "Inline directory data truncated to {} bytes",
+                data.len()
+            )));
+        }
+        let (block, xattr) = data.split_at(INODE_BLOCK_SIZE);
+        Ok(InlineDirectory {
+            parent: LittleEndian::read_u32(&block[..EXT4_INLINE_DOTDOT_SIZE]),
+            block_entries: DirectoryBlock::parse(&block[EXT4_INLINE_DOTDOT_SIZE..])?,
+            xattr_entries: if xattr.is_empty() {
+                None
+            } else {
+                Some(DirectoryBlock::parse(xattr)?)
+            },
+        })
+    }
+
+    /// Get the entries in use, without "." and "..".
+    pub fn entries(&self) -> impl Iterator<Item = &DirectoryRecord> {
+        self.block_entries
+            .entries()
+            .chain(self.xattr_entries.iter().flat_map(DirectoryBlock::entries))
+    }
+
+    /// Find a name in the directory, returning the inode it links to.
+    ///
+    /// ".." is the parent; "." is left to the caller, which knows the directory's inode.
+    pub fn find(&self, name: &[u8]) -> Option<u32> {
+        if name == b".." {
+            return Some(self.parent);
+        }
+        self.block_entries
+            .find(name)
+            .or_else(|| self.xattr_entries.as_ref()?.find(name))
+    }
+
+    /// Insert an entry into `i_block` or else the attribute value, compacting either if
+    /// that makes room.
+    ///
+    /// Returns false if neither has enough free space.
+    pub fn insert(&mut self, record: DirectoryRecord) -> bool {
+        if self.block_entries.free_space() >= record.rec_len {
+            return self.block_entries.insert_or_compact(record);
+        }
+        match &mut self.xattr_entries {
+            Some(xattr_entries) => xattr_entries.insert_or_compact(record),
+            None => false,
+        }
+    }
+
+    /// Stretch the attribute value to `len` bytes, packing its entries at the start.
+    pub fn grow(&mut self, len: usize) {
+        let entries = self
+            .xattr_entries
+            .take()
+            .map(|xattr_entries| xattr_entries.entries().cloned().collect::<Vec<_>>())
+            .unwrap_or_default();
+        self.xattr_entries = Some(DirectoryBlock::pack(len, entries));
+    }
+
+    /// Remove an entry, returning the inode it linked to.
+    pub fn remove(&mut self, name: &[u8]) -> Option<u32> {
+        self.block_entries
+            .remove(name)
+            .or_else(|| self.xattr_entries.as_mut()?.remove(name))
+    }
+
+    /// Get the length of the attribute value.
+    pub fn xattr_len(&self) -> usize {
+        self.xattr_entries
+            .as_ref()
+            .map_or(0, DirectoryBlock::block_len)
+    }
+
+    /// Serialize the directory as inline data.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = vec![0u8; INODE_BLOCK_SIZE + self.xattr_len()];
+        LittleEndian::write_u32(&mut data[..EXT4_INLINE_DOTDOT_SIZE], self.parent);
+        self.block_entries
+            .write_to(&mut data[EXT4_INLINE_DOTDOT_SIZE..INODE_BLOCK_SIZE]);
+        if let Some(xattr_entries) = &self.xattr_entries {
+            xattr_entries.write_to(&mut data[INODE_BLOCK_SIZE..]);
+        }
+        data
     }
 }
\ No newline at end of file
@@ -49,4 +49,20 @@ pub enum Ext4Error {
     /// The block is invalid.
     #[error("Invalid block: {0}")]
     InvalidBlock(String),
+
+    /// The filesystem uses features this implementation does not support.
+    #[error("Unsupported filesystem features: {0}")]
+    UnsupportedFeature(String),
+
+    /// A metadata checksum does not match the data it protects.
+    #[error("Checksum mismatch: {0}")]
+    ChecksumMismatch(String),
+
+    /// The filesystem is mounted read-only.
+    #[error("Filesystem is read-only: {0}")]
+    ReadOnly(String),
+
+    /// Resolving a path followed too many symbolic links.
+    #[error("Too many levels of symbolic links: {0}")]
+    SymlinkLoop(String),
 }
\ No newline at end of file
@@ -0,0 +1,502 @@
+//! Extent tree for ext4 filesystem.
+
+use byteorder::{ByteOrder, LittleEndian};
+use std::io::{Read, Seek, SeekFrom};
+use std::ops::Range;
+use crate::error::Ext4Error;
+
+/// The magic number of an extent tree node header.
+pub const EXT4_EXT_MAGIC: u16 = 0xF30A;
+
+/// The size of an extent header, extent or index entry on disk.
+pub const EXT4_EXT_ENTRY_SIZE: usize = 12;
+
+/// Extents longer than this are uninitialized (unwritten).
+pub const EXT_INIT_MAX_LEN: u16 = 32768;
+
+/// The longest an unwritten extent can be.
+pub const EXT_UNWRITTEN_MAX_LEN: u16 = EXT_INIT_MAX_LEN - 1;
+
+/// Maximum number of entries in the extent tree root stored in `i_block`.
+pub const EXT4_EXT_ROOT_ENTRIES: u16 = 4;
+
+/// The deepest tree the kernel will ever build.
+const EXT4_MAX_EXTENT_DEPTH: u16 = 5;
+
+/// The header at the start of every extent tree node.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentHeader {
+    /// Magic number (0xF30A).
+    pub magic: u16,
+    /// Number of valid entries following the header.
+    pub entries: u16,
+    /// Maximum number of entries that could follow the header.
+    pub max: u16,
+    /// Depth of this node in the tree (0 for leaves).
+    pub depth: u16,
+    /// Generation of the tree.
+    pub generation: u32,
+}
+
+/// A leaf entry mapping a run of logical blocks to physical blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    /// First logical block covered by this extent.
+    pub block: u32,
+    /// Number of blocks covered, with the high bit marking an unwritten extent.
+    pub len: u16,
+    /// First physical block covered by this extent.
+    pub start: u64,
+}
+
+/// An interior entry pointing to the next level of the tree.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtentIndex {
+    /// First logical block covered by the subtree.
+    pub block: u32,
+    /// Physical block holding the next level of the tree.
+    pub leaf: u64,
+}
+
+/// A parsed extent tree node.
+#[derive(Debug, Clone)]
+pub enum ExtentNode {
+    /// A leaf node holding extents.
+    Leaf(Vec<Extent>),
+    /// An interior node holding indexes.
+    Index(Vec<ExtentIndex>),
+}
+
+impl ExtentHeader {
+    /// Parse an extent header from the start of a buffer.
+    pub fn parse(data: &[u8]) -> Result<Self, Ext4Error> {
+        if data.len() < EXT4_EXT_ENTRY_SIZE {
+            return Err(Ext4Error::InvalidInode("Extent header truncated".to_string()));
+        }
+
+        let header = ExtentHeader {
+            magic: LittleEndian::read_u16(&data[0..2]),
+            entries: LittleEndian::read_u16(&data[2..4]),
+            max: LittleEndian::read_u16(&data[4..6]),
+            depth: LittleEndian::read_u16(&data[6..8]),
+            generation: LittleEndian::read_u32(&data[8..12]),
+        };
+
+        if header.magic != EXT4_EXT_MAGIC {
+            return Err(Ext4Error::InvalidInode(format!(
+                "Invalid extent header magic: {:x}, expected: {:x}",
+                header.magic, EXT4_EXT_MAGIC
+            )));
+        }
+        if header.entries > header.max
+            || EXT4_EXT_ENTRY_SIZE * (header.max as usize + 1) > data.len()
+        {
+            return Err(Ext4Error::InvalidInode(format!(
+                "Invalid extent header: {} entries, {} max",
+                header.entries, header.max
+            )));
+        }
+        if header.depth > EXT4_MAX_EXTENT_DEPTH {
+            return Err(Ext4Error::InvalidInode(format!(
+                "Extent tree too deep: {}",
+                header.depth
+            )));
+        }
+
+        Ok(header)
+    }
+
+    /// Write the header to the start of a buffer.
+    pub fn write_to(&self, buf: &mut [u8]) {
+        LittleEndian::write_u16(&mut buf[0..2], self.magic);
+        LittleEndian::write_u16(&mut buf[2..4], self.entries);
+        LittleEndian::write_u16(&mut buf[4..6], self.max);
+        LittleEndian::write_u16(&mut buf[6..8], self.depth);
+        LittleEndian::write_u32(&mut buf[8..12], self.generation);
+    }
+}
+
+impl Extent {
+    /// Check if this extent is allocated but not yet written.
+    pub fn is_unwritten(&self) -> bool {
+        self.len > EXT_INIT_MAX_LEN
+    }
+
+    /// Get the number of blocks covered by this extent.
+    pub fn length(&self) -> u32 {
+        if self.len > EXT_INIT_MAX_LEN {
+            (self.len - EXT_INIT_MAX_LEN) as u32
+        } else {
+            self.len as u32
+        }
+    }
+
+    /// Write the extent as a 12-byte leaf entry.
+    pub fn write_to(&self, buf: &mut [u8]) {
+        LittleEndian::write_u32(&mut buf[0..4], self.block);
+        LittleEndian::write_u16(&mut buf[4..6], self.len);
+        LittleEndian::write_u16(&mut buf[6..8], (self.start >> 32) as u16);
+        LittleEndian::write_u32(&mut buf[8..12], self.start as u32);
+    }
+
+    /// Check if a logical block falls inside this extent.
+    pub fn contains(&self, logical: u32) -> bool {
+        logical >= self.block && ((logical - self.block) as u64) < self.length() as u64
+    }
+}
+
+impl ExtentIndex {
+    /// Write the index as a 12-byte interior entry.
+    pub fn write_to(&self, buf: &mut [u8]) {
+        LittleEndian::write_u32(&mut buf[0..4], self.block);
+        LittleEndian::write_u32(&mut buf[4..8], self.leaf as u32);
+        LittleEndian::write_u16(&mut buf[8..10], (self.leaf >> 32) as u16);
+        LittleEndian::write_u16(&mut buf[10..12], 0);
+    }
+}
+
+impl ExtentNode {
+    /// Get the number of entries in the node.
+    pub fn len(&self) -> usize {
+        match self {
+            ExtentNode::Leaf(extents) => extents.len(),
+            ExtentNode::Index(indexes) => indexes.len(),
+        }
+    }
+
+    /// Check if the node has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the first logical block covered by the node.
+    pub fn first_block(&self) -> u32 {
+        match self {
+            ExtentNode::Leaf(extents) => extents.first().map_or(0, |e| e.block),
+            ExtentNode::Index(indexes) => indexes.first().map_or(0, |i| i.block),
+        }
+    }
+
+    /// Split the node into nodes of at most `max` entries each.
+    pub fn chunks(&self, max: usize) -> Vec<ExtentNode> {
+        match self {
+            ExtentNode::Leaf(extents) => extents
+                .chunks(max)
+                .map(|c| ExtentNode::Leaf(c.to_vec()))
+                .collect(),
+            ExtentNode::Index(indexes) => indexes
+                .chunks(max)
+                .map(|c| ExtentNode::Index(c.to_vec()))
+                .collect(),
+        }
+    }
+
+    /// Write the node (header and entries) to a buffer, zeroing the unused entries.
+    pub fn write_to(&self, buf: &mut [u8], max: u16, depth: u16) {
+        let header = ExtentHeader {
+            magic: EXT4_EXT_MAGIC,
+            entries: self.len() as u16,
+            max,
+            depth,
+            generation: 0,
+        };
+        buf[..EXT4_EXT_ENTRY_SIZE * (max as usize + 1)].fill(0);
+        header.write_to(buf);
+
+        let slots = buf[EXT4_EXT_ENTRY_SIZE..].chunks_exact_mut(EXT4_EXT_ENTRY_SIZE);
+        match self {
+            ExtentNode::Leaf(extents) => {
+                for (slot, extent) in slots.zip(extents) {
+                    extent.write_to(slot);
+                }
+            }
+            ExtentNode::Index(indexes) => {
+                for (slot, index) in slots.zip(indexes) {
+                    index.write_to(slot);
+                }
+            }
+        }
+    }
+
+    /// Parse an extent tree node (header and entries) from a buffer.
+    pub fn parse(data: &[u8]) -> Result<(ExtentHeader, Self), Ext4Error> {
+        let header = ExtentHeader::parse(data)?;
+        let entries = (1..=header.entries as usize)
+            .map(|i| &data[i * EXT4_EXT_ENTRY_SIZE..(i + 1) * EXT4_EXT_ENTRY_SIZE]);
+
+        let node = if header.depth == 0 {
+            ExtentNode::Leaf(
+                entries
+                    .map(|e| Extent {
+                        block: LittleEndian::read_u32(&e[0..4]),
+                        len: LittleEndian::read_u16(&e[4..6]),
+                        start: ((LittleEndian::read_u16(&e[6..8]) as u64) << 32)
+                            | LittleEndian::read_u32(&e[8..12]) as u64,
+                    })
+                    .collect(),
+            )
+        } else {
+            ExtentNode::Index(
+                entries
+                    .map(|e| ExtentIndex {
+                        block: LittleEndian::read_u32(&e[0..4]),
+                        leaf: LittleEndian::read_u32(&e[4..8]) as u64
+                            | ((LittleEndian::read_u16(&e[8..10]) as u64) << 32),
+                    })
+                    .collect(),
+            )
+        };
+
+        Ok((header, node))
+    }
+}
+
+/// Read a tree node stored in a full block.
+fn read_node<R: Read + Seek>(
+    reader: &mut R,
+    block: u64,
+    block_size: u32,
+    expected_depth: u16,
+) -> Result<(ExtentHeader, ExtentNode), Ext4Error> {
+    let mut data = vec![0u8; block_size as usize];
+    reader.seek(SeekFrom::Start(block * block_size as u64))?;
+    reader.read_exact(&mut data)?;
+
+    let (header, node) = ExtentNode::parse(&data)?;
+    if header.depth != expected_depth {
+        return Err(Ext4Error::InvalidInode(format!(
+            "Extent block {} has depth {}, expected {}",
+            block, header.depth, expected_depth
+        )));
+    }
+    Ok((header, node))
+}
+
+/// Find the extent covering a logical block, starting from the root in `i_block`.
+pub fn find_extent<R: Read + Seek>(
+    reader: &mut R,
+    root: &[u8],
+    logical: u32,
+    block_size: u32,
+) -> Result<Option<Extent>, Ext4Error> {
+    let (mut header, mut node) = ExtentNode::parse(root)?;
+
+    loop {
+        match node {
+            ExtentNode::Leaf(extents) => {
+                return Ok(extents.into_iter().find(|e| e.contains(logical)));
+            }
+            ExtentNode::Index(indexes) => {
+                // Follow the last index whose first block is not after the target
+                let next = match indexes.iter().rev().find(|idx| idx.block <= logical) {
+                    Some(idx) => idx.leaf,
+                    None => return Ok(None),
+                };
+                let (h, n) = read_node(reader, next, block_size, header.depth - 1)?;
+                header = h;
+                node = n;
+            }
+        }
+    }
+}
+
+/// Collect every leaf extent of the tree, in logical order.
+pub fn collect_extents<R: Read + Seek>(
+    reader: &mut R,
+    root: &[u8],
+    block_size: u32,
+) -> Result<Vec<Extent>, Ext4Error> {
+    let mut extents = Vec::new();
+    walk_tree(reader, root, block_size, &mut extents, &mut Vec::new())?;
+    Ok(extents)
+}
+
+/// Collect the blocks holding the tree itself (every node below the root).
+pub fn collect_tree_blocks<R: Read + Seek>(
+    reader: &mut R,
+    root: &[u8],
+    block_size: u32,
+) -> Result<Vec<u64>, Ext4Error> {
+    let mut tree_blocks = Vec::new();
+    walk_tree(reader, root, block_size, &mut Vec::new(), &mut tree_blocks)?;
+    Ok(tree_blocks)
+}
+
+fn walk_tree<R: Read + Seek>(
+    reader: &mut R,
+    root: &[u8],
+    block_size: u32,
+    extents: &mut Vec<Extent>,
+    tree_blocks: &mut Vec<u64>,
+) -> Result<(), Ext4Error> {
+    let (header, node) = ExtentNode::parse(root)?;
+    let mut stack = vec![(header, node)];
+
+    while let Some((header, node)) = stack.pop() {
+        match node {
+            ExtentNode::Leaf(leaf) => extents.extend(leaf),
+            ExtentNode::Index(indexes) => {
+                // Push children in reverse so they are visited in logical order
+                for idx in indexes.iter().rev() {
+                    tree_blocks.push(idx.leaf);
+                    stack.push(read_node(reader, idx.leaf, block_size, header.depth - 1)?);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get the number of entries that fit in a tree block.
+pub fn entries_per_block(block_size: u32) -> u16 {
+    ((block_size as usize - EXT4_EXT_ENTRY_SIZE) / EXT4_EXT_ENTRY_SIZE) as u16
+}
+
+/// Unmap logical blocks `block..block + len` from a sorted list of extents, splitting the
+/// extents that straddle the range.
+///
+/// Returns the pieces that were unmapped.
+pub fn punch_extents(extents: &mut Vec<Extent>, block: u32, len: u32) -> Vec<Extent> {
+    let start = block as u64;
+    let end = start + len as u64;
+    let mut kept = Vec::with_capacity(extents.len());
+    let mut removed = Vec::new();
+
+    for e in extents.drain(..) {
+        let e_start = e.block as u64;
+        let e_end = e_start + e.length() as u64;
+        if e_end <= start || e_start >= end {
+            kept.push(e);
+            continue;
+        }
+
+        let flag = if e.is_unwritten() {
+            EXT_INIT_MAX_LEN
+        } else {
+            0
+        };
+        let piece = |from: u64, to: u64| Extent {
+            block: from as u32,
+            len: (to - from) as u16 + flag,
+            start: e.start + (from - e_start),
+        };
+        if e_start < start {
+            kept.push(piece(e_start, start));
+        }
+        removed.push(piece(
+            std::cmp::max(e_start, start),
+            std::cmp::min(e_end, end),
+        ));
+        if e_end > end {
+            kept.push(piece(end, e_end));
+        }
+    }
+
+    *extents = kept;
+    removed
+}
+
+/// Map an extent into a sorted list of extents, replacing whatever mapped its range before
+/// and merging it with its neighbours where possible.
+///
+/// Returns the pieces that were replaced.
+pub fn insert_extent(extents: &mut Vec<Extent>, extent: Extent) -> Vec<Extent> {
+    let removed = punch_extents(extents, extent.block, extent.length());
+    if extent.length() == 0 {
+        return removed;
+    }
+
+    let mut pos = extents.partition_point(|e| e.block < extent.block);
+    extents.insert(pos, extent);
+    if pos + 1 < extents.len() {
+        if let Some(merged) = merge_extents(&extents[pos], &extents[pos + 1]) {
+            extents[pos] = merged;
+            extents.remove(pos + 1);
+        }
+    }
+    if pos > 0 {
+        if let Some(merged) = merge_extents(&extents[pos - 1], &extents[pos]) {
+            pos -= 1;
+            extents[pos] = merged;
+            extents.remove(pos + 1);
+        }
+    }
+    removed
+}
+
+/// Mark the mapped blocks among logical blocks `block..block + len` of a sorted list of
+/// extents as unwritten, so they read as zeros, or as written, keeping their blocks.
+pub fn set_unwritten(extents: &mut Vec<Extent>, block: u32, len: u32, unwritten: bool) {
+    let (max_len, flag) = if unwritten {
+        (EXT_UNWRITTEN_MAX_LEN as u32, EXT_INIT_MAX_LEN)
+    } else {
+        (EXT_INIT_MAX_LEN as u32, 0)
+    };
+    for piece in punch_extents(extents, block, len) {
+        let mut done = 0;
+        while done < piece.length() {
+            let len = std::cmp::min(piece.length() - done, max_len);
+            insert_extent(
+                extents,
+                Extent {
+                    block: piece.block + done,
+                    len: len as u16 + flag,
+                    start: piece.start + done as u64,
+                },
+            );
+            done += len;
+        }
+    }
+}
+
+/// Find the runs of logical blocks in `from..to` that a sorted list of extents leaves
+/// unmapped.
+pub fn find_holes(extents: &[Extent], from: u32, to: u32) -> Vec<Range<u32>> {
+    let mut holes = Vec::new();
+    let mut next = from;
+    for e in extents {
+        if next >= to || e.block >= to {
+            break;
+        }
+        let e_end = e.block as u64 + e.length() as u64;
+        if e_end <= next as u64 {
+            continue;
+        }
+        if e.block > next {
+            holes.push(next..e.block);
+        }
+        next = std::cmp::min(e_end, to as u64) as u32;
+    }
+    if next < to {
+        holes.push(next..to);
+    }
+    holes
+}
+
+/// Join two extents into one if the second directly follows the first, on disk and in the
+/// file, and the result is not too long.
+fn merge_extents(first: &Extent, second: &Extent) -> Option<Extent> {
+    let len = first.length() + second.length();
+    let max_len = if first.is_unwritten() {
+        EXT_UNWRITTEN_MAX_LEN as u32
+    } else {
+        EXT_INIT_MAX_LEN as u32
+    };
+    if first.is_unwritten() != second.is_unwritten()
+        || first.block as u64 + first.length() as u64 != second.block as u64
+        || first.start + first.length() as u64 != second.start
+        || len > max_len
+    {
+        return None;
+    }
+
+    let flag = if first.is_unwritten() {
+        EXT_INIT_MAX_LEN
+    } else {
+        0
+    };
+    Some(Extent {
+        len: len as u16 + flag,
+        ..*first
+    })
+}
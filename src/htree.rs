@@ -0,0 +1,455 @@
+//! Hashed b-tree (htree) directory indexes, used with the dir_index feature.
+
+use byteorder::{ByteOrder, LittleEndian};
+use crate::directory;
+use crate::error::Ext4Error;
+
+/// Offset of the index entries in the root block, after "." , ".." and the root info.
+pub const DX_ROOT_ENTRIES_OFFSET: usize = 32;
+
+/// Offset of the index entries in an interior node, after the fake empty directory entry.
+pub const DX_NODE_ENTRIES_OFFSET: usize = 8;
+
+/// The size of an index entry on disk.
+pub const DX_ENTRY_SIZE: usize = 8;
+
+/// The size of the checksum tail following the entries of an index block.
+pub const DX_TAIL_SIZE: usize = 8;
+
+/// Hashes with this value are reserved to mark the end of a directory in `telldir()` cookies.
+const EXT4_HTREE_EOF_32BIT: u32 = 0x7FFF_FFFF;
+
+/// The default seed used when the superblock has none.
+const DEFAULT_HASH_SEED: [u32; 4] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476];
+
+/// The TEA key schedule constant.
+const TEA_DELTA: u32 = 0x9E3779B9;
+
+/// The round constants of the second and third half-MD4 rounds.
+const MD4_K2: u32 = 0x5A827999;
+const MD4_K3: u32 = 0x6ED9EBA1;
+
+/// The hash algorithm of a directory index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashVersion {
+    /// The original ext3 hash, hashing names as signed chars.
+    Legacy = 0,
+    /// Half-MD4, hashing names as signed chars.
+    HalfMd4 = 1,
+    /// Tiny Encryption Algorithm, hashing names as signed chars.
+    Tea = 2,
+    /// The original ext3 hash, hashing names as unsigned chars.
+    LegacyUnsigned = 3,
+    /// Half-MD4, hashing names as unsigned chars.
+    HalfMd4Unsigned = 4,
+    /// Tiny Encryption Algorithm, hashing names as unsigned chars.
+    TeaUnsigned = 5,
+}
+
+impl HashVersion {
+    /// Get the hash of an index root, switching to the unsigned variant when the
+    /// filesystem was created on a platform with unsigned chars.
+    pub fn from_root(version: u8, unsigned: bool) -> Option<Self> {
+        let version = match version {
+            0..=2 if unsigned => version + 3,
+            _ => version,
+        };
+        match version {
+            0 => Some(HashVersion::Legacy),
+            1 => Some(HashVersion::HalfMd4),
+            2 => Some(HashVersion::Tea),
+            3 => Some(HashVersion::LegacyUnsigned),
+            4 => Some(HashVersion::HalfMd4Unsigned),
+            5 => Some(HashVersion::TeaUnsigned),
+            _ => None,
+        }
+    }
+
+    /// Check if names are hashed as signed chars.
+    fn is_signed(self) -> bool {
+        matches!(
+            self,
+            HashVersion::Legacy | HashVersion::HalfMd4 | HashVersion::Tea
+        )
+    }
+}
+
+/// An entry of an index node, covering names hashing to `hash` and above.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DxEntry {
+    /// The lowest hash in the subtree; always 0 for the first entry of a node.
+    pub hash: u32,
+    /// Logical block of the directory holding the subtree.
+    pub block: u32,
+}
+
+/// The entries of an index node, either the root or an interior node.
+#[derive(Debug, Clone)]
+pub struct DxNode {
+    /// Maximum number of entries that fit in the node.
+    pub limit: u16,
+    /// The entries in use, sorted by hash.
+    pub entries: Vec<DxEntry>,
+}
+
+/// The root of a directory index, stored in the first block of the directory.
+#[derive(Debug, Clone)]
+pub struct DxRoot {
+    /// The hash algorithm, before applying the filesystem's unsigned flag.
+    pub hash_version: u8,
+    /// The number of interior levels below the root.
+    pub indirect_levels: u8,
+    /// The root's entries.
+    pub node: DxNode,
+}
+
+impl DxNode {
+    /// Parse the count/limit header and entries starting at `offset` in an index block.
+    pub fn parse(block: &[u8], offset: usize) -> Result<Self, Ext4Error> {
+        if offset + DX_ENTRY_SIZE > block.len() {
+            return Err(Ext4Error::InvalidDirectory(
+                "Index node truncated".to_string(),
+            ));
+        }
+
+        let limit = LittleEndian::read_u16(&block[offset..offset + 2]);
+        let count = LittleEndian::read_u16(&block[offset + 2..offset + 4]);
+        if count == 0 || count > limit || offset + limit as usize * DX_ENTRY_SIZE > block.len() {
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "Invalid index node: {} entries, {} limit",
+                count, limit
+            )));
+        }
+
+        // The first entry's hash field holds the count and limit instead
+        let entries = (0..count as usize)
+            .map(|i| {
+                let entry = &block[offset + i * DX_ENTRY_SIZE..];
+                DxEntry {
+                    hash: if i == 0 {
+                        0
+                    } else {
+                        LittleEndian::read_u32(&entry[0..4])
+                    },
+                    block: LittleEndian::read_u32(&entry[4..8]) & 0x0FFF_FFFF,
+                }
+            })
+            .collect();
+        Ok(DxNode { limit, entries })
+    }
+
+    /// Parse an interior index node, which hides behind an empty directory entry spanning
+    /// the whole block.
+    pub fn parse_interior(block: &[u8]) -> Result<Self, Ext4Error> {
+        if !is_interior_node(block) {
+            return Err(Ext4Error::InvalidDirectory(
+                "Index node does not start with an empty entry".to_string(),
+            ));
+        }
+        Self::parse(block, DX_NODE_ENTRIES_OFFSET)
+    }
+
+    /// Find the entry whose subtree may hold names with this hash.
+    pub fn find(&self, hash: u32) -> usize {
+        // The last entry whose hash is not above the one looked up; entry 0 covers everything below
+        self.entries[1..].partition_point(|entry| entry.hash <= hash)
+    }
+
+    /// Check if the node has no room for another entry.
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= self.limit as usize
+    }
+
+    /// Write the count/limit header and entries at `offset` in an index block.
+    pub fn write_to(&self, block: &mut [u8], offset: usize) {
+        for (i, entry) in self.entries.iter().enumerate() {
+            let slot = &mut block[offset + i * DX_ENTRY_SIZE..offset + (i + 1) * DX_ENTRY_SIZE];
+            LittleEndian::write_u32(&mut slot[0..4], entry.hash);
+            LittleEndian::write_u32(&mut slot[4..8], entry.block);
+        }
+        LittleEndian::write_u16(&mut block[offset..offset + 2], self.limit);
+        LittleEndian::write_u16(
+            &mut block[offset + 2..offset + 4],
+            self.entries.len() as u16,
+        );
+    }
+
+    /// Write an interior node to a block, behind an empty entry spanning the block.
+    pub fn write_interior(&self, block: &mut [u8]) {
+        let rec_len = directory::rec_len_to_disk(block.len(), block.len());
+        block[..DX_NODE_ENTRIES_OFFSET].fill(0);
+        LittleEndian::write_u16(&mut block[4..6], rec_len);
+        self.write_to(block, DX_NODE_ENTRIES_OFFSET);
+    }
+}
+
+/// An index node on the path from the root to a leaf, with the entry followed from it.
+#[derive(Debug, Clone)]
+pub struct DxFrame {
+    /// The logical block holding the node; 0 for the root.
+    pub logical: u32,
+    /// The node itself.
+    pub node: DxNode,
+    /// The entry followed to the next level.
+    pub at: usize,
+}
+
+impl DxFrame {
+    /// Get the logical block the followed entry points to.
+    pub fn child(&self) -> u32 {
+        self.node.entries[self.at].block
+    }
+}
+
+impl DxRoot {
+    /// Parse the index root in the first block of an indexed directory.
+    pub fn parse(block: &[u8], max_levels: u8) -> Result<Self, Ext4Error> {
+        if block.len() < DX_ROOT_ENTRIES_OFFSET + DX_ENTRY_SIZE {
+            return Err(Ext4Error::InvalidDirectory(
+                "Index root truncated".to_string(),
+            ));
+        }
+
+        // "." and ".." come first, with ".." spanning the rest of the block
+        let dot_rec_len = LittleEndian::read_u16(&block[4..6]);
+        if dot_rec_len != 12 || block[6] != 1 || block[18] != 2 {
+            return Err(Ext4Error::InvalidDirectory(
+                "Index root does not start with \".\" and \"..\"".to_string(),
+            ));
+        }
+
+        let info = &block[24..DX_ROOT_ENTRIES_OFFSET];
+        let hash_version = info[4];
+        let info_length = info[5];
+        let indirect_levels = info[6];
+        if info_length != 8 {
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "Invalid index root info length: {}",
+                info_length
+            )));
+        }
+        if indirect_levels >= max_levels {
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "Index too deep: {} levels",
+                indirect_levels
+            )));
+        }
+
+        Ok(DxRoot {
+            hash_version,
+            indirect_levels,
+            node: DxNode::parse(block, DX_ROOT_ENTRIES_OFFSET)?,
+        })
+    }
+
+    /// Write the root to the first block of a directory that already starts with "." and "..".
+    ///
+    /// ".." is stretched over the rest of the block so the index is invisible to readers
+    /// that do not know about it.
+    pub fn write_to(&self, block: &mut [u8]) {
+        let rec_len = block.len() as u16 - 12;
+        LittleEndian::write_u16(&mut block[16..18], rec_len);
+        let info = &mut block[24..DX_ROOT_ENTRIES_OFFSET];
+        info.fill(0);
+        info[4] = self.hash_version;
+        info[5] = 8;
+        info[6] = self.indirect_levels;
+        self.node.write_to(block, DX_ROOT_ENTRIES_OFFSET);
+    }
+}
+
+/// Get the number of entries that fit in the root block, leaving room for a checksum tail.
+pub fn root_limit(block_size: usize, checksums: bool) -> u16 {
+    let tail = if checksums { DX_TAIL_SIZE } else { 0 };
+    ((block_size - DX_ROOT_ENTRIES_OFFSET - tail) / DX_ENTRY_SIZE) as u16
+}
+
+/// Get the number of entries that fit in an interior node, leaving room for a checksum tail.
+pub fn node_limit(block_size: usize, checksums: bool) -> u16 {
+    let tail = if checksums { DX_TAIL_SIZE } else { 0 };
+    ((block_size - DX_NODE_ENTRIES_OFFSET - tail) / DX_ENTRY_SIZE) as u16
+}
+
+/// Check if a block of an indexed directory is an interior index node rather than a leaf.
+pub fn is_interior_node(block: &[u8]) -> bool {
+    let inode = LittleEndian::read_u32(&block[0..4]);
+    let rec_len = directory::rec_len_from_disk(LittleEndian::read_u16(&block[4..6]), block.len());
+    inode == 0 && rec_len == block.len()
+}
+
+/// Find the count/limit header of an index block: after the empty entry of an interior
+/// node, or after the root info when the block starts with "." and "..".
+///
+/// Returns `None` if the block looks like neither.
+pub fn count_offset(block: &[u8]) -> Option<usize> {
+    if is_interior_node(block) {
+        return Some(DX_NODE_ENTRIES_OFFSET);
+    }
+    let dot_rec_len = LittleEndian::read_u16(&block[4..6]) as usize;
+    let dotdot_rec_len = LittleEndian::read_u16(&block[16..18]) as usize;
+    if dot_rec_len == 12 && dotdot_rec_len == block.len() - 12 && block[29] == 8 {
+        Some(DX_ROOT_ENTRIES_OFFSET)
+    } else {
+        None
+    }
+}
+
+/// Hash a name the way the kernel does for directory indexes.
+///
+/// Returns the major hash, which orders the index, and the minor hash.
+pub fn dx_hash(name: &[u8], version: HashVersion, seed: &[u32; 4]) -> (u32, u32) {
+    let mut buf = if seed.iter().any(|&word| word != 0) {
+        *seed
+    } else {
+        DEFAULT_HASH_SEED
+    };
+    let signed = version.is_signed();
+
+    let (hash, minor) = match version {
+        HashVersion::Legacy | HashVersion::LegacyUnsigned => (legacy_hash(name, signed), 0),
+        HashVersion::HalfMd4 | HashVersion::HalfMd4Unsigned => {
+            let mut input = [0u32; 8];
+            let mut rest = name;
+            loop {
+                str_to_hash_buf(rest, &mut input, signed);
+                half_md4_transform(&mut buf, &input);
+                if rest.len() <= 32 {
+                    break;
+                }
+                rest = &rest[32..];
+            }
+            (buf[1], buf[2])
+        }
+        HashVersion::Tea | HashVersion::TeaUnsigned => {
+            let mut input = [0u32; 4];
+            let mut rest = name;
+            loop {
+                str_to_hash_buf(rest, &mut input, signed);
+                tea_transform(&mut buf, &input);
+                if rest.len() <= 16 {
+                    break;
+                }
+                rest = &rest[16..];
+            }
+            (buf[0], buf[1])
+        }
+    };
+
+    // The low bit marks hash collisions in the index, and the top value is reserved
+    let mut hash = hash & !1;
+    if hash == EXT4_HTREE_EOF_32BIT << 1 {
+        hash = (EXT4_HTREE_EOF_32BIT - 1) << 1;
+    }
+    (hash, minor)
+}
+
+/// Convert a name byte to the integer the hash functions see.
+fn char_value(byte: u8, signed: bool) -> u32 {
+    if signed {
+        byte as i8 as i32 as u32
+    } else {
+        byte as u32
+    }
+}
+
+/// The original ext3 directory hash.
+fn legacy_hash(name: &[u8], signed: bool) -> u32 {
+    let (mut hash0, mut hash1) = (0x12A3FE2Du32, 0x37ABE8F9u32);
+    for &byte in name {
+        let mut hash = hash1.wrapping_add(hash0 ^ char_value(byte, signed).wrapping_mul(7152373));
+        if hash & 0x8000_0000 != 0 {
+            hash = hash.wrapping_sub(0x7FFF_FFFF);
+        }
+        hash1 = hash0;
+        hash0 = hash;
+    }
+    hash0 << 1
+}
+
+/// Pack the next chunk of a name into words, padding with its remaining length.
+fn str_to_hash_buf(name: &[u8], buf: &mut [u32], signed: bool) {
+    let len = name.len() as u32;
+    let mut pad = len | (len << 8);
+    pad |= pad << 16;
+
+    let mut val = pad;
+    let mut words = 0;
+    for (i, &byte) in name.iter().take(buf.len() * 4).enumerate() {
+        val = char_value(byte, signed).wrapping_add(val << 8);
+        if i % 4 == 3 {
+            buf[words] = val;
+            words += 1;
+            val = pad;
+        }
+    }
+    if words < buf.len() {
+        buf[words] = val;
+        words += 1;
+    }
+    for word in &mut buf[words..] {
+        *word = pad;
+    }
+}
+
+/// One block of the Tiny Encryption Algorithm, folded into the first two words of `buf`.
+fn tea_transform(buf: &mut [u32; 4], input: &[u32; 4]) {
+    let (mut b0, mut b1) = (buf[0], buf[1]);
+    let (a, b, c, d) = (input[0], input[1], input[2], input[3]);
+    let mut sum = 0u32;
+    for _ in 0..16 {
+        sum = sum.wrapping_add(TEA_DELTA);
+        b0 = b0.wrapping_add(
+            (b1 << 4).wrapping_add(a) ^ b1.wrapping_add(sum) ^ (b1 >> 5).wrapping_add(b),
+        );
+        b1 = b1.wrapping_add(
+            (b0 << 4).wrapping_add(c) ^ b0.wrapping_add(sum) ^ (b0 >> 5).wrapping_add(d),
+        );
+    }
+    buf[0] = buf[0].wrapping_add(b0);
+    buf[1] = buf[1].wrapping_add(b1);
+}
+
+/// The MD4 compression function cut down to three rounds of eight steps.
+fn half_md4_transform(buf: &mut [u32; 4], input: &[u32; 8]) {
+    let f = |x: u32, y: u32, z: u32| z ^ (x & (y ^ z));
+    let g = |x: u32, y: u32, z: u32| (x & y).wrapping_add((x ^ y) & z);
+    let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+    let step =
+        |a: u32, mixed: u32, x: u32, s: u32| a.wrapping_add(mixed).wrapping_add(x).rotate_left(s);
+    let [mut a, mut b, mut c, mut d] = *buf;
+
+    // Round 1
+    a = step(a, f(b, c, d), input[0], 3);
+    d = step(d, f(a, b, c), input[1], 7);
+    c = step(c, f(d, a, b), input[2], 11);
+    b = step(b, f(c, d, a), input[3], 19);
+    a = step(a, f(b, c, d), input[4], 3);
+    d = step(d, f(a, b, c), input[5], 7);
+    c = step(c, f(d, a, b), input[6], 11);
+    b = step(b, f(c, d, a), input[7], 19);
+
+    // Round 2
+    a = step(a, g(b, c, d), input[1].wrapping_add(MD4_K2), 3);
+    d = step(d, g(a, b, c), input[3].wrapping_add(MD4_K2), 5);
+    c = step(c, g(d, a, b), input[5].wrapping_add(MD4_K2), 9);
+    b = step(b, g(c, d, a), input[7].wrapping_add(MD4_K2), 13);
+    a = step(a, g(b, c, d), input[0].wrapping_add(MD4_K2), 3);
+    d = step(d, g(a, b, c), input[2].wrapping_add(MD4_K2), 5);
+    c = step(c, g(d, a, b), input[4].wrapping_add(MD4_K2), 9);
+    b = step(b, g(c, d, a), input[6].wrapping_add(MD4_K2), 13);
+
+    // Round 3
+    a = step(a, h(b, c, d), input[3].wrapping_add(MD4_K3), 3);
+    d = step(d, h(a, b, c), input[7].wrapping_add(MD4_K3), 9);
+    c = step(c, h(d, a, b), input[2].wrapping_add(MD4_K3), 11);
+    b = step(b, h(c, d, a), input[6].wrapping_add(MD4_K3), 15);
+    a = step(a, h(b, c, d), input[1].wrapping_add(MD4_K3), 3);
+    d = step(d, h(a, b, c), input[5].wrapping_add(MD4_K3), 9);
+    c = step(c, h(d, a, b), input[0].wrapping_add(MD4_K3), 11);
+    b = step(b, h(c, d, a), input[4].wrapping_add(MD4_K3), 15);
+
+    buf[0] = buf[0].wrapping_add(a);
+    buf[1] = buf[1].wrapping_add(b);
+    buf[2] = buf[2].wrapping_add(c);
+    buf[3] = buf[3].wrapping_add(d);
+}
@@ -1,58 +1,128 @@
 //! Inode structure for ext4 filesystem.
 
-use byteorder::{LittleEndian, ReadBytesExt};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use std::io::{Read, Seek, SeekFrom};
 use crate::error::Ext4Error;
+use crate::block_map;
+use crate::extent;
+
+/// Inode uses an extent tree instead of block maps.
+pub const EXT4_EXTENTS_FL: u32 = 0x80000;
+
+/// `i_blocks` counts filesystem blocks rather than 512-byte sectors.
+pub const EXT4_HUGE_FILE_FL: u32 = 0x40000;
+
+/// Directory is indexed by a hash tree.
+pub const EXT4_INDEX_FL: u32 = 0x1000;
+
+/// Inode stores its data inside the inode itself.
+pub const EXT4_INLINE_DATA_FL: u32 = 0x10000000;
+
+/// The size of the original ext2 inode, before the extra fields.
+pub const EXT4_GOOD_OLD_INODE_SIZE: usize = 128;
+
+/// Offset of `i_block` in an on-disk inode.
+pub const INODE_BLOCK_OFFSET: usize = 0x28;
+
+/// The size of `i_block` in bytes.
+pub const INODE_BLOCK_SIZE: usize = 60;
+
+/// The number of extra bytes needed to hold every known extra field.
+pub const EXT4_INODE_EXTRA_SIZE: u16 = 32;
+
+/// The most links an inode may have, past which directories stop counting them.
+pub const EXT4_LINK_MAX: u16 = 65000;
 
 /// The inode structure of an ext4 filesystem.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Inode {
     /// File mode.
     pub mode: u16,
-    /// Owner's user ID.
+    /// Owner's user ID (lower 16 bits).
     pub uid: u16,
     /// Size in bytes (lower 32 bits).
     pub size: u32,
     /// Last access time.
     pub atime: u32,
-    /// Creation time.
+    /// Last inode change time.
     pub ctime: u32,
     /// Last modification time.
     pub mtime: u32,
     /// Deletion time.
     pub dtime: u32,
-    /// Group ID.
+    /// Group ID (lower 16 bits).
     pub gid: u16,
     /// Hard link count.
     pub links_count: u16,
-    /// Blocks count (in 512-byte units).
+    /// Blocks count (in 512-byte units, lower 32 bits).
     pub blocks: u32,
     /// File flags.
     pub flags: u32,
-    /// OS-specific value.
+    /// OS-specific value (inode version on Linux).
     pub osd1: u32,
     /// Direct block pointers.
     pub block: [u32; 15],
     /// File version (for NFS).
     pub generation: u32,
-    /// Extended attribute block.
+    /// Extended attribute block (lower 32 bits).
     pub file_acl: u32,
     /// Size in bytes (upper 32 bits) or directory ACL.
     pub dir_acl: u32,
     /// Fragment address.
     pub faddr: u32,
-    /// OS-specific value.
-    pub osd2: [u8; 12],
+    /// Blocks count (upper 16 bits).
+    pub blocks_high: u16,
+    /// Extended attribute block (upper 16 bits).
+    pub file_acl_high: u16,
+    /// Owner's user ID (upper 16 bits).
+    pub uid_high: u16,
+    /// Group ID (upper 16 bits).
+    pub gid_high: u16,
+    /// Inode checksum (lower 16 bits).
+    pub checksum_lo: u16,
+    /// Reserved.
+    pub osd2_reserved: u16,
+    /// Number of bytes used by the extra fields past the first 128 bytes.
+    pub extra_isize: u16,
+    /// Inode checksum (upper 16 bits).
+    pub checksum_hi: u16,
+    /// Extra change time bits (epoch and nanoseconds).
+    pub ctime_extra: u32,
+    /// Extra modification time bits (epoch and nanoseconds).
+    pub mtime_extra: u32,
+    /// Extra access time bits (epoch and nanoseconds).
+    pub atime_extra: u32,
+    /// Creation time.
+    pub crtime: u32,
+    /// Extra creation time bits (epoch and nanoseconds).
+    pub crtime_extra: u32,
+    /// Inode version (upper 32 bits).
+    pub version_hi: u32,
+    /// Project ID.
+    pub projid: u32,
 }
 
 impl Inode {
     /// Read an inode from a reader.
-    pub fn read<R: Read + Seek>(reader: &mut R, inode_size: u32, inode_num: u32, inodes_per_group: u32, inode_table_block: u32, block_size: u32) -> Result<Self, Ext4Error> {
+    pub fn read<R: Read + Seek>(reader: &mut R, inode_size: u32, inode_num: u32, inodes_per_group: u32, inode_table_block: u64, block_size: u32) -> Result<Self, Ext4Error> {
         let _group = (inode_num - 1) / inodes_per_group;
         let index = (inode_num - 1) % inodes_per_group;
-        let offset = inode_table_block * block_size + index * inode_size;
-        
-        reader.seek(SeekFrom::Start(offset as u64))?;
+        let offset = inode_table_block * block_size as u64 + index as u64 * inode_size as u64;
+
+        reader.seek(SeekFrom::Start(offset))?;
+
+        let mut data = vec![0u8; inode_size as usize];
+        reader.read_exact(&mut data)?;
+        Self::parse(&data)
+    }
+
+    /// Parse an inode from its on-disk bytes.
+    pub fn parse(data: &[u8]) -> Result<Self, Ext4Error> {
+        if data.len() < EXT4_GOOD_OLD_INODE_SIZE {
+            return Err(Ext4Error::InvalidInode("Inode truncated".to_string()));
+        }
+        let mut reader = std::io::Cursor::new(data);
+        let reader = &mut reader;
 
         let mode = reader.read_u16::<LittleEndian>()?;
         let uid = reader.read_u16::<LittleEndian>()?;
@@ -66,21 +136,25 @@ impl Inode {
         let blocks = reader.read_u32::<LittleEndian>()?;
         let flags = reader.read_u32::<LittleEndian>()?;
         let osd1 = reader.read_u32::<LittleEndian>()?;
-        
+
         let mut block = [0u32; 15];
-        for i in 0..15 {
-            block[i] = reader.read_u32::<LittleEndian>()?;
+        for ptr in block.iter_mut() {
+            *ptr = reader.read_u32::<LittleEndian>()?;
         }
-        
+
         let generation = reader.read_u32::<LittleEndian>()?;
         let file_acl = reader.read_u32::<LittleEndian>()?;
         let dir_acl = reader.read_u32::<LittleEndian>()?;
         let faddr = reader.read_u32::<LittleEndian>()?;
-        
-        let mut osd2 = [0u8; 12];
-        reader.read_exact(&mut osd2)?;
 
-        Ok(Inode {
+        let blocks_high = reader.read_u16::<LittleEndian>()?;
+        let file_acl_high = reader.read_u16::<LittleEndian>()?;
+        let uid_high = reader.read_u16::<LittleEndian>()?;
+        let gid_high = reader.read_u16::<LittleEndian>()?;
+        let checksum_lo = reader.read_u16::<LittleEndian>()?;
+        let osd2_reserved = reader.read_u16::<LittleEndian>()?;
+
+        let mut inode = Inode {
             mode,
             uid,
             size,
@@ -98,8 +172,134 @@ impl Inode {
             file_acl,
             dir_acl,
             faddr,
-            osd2,
-        })
+            blocks_high,
+            file_acl_high,
+            uid_high,
+            gid_high,
+            checksum_lo,
+            osd2_reserved,
+            ..Default::default()
+        };
+
+        if data.len() == EXT4_GOOD_OLD_INODE_SIZE {
+            return Ok(inode);
+        }
+
+        // Large inodes: only the fields covered by i_extra_isize are valid
+        inode.extra_isize = reader.read_u16::<LittleEndian>()?;
+        if !inode.extra_isize.is_multiple_of(4)
+            || EXT4_GOOD_OLD_INODE_SIZE + inode.extra_isize as usize > data.len()
+        {
+            return Err(Ext4Error::InvalidInode(format!(
+                "Invalid extra inode size: {}",
+                inode.extra_isize
+            )));
+        }
+
+        let mut extra = [0u8; EXT4_INODE_EXTRA_SIZE as usize];
+        let covered = std::cmp::min(inode.extra_isize, EXT4_INODE_EXTRA_SIZE) as usize;
+        let base = EXT4_GOOD_OLD_INODE_SIZE;
+        extra[..covered].copy_from_slice(&data[base..base + covered]);
+
+        let mut reader = std::io::Cursor::new(&extra[2..]);
+        let reader = &mut reader;
+        inode.checksum_hi = reader.read_u16::<LittleEndian>()?;
+        inode.ctime_extra = reader.read_u32::<LittleEndian>()?;
+        inode.mtime_extra = reader.read_u32::<LittleEndian>()?;
+        inode.atime_extra = reader.read_u32::<LittleEndian>()?;
+        inode.crtime = reader.read_u32::<LittleEndian>()?;
+        inode.crtime_extra = reader.read_u32::<LittleEndian>()?;
+        inode.version_hi = reader.read_u32::<LittleEndian>()?;
+        inode.projid = reader.read_u32::<LittleEndian>()?;
+
+        Ok(inode)
+    }
+
+    /// Serialize the inode: the first 128 bytes plus the known extra fields covered by `extra_isize`.
+    ///
+    /// Anything stored past those fields (such as in-inode extended attributes) is not
+    /// part of the result and must be left untouched on disk.
+    pub fn to_bytes(&self, inode_size: u32) -> Result<Vec<u8>, Ext4Error> {
+        let mut writer = Vec::with_capacity(EXT4_GOOD_OLD_INODE_SIZE + self.extra_isize as usize);
+
+        writer.write_u16::<LittleEndian>(self.mode)?;
+        writer.write_u16::<LittleEndian>(self.uid)?;
+        writer.write_u32::<LittleEndian>(self.size)?;
+        writer.write_u32::<LittleEndian>(self.atime)?;
+        writer.write_u32::<LittleEndian>(self.ctime)?;
+        writer.write_u32::<LittleEndian>(self.mtime)?;
+        writer.write_u32::<LittleEndian>(self.dtime)?;
+        writer.write_u16::<LittleEndian>(self.gid)?;
+        writer.write_u16::<LittleEndian>(self.links_count)?;
+        writer.write_u32::<LittleEndian>(self.blocks)?;
+        writer.write_u32::<LittleEndian>(self.flags)?;
+        writer.write_u32::<LittleEndian>(self.osd1)?;
+
+        for &ptr in &self.block {
+            writer.write_u32::<LittleEndian>(ptr)?;
+        }
+
+        writer.write_u32::<LittleEndian>(self.generation)?;
+        writer.write_u32::<LittleEndian>(self.file_acl)?;
+        writer.write_u32::<LittleEndian>(self.dir_acl)?;
+        writer.write_u32::<LittleEndian>(self.faddr)?;
+
+        writer.write_u16::<LittleEndian>(self.blocks_high)?;
+        writer.write_u16::<LittleEndian>(self.file_acl_high)?;
+        writer.write_u16::<LittleEndian>(self.uid_high)?;
+        writer.write_u16::<LittleEndian>(self.gid_high)?;
+        writer.write_u16::<LittleEndian>(self.checksum_lo)?;
+        writer.write_u16::<LittleEndian>(self.osd2_reserved)?;
+
+        if inode_size as usize <= EXT4_GOOD_OLD_INODE_SIZE {
+            return Ok(writer);
+        }
+
+        writer.write_u16::<LittleEndian>(self.extra_isize)?;
+        writer.write_u16::<LittleEndian>(self.checksum_hi)?;
+        writer.write_u32::<LittleEndian>(self.ctime_extra)?;
+        writer.write_u32::<LittleEndian>(self.mtime_extra)?;
+        writer.write_u32::<LittleEndian>(self.atime_extra)?;
+        writer.write_u32::<LittleEndian>(self.crtime)?;
+        writer.write_u32::<LittleEndian>(self.crtime_extra)?;
+        writer.write_u32::<LittleEndian>(self.version_hi)?;
+        writer.write_u32::<LittleEndian>(self.projid)?;
+
+        // Only the fields covered by i_extra_isize belong to the inode, but the size
+        // itself is always written so stale extra fields are never picked up again
+        let covered = std::cmp::min(self.extra_isize, EXT4_INODE_EXTRA_SIZE) as usize;
+        writer.truncate(EXT4_GOOD_OLD_INODE_SIZE + std::cmp::max(covered, 2));
+        Ok(writer)
+    }
+
+    /// Get the full owner's user ID.
+    pub fn get_uid(&self) -> u32 {
+        ((self.uid_high as u32) << 16) | self.uid as u32
+    }
+
+    /// Get the full group ID.
+    pub fn get_gid(&self) -> u32 {
+        ((self.gid_high as u32) << 16) | self.gid as u32
+    }
+
+    /// Get the full blocks count.
+    pub fn get_blocks(&self) -> u64 {
+        ((self.blocks_high as u64) << 32) | self.blocks as u64
+    }
+
+    /// Get the number of 512-byte sectors the inode owns, which `i_blocks` counts in
+    /// filesystem blocks instead when `EXT4_HUGE_FILE_FL` is set.
+    pub fn get_sectors(&self, block_size: u32) -> u64 {
+        if self.flags & EXT4_HUGE_FILE_FL != 0 {
+            self.get_blocks() * (block_size / 512) as u64
+        } else {
+            self.get_blocks()
+        }
+    }
+
+    /// Get the full extended attribute block.
+    pub fn get_file_acl(&self) -> u64 {
+        ((self.file_acl_high as u64) << 32) | self.file_acl as u64
     }
 
     /// Check if this inode represents a regular file.
@@ -117,6 +317,74 @@ impl Inode {
         (self.mode & 0xF000) == 0xA000
     }
 
+    /// Check if this inode is a symbolic link whose target is stored in `i_block`.
+    ///
+    /// Like the kernel, a symlink is fast when it owns no blocks besides its extended
+    /// attribute block.
+    pub fn is_fast_symlink(&self, block_size: u32) -> bool {
+        let xattr_blocks = if self.get_file_acl() != 0 {
+            block_size as u64 / 512
+        } else {
+            0
+        };
+        self.is_symlink() && !self.has_inline_data() && self.get_blocks() == xattr_blocks
+    }
+
+    /// Get the directory entry file type matching the inode mode.
+    pub fn file_type(&self) -> u8 {
+        match self.mode & 0xF000 {
+            0x8000 => 1,
+            0x4000 => 2,
+            0x2000 => 3,
+            0x6000 => 4,
+            0x1000 => 5,
+            0xC000 => 6,
+            0xA000 => 7,
+            _ => 0,
+        }
+    }
+
+    /// Check if this inode maps its blocks through an extent tree.
+    pub fn uses_extents(&self) -> bool {
+        (self.flags & EXT4_EXTENTS_FL) != 0
+    }
+
+    /// Check if this inode keeps its data inline rather than in blocks.
+    pub fn has_inline_data(&self) -> bool {
+        (self.flags & EXT4_INLINE_DATA_FL) != 0
+    }
+
+    /// Get the raw contents of `i_block` as bytes.
+    pub fn block_bytes(&self) -> [u8; 60] {
+        let mut bytes = [0u8; 60];
+        for (chunk, word) in bytes.chunks_exact_mut(4).zip(self.block.iter()) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Replace the contents of `i_block` with raw bytes.
+    pub fn set_block_bytes(&mut self, bytes: &[u8; 60]) {
+        for (word, chunk) in self.block.iter_mut().zip(bytes.chunks_exact(4)) {
+            *word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        }
+    }
+
+    /// Map a logical block of this inode to a physical block.
+    ///
+    /// Returns `None` for holes and for unwritten extents, both of which read as zeros.
+    pub fn map_block<R: Read + Seek>(&self, reader: &mut R, logical: u32, block_size: u32) -> Result<Option<u64>, Ext4Error> {
+        if self.uses_extents() {
+            let found = extent::find_extent(reader, &self.block_bytes(), logical, block_size)?;
+            return Ok(found
+                .filter(|e| !e.is_unwritten())
+                .map(|e| e.start + (logical - e.block) as u64));
+        }
+
+        let found = block_map::map_block(reader, &self.block, logical as u64, block_size)?;
+        Ok(found.map(|block| block as u64))
+    }
+
     /// Get the full size of the file in bytes.
     pub fn get_size(&self) -> u64 {
         if self.is_directory() {
@@ -125,29 +393,12 @@ impl Inode {
             ((self.dir_acl as u64) << 32) | (self.size as u64)
         }
     }
-}
 
-impl Default for Inode {
-    fn default() -> Self {
-        Inode {
-            mode: 0,
-            uid: 0,
-            size: 0,
-            atime: 0,
-            ctime: 0,
-            mtime: 0,
-            dtime: 0,
-            gid: 0,
-            links_count: 0,
-            blocks: 0,
-            flags: 0,
-            osd1: 0,
-            block: [0; 15],
-            generation: 0,
-            file_acl: 0,
-            dir_acl: 0,
-            faddr: 0,
-            osd2: [0; 12],
+    /// Set the full size of the file in bytes.
+    pub fn set_size(&mut self, size: u64) {
+        self.size = size as u32;
+        if !self.is_directory() {
+            self.dir_acl = (size >> 32) as u32;
         }
     }
-}
\ No newline at end of file
+}
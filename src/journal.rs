@@ -1,11 +1,166 @@
 //! Journal for ext4 filesystem.
 
-use std::io::{Read, Seek};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use std::collections::HashMap;
+use std::fs::File as StdFile;
+use std::io::{Read, Seek, SeekFrom, Write};
+use crate::checksum;
 use crate::error::Ext4Error;
+use crate::extent::Extent;
+use crate::inode::{Inode, EXT4_GOOD_OLD_INODE_SIZE};
+use crate::superblock::{format_uuid, IncompatFeature, Superblock};
 
 /// The magic number of an ext4 journal.
 const JBD2_MAGIC_NUMBER: u32 = 0xC03B3998;
 
+/// The size of the journal superblock in bytes.
+const JBD2_SUPERBLOCK_SIZE: usize = 1024;
+
+/// Offset of `s_checksum` in the journal superblock.
+const JBD2_SUPERBLOCK_CHECKSUM: usize = 0xFC;
+
+/// Offset of the `s_users` array in the journal superblock.
+const JBD2_SUPERBLOCK_USERS: usize = 0x100;
+
+/// The largest number of filesystems that may share an external journal.
+const JBD2_USERS_MAX: usize = 48;
+
+/// Journal block type: descriptor block.
+pub const JBD2_DESCRIPTOR_BLOCK: u32 = 1;
+/// Journal block type: commit block.
+pub const JBD2_COMMIT_BLOCK: u32 = 2;
+/// Journal block type: version 1 superblock.
+pub const JBD2_SUPERBLOCK_V1: u32 = 3;
+/// Journal block type: version 2 superblock.
+pub const JBD2_SUPERBLOCK_V2: u32 = 4;
+/// Journal block type: revoke block.
+pub const JBD2_REVOKE_BLOCK: u32 = 5;
+
+/// Block tag flag: the block started with the journal magic, which was zeroed in the log.
+pub const JBD2_FLAG_ESCAPE: u32 = 1;
+/// Block tag flag: the tag is not followed by a UUID.
+pub const JBD2_FLAG_SAME_UUID: u32 = 2;
+/// Block tag flag: this is the last tag in the descriptor block.
+pub const JBD2_FLAG_LAST_TAG: u32 = 8;
+
+/// The size of a journal block header.
+const JBD2_HEADER_SIZE: usize = 12;
+
+/// The size of the checksum tail of descriptor and revoke blocks.
+const JBD2_TAIL_SIZE: usize = 4;
+
+/// Offset of `h_chksum[0]` in a commit block.
+const JBD2_COMMIT_CHECKSUM: usize = 0x10;
+
+/// Offset of `h_commit_sec` in a commit block.
+const JBD2_COMMIT_SEC: usize = 0x30;
+
+/// Offset of `h_commit_nsec` in a commit block.
+const JBD2_COMMIT_NSEC: usize = 0x38;
+
+/// Offset of `s_sequence` in the journal superblock.
+const JBD2_SUPERBLOCK_SEQUENCE: usize = 0x18;
+
+/// Offset of `s_start` in the journal superblock.
+const JBD2_SUPERBLOCK_START: usize = 0x1C;
+
+/// Offset of `r_count` in a revoke block.
+const JBD2_REVOKE_COUNT: usize = 0xC;
+
+/// The number of fast-commit blocks when the superblock does not say.
+const JBD2_DEFAULT_FAST_COMMIT_BLOCKS: u32 = 256;
+
+/// Journal checksum type: CRC32C.
+pub const JBD2_CRC32C_CHKSUM: u8 = 4;
+
+/// Fast-commit tag: blocks added to an inode.
+pub const EXT4_FC_TAG_ADD_RANGE: u16 = 1;
+/// Fast-commit tag: blocks removed from an inode.
+pub const EXT4_FC_TAG_DEL_RANGE: u16 = 2;
+/// Fast-commit tag: a new inode linked into a directory.
+pub const EXT4_FC_TAG_CREAT: u16 = 3;
+/// Fast-commit tag: a directory entry added for an existing inode.
+pub const EXT4_FC_TAG_LINK: u16 = 4;
+/// Fast-commit tag: a directory entry removed.
+pub const EXT4_FC_TAG_UNLINK: u16 = 5;
+/// Fast-commit tag: a copy of an on-disk inode.
+pub const EXT4_FC_TAG_INODE: u16 = 6;
+/// Fast-commit tag: padding up to the end of the block.
+pub const EXT4_FC_TAG_PAD: u16 = 7;
+/// Fast-commit tag: the end of a fast commit, with its checksum.
+pub const EXT4_FC_TAG_TAIL: u16 = 8;
+/// Fast-commit tag: the start of the fast-commit area.
+pub const EXT4_FC_TAG_HEAD: u16 = 9;
+
+/// The size of a fast-commit tag header (`fc_tag` and `fc_len`).
+const EXT4_FC_TAG_BASE_LEN: usize = 4;
+
+/// The size of the parent and inode numbers before the name in a dentry tag.
+const EXT4_FC_DENTRY_INFO_LEN: usize = 8;
+
+/// The longest file name a directory entry can hold.
+const EXT4_NAME_LEN: usize = 255;
+
+/// Compatible journal features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalCompatFeature {
+    /// Commit blocks carry a checksum of the transaction (checksum v1).
+    Checksum = 0x1,
+}
+
+/// Incompatible journal features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JournalIncompatFeature {
+    /// The journal contains revoke blocks.
+    Revoke = 0x1,
+    /// Block tags hold 64-bit block numbers.
+    SixtyFourBit = 0x2,
+    /// Commit blocks may be written without waiting for the descriptor blocks.
+    AsyncCommit = 0x4,
+    /// Blocks carry crc32c checksums in 16-bit tags.
+    CsumV2 = 0x8,
+    /// Blocks carry crc32c checksums in 32-bit tags.
+    CsumV3 = 0x10,
+    /// A fast-commit area follows the regular log.
+    FastCommit = 0x20,
+}
+
+impl JournalCompatFeature {
+    /// Every known compatible journal feature.
+    pub const ALL: [JournalCompatFeature; 1] = [JournalCompatFeature::Checksum];
+
+    /// Get the feature name used by e2fsprogs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            JournalCompatFeature::Checksum => "journal_checksum",
+        }
+    }
+}
+
+impl JournalIncompatFeature {
+    /// Every known incompatible journal feature.
+    pub const ALL: [JournalIncompatFeature; 6] = [
+        JournalIncompatFeature::Revoke,
+        JournalIncompatFeature::SixtyFourBit,
+        JournalIncompatFeature::AsyncCommit,
+        JournalIncompatFeature::CsumV2,
+        JournalIncompatFeature::CsumV3,
+        JournalIncompatFeature::FastCommit,
+    ];
+
+    /// Get the feature name used by e2fsprogs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            JournalIncompatFeature::Revoke => "journal_incompat_revoke",
+            JournalIncompatFeature::SixtyFourBit => "journal_64bit",
+            JournalIncompatFeature::AsyncCommit => "journal_async_commit",
+            JournalIncompatFeature::CsumV2 => "journal_checksum_v2",
+            JournalIncompatFeature::CsumV3 => "journal_checksum_v3",
+            JournalIncompatFeature::FastCommit => "journal_fast_commit",
+        }
+    }
+}
+
 /// The journal superblock of an ext4 filesystem.
 #[derive(Debug, Clone)]
 pub struct JournalSuperblock {
@@ -23,8 +178,407 @@ pub struct JournalSuperblock {
     pub first: u32,
     /// First commit ID expected in log.
     pub sequence_id: u32,
-    /// Start of log. */
+    /// Start of log, or 0 if the journal is clean.
     pub start: u32,
+    /// Error value, as set by `jbd2_journal_abort()`.
+    pub errno: i32,
+    /// Compatible feature set flags (v2 only).
+    pub feature_compat: u32,
+    /// Incompatible feature set flags (v2 only).
+    pub feature_incompat: u32,
+    /// Read-only compatible feature set flags (v2 only).
+    pub feature_ro_compat: u32,
+    /// 128-bit UUID of the journal.
+    pub uuid: [u8; 16],
+    /// Number of filesystems sharing the journal.
+    pub nr_users: u32,
+    /// Location of a dynamic superblock copy.
+    pub dynsuper: u32,
+    /// Limit of journal blocks per transaction.
+    pub max_transaction: u32,
+    /// Limit of data blocks per transaction.
+    pub max_trans_data: u32,
+    /// Checksum algorithm used for the journal.
+    pub checksum_type: u8,
+    /// Number of fast-commit blocks.
+    pub num_fc_blocks: u32,
+    /// Block number of the head of the journal.
+    pub head: u32,
+    /// Checksum of the journal superblock.
+    pub checksum: u32,
+    /// UUIDs of the filesystems sharing the journal.
+    pub users: Vec<[u8; 16]>,
+}
+
+impl JournalSuperblock {
+    /// Parse a journal superblock.
+    pub fn parse(data: &[u8]) -> Result<Self, Ext4Error> {
+        if data.len() < JBD2_SUPERBLOCK_SIZE {
+            return Err(Ext4Error::InvalidJournal(
+                "Journal superblock truncated".to_string(),
+            ));
+        }
+
+        let be32 = |offset: usize| BigEndian::read_u32(&data[offset..offset + 4]);
+        let magic = be32(0x0);
+        if magic != JBD2_MAGIC_NUMBER {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "Invalid journal magic: {:#x}",
+                magic
+            )));
+        }
+
+        let block_type = be32(0x4);
+        if block_type != JBD2_SUPERBLOCK_V1 && block_type != JBD2_SUPERBLOCK_V2 {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "Unknown journal superblock type: {}",
+                block_type
+            )));
+        }
+
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&data[0x30..0x40]);
+        let mut superblock = JournalSuperblock {
+            magic,
+            block_type,
+            sequence: be32(0x8),
+            blocksize: be32(0xC),
+            maxlen: be32(0x10),
+            first: be32(0x14),
+            sequence_id: be32(0x18),
+            start: be32(0x1C),
+            errno: be32(0x20) as i32,
+            feature_compat: 0,
+            feature_incompat: 0,
+            feature_ro_compat: 0,
+            uuid,
+            nr_users: be32(0x40),
+            dynsuper: be32(0x44),
+            max_transaction: be32(0x48),
+            max_trans_data: be32(0x4C),
+            checksum_type: data[0x50],
+            num_fc_blocks: be32(0x54),
+            head: be32(0x58),
+            checksum: be32(JBD2_SUPERBLOCK_CHECKSUM),
+            users: Vec::new(),
+        };
+
+        // Version 1 superblocks have no feature fields
+        if block_type == JBD2_SUPERBLOCK_V2 {
+            superblock.feature_compat = be32(0x24);
+            superblock.feature_incompat = be32(0x28);
+            superblock.feature_ro_compat = be32(0x2C);
+        }
+
+        let users = std::cmp::min(superblock.nr_users as usize, JBD2_USERS_MAX);
+        for i in 0..users {
+            let offset = JBD2_SUPERBLOCK_USERS + i * 16;
+            let mut user = [0u8; 16];
+            user.copy_from_slice(&data[offset..offset + 16]);
+            superblock.users.push(user);
+        }
+
+        if superblock.first == 0 || superblock.first >= superblock.maxlen {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "Invalid log start {} for a journal of {} blocks",
+                superblock.first, superblock.maxlen
+            )));
+        }
+
+        Ok(superblock)
+    }
+
+    /// Check if a compatible feature is enabled.
+    pub fn has_compat(&self, feature: JournalCompatFeature) -> bool {
+        (self.feature_compat & feature as u32) != 0
+    }
+
+    /// Check if an incompatible feature is enabled.
+    pub fn has_incompat(&self, feature: JournalIncompatFeature) -> bool {
+        (self.feature_incompat & feature as u32) != 0
+    }
+
+    /// Get the names of every enabled journal feature, unknown ones included.
+    pub fn feature_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        let mut known = 0;
+        for feature in JournalCompatFeature::ALL {
+            known |= feature as u32;
+            if self.has_compat(feature) {
+                names.push(feature.name().to_string());
+            }
+        }
+        for bit in 0..32 {
+            if self.feature_compat & !known & (1 << bit) != 0 {
+                names.push(format!("FEATURE_C{}", bit));
+            }
+        }
+
+        let mut known = 0;
+        for feature in JournalIncompatFeature::ALL {
+            known |= feature as u32;
+            if self.has_incompat(feature) {
+                names.push(feature.name().to_string());
+            }
+        }
+        for bit in 0..32 {
+            if self.feature_incompat & !known & (1 << bit) != 0 {
+                names.push(format!("FEATURE_I{}", bit));
+            }
+        }
+        for bit in 0..32 {
+            if self.feature_ro_compat & (1 << bit) != 0 {
+                names.push(format!("FEATURE_R{}", bit));
+            }
+        }
+
+        names
+    }
+
+    /// Check if the journal blocks carry crc32c checksums (checksum v2 or v3).
+    pub fn has_csum_v2or3(&self) -> bool {
+        self.has_incompat(JournalIncompatFeature::CsumV2)
+            || self.has_incompat(JournalIncompatFeature::CsumV3)
+    }
+
+    /// Get the seed for journal block checksums, if the journal has them.
+    pub fn csum_seed(&self) -> Option<u32> {
+        if self.has_csum_v2or3() {
+            Some(checksum::uuid_seed(&self.uuid))
+        } else {
+            None
+        }
+    }
+
+    /// Compute the checksum of a raw journal superblock, ignoring its stored `s_checksum`.
+    pub fn compute_checksum(raw: &[u8]) -> u32 {
+        let crc = checksum::crc32c(!0, &raw[..JBD2_SUPERBLOCK_CHECKSUM]);
+        let crc = checksum::crc32c(crc, &[0; 4]);
+        checksum::crc32c(crc, &raw[JBD2_SUPERBLOCK_CHECKSUM + 4..JBD2_SUPERBLOCK_SIZE])
+    }
+
+    /// Check if the journal contains transactions that have not been checkpointed.
+    pub fn needs_recovery(&self) -> bool {
+        self.start != 0
+    }
+
+    /// Get the names of incompatible journal features we cannot replay.
+    pub fn unsupported_incompat_features(&self) -> Vec<String> {
+        let known = JournalIncompatFeature::ALL
+            .iter()
+            .fold(0, |mask, &feature| mask | feature as u32);
+        (0..32)
+            .filter(|bit| self.feature_incompat & !known & (1 << bit) != 0)
+            .map(|bit| format!("FEATURE_I{}", bit))
+            .collect()
+    }
+
+    /// Get the reason this crate cannot write transactions to the journal, if there is one.
+    pub fn unwritable_reason(&self) -> Option<String> {
+        let unsupported = self.unsupported_incompat_features();
+        if !unsupported.is_empty() {
+            return Some(format!(
+                "unsupported incompatible journal features: {}",
+                unsupported.join(", ")
+            ));
+        }
+        if self.has_compat(JournalCompatFeature::Checksum) && !self.has_csum_v2or3() {
+            return Some("the journal uses version 1 checksums".to_string());
+        }
+        None
+    }
+
+    /// Get the size in bytes of a block tag in descriptor blocks, excluding the UUID.
+    pub fn tag_bytes(&self) -> usize {
+        if self.has_incompat(JournalIncompatFeature::CsumV3) {
+            return 16;
+        }
+
+        let mut size = 12;
+        if self.has_incompat(JournalIncompatFeature::CsumV2) {
+            size += 2;
+        }
+        if self.has_incompat(JournalIncompatFeature::SixtyFourBit) {
+            size
+        } else {
+            size - 4
+        }
+    }
+
+    /// Get the journal block just past the regular log, where any fast-commit area starts.
+    pub fn log_end(&self) -> u32 {
+        if !self.has_incompat(JournalIncompatFeature::FastCommit) {
+            return self.maxlen;
+        }
+        let fc_blocks = match self.num_fc_blocks {
+            0 => JBD2_DEFAULT_FAST_COMMIT_BLOCKS,
+            blocks => blocks,
+        };
+        self.maxlen.saturating_sub(fc_blocks)
+    }
+}
+
+/// A block logged by a transaction.
+#[derive(Debug, Clone)]
+pub struct LoggedBlock {
+    /// The journal block holding the logged copy.
+    pub log_block: u64,
+    /// The filesystem block the copy belongs to.
+    pub home_block: u64,
+    /// The tag flags (`JBD2_FLAG_*`).
+    pub flags: u32,
+    /// Whether the checksum in the tag matches the logged copy, if the journal has checksums.
+    pub checksum_valid: Option<bool>,
+}
+
+/// A transaction found in the log.
+#[derive(Debug, Clone)]
+pub struct Transaction {
+    /// The transaction sequence number.
+    pub sequence: u32,
+    /// The journal block where the transaction starts.
+    pub start_block: u64,
+    /// The blocks logged by the transaction, in log order.
+    pub blocks: Vec<LoggedBlock>,
+    /// The filesystem blocks revoked by the transaction.
+    pub revoked: Vec<u64>,
+    /// Whether the transaction has a valid commit block.
+    pub committed: bool,
+    /// Commit time in seconds since the epoch.
+    pub commit_sec: u64,
+    /// Nanoseconds part of the commit time.
+    pub commit_nsec: u32,
+}
+
+/// A tag of the fast-commit area, describing one change made since the last full commit.
+#[derive(Debug, Clone)]
+pub enum FastCommitTag {
+    /// Blocks mapped into an inode.
+    AddRange { inode: u32, extent: Extent },
+    /// Logical blocks `block..block + len` unmapped from an inode.
+    DelRange { inode: u32, block: u32, len: u32 },
+    /// A new inode linked into a directory.
+    Create {
+        parent: u32,
+        inode: u32,
+        name: String,
+    },
+    /// An existing inode linked into a directory.
+    Link {
+        parent: u32,
+        inode: u32,
+        name: String,
+    },
+    /// A directory entry removed.
+    Unlink {
+        parent: u32,
+        inode: u32,
+        name: String,
+    },
+    /// The leading part of an on-disk inode.
+    Inode { inode: u32, raw: Vec<u8> },
+    /// Padding.
+    Pad,
+    /// The end of a fast commit.
+    Tail { tid: u32, crc: u32 },
+    /// The start of the fast-commit area.
+    Head { features: u32, tid: u32 },
+}
+
+impl FastCommitTag {
+    /// Parse the value of a fast-commit tag, or return `None` if its length is not valid.
+    fn parse(tag: u16, value: &[u8]) -> Option<Self> {
+        let le32 = |offset: usize| LittleEndian::read_u32(&value[offset..offset + 4]);
+        let dentry = || {
+            let name = &value[EXT4_FC_DENTRY_INFO_LEN..];
+            (le32(0), le32(4), String::from_utf8_lossy(name).to_string())
+        };
+        let dentry_len_valid = (EXT4_FC_DENTRY_INFO_LEN + 1
+            ..=EXT4_FC_DENTRY_INFO_LEN + EXT4_NAME_LEN)
+            .contains(&value.len());
+
+        match tag {
+            EXT4_FC_TAG_ADD_RANGE if value.len() == 16 => Some(FastCommitTag::AddRange {
+                inode: le32(0),
+                extent: Extent {
+                    block: le32(4),
+                    len: LittleEndian::read_u16(&value[8..10]),
+                    start: (LittleEndian::read_u16(&value[10..12]) as u64) << 32 | le32(12) as u64,
+                },
+            }),
+            EXT4_FC_TAG_DEL_RANGE if value.len() == 12 => Some(FastCommitTag::DelRange {
+                inode: le32(0),
+                block: le32(4),
+                len: le32(8),
+            }),
+            EXT4_FC_TAG_CREAT if dentry_len_valid => {
+                let (parent, inode, name) = dentry();
+                Some(FastCommitTag::Create {
+                    parent,
+                    inode,
+                    name,
+                })
+            }
+            EXT4_FC_TAG_LINK if dentry_len_valid => {
+                let (parent, inode, name) = dentry();
+                Some(FastCommitTag::Link {
+                    parent,
+                    inode,
+                    name,
+                })
+            }
+            EXT4_FC_TAG_UNLINK if dentry_len_valid => {
+                let (parent, inode, name) = dentry();
+                Some(FastCommitTag::Unlink {
+                    parent,
+                    inode,
+                    name,
+                })
+            }
+            EXT4_FC_TAG_INODE if value.len() >= 4 + EXT4_GOOD_OLD_INODE_SIZE => {
+                Some(FastCommitTag::Inode {
+                    inode: le32(0),
+                    raw: value[4..].to_vec(),
+                })
+            }
+            EXT4_FC_TAG_PAD => Some(FastCommitTag::Pad),
+            EXT4_FC_TAG_TAIL if value.len() >= 8 => Some(FastCommitTag::Tail {
+                tid: le32(0),
+                crc: le32(4),
+            }),
+            EXT4_FC_TAG_HEAD if value.len() == 8 => Some(FastCommitTag::Head {
+                features: le32(0),
+                tid: le32(4),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// The result of replaying the log.
+#[derive(Debug, Clone, Default)]
+pub struct Recovery {
+    /// The final contents of every filesystem block written by the log.
+    pub blocks: HashMap<u64, Vec<u8>>,
+    /// The number of committed transactions replayed.
+    pub transactions: usize,
+    /// The number of logged blocks skipped because they were revoked.
+    pub revoked: usize,
+    /// The sequence number the next transaction should use.
+    pub next_sequence: u32,
+    /// The fast commits to apply on top of the replayed blocks, in order.
+    pub fast_commits: Vec<FastCommitTag>,
+    /// The transaction the fast commits belong to, which never made it to the log.
+    pub fast_commit_sequence: u32,
+}
+
+/// Where the blocks of a journal are stored.
+#[derive(Debug, Clone)]
+pub enum JournalLocation {
+    /// The journal is a file in the filesystem, with the given inode number.
+    Inode(u32),
+    /// The journal is on an external device with the given UUID.
+    External([u8; 16]),
 }
 
 /// The journal of an ext4 filesystem.
@@ -32,24 +586,738 @@ pub struct JournalSuperblock {
 pub struct Journal {
     /// The journal superblock.
     pub superblock: JournalSuperblock,
+    /// Where the journal is stored.
+    pub location: JournalLocation,
+    /// The journal inode, mapping journal blocks for internal journals.
+    inode: Option<Inode>,
+    /// The block size of the journal.
+    block_size: u32,
+    /// The journal block holding the journal superblock.
+    sb_block: u64,
+    /// The raw journal superblock, as read from disk.
+    raw_superblock: Vec<u8>,
 }
 
 impl Journal {
-    /// Read a journal from a reader.
-    pub fn read<R: Read + Seek>(_reader: &mut R, _journal_inode: u32, block_size: u32) -> Result<Self, Ext4Error> {
-        // TODO: Implement reading the journal from the journal inode
-        // For now, we'll just create a dummy journal
-        let superblock = JournalSuperblock {
-            magic: JBD2_MAGIC_NUMBER,
-            block_type: 0,
-            sequence: 0,
-            blocksize: block_size,
-            maxlen: 0,
-            first: 0,
-            sequence_id: 0,
-            start: 0,
-        };
-
-        Ok(Journal { superblock })
-    }
-}
\ No newline at end of file
+    /// Read the journal stored in an inode of the filesystem.
+    pub fn read<R: Read + Seek>(
+        reader: &mut R,
+        inode_num: u32,
+        inode: Inode,
+        block_size: u32,
+    ) -> Result<Self, Ext4Error> {
+        // The log must fit in the journal inode
+        let inode_blocks = inode.get_size() / block_size as u64;
+        let journal = Self::open(
+            reader,
+            JournalLocation::Inode(inode_num),
+            Some(inode),
+            block_size,
+            0,
+        )?;
+        if journal.superblock.maxlen as u64 > inode_blocks {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "Journal of {} blocks does not fit in inode {} of {} blocks",
+                journal.superblock.maxlen, inode_num, inode_blocks
+            )));
+        }
+
+        Ok(journal)
+    }
+
+    /// Read an external journal from its device.
+    ///
+    /// The device must be a journal device whose UUID matches `superblock.journal_uuid`, and
+    /// the filesystem must be listed among the users of the journal.
+    pub fn read_external<R: Read + Seek>(
+        reader: &mut R,
+        superblock: &Superblock,
+    ) -> Result<Self, Ext4Error> {
+        let device = Superblock::read(reader)?;
+        if !device.has_incompat(IncompatFeature::JournalDev) {
+            return Err(Ext4Error::InvalidJournal(
+                "External journal device is not a journal device".to_string(),
+            ));
+        }
+        if device.uuid != superblock.journal_uuid {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "External journal has UUID {}, expected {}",
+                device.uuid_string(),
+                format_uuid(&superblock.journal_uuid)
+            )));
+        }
+
+        // The journal superblock follows the ext4 superblock of the device
+        let journal = Self::open(
+            reader,
+            JournalLocation::External(device.uuid),
+            None,
+            superblock.block_size(),
+            device.first_data_block as u64 + 1,
+        )?;
+        if !journal.superblock.users.contains(&superblock.uuid) {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "Filesystem {} is not a user of the external journal",
+                superblock.uuid_string()
+            )));
+        }
+        if journal.superblock.maxlen as u64 > device.blocks_count_64() {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "Journal of {} blocks does not fit on a device of {} blocks",
+                journal.superblock.maxlen,
+                device.blocks_count_64()
+            )));
+        }
+
+        Ok(journal)
+    }
+
+    /// Read and validate the journal superblock stored in journal block `sb_block`.
+    fn open<R: Read + Seek>(
+        reader: &mut R,
+        location: JournalLocation,
+        inode: Option<Inode>,
+        block_size: u32,
+        sb_block: u64,
+    ) -> Result<Self, Ext4Error> {
+        let offset = Self::map_block(inode.as_ref(), reader, sb_block, block_size)?;
+        let mut data = vec![0u8; JBD2_SUPERBLOCK_SIZE];
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut data)?;
+        let superblock = JournalSuperblock::parse(&data)?;
+
+        if superblock.blocksize != block_size {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "Journal block size {} does not match filesystem block size {}",
+                superblock.blocksize, block_size
+            )));
+        }
+        if superblock.has_csum_v2or3() && superblock.checksum_type != JBD2_CRC32C_CHKSUM {
+            return Err(Ext4Error::InvalidJournal(format!(
+                "Unknown journal checksum type {}",
+                superblock.checksum_type
+            )));
+        }
+
+        Ok(Journal {
+            superblock,
+            location,
+            inode,
+            block_size,
+            sb_block,
+            raw_superblock: data,
+        })
+    }
+
+    /// Get the block size of the journal.
+    pub fn block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    /// Get the stored and computed checksums of the journal superblock, if it has one.
+    pub fn superblock_checksums(&self) -> Option<(u32, u32)> {
+        if !self.superblock.has_csum_v2or3() {
+            return None;
+        }
+        Some((
+            self.superblock.checksum,
+            JournalSuperblock::compute_checksum(&self.raw_superblock),
+        ))
+    }
+
+    /// Get the byte offset of a journal block on the device holding the journal.
+    pub fn block_offset<R: Read + Seek>(&self, reader: &mut R, block: u64) -> Result<u64, Ext4Error> {
+        Self::map_block(self.inode.as_ref(), reader, block, self.block_size)
+    }
+
+    /// Map a journal block through the journal inode, if there is one.
+    fn map_block<R: Read + Seek>(
+        inode: Option<&Inode>,
+        reader: &mut R,
+        block: u64,
+        block_size: u32,
+    ) -> Result<u64, Ext4Error> {
+        let physical = match inode {
+            Some(inode) => {
+                let logical = u32::try_from(block).map_err(|_| {
+                    Ext4Error::InvalidJournal(format!("Journal block {} out of range", block))
+                })?;
+                inode.map_block(reader, logical, block_size)?.ok_or_else(|| {
+                    Ext4Error::InvalidJournal(format!("Journal block {} is not mapped", block))
+                })?
+            }
+            None => block,
+        };
+
+        Ok(physical * block_size as u64)
+    }
+
+    /// Read a journal block.
+    pub fn read_block<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        block: u64,
+        buffer: &mut [u8],
+    ) -> Result<(), Ext4Error> {
+        let offset = self.block_offset(reader, block)?;
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(buffer)?;
+        Ok(())
+    }
+
+    /// Read the logged copy of a block, restoring the magic number of escaped blocks.
+    pub fn read_logged_block<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        logged: &LoggedBlock,
+    ) -> Result<Vec<u8>, Ext4Error> {
+        let mut data = vec![0u8; self.block_size as usize];
+        self.read_block(reader, logged.log_block, &mut data)?;
+        if logged.flags & JBD2_FLAG_ESCAPE != 0 {
+            BigEndian::write_u32(&mut data[0..4], JBD2_MAGIC_NUMBER);
+        }
+        Ok(data)
+    }
+
+    /// Get the journal block following `block` in the circular log.
+    fn next_log_block(&self, block: u64) -> u64 {
+        if block + 1 >= self.superblock.log_end() as u64 {
+            self.superblock.first as u64
+        } else {
+            block + 1
+        }
+    }
+
+    /// Check the checksum tail of a descriptor or revoke block.
+    fn tail_checksum_valid(&self, block: &[u8]) -> bool {
+        let seed = match self.superblock.csum_seed() {
+            Some(seed) => seed,
+            None => return true,
+        };
+        let tail = block.len() - JBD2_TAIL_SIZE;
+        let crc = checksum::crc32c(seed, &block[..tail]);
+        let crc = checksum::crc32c(crc, &[0; JBD2_TAIL_SIZE]);
+        crc == BigEndian::read_u32(&block[tail..])
+    }
+
+    /// Check the checksum of a commit block.
+    fn commit_checksum_valid(&self, block: &[u8]) -> bool {
+        let seed = match self.superblock.csum_seed() {
+            Some(seed) => seed,
+            None => return true,
+        };
+        let crc = checksum::crc32c(seed, &block[..JBD2_COMMIT_CHECKSUM]);
+        let crc = checksum::crc32c(crc, &[0; 4]);
+        let crc = checksum::crc32c(crc, &block[JBD2_COMMIT_CHECKSUM + 4..]);
+        crc == BigEndian::read_u32(&block[JBD2_COMMIT_CHECKSUM..JBD2_COMMIT_CHECKSUM + 4])
+    }
+
+    /// Check the checksum a descriptor tag holds for a logged block.
+    fn block_checksum_valid(&self, sequence: u32, stored: u32, data: &[u8]) -> Option<bool> {
+        self.block_checksum(sequence, data).map(|crc| crc == stored)
+    }
+
+    /// Compute the checksum a descriptor tag holds for a logged block, truncated to the
+    /// width of the tag field.
+    fn block_checksum(&self, sequence: u32, data: &[u8]) -> Option<u32> {
+        let seed = self.superblock.csum_seed()?;
+        let crc = checksum::crc32c(seed, &sequence.to_be_bytes());
+        let crc = checksum::crc32c(crc, data);
+        if self.superblock.has_incompat(JournalIncompatFeature::CsumV3) {
+            Some(crc)
+        } else {
+            Some(crc as u16 as u32)
+        }
+    }
+
+    /// Parse the block tags of a descriptor block.
+    ///
+    /// Returns the home block, flags and stored checksum of every tag.
+    fn parse_tags(&self, block: &[u8]) -> Vec<(u64, u32, u32)> {
+        let sb = &self.superblock;
+        let tag_bytes = sb.tag_bytes();
+        let csum_v3 = sb.has_incompat(JournalIncompatFeature::CsumV3);
+        let sixty_four = sb.has_incompat(JournalIncompatFeature::SixtyFourBit);
+        let end = if sb.has_csum_v2or3() {
+            block.len() - JBD2_TAIL_SIZE
+        } else {
+            block.len()
+        };
+
+        let mut tags = Vec::new();
+        let mut offset = JBD2_HEADER_SIZE;
+        while offset + tag_bytes <= end {
+            let tag = &block[offset..offset + tag_bytes];
+            let mut home = BigEndian::read_u32(&tag[0..4]) as u64;
+            let (flags, stored) = if csum_v3 {
+                (BigEndian::read_u32(&tag[4..8]), BigEndian::read_u32(&tag[12..16]))
+            } else {
+                (
+                    BigEndian::read_u16(&tag[6..8]) as u32,
+                    BigEndian::read_u16(&tag[4..6]) as u32,
+                )
+            };
+            if sixty_four {
+                home |= (BigEndian::read_u32(&tag[8..12]) as u64) << 32;
+            }
+            tags.push((home, flags, stored));
+
+            offset += tag_bytes;
+            if flags & JBD2_FLAG_SAME_UUID == 0 {
+                offset += 16;
+            }
+            if flags & JBD2_FLAG_LAST_TAG != 0 {
+                break;
+            }
+        }
+
+        tags
+    }
+
+    /// Parse the records of a revoke block.
+    fn parse_revoke(&self, block: &[u8]) -> Vec<u64> {
+        let record_size = if self.superblock.has_incompat(JournalIncompatFeature::SixtyFourBit) {
+            8
+        } else {
+            4
+        };
+        let count = BigEndian::read_u32(&block[JBD2_REVOKE_COUNT..JBD2_REVOKE_COUNT + 4]) as usize;
+        let end = std::cmp::min(count, block.len());
+
+        let mut records = Vec::new();
+        let mut offset = JBD2_REVOKE_COUNT + 4;
+        while offset + record_size <= end {
+            records.push(if record_size == 8 {
+                BigEndian::read_u64(&block[offset..offset + 8])
+            } else {
+                BigEndian::read_u32(&block[offset..offset + 4]) as u64
+            });
+            offset += record_size;
+        }
+
+        records
+    }
+
+    /// Walk the log from `s_start` and collect every transaction in it (the scan pass).
+    ///
+    /// The walk stops at the first block that does not belong to the expected transaction,
+    /// or that fails its checksum. The last transaction returned may be uncommitted.
+    pub fn scan<R: Read + Seek>(&self, reader: &mut R) -> Result<Vec<Transaction>, Ext4Error> {
+        let sb = &self.superblock;
+        let mut transactions: Vec<Transaction> = Vec::new();
+        if !sb.needs_recovery() {
+            return Ok(transactions);
+        }
+
+        let unsupported = sb.unsupported_incompat_features();
+        if !unsupported.is_empty() {
+            return Err(Ext4Error::UnsupportedFeature(format!(
+                "cannot replay journal with incompatible features: {}",
+                unsupported.join(", ")
+            )));
+        }
+
+        let mut sequence = sb.sequence_id;
+        let mut block_num = sb.start as u64;
+        let mut block = vec![0u8; self.block_size as usize];
+        let mut data = vec![0u8; self.block_size as usize];
+        let mut current: Option<Transaction> = None;
+
+        // Never walk more than one lap around the log
+        for _ in 0..sb.log_end() {
+            self.read_block(reader, block_num, &mut block)?;
+            let magic = BigEndian::read_u32(&block[0..4]);
+            let block_type = BigEndian::read_u32(&block[4..8]);
+            if magic != JBD2_MAGIC_NUMBER || BigEndian::read_u32(&block[8..12]) != sequence {
+                break;
+            }
+
+            let transaction = current.get_or_insert_with(|| Transaction {
+                sequence,
+                start_block: block_num,
+                blocks: Vec::new(),
+                revoked: Vec::new(),
+                committed: false,
+                commit_sec: 0,
+                commit_nsec: 0,
+            });
+
+            match block_type {
+                JBD2_DESCRIPTOR_BLOCK => {
+                    if !self.tail_checksum_valid(&block) {
+                        log::warn!("Journal descriptor block {} has a bad checksum", block_num);
+                        break;
+                    }
+                    for (home_block, flags, stored) in self.parse_tags(&block) {
+                        block_num = self.next_log_block(block_num);
+                        self.read_block(reader, block_num, &mut data)?;
+                        transaction.blocks.push(LoggedBlock {
+                            log_block: block_num,
+                            home_block,
+                            flags,
+                            checksum_valid: self.block_checksum_valid(sequence, stored, &data),
+                        });
+                    }
+                }
+                JBD2_COMMIT_BLOCK => {
+                    if !self.commit_checksum_valid(&block) {
+                        log::warn!("Journal commit block {} has a bad checksum", block_num);
+                        break;
+                    }
+                    transaction.committed = true;
+                    transaction.commit_sec =
+                        BigEndian::read_u64(&block[JBD2_COMMIT_SEC..JBD2_COMMIT_SEC + 8]);
+                    transaction.commit_nsec =
+                        BigEndian::read_u32(&block[JBD2_COMMIT_NSEC..JBD2_COMMIT_NSEC + 4]);
+                    transactions.extend(current.take());
+                    sequence = sequence.wrapping_add(1);
+                }
+                JBD2_REVOKE_BLOCK => {
+                    if !self.tail_checksum_valid(&block) {
+                        log::warn!("Journal revoke block {} has a bad checksum", block_num);
+                        break;
+                    }
+                    let records = self.parse_revoke(&block);
+                    transaction.revoked.extend(records);
+                }
+                _ => break,
+            }
+
+            block_num = self.next_log_block(block_num);
+        }
+
+        // Keep the trailing uncommitted transaction for inspection
+        transactions.extend(current);
+        Ok(transactions)
+    }
+
+    /// Replay the committed transactions of the log (the revoke and replay passes).
+    ///
+    /// Nothing is written: the returned recovery holds the final contents of every block
+    /// the log rewrites, for the caller to write home or keep in memory.
+    pub fn recover<R: Read + Seek>(&self, reader: &mut R) -> Result<Recovery, Ext4Error> {
+        let transactions = self.scan(reader)?;
+        let committed: Vec<&Transaction> = transactions.iter().filter(|t| t.committed).collect();
+
+        // A revoke record cancels logged copies of the block from its transaction and earlier ones
+        let mut revoked: HashMap<u64, u32> = HashMap::new();
+        for transaction in &committed {
+            for &block in &transaction.revoked {
+                let entry = revoked.entry(block).or_insert(transaction.sequence);
+                if transaction.sequence.wrapping_sub(*entry) as i32 > 0 {
+                    *entry = transaction.sequence;
+                }
+            }
+        }
+
+        // Like the kernel, skip a sequence number so a torn transaction is never mistaken for
+        // the next one
+        let mut recovery = Recovery {
+            next_sequence: self.superblock.sequence_id.wrapping_add(1),
+            ..Default::default()
+        };
+        for transaction in &committed {
+            for logged in &transaction.blocks {
+                if let Some(&revoked_in) = revoked.get(&logged.home_block) {
+                    if revoked_in.wrapping_sub(transaction.sequence) as i32 >= 0 {
+                        recovery.revoked += 1;
+                        continue;
+                    }
+                }
+                if logged.checksum_valid == Some(false) {
+                    log::warn!(
+                        "Skipping journal block {} for block {}: bad checksum",
+                        logged.log_block, logged.home_block
+                    );
+                    continue;
+                }
+
+                let data = self.read_logged_block(reader, logged)?;
+                recovery.blocks.insert(logged.home_block, data);
+            }
+            recovery.transactions += 1;
+            recovery.next_sequence = transaction.sequence.wrapping_add(2);
+        }
+
+        // Fast commits extend the transaction that was running after the last full commit
+        if self.superblock.needs_recovery() {
+            let sequence = committed
+                .last()
+                .map_or(self.superblock.sequence_id, |t| t.sequence.wrapping_add(1));
+            recovery.fast_commits = self.scan_fast_commits(reader, sequence)?;
+            recovery.fast_commit_sequence = sequence;
+        }
+
+        Ok(recovery)
+    }
+
+    /// Read the fast-commit area and collect the fast commits made during transaction
+    /// `sequence` (the fast-commit scan pass).
+    ///
+    /// Only tags covered by a tail with the right transaction ID and checksum are returned.
+    /// The scan stops at the first malformed tag or the first tag of another transaction.
+    pub fn scan_fast_commits<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+        sequence: u32,
+    ) -> Result<Vec<FastCommitTag>, Ext4Error> {
+        let sb = &self.superblock;
+        let mut tags = Vec::new();
+        if !sb.has_incompat(JournalIncompatFeature::FastCommit) {
+            return Ok(tags);
+        }
+
+        // Tags only count once a valid tail follows them; the checksum restarts after each tail
+        let mut pending = Vec::new();
+        let mut crc = 0;
+        let first = sb.log_end() as u64 + 1;
+        let mut block = vec![0u8; self.block_size as usize];
+        'blocks: for block_num in first..sb.maxlen as u64 {
+            self.read_block(reader, block_num, &mut block)?;
+
+            let mut offset = 0;
+            while offset + EXT4_FC_TAG_BASE_LEN <= block.len() {
+                let tag = LittleEndian::read_u16(&block[offset..offset + 2]);
+                let len = LittleEndian::read_u16(&block[offset + 2..offset + 4]) as usize;
+                let value_start = offset + EXT4_FC_TAG_BASE_LEN;
+                if len > block.len() - value_start
+                    || (block_num == first && offset == 0 && tag != EXT4_FC_TAG_HEAD)
+                {
+                    break 'blocks;
+                }
+                let parsed = match FastCommitTag::parse(tag, &block[value_start..value_start + len])
+                {
+                    Some(parsed) => parsed,
+                    None => break 'blocks,
+                };
+
+                match parsed {
+                    FastCommitTag::Tail { tid, crc: stored } => {
+                        // The checksum covers the tail up to its own crc field
+                        crc = checksum::crc32c(crc, &block[offset..value_start + 4]);
+                        if tid != sequence || stored != crc {
+                            break 'blocks;
+                        }
+                        pending.push(parsed);
+                        tags.append(&mut pending);
+                        crc = 0;
+                    }
+                    FastCommitTag::Head { features, tid } => {
+                        if features != 0 {
+                            return Err(Ext4Error::UnsupportedFeature(format!(
+                                "cannot replay fast commits with features {:#x}",
+                                features
+                            )));
+                        }
+                        if tid != sequence {
+                            break 'blocks;
+                        }
+                        crc = checksum::crc32c(crc, &block[offset..value_start + len]);
+                        pending.push(parsed);
+                    }
+                    _ => {
+                        crc = checksum::crc32c(crc, &block[offset..value_start + len]);
+                        pending.push(parsed);
+                    }
+                }
+                offset = value_start + len;
+            }
+        }
+
+        Ok(tags)
+    }
+
+    /// Mark the log empty, so the next transaction uses `next_sequence`.
+    pub fn mark_clean<F: Read + Write + Seek>(
+        &mut self,
+        file: &mut F,
+        next_sequence: u32,
+    ) -> Result<(), Ext4Error> {
+        self.write_log_tail(file, next_sequence, 0)
+    }
+
+    /// Point the journal superblock at the oldest transaction in the log, or mark the log
+    /// empty when `start` is 0.
+    fn write_log_tail<F: Read + Write + Seek>(
+        &mut self,
+        file: &mut F,
+        sequence: u32,
+        start: u32,
+    ) -> Result<(), Ext4Error> {
+        let raw = &mut self.raw_superblock;
+        BigEndian::write_u32(
+            &mut raw[JBD2_SUPERBLOCK_SEQUENCE..JBD2_SUPERBLOCK_SEQUENCE + 4],
+            sequence,
+        );
+        BigEndian::write_u32(&mut raw[JBD2_SUPERBLOCK_START..JBD2_SUPERBLOCK_START + 4], start);
+        if self.superblock.has_csum_v2or3() {
+            let crc = JournalSuperblock::compute_checksum(raw);
+            BigEndian::write_u32(
+                &mut raw[JBD2_SUPERBLOCK_CHECKSUM..JBD2_SUPERBLOCK_CHECKSUM + 4],
+                crc,
+            );
+            self.superblock.checksum = crc;
+        }
+        self.superblock.sequence_id = sequence;
+        self.superblock.start = start;
+
+        let offset = self.block_offset(file, self.sb_block)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&self.raw_superblock)?;
+        Ok(())
+    }
+
+    /// Get how many block tags fit in one descriptor block.
+    fn tags_per_descriptor(&self) -> usize {
+        let mut space = self.block_size as usize - JBD2_HEADER_SIZE;
+        if self.superblock.has_csum_v2or3() {
+            space -= JBD2_TAIL_SIZE;
+        }
+        // The first tag of each descriptor is followed by the journal UUID
+        (space - 16) / self.superblock.tag_bytes()
+    }
+
+    /// Get the largest number of blocks a single transaction can log.
+    pub fn max_transaction_blocks(&self) -> usize {
+        let sb = &self.superblock;
+        // Leave room for the commit block; every run of tags needs its descriptor
+        let space = (sb.log_end() as usize).saturating_sub(sb.first as usize + 1);
+        let per_run = self.tags_per_descriptor() + 1;
+        space - space.div_ceil(per_run)
+    }
+
+    /// Build an empty journal block starting with a block header.
+    fn new_block(&self, block_type: u32, sequence: u32) -> Vec<u8> {
+        let mut block = vec![0u8; self.block_size as usize];
+        BigEndian::write_u32(&mut block[0..4], JBD2_MAGIC_NUMBER);
+        BigEndian::write_u32(&mut block[4..8], block_type);
+        BigEndian::write_u32(&mut block[8..12], sequence);
+        block
+    }
+
+    /// Write a descriptor tag for `home_block`.
+    fn write_tag(&self, tag: &mut [u8], home_block: u64, flags: u32, csum: u32) {
+        BigEndian::write_u32(&mut tag[0..4], home_block as u32);
+        if self.superblock.has_incompat(JournalIncompatFeature::CsumV3) {
+            BigEndian::write_u32(&mut tag[4..8], flags);
+            BigEndian::write_u32(&mut tag[12..16], csum);
+        } else {
+            BigEndian::write_u16(&mut tag[4..6], csum as u16);
+            BigEndian::write_u16(&mut tag[6..8], flags as u16);
+        }
+        if self.superblock.has_incompat(JournalIncompatFeature::SixtyFourBit) {
+            BigEndian::write_u32(&mut tag[8..12], (home_block >> 32) as u32);
+        }
+    }
+
+    /// Write a journal block.
+    fn write_block<F: Read + Write + Seek>(
+        &self,
+        file: &mut F,
+        block: u64,
+        data: &[u8],
+    ) -> Result<(), Ext4Error> {
+        let offset = self.block_offset(file, block)?;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)?;
+        Ok(())
+    }
+
+    /// Log `blocks` as one transaction and commit it.
+    ///
+    /// The log must be empty. The transaction is written from the start of the log, and the
+    /// device is synced before the commit block so it never reaches the disk ahead of the
+    /// blocks it commits. Nothing is written home: the caller checkpoints the blocks and then
+    /// calls [`Journal::mark_clean`].
+    ///
+    /// Returns the sequence number of the transaction.
+    pub fn write_transaction(
+        &mut self,
+        file: &mut StdFile,
+        blocks: &[(u64, Vec<u8>)],
+    ) -> Result<u32, Ext4Error> {
+        if blocks.len() > self.max_transaction_blocks() {
+            return Err(Ext4Error::NoSpace(format!(
+                "Transaction of {} blocks does not fit in the journal",
+                blocks.len()
+            )));
+        }
+        if !self.superblock.has_incompat(JournalIncompatFeature::SixtyFourBit) {
+            if let Some((home_block, _)) = blocks.iter().find(|(block, _)| *block > u32::MAX as u64) {
+                return Err(Ext4Error::InvalidJournal(format!(
+                    "Block {} cannot be logged without the 64bit journal feature",
+                    home_block
+                )));
+            }
+        }
+
+        // Point the log at the new transaction before any of it is written
+        let sequence = self.superblock.sequence_id;
+        let first = self.superblock.first;
+        self.write_log_tail(file, sequence, first)?;
+        file.sync_data()?;
+
+        let tag_bytes = self.superblock.tag_bytes();
+        let mut log_block = first as u64;
+        for run in blocks.chunks(self.tags_per_descriptor()) {
+            let descriptor_block = log_block;
+            let mut descriptor = self.new_block(JBD2_DESCRIPTOR_BLOCK, sequence);
+            let mut offset = JBD2_HEADER_SIZE;
+
+            for (i, (home_block, data)) in run.iter().enumerate() {
+                log_block += 1;
+
+                // Blocks that look like journal metadata are logged with their magic cleared
+                let mut data = data.clone();
+                let mut flags = 0;
+                if BigEndian::read_u32(&data[0..4]) == JBD2_MAGIC_NUMBER {
+                    BigEndian::write_u32(&mut data[0..4], 0);
+                    flags |= JBD2_FLAG_ESCAPE;
+                }
+                if i > 0 {
+                    flags |= JBD2_FLAG_SAME_UUID;
+                }
+                if i == run.len() - 1 {
+                    flags |= JBD2_FLAG_LAST_TAG;
+                }
+
+                let csum = self.block_checksum(sequence, &data).unwrap_or(0);
+                self.write_tag(&mut descriptor[offset..offset + tag_bytes], *home_block, flags, csum);
+                offset += tag_bytes;
+                if i == 0 {
+                    descriptor[offset..offset + 16].copy_from_slice(&self.superblock.uuid);
+                    offset += 16;
+                }
+                self.write_block(file, log_block, &data)?;
+            }
+
+            if let Some(seed) = self.superblock.csum_seed() {
+                let tail = descriptor.len() - JBD2_TAIL_SIZE;
+                let crc = checksum::crc32c(seed, &descriptor);
+                BigEndian::write_u32(&mut descriptor[tail..], crc);
+            }
+            self.write_block(file, descriptor_block, &descriptor)?;
+            log_block += 1;
+        }
+        file.sync_data()?;
+
+        // The transaction only counts once its commit block is on disk
+        let mut commit = self.new_block(JBD2_COMMIT_BLOCK, sequence);
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        BigEndian::write_u64(&mut commit[JBD2_COMMIT_SEC..JBD2_COMMIT_SEC + 8], now.as_secs());
+        BigEndian::write_u32(
+            &mut commit[JBD2_COMMIT_NSEC..JBD2_COMMIT_NSEC + 4],
+            now.subsec_nanos(),
+        );
+        if let Some(seed) = self.superblock.csum_seed() {
+            let crc = checksum::crc32c(seed, &commit);
+            BigEndian::write_u32(&mut commit[JBD2_COMMIT_CHECKSUM..JBD2_COMMIT_CHECKSUM + 4], crc);
+        }
+        self.write_block(file, log_block, &commit)?;
+        file.sync_data()?;
+
+        Ok(sequence)
+    }
+}
@@ -1,25 +1,55 @@
 //! A Rust implementation of the ext4 filesystem.
 
 mod block_group;
+mod block_map;
+mod checksum;
 mod directory;
 mod error;
+mod extent;
 mod file;
+mod htree;
 mod inode;
 mod journal;
+mod overlay;
 mod superblock;
+mod xattr;
 
+#[cfg(test)]
+mod tests;
+
+use byteorder::ByteOrder;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
 use std::fs::File as StdFile;
 use std::io::{Read, Seek, SeekFrom, Write};
 
 pub use block_group::BlockGroup;
-use byteorder::ReadBytesExt;
+pub use checksum::ChecksumPolicy;
 pub use byteorder::{LittleEndian, WriteBytesExt};
-pub use directory::Directory;
+pub use directory::{Directory, DirectoryBlock, DirectoryRecord, InlineDirectory};
 pub use error::Ext4Error;
+pub use extent::{Extent, ExtentHeader, ExtentIndex, ExtentNode};
 pub use file::File;
 pub use inode::Inode;
-pub use journal::Journal;
-pub use superblock::Superblock;
+pub use journal::{
+    FastCommitTag, Journal, JournalCompatFeature, JournalIncompatFeature, JournalLocation,
+    JournalSuperblock, LoggedBlock, Recovery, Transaction,
+};
+pub use superblock::{format_uuid, CompatFeature, IncompatFeature, RoCompatFeature, Superblock};
+
+use block_group::{EXT4_BG_BLOCK_UNINIT, EXT4_BG_INODE_UNINIT, EXT4_MIN_DESC_SIZE_64BIT};
+use htree::{DxEntry, DxFrame, DxNode, DxRoot, HashVersion};
+use inode::{
+    EXT4_EXTENTS_FL, EXT4_GOOD_OLD_INODE_SIZE, EXT4_HUGE_FILE_FL, EXT4_INDEX_FL,
+    EXT4_INLINE_DATA_FL, EXT4_INODE_EXTRA_SIZE, EXT4_LINK_MAX, INODE_BLOCK_OFFSET,
+    INODE_BLOCK_SIZE,
+};
+use overlay::{BlockOverlay, OverlayReader};
+use superblock::{EXT2_FLAGS_UNSIGNED_HASH, SUPERBLOCK_OFFSET};
+use xattr::{InodeXattrs, EXT4_XATTR_INDEX_SYSTEM, EXT4_XATTR_MAGIC, EXT4_XATTR_SYSTEM_DATA};
+
+/// The most symbolic links followed while resolving a path, like the kernel's `MAXSYMLINKS`.
+const MAX_SYMLINK_FOLLOWS: u32 = 40;
 
 /// The main struct representing an ext4 filesystem.
 pub struct Ext4Filesystem {
@@ -29,13 +59,78 @@ pub struct Ext4Filesystem {
     block_groups: Vec<BlockGroup>,
     /// The journal of the filesystem.
     journal: Option<Journal>,
+    /// The file handle for an external journal device.
+    journal_file: Option<StdFile>,
     /// The file handle for the filesystem.
     file: StdFile,
+    /// Blocks held in memory in place of their on-disk contents.
+    overlay: BlockOverlay,
+    /// How many handles of the running transaction are open.
+    handle_depth: u32,
+    /// Why the filesystem is mounted read-only, if it is.
+    read_only: Option<String>,
+    /// The metadata checksum seed, if the filesystem uses metadata_csum.
+    csum_seed: Option<u32>,
+    /// How checksum mismatches are handled.
+    checksum_policy: ChecksumPolicy,
+}
+
+/// Options controlling how a filesystem is mounted.
+#[derive(Debug, Clone, Default)]
+pub struct MountOptions {
+    /// How metadata checksum mismatches are handled.
+    pub checksum_policy: ChecksumPolicy,
+    /// Path of the external journal device, for filesystems that use one.
+    pub journal_device: Option<String>,
+    /// How a journal that needs recovery is handled.
+    pub recovery: JournalRecovery,
+}
+
+/// How to handle a journal left dirty by an unclean shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JournalRecovery {
+    /// Replay the journal onto the image and mark it empty.
+    #[default]
+    Replay,
+    /// Replay the journal into memory only and mount read-only, leaving the image untouched.
+    InMemory,
+    /// Do not replay the journal, and mount read-only with stale metadata.
+    Skip,
+}
+
+/// How a rename treats an existing entry at the new path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RenameMode {
+    /// Replace the entry, like `rename(2)`.
+    #[default]
+    Replace,
+    /// Fail if there is an entry, like `RENAME_NOREPLACE`.
+    NoReplace,
+    /// Swap the two entries, which must both exist, like `RENAME_EXCHANGE`.
+    Exchange,
+}
+
+/// What `fallocate` does to a byte range of a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FallocateMode {
+    /// Preallocate unwritten blocks for the holes in the range, like mode 0 or, with
+    /// `keep_size`, `FALLOC_FL_KEEP_SIZE`.
+    Allocate { keep_size: bool },
+    /// Free the blocks in the range, leaving a hole, like `FALLOC_FL_PUNCH_HOLE`. The size
+    /// never changes.
+    PunchHole,
+    /// Make the range read as zeros, turning whole blocks unwritten and preallocating the
+    /// holes, like `FALLOC_FL_ZERO_RANGE`.
+    ZeroRange { keep_size: bool },
 }
 
 impl Ext4Filesystem {
     /// 将文件系统所有更改持久化到磁盘
     pub fn sync(&mut self) -> Result<(), Ext4Error> {
+        // 只读挂载时没有需要写回的内容
+        if self.is_read_only() {
+            return Ok(());
+        }
         println!("开始同步文件系统到磁盘...");
 
         // 1. 同步元数据（超级块和块组描述符）
@@ -46,13 +141,13 @@ impl Ext4Filesystem {
         for (i, bg) in self.block_groups.iter().enumerate() {
             // 同步 inode 位图 - 只读取和验证，不重复写入
             let mut file_clone = self.file.try_clone()?;
-            let inode_bitmap_offset = (bg.inode_bitmap * self.superblock.block_size()) as u64;
+            let inode_bitmap_offset = bg.inode_bitmap * self.superblock.block_size() as u64;
             file_clone.seek(SeekFrom::Start(inode_bitmap_offset))?;
             file_clone.sync_data()?;
 
             // 同步块位图 - 只读取和验证，不重复写入
             let mut file_clone = self.file.try_clone()?;
-            let block_bitmap_offset = (bg.block_bitmap * self.superblock.block_size()) as u64;
+            let block_bitmap_offset = bg.block_bitmap * self.superblock.block_size() as u64;
             file_clone.seek(SeekFrom::Start(block_bitmap_offset))?;
             file_clone.sync_data()?;
 
@@ -79,1045 +174,4149 @@ impl Ext4Filesystem {
 
     /// 将文件系统的关键数据结构持久化到磁盘
     pub fn sync_fs_metadata(&mut self) -> Result<(), Ext4Error> {
-        println!("开始同步文件系统元数据到磁盘...");
+        self.transaction(|fs| {
+            println!("开始同步文件系统元数据到磁盘...");
 
-        // 1. 写入超级块（这部分保持不变）
-        println!("同步超级块...");
-        self.write_superblock()?;
+            // 1. 写入超级块（这部分保持不变）
+            println!("同步超级块...");
+            fs.write_superblock()?;
 
-        // 2. 写入块组描述符表 - 修复重复写入问题
-        println!("同步块组描述符...");
-        let block_size = self.superblock.block_size();
-        let mut file_clone = self.file.try_clone()?;
+            // 2. 写入块组描述符表
+            println!("同步块组描述符...");
+            fs.write_group_descriptors()?;
 
-        // 计算块组描述符表的起始位置
-        let bgdt_start = if self.superblock.first_data_block == 0 {
-            2048 // 超级块后的第一个块
-        } else {
-            (self.superblock.first_data_block + 1) * block_size
-        };
+            println!("文件系统元数据同步完成");
+            Ok(())
+        })?;
 
-        // 一次性写入所有块组描述符
-        file_clone.seek(SeekFrom::Start(bgdt_start as u64))?;
+        // 确保数据写入磁盘
+        println!("强制同步到磁盘...");
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    /// Write the primary group descriptor table, refreshing the descriptor checksums.
+    fn write_group_descriptors(&mut self) -> Result<(), Ext4Error> {
+        // 计算块组描述符表的起始位置（超级块所在块之后的第一个块）
+        let block_size = self.superblock.block_size();
+        let bgdt_start = (self.superblock.first_data_block as u64 + 1) * block_size as u64;
 
         // 创建一个缓冲区来存储所有块组描述符
-        let mut bgdt_buffer = Vec::with_capacity(self.block_groups.len() * 32);
+        let desc_size = self.superblock.group_desc_size();
+        let mut bgdt_buffer = Vec::with_capacity(self.block_groups.len() * desc_size as usize);
 
         // 将所有块组描述符打包到缓冲区
-        for (i, bg) in self.block_groups.iter().enumerate() {
-            println!("打包块组 {} 的描述符", i);
-            bgdt_buffer.extend_from_slice(&bg.block_bitmap.to_le_bytes());
-            bgdt_buffer.extend_from_slice(&bg.inode_bitmap.to_le_bytes());
-            bgdt_buffer.extend_from_slice(&bg.inode_table.to_le_bytes());
-            bgdt_buffer.extend_from_slice(&bg.free_blocks_count.to_le_bytes());
-            bgdt_buffer.extend_from_slice(&bg.free_inodes_count.to_le_bytes());
-            bgdt_buffer.extend_from_slice(&bg.used_dirs_count.to_le_bytes());
-            bgdt_buffer.extend_from_slice(&[0u8; 14]); // 填充和保留字段
+        for (i, bg) in self.block_groups.iter_mut().enumerate() {
+            if let Some(seed) = self.csum_seed {
+                bg.checksum =
+                    checksum::group_desc_checksum(seed, i as u32, &bg.to_bytes(desc_size));
+            }
+            bgdt_buffer.extend_from_slice(&bg.to_bytes(desc_size));
         }
 
-        // 一次性写入所有数据
-        file_clone.write_all(&bgdt_buffer)?;
-
-        // 确保数据写入磁盘
-        println!("强制同步到磁盘...");
-        file_clone.sync_data()?;
-
-        println!("文件系统元数据同步完成");
-        Ok(())
+        // 一次性写入所有块组描述符
+        self.write_metadata(bgdt_start, &bgdt_buffer)
     }
 
     /// Create a new ext4 filesystem from a file.
     pub fn new(path: &str) -> Result<Self, Ext4Error> {
-        // Open the file with read-write permissions
-        let file = StdFile::options().read(true).write(true).open(path)?;
+        Self::mount_with_options(path, MountOptions::default())
+    }
 
+    /// Mount an existing ext4 filesystem with the given options.
+    pub fn mount_with_options(path: &str, options: MountOptions) -> Result<Self, Ext4Error> {
+        // Open the file with read-write permissions, falling back to read-only
+        let mut read_only = None;
+        let file = match StdFile::options().read(true).write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                read_only = Some("the image is not writable".to_string());
+                StdFile::open(path)?
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut fs = Ext4Filesystem {
+            superblock: Superblock::read(&mut file.try_clone()?)?,
+            block_groups: Vec::new(),
+            journal: None,
+            journal_file: None,
+            file,
+            overlay: BlockOverlay::default(),
+            handle_depth: 0,
+            read_only,
+            csum_seed: None,
+            checksum_policy: options.checksum_policy,
+        };
+        fs.load_metadata()?;
+
+        // Read the journal if it exists, and bring the filesystem up to date from it
+        if fs.superblock.has_compat(CompatFeature::HasJournal) {
+            fs.load_journal(options.journal_device.as_deref())?;
+        }
+        if fs.superblock.has_incompat(IncompatFeature::Recover) {
+            fs.recover_journal(options.recovery)?;
+        }
+
+        Ok(fs)
+    }
+
+    /// Read and verify the superblock and group descriptors.
+    fn load_metadata(&mut self) -> Result<(), Ext4Error> {
         // Read the superblock
-        let mut file_clone = file.try_clone()?;
-        let superblock = Superblock::read(&mut file_clone)?;
+        let mut reader = self.reader()?;
+        reader.seek(SeekFrom::Start(0))?;
+        let superblock = Superblock::read(&mut reader)?;
+
+        // Refuse features we do not understand, and avoid writing those we cannot maintain
+        if let Some(reason) = Self::check_features(&superblock)? {
+            log::warn!("Mounting read-only: {}", reason);
+            self.read_only.get_or_insert(reason);
+        }
+
+        // Verify the superblock checksum
+        if superblock.has_ro_compat(RoCompatFeature::MetadataCsum) {
+            if superblock.checksum_type != 1 {
+                return Err(Ext4Error::UnsupportedFeature(format!(
+                    "unknown metadata checksum type {}",
+                    superblock.checksum_type
+                )));
+            }
+            self.checksum_policy.check(
+                "superblock",
+                superblock.checksum,
+                superblock.compute_checksum()?,
+            )?;
+        }
+        let csum_seed = superblock.csum_seed();
 
         // Read the block groups
         let mut block_groups = Vec::new();
-        let block_groups_count = superblock.block_groups_count();
         let block_size = superblock.block_size();
-
-        for i in 0..block_groups_count {
-            let mut file_clone = file.try_clone()?;
-            let block_group =
-                BlockGroup::read(&mut file_clone, i, superblock.first_data_block, block_size)?;
+        for i in 0..superblock.block_groups_count() {
+            let block_group = BlockGroup::read(
+                &mut reader,
+                i,
+                superblock.first_data_block,
+                block_size,
+                superblock.group_desc_size(),
+            )?;
+            if let Some(seed) = csum_seed {
+                let computed = checksum::group_desc_checksum(
+                    seed,
+                    i,
+                    &block_group.to_bytes(superblock.group_desc_size()),
+                );
+                self.checksum_policy.check(
+                    &format!("group descriptor {}", i),
+                    block_group.checksum as u32,
+                    computed as u32,
+                )?;
+            }
             block_groups.push(block_group);
         }
 
-        // Read the journal if it exists
-        let journal = if superblock.rev_level >= 1 {
-            // TODO: Implement reading the journal
-            None
+        self.superblock = superblock;
+        self.block_groups = block_groups;
+        self.csum_seed = csum_seed;
+        Ok(())
+    }
+
+    /// Locate and read the journal, either from the journal inode or an external device.
+    fn load_journal(&mut self, journal_device: Option<&str>) -> Result<(), Ext4Error> {
+        let journal = if self.superblock.journal_inum != 0 {
+            let inode_num = self.superblock.journal_inum;
+            let inode = self.read_inode(inode_num)?;
+            let mut reader = self.reader()?;
+            Journal::read(&mut reader, inode_num, inode, self.superblock.block_size())?
+        } else if let Some(path) = journal_device {
+            let mut file = match StdFile::options().read(true).write(true).open(path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+                    self.read_only
+                        .get_or_insert("the external journal is not writable".to_string());
+                    StdFile::open(path)?
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let journal = Journal::read_external(&mut file, &self.superblock)?;
+            self.journal_file = Some(file);
+            journal
         } else {
-            None
+            // Without the journal we can neither recover nor keep it consistent
+            let reason = format!(
+                "the external journal {} was not provided",
+                format_uuid(&self.superblock.journal_uuid)
+            );
+            log::warn!("Mounting read-only: {}", reason);
+            self.read_only.get_or_insert(reason);
+            return Ok(());
         };
 
-        Ok(Ext4Filesystem {
-            superblock,
-            block_groups,
-            journal,
-            file,
-        })
+        if let Some((stored, computed)) = journal.superblock_checksums() {
+            self.checksum_policy.check("journal superblock", stored, computed)?;
+        }
+        if let Some(reason) = journal.superblock.unwritable_reason() {
+            log::warn!("Mounting read-only: {}", reason);
+            self.read_only.get_or_insert(reason);
+        }
+
+        self.journal = Some(journal);
+        Ok(())
     }
 
-    /// Safely read data from a file, handling potential EOF conditions
-    fn safe_read(&mut self, offset: u64, buffer: &mut [u8]) -> Result<usize, Ext4Error> {
+    /// Replay the journal of a filesystem that was not cleanly unmounted.
+    ///
+    /// The log is written back to the image and the journal marked empty, unless recovery
+    /// is skipped or the filesystem cannot be written, in which case the replayed blocks are
+    /// kept in memory and the filesystem is mounted read-only. Fast commits made after the
+    /// last full commit are applied on top of the log either way.
+    fn recover_journal(&mut self, mode: JournalRecovery) -> Result<(), Ext4Error> {
+        let needs_recovery = "the journal needs recovery".to_string();
+        let journal = match &self.journal {
+            Some(journal) if mode != JournalRecovery::Skip => journal,
+            _ => {
+                log::warn!("Mounting read-only: {}", needs_recovery);
+                self.read_only.get_or_insert(needs_recovery);
+                return Ok(());
+            }
+        };
+
+        let mut journal_reader = self.journal_reader()?;
+        let recovery = journal.recover(&mut journal_reader)?;
+        log::info!(
+            "Replayed {} transactions ({} blocks, {} revoked, {} fast-commit tags)",
+            recovery.transactions,
+            recovery.blocks.len(),
+            recovery.revoked,
+            recovery.fast_commits.len()
+        );
+
+        if mode == JournalRecovery::InMemory || self.read_only.is_some() {
+            self.overlay = BlockOverlay::new(recovery.blocks);
+            self.load_metadata()?;
+            let reason = "the journal was only replayed in memory".to_string();
+            log::warn!("Mounting read-only: {}", reason);
+            self.read_only.get_or_insert(reason);
+
+            // Now read-only, the fast commits land in memory next to the log
+            return self.replay_fast_commits(&recovery.fast_commits);
+        }
+
+        // Write the replayed blocks home before the log forgets them
+        let block_size = self.superblock.block_size() as u64;
         let mut file_clone = self.file.try_clone()?;
-        file_clone.seek(SeekFrom::Start(offset))?;
+        for (block_num, data) in &recovery.blocks {
+            file_clone.seek(SeekFrom::Start(block_num * block_size))?;
+            file_clone.write_all(data)?;
+        }
+        file_clone.sync_data()?;
 
-        // Try to read as much as possible, but don't fail if we hit EOF
-        match file_clone.read(buffer) {
-            Ok(bytes_read) => Ok(bytes_read),
-            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                // Return the number of bytes we were able to read (could be 0)
-                Ok(0)
+        // The log may have rewritten the superblock and descriptors
+        self.load_metadata()?;
+        self.superblock.feature_incompat &= !(IncompatFeature::Recover as u32);
+
+        // The fast commits are logged as the transaction they were part of; until it commits,
+        // a crash leaves them in place to be replayed again on the next mount
+        if !recovery.fast_commits.is_empty() {
+            if let Some(journal) = self.journal.as_mut() {
+                journal.superblock.sequence_id = recovery.fast_commit_sequence;
             }
-            Err(e) => Err(Ext4Error::Io(e)),
+            self.transaction(|fs| fs.replay_fast_commits(&recovery.fast_commits))?;
         }
-    }
 
-    /// Read exactly the requested amount of data or return an error if not enough data is available
-    fn read_exact_or_eof(&mut self, offset: u64, buffer: &mut [u8]) -> Result<bool, Ext4Error> {
-        let bytes_read = self.safe_read(offset, buffer)?;
-
-        if bytes_read < buffer.len() {
-            // Fill the rest of the buffer with zeros
-            for i in bytes_read..buffer.len() {
-                buffer[i] = 0;
+        let mut journal_file = self.journal_reader()?;
+        if let Some(journal) = self.journal.as_mut() {
+            if journal.superblock.needs_recovery() {
+                journal.mark_clean(&mut journal_file, recovery.next_sequence)?;
             }
-            // Return false to indicate we hit EOF
-            Ok(false)
-        } else {
-            // Return true to indicate we read the full buffer
-            Ok(true)
         }
-    }
+        journal_file.sync_data()?;
 
-    /// Mount an existing ext4 filesystem.
-    pub fn mount(path: &str) -> Result<Self, Ext4Error> {
-        Self::new(path)
+        if self.read_only.is_none() {
+            self.write_superblock()?;
+        }
+        Ok(())
     }
 
-    /// Get the superblock of the filesystem.
-    pub fn superblock(&self) -> &Superblock {
-        &self.superblock
-    }
+    /// Apply the fast commits found after the log (the fast-commit replay pass).
+    ///
+    /// Block ranges are gathered per inode and written out as a new extent tree when the
+    /// inode itself is replayed, or at the end. Blocks unmapped along the way stay in use
+    /// until the end, so a tree block is never allocated from under a later tag.
+    fn replay_fast_commits(&mut self, tags: &[FastCommitTag]) -> Result<(), Ext4Error> {
+        let mut ranges = HashMap::new();
+        let mut touched = HashSet::new();
+        let mut released = Vec::new();
+        let mut unlinked = Vec::new();
+
+        for tag in tags {
+            if let FastCommitTag::AddRange { extent, .. } = tag {
+                self.set_blocks_in_use(extent.start, extent.length(), true)?;
+            }
+        }
 
-    /// Get the block groups of the filesystem.
-    pub fn block_groups(&self) -> &[BlockGroup] {
-        &self.block_groups
-    }
+        for tag in tags {
+            match tag {
+                FastCommitTag::AddRange { inode, extent } => {
+                    touched.insert(*inode);
+                    let (extents, _) = self.fast_commit_extents(&mut ranges, *inode)?;
+                    released.extend(extent::insert_extent(extents, *extent));
+                }
+                FastCommitTag::DelRange { inode, block, len } => {
+                    touched.insert(*inode);
+                    let (extents, _) = self.fast_commit_extents(&mut ranges, *inode)?;
+                    released.extend(extent::punch_extents(extents, *block, *len));
+                }
+                FastCommitTag::Inode { inode, raw } => {
+                    self.flush_fast_commit_extents(&mut ranges, *inode, &mut released)?;
+                    self.replay_fast_commit_inode(*inode, raw)?;
+                }
+                FastCommitTag::Create {
+                    parent,
+                    inode,
+                    name,
+                } => {
+                    self.flush_fast_commit_extents(&mut ranges, *parent, &mut released)?;
+                    let mut child = self.read_inode(*inode)?;
+                    if child.is_directory() {
+                        touched.insert(*inode);
+                        self.init_fast_commit_directory(
+                            &mut ranges,
+                            &mut released,
+                            *parent,
+                            *inode,
+                        )?;
+                        child = self.read_inode(*inode)?;
+                    }
+                    self.replay_fast_commit_link(*parent, *inode, &mut child, name)?;
 
-    /// Get the journal of the filesystem.
-    pub fn journal(&self) -> Option<&Journal> {
-        self.journal.as_ref()
-    }
+                    // A new inode starts out with just this link; its next inode tag has the rest
+                    child.links_count = if child.is_directory() { 2 } else { 1 };
+                    self.write_inode(*inode, &child)?;
+                    self.set_inode_in_use(*inode, true, child.is_directory())?;
+                }
+                FastCommitTag::Link {
+                    parent,
+                    inode,
+                    name,
+                } => {
+                    self.flush_fast_commit_extents(&mut ranges, *parent, &mut released)?;
+                    let mut child = self.read_inode(*inode)?;
+                    if self.replay_fast_commit_link(*parent, *inode, &mut child, name)? {
+                        child.links_count = child.links_count.saturating_add(1);
+                        self.write_inode(*inode, &child)?;
+                    }
+                }
+                FastCommitTag::Unlink {
+                    parent,
+                    inode,
+                    name,
+                } => {
+                    self.flush_fast_commit_extents(&mut ranges, *parent, &mut released)?;
+                    if self.lookup(*parent, name)? != Some(*inode) {
+                        continue;
+                    }
+                    self.remove_directory_entry(*parent, name)?;
+
+                    // Only empty directories are removed, taking their "." link with them
+                    let mut child = self.read_inode(*inode)?;
+                    child.links_count = if child.is_directory() {
+                        0
+                    } else {
+                        child.links_count.saturating_sub(1)
+                    };
+                    self.write_inode(*inode, &child)?;
+                    unlinked.push(*inode);
+                }
+                FastCommitTag::Pad | FastCommitTag::Tail { .. } | FastCommitTag::Head { .. } => {}
+            }
+        }
 
-    /// Read an inode from the filesystem.
-    pub fn read_inode(&mut self, inode_num: u32) -> Result<Inode, Ext4Error> {
-        if inode_num == 0 || inode_num > self.superblock.inodes_count {
-            return Err(Ext4Error::InvalidInode(format!(
-                "Invalid inode number: {}",
-                inode_num
-            )));
+        let pending: Vec<u32> = ranges.keys().copied().collect();
+        for inode_num in pending {
+            self.flush_fast_commit_extents(&mut ranges, inode_num, &mut released)?;
         }
 
-        let group_idx = (inode_num - 1) / self.superblock.inodes_per_group;
-        if group_idx as usize >= self.block_groups.len() {
-            return Err(Ext4Error::InvalidInode(format!(
-                "Invalid block group index: {}",
-                group_idx
-            )));
+        // Inodes whose last link went away are deleted, as the kernel would once closed
+        for inode_num in unlinked {
+            let mut inode = self.read_inode(inode_num)?;
+            if inode.links_count != 0 || inode.dtime != 0 {
+                continue;
+            }
+            self.delete_inode(inode_num, &mut inode)?;
+            touched.remove(&inode_num);
         }
 
-        let block_group = &self.block_groups[group_idx as usize];
-        let mut file_clone = self.file.try_clone()?;
+        // Give back the unmapped blocks that no surviving inode mapped again
+        let mut kept = Vec::new();
+        let block_size = self.superblock.block_size();
+        for inode_num in touched {
+            let inode = self.read_inode(inode_num)?;
+            if !inode.uses_extents() {
+                continue;
+            }
+            let root = inode.block_bytes();
+            let mut reader = self.reader()?;
+            for e in extent::collect_extents(&mut reader, &root, block_size)? {
+                kept.push((e.start, e.start + e.length() as u64));
+            }
+            for block in extent::collect_tree_blocks(&mut reader, &root, block_size)? {
+                kept.push((block, block + 1));
+            }
+        }
+        kept.sort_unstable();
+        for e in released {
+            let mut start = e.start;
+            let end = e.start + e.length() as u64;
+            for &(kept_start, kept_end) in &kept {
+                if kept_end <= start || kept_start >= end {
+                    continue;
+                }
+                if kept_start > start {
+                    self.set_blocks_in_use(start, (kept_start - start) as u32, false)?;
+                }
+                start = std::cmp::max(start, kept_end);
+            }
+            if start < end {
+                self.set_blocks_in_use(start, (end - start) as u32, false)?;
+            }
+        }
 
-        Inode::read(
-            &mut file_clone,
-            256, // Assuming inode size is 256 bytes
-            inode_num,
-            self.superblock.inodes_per_group,
-            block_group.inode_table,
-            self.superblock.block_size(),
-        )
+        // The logged superblock predates the fast commits; its totals follow the groups
+        let free_blocks = self
+            .block_groups
+            .iter()
+            .map(|bg| bg.free_blocks_count as u64)
+            .sum();
+        self.superblock.set_free_blocks_count_64(free_blocks);
+        self.superblock.free_inodes_count = self
+            .block_groups
+            .iter()
+            .map(|bg| bg.free_inodes_count)
+            .sum();
+        self.write_group_descriptors()
     }
 
-    /// Read a directory from the filesystem.
-    pub fn read_directory(&mut self, inode_num: u32) -> Result<Directory, Ext4Error> {
+    /// Get the extents and tree blocks of an inode being replayed, reading them from its
+    /// extent tree on first use.
+    fn fast_commit_extents<'a>(
+        &mut self,
+        ranges: &'a mut HashMap<u32, (Vec<Extent>, Vec<u64>)>,
+        inode_num: u32,
+    ) -> Result<&'a mut (Vec<Extent>, Vec<u64>), Ext4Error> {
+        let vacant = match ranges.entry(inode_num) {
+            Entry::Occupied(entry) => return Ok(entry.into_mut()),
+            Entry::Vacant(entry) => entry,
+        };
+
         let inode = self.read_inode(inode_num)?;
-        if !inode.is_directory() {
-            return Err(Ext4Error::InvalidDirectory(format!(
-                "Inode {} is not a directory",
+        // A free inode is being reused, and whatever its block map held is long gone
+        let mapping = if inode.links_count == 0 || inode.dtime != 0 {
+            (Vec::new(), Vec::new())
+        } else if inode.uses_extents() {
+            self.read_extent_map(&inode)?
+        } else if inode.block.iter().all(|&block| block == 0) {
+            (Vec::new(), Vec::new())
+        } else {
+            return Err(Ext4Error::UnsupportedFeature(format!(
+                "cannot replay fast commits to block-mapped inode {}",
                 inode_num
             )));
+        };
+        Ok(vacant.insert(mapping))
+    }
+
+    /// Write the gathered extents of an inode out as its extent tree.
+    ///
+    /// The blocks of the old tree are added to `released`.
+    fn flush_fast_commit_extents(
+        &mut self,
+        ranges: &mut HashMap<u32, (Vec<Extent>, Vec<u64>)>,
+        inode_num: u32,
+        released: &mut Vec<Extent>,
+    ) -> Result<(), Ext4Error> {
+        let (extents, tree_blocks) = match ranges.remove(&inode_num) {
+            Some(mapping) => mapping,
+            None => return Ok(()),
+        };
+        released.extend(tree_blocks.into_iter().map(|start| Extent {
+            block: 0,
+            len: 1,
+            start,
+        }));
+
+        let mut inode = self.read_inode(inode_num)?;
+        self.write_extent_tree(inode_num, &mut inode, &extents)?;
+        self.count_extent_blocks(&mut inode)?;
+        self.write_inode(inode_num, &inode)
+    }
+
+    /// Copy an inode logged by a fast commit over the on-disk inode.
+    ///
+    /// Like the kernel, the block map of the logged copy is ignored: the extent tree is the
+    /// one built from the block ranges, and `i_blocks` is recounted from it.
+    fn replay_fast_commit_inode(&mut self, inode_num: u32, logged: &[u8]) -> Result<(), Ext4Error> {
+        let mut raw = self.read_inode_record(inode_num)?;
+        let len = std::cmp::min(logged.len(), raw.len());
+        let block_end = INODE_BLOCK_OFFSET + INODE_BLOCK_SIZE;
+        raw[..INODE_BLOCK_OFFSET].copy_from_slice(&logged[..INODE_BLOCK_OFFSET]);
+        raw[block_end..len].copy_from_slice(&logged[block_end..len]);
+
+        let mut inode = Inode::parse(&raw)?;
+        if inode.flags & EXT4_INLINE_DATA_FL != 0 || (inode.is_symlink() && !inode.uses_extents()) {
+            // Inline data and fast symlinks keep their contents in i_block
+            raw[INODE_BLOCK_OFFSET..block_end]
+                .copy_from_slice(&logged[INODE_BLOCK_OFFSET..block_end]);
+            inode = Inode::parse(&raw)?;
+        } else if inode.uses_extents() {
+            if ExtentNode::parse(&inode.block_bytes()).is_err() {
+                let mut root = [0u8; INODE_BLOCK_SIZE];
+                ExtentNode::Leaf(Vec::new()).write_to(&mut root, extent::EXT4_EXT_ROOT_ENTRIES, 0);
+                inode.set_block_bytes(&root);
+            }
+            self.count_extent_blocks(&mut inode)?;
         }
 
-        let mut file_clone = self.file.try_clone()?;
-        Directory::read(&mut file_clone, inode, self.superblock.block_size())
+        // Write the raw copy first so fields this crate does not parse come along
+        self.write_metadata(self.inode_offset(inode_num)?, &raw)?;
+        self.write_inode(inode_num, &inode)?;
+        if inode.links_count != 0 {
+            self.set_inode_in_use(inode_num, true, inode.is_directory())?;
+        }
+        Ok(())
     }
 
-    /// Open a file from the filesystem.
-    pub fn open_file(&mut self, inode_num: u32) -> Result<File, Ext4Error> {
-        let inode = self.read_inode(inode_num)?;
-        if !inode.is_file() {
-            return Err(Ext4Error::InvalidFile(format!(
-                "Inode {} is not a regular file",
-                inode_num
-            )));
+    /// Add a directory entry for a replayed link, replacing any entry of the same name.
+    ///
+    /// Returns false if the entry was already there.
+    fn replay_fast_commit_link(
+        &mut self,
+        parent: u32,
+        inode_num: u32,
+        inode: &mut Inode,
+        name: &str,
+    ) -> Result<bool, Ext4Error> {
+        match self.lookup(parent, name)? {
+            Some(existing) if existing == inode_num => return Ok(false),
+            Some(_) => self.remove_directory_entry(parent, name)?,
+            None => {}
         }
 
-        Ok(File::new(inode))
+        let file_type = self.entry_file_type(inode);
+        self.add_directory_entry(parent, name, inode_num, file_type)?;
+        Ok(true)
     }
 
-    /// Read data from a file.
-    pub fn read_file(
+    /// Give a directory created by a fast commit a first block holding "." and "..".
+    ///
+    /// The contents of directory blocks are never logged by fast commits, so whatever the
+    /// block held before is replaced.
+    fn init_fast_commit_directory(
         &mut self,
+        ranges: &mut HashMap<u32, (Vec<Extent>, Vec<u64>)>,
+        released: &mut Vec<Extent>,
+        parent: u32,
         inode_num: u32,
-        buffer: &mut [u8],
-        position: u64,
-    ) -> Result<usize, Ext4Error> {
-        let mut file = self.open_file(inode_num)?;
-        file.seek(position)?;
+    ) -> Result<(), Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let (extents, _) = self.fast_commit_extents(ranges, inode_num)?;
+        let block_num = match extents.iter().find(|e| e.contains(0) && !e.is_unwritten()) {
+            Some(e) => e.start,
+            None => {
+                let block_num = self.allocate_block()?;
+                let (extents, _) = self.fast_commit_extents(ranges, inode_num)?;
+                released.extend(extent::insert_extent(
+                    extents,
+                    Extent {
+                        block: 0,
+                        len: 1,
+                        start: block_num,
+                    },
+                ));
+                block_num
+            }
+        };
+        self.flush_fast_commit_extents(ranges, inode_num, released)?;
 
-        let mut file_clone = self.file.try_clone()?;
-        file.read(&mut file_clone, buffer, self.superblock.block_size())
+        let mut inode = self.read_inode(inode_num)?;
+        if inode.size < block_size {
+            inode.size = block_size;
+            self.write_inode(inode_num, &inode)?;
+        }
+
+        self.write_dot_entries(inode_num, &inode, block_num, parent)
     }
 
-    /// Get the root directory of the filesystem.
-    pub fn root_directory(&mut self) -> Result<Directory, Ext4Error> {
-        // The root directory is always inode 2 in ext4
-        self.read_directory(2)
+    /// Read the extents of an extent-mapped inode along with the blocks of its tree.
+    fn read_extent_map(&self, inode: &Inode) -> Result<(Vec<Extent>, Vec<u64>), Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let root = inode.block_bytes();
+        let mut reader = self.reader()?;
+        Ok((
+            extent::collect_extents(&mut reader, &root, block_size)?,
+            extent::collect_tree_blocks(&mut reader, &root, block_size)?,
+        ))
     }
 
-    /// Find a file or directory by path.
-    pub fn find_by_path(&mut self, path: &str) -> Result<u32, Ext4Error> {
-        if path.is_empty() || path == "/" {
-            return Ok(2); // Root directory inode
+    /// Replace the extent tree of an inode with one for `extents`, freeing the blocks of the
+    /// old tree, and recount `i_blocks`.
+    fn rewrite_extent_tree(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        extents: &[Extent],
+        old_tree: Vec<u64>,
+    ) -> Result<(), Ext4Error> {
+        for block_num in old_tree {
+            self.free_block(block_num)?;
         }
+        self.write_extent_tree(inode_num, inode, extents)?;
+        self.count_extent_blocks(inode)
+    }
 
-        let components: Vec<&str> = path.trim_start_matches('/').split('/').collect();
-        let mut current_inode = 2; // Start from the root directory
-
-        for component in components {
-            if component.is_empty() {
-                continue;
-            }
+    /// Recount `i_blocks` of an extent-mapped inode from its extent tree.
+    fn count_extent_blocks(&self, inode: &mut Inode) -> Result<(), Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let root = inode.block_bytes();
+        let mut reader = self.reader()?;
+        let data: u64 = extent::collect_extents(&mut reader, &root, block_size)?
+            .iter()
+            .map(|e| e.length() as u64)
+            .sum();
+        let tree = extent::collect_tree_blocks(&mut reader, &root, block_size)?.len() as u64;
+        let xattr = (inode.get_file_acl() != 0) as u64;
+
+        let sectors = (data + tree + xattr) * (block_size / 512) as u64;
+        self.set_inode_blocks(inode, sectors)
+    }
 
-            let directory = self.read_directory(current_inode)?;
-            match directory.find_entry(component) {
-                Some(entry) => {
-                    current_inode = entry.inode;
-                }
-                None => {
-                    return Err(Ext4Error::InvalidFile(format!(
-                        "Path component not found: {}",
-                        component
-                    )));
-                }
-            }
+    /// Get a handle on the device holding the journal.
+    fn journal_reader(&self) -> Result<StdFile, Ext4Error> {
+        match &self.journal_file {
+            Some(file) => Ok(file.try_clone()?),
+            None => Ok(self.file.try_clone()?),
         }
+    }
 
-        Ok(current_inode)
+    /// Get a reader over the image that sees the blocks held in memory.
+    fn reader(&self) -> Result<OverlayReader, Ext4Error> {
+        Ok(OverlayReader::new(
+            self.file.try_clone()?,
+            self.overlay.clone(),
+            self.superblock.block_size(),
+        ))
     }
 
-    /// Write a file to the filesystem.
-    pub fn write_file(
-        &mut self,
-        parent_path: &str,
-        filename: &str,
-        data: &[u8],
-    ) -> Result<(), Ext4Error> {
-        // Find the parent directory inode
-        let parent_inode_num = self.find_by_path(parent_path)?;
-        let parent_inode = self.read_inode(parent_inode_num)?;
+    /// Check the feature masks of a superblock against what this crate supports.
+    ///
+    /// Returns an error for unsupported incompatible features, and the reason the
+    /// filesystem has to be mounted read-only if it uses features we cannot write.
+    fn check_features(superblock: &Superblock) -> Result<Option<String>, Ext4Error> {
+        let incompat = superblock.unsupported_incompat_features();
+        if !incompat.is_empty() {
+            return Err(Ext4Error::UnsupportedFeature(format!(
+                "cannot mount filesystem with incompatible features: {}",
+                incompat.join(", ")
+            )));
+        }
 
-        if !parent_inode.is_directory() {
-            return Err(Ext4Error::InvalidDirectory(format!(
-                "'{}' is not a directory",
-                parent_path
+        let ro_compat = superblock.unsupported_ro_compat_features();
+        if !ro_compat.is_empty() {
+            return Ok(Some(format!(
+                "unsupported read-only compatible features: {}",
+                ro_compat.join(", ")
             )));
         }
+        if superblock.has_ro_compat(RoCompatFeature::ReadOnly) {
+            return Ok(Some("the filesystem is marked read-only".to_string()));
+        }
 
-        // Check if file already exists
-        let directory = self.read_directory(parent_inode_num)?;
-        let existing_entry = directory.find_entry(filename);
+        Ok(None)
+    }
 
-        let inode_num = match existing_entry {
-            Some(entry) => {
-                // File exists, read its inode
-                let inode_num = entry.inode;
-                let inode = self.read_inode(inode_num)?;
+    /// Check if the filesystem is mounted read-only.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.is_some()
+    }
 
-                if !inode.is_file() {
-                    return Err(Ext4Error::InvalidFile(format!(
-                        "'{}' exists but is not a regular file",
-                        filename
-                    )));
-                }
+    /// Fail if the filesystem may not be modified.
+    fn check_writable(&self) -> Result<(), Ext4Error> {
+        match &self.read_only {
+            Some(reason) => Err(Ext4Error::ReadOnly(reason.clone())),
+            None => Ok(()),
+        }
+    }
 
-                // Free the existing blocks
-                for i in 0..15 {
-                    if inode.block[i] != 0 {
-                        self.free_block(inode.block[i])?;
-                    }
-                }
+    /// Run `f` as one transaction.
+    ///
+    /// With a journal, the metadata updates made by `f` are logged and committed together
+    /// when it returns, so a crash leaves either all or none of them. If `f` fails, the
+    /// updates are discarded. Transactions may be nested, in which case only the outermost
+    /// one commits.
+    pub fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Self) -> Result<T, Ext4Error>,
+    ) -> Result<T, Ext4Error> {
+        self.check_writable()?;
+
+        self.handle_depth += 1;
+        let mut result = f(self);
+        if self.handle_depth == 1 {
+            result = match result {
+                Ok(value) => self.commit_transaction().map(|()| value),
+                Err(e) => self.abort_transaction().and(Err(e)),
+            };
+        }
+        self.handle_depth -= 1;
+        result
+    }
 
-                inode_num
-            }
-            None => {
-                // File doesn't exist, allocate a new inode
-                self.allocate_inode()?
-            }
-        };
-
-        // Create or update the inode
-        let mut inode = Inode::default();
-        inode.mode = 0x81A4; // Regular file with 0644 permissions
-        inode.links_count = 1;
-        inode.size = data.len() as u32;
-
-        // Get current time
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs() as u32;
+    /// Check if the filesystem has a journal it writes transactions to.
+    fn in_journal_mode(&self) -> bool {
+        self.journal.is_some() && self.read_only.is_none()
+    }
 
-        inode.atime = now;
-        inode.ctime = now;
-        inode.mtime = now;
+    /// Check if metadata updates currently go through the journal.
+    fn in_transaction(&self) -> bool {
+        self.handle_depth > 0 && self.in_journal_mode()
+    }
 
-        // Calculate how many blocks we need
-        let block_size = self.superblock.block_size() as usize;
-        let blocks_needed = (data.len() + block_size - 1) / block_size;
+    /// Write the blocks of the running transaction to the journal, then checkpoint them.
+    ///
+    /// The superblock is flagged as needing recovery while the log holds the transaction,
+    /// so both this crate and the kernel replay it after a crash.
+    fn commit_transaction(&mut self) -> Result<(), Ext4Error> {
+        if self.overlay.lock().is_empty() {
+            return Ok(());
+        }
 
-        if blocks_needed > 12 {
-            return Err(Ext4Error::InvalidOperation(
-                "Files larger than 12 direct blocks are not supported yet".to_string(),
-            ));
+        // Log the counters and descriptors as they stand at the end of the transaction
+        self.write_superblock()?;
+        self.write_group_descriptors()?;
+        let mut blocks: Vec<(u64, Vec<u8>)> = self
+            .overlay
+            .lock()
+            .iter()
+            .map(|(&block, data)| (block, data.clone()))
+            .collect();
+        blocks.sort_unstable_by_key(|&(block, _)| block);
+
+        if let Err(e) = self.write_transaction(&blocks) {
+            // The image may be half checkpointed; only recovery can sort it out
+            let reason = format!("the journal was aborted: {}", e);
+            log::error!("Remounting read-only: {}", reason);
+            self.read_only.get_or_insert(reason);
+            return Err(e);
         }
 
-        // Allocate blocks and write data
-        let mut blocks_allocated = 0;
-        for i in 0..blocks_needed {
-            let block_num = self.allocate_block()?;
-            inode.block[i] = block_num;
-            blocks_allocated += 1;
+        self.overlay.lock().clear();
+        self.write_backup_superblocks()
+    }
 
-            // Write data to this block
-            let start = i * block_size;
-            let end = std::cmp::min((i + 1) * block_size, data.len());
-            let block_data = &data[start..end];
+    /// Log and checkpoint a committed set of metadata blocks.
+    fn write_transaction(&mut self, blocks: &[(u64, Vec<u8>)]) -> Result<(), Ext4Error> {
+        let block_size = self.superblock.block_size() as u64;
+        let sb_block = SUPERBLOCK_OFFSET / block_size;
 
-            let mut file_clone = self.file.try_clone()?;
-            file_clone.seek(SeekFrom::Start(
-                (block_num * self.superblock.block_size()) as u64,
-            ))?;
-            file_clone.write_all(block_data)?;
+        // File data written in place must be on disk before the metadata pointing at it
+        self.file.sync_data()?;
+        self.set_needs_recovery(true)?;
+        self.file.sync_data()?;
 
-            // If this is the last block and it's not full, zero the rest
-            if end < (i + 1) * block_size {
-                let zeros = vec![0u8; (i + 1) * block_size - end];
-                file_clone.write_all(&zeros)?;
-            }
-        }
+        let mut journal_file = self.journal_reader()?;
+        let journal = self
+            .journal
+            .as_mut()
+            .ok_or_else(|| Ext4Error::InvalidJournal("No journal to commit to".to_string()))?;
+        let sequence = journal.write_transaction(&mut journal_file, blocks)?;
 
-        // Update inode blocks count (in 512-byte units)
-        inode.blocks = blocks_allocated * (self.superblock.block_size() / 512);
+        // Checkpoint everything but the superblock, which still has to say the log is in use
+        let mut file_clone = self.file.try_clone()?;
+        for (block_num, data) in blocks.iter().filter(|&&(block, _)| block != sb_block) {
+            file_clone.seek(SeekFrom::Start(block_num * block_size))?;
+            file_clone.write_all(data)?;
+        }
+        file_clone.sync_data()?;
 
-        // Write the inode to disk
-        self.write_inode(inode_num, &inode)?;
+        journal.mark_clean(&mut journal_file, sequence.wrapping_add(1))?;
+        journal_file.sync_data()?;
 
-        // If this is a new file, add an entry to the parent directory
-        if existing_entry.is_none() {
-            self.add_directory_entry(parent_inode_num, filename, inode_num, 1)?;
-            // 1 = regular file
+        match blocks.iter().find(|&&(block, _)| block == sb_block) {
+            Some((_, data)) => {
+                file_clone.seek(SeekFrom::Start(sb_block * block_size))?;
+                file_clone.write_all(data)?;
+            }
+            None => self.set_needs_recovery(false)?,
         }
+        file_clone.sync_data()?;
+        Ok(())
+    }
 
-        // Update superblock
-        self.superblock.free_blocks_count -= blocks_allocated;
-        if existing_entry.is_none() {
-            self.superblock.free_inodes_count -= 1;
+    /// Discard the metadata updates of a failed transaction.
+    fn abort_transaction(&mut self) -> Result<(), Ext4Error> {
+        if !self.in_journal_mode() {
+            return Ok(());
         }
-        self.write_superblock()?;
+        self.overlay.lock().clear();
+        self.load_metadata()
+    }
 
+    /// Set or clear the needs_recovery flag in the primary superblock on disk.
+    ///
+    /// Only the flag changes: the rest of the superblock stays as last checkpointed.
+    fn set_needs_recovery(&mut self, needs_recovery: bool) -> Result<(), Ext4Error> {
+        let mut file_clone = self.file.try_clone()?;
+        let mut superblock = Superblock::read(&mut file_clone)?;
+        if needs_recovery {
+            superblock.feature_incompat |= IncompatFeature::Recover as u32;
+        } else {
+            superblock.feature_incompat &= !(IncompatFeature::Recover as u32);
+        }
+        if self.csum_seed.is_some() {
+            superblock.checksum = superblock.compute_checksum()?;
+        }
+        file_clone.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        superblock.write(&mut file_clone)?;
         Ok(())
     }
 
-    /// Remove a file from the filesystem.
-    pub fn remove_file(&mut self, path: &str) -> Result<(), Ext4Error> {
-        // Find the file inode
-        let inode_num = self.find_by_path(path)?;
-        let inode = self.read_inode(inode_num)?;
-
-        if !inode.is_file() {
-            return Err(Ext4Error::InvalidFile(format!(
-                "'{}' is not a regular file",
-                path
-            )));
-        }
+    /// Mount an existing ext4 filesystem.
+    pub fn mount(path: &str) -> Result<Self, Ext4Error> {
+        Self::new(path)
+    }
 
-        // Get the parent directory path and filename
-        let (parent_path, filename) = match path.rfind('/') {
-            Some(pos) => {
-                let parent = if pos == 0 { "/" } else { &path[..pos] };
-                let name = &path[pos + 1..];
-                (parent, name)
-            }
-            None => ("/", path),
-        };
+    /// Get the superblock of the filesystem.
+    pub fn superblock(&self) -> &Superblock {
+        &self.superblock
+    }
 
-        // Find the parent directory inode
-        let parent_inode_num = self.find_by_path(parent_path)?;
+    /// Get the block groups of the filesystem.
+    pub fn block_groups(&self) -> &[BlockGroup] {
+        &self.block_groups
+    }
 
-        // Remove the directory entry from the parent directory
-        self.remove_directory_entry(parent_inode_num, filename)?;
+    /// Get the journal of the filesystem.
+    pub fn journal(&self) -> Option<&Journal> {
+        self.journal.as_ref()
+    }
 
-        // Free all blocks used by the file
-        let mut blocks_freed = 0;
-        for i in 0..15 {
-            if inode.block[i] != 0 {
-                self.free_block(inode.block[i])?;
-                blocks_freed += 1;
-            }
+    /// Walk the log of the journal and list the transactions in it.
+    pub fn journal_transactions(&self) -> Result<Vec<Transaction>, Ext4Error> {
+        match &self.journal {
+            Some(journal) => journal.scan(&mut self.journal_reader()?),
+            None => Err(Ext4Error::InvalidJournal("The filesystem has no journal".to_string())),
         }
+    }
 
-        // Mark the inode as free
-        self.free_inode(inode_num)?;
-
-        // Update superblock and block group descriptors
-        self.superblock.free_blocks_count += blocks_freed;
-        self.superblock.free_inodes_count += 1;
-        self.write_superblock()?;
-
-        Ok(())
+    /// Read the copy of a block logged in the journal.
+    pub fn read_logged_block(&self, logged: &LoggedBlock) -> Result<Vec<u8>, Ext4Error> {
+        match &self.journal {
+            Some(journal) => journal.read_logged_block(&mut self.journal_reader()?, logged),
+            None => Err(Ext4Error::InvalidJournal("The filesystem has no journal".to_string())),
+        }
     }
 
-    /// Remove a directory from the filesystem.
-    pub fn remove_directory(&mut self, path: &str, force: bool) -> Result<(), Ext4Error> {
-        println!("开始删除目录: path={}, force={}", path, force);
+    /// Read an inode from the filesystem.
+    pub fn read_inode(&mut self, inode_num: u32) -> Result<Inode, Ext4Error> {
+        let raw = self.read_inode_record(inode_num)?;
+
+        // Verify the inode checksum; never-used inodes are all zeroes and carry none
+        if let Some(seed) = self.csum_seed {
+            if raw.iter().any(|&byte| byte != 0) {
+                let (stored, computed) = checksum::inode_checksums(seed, inode_num, &raw);
+                self.checksum_policy
+                    .check(&format!("inode {}", inode_num), stored, computed)?;
+            }
+        }
 
-        // Find the directory inode
-        println!("查找目录的 inode 号: {}", path);
-        let inode_num = self.find_by_path(path)?;
-        println!("找到目录 inode 号: {}", inode_num);
+        Inode::parse(&raw)
+    }
 
+    /// Read a directory from the filesystem.
+    pub fn read_directory(&mut self, inode_num: u32) -> Result<Directory, Ext4Error> {
         let inode = self.read_inode(inode_num)?;
-        println!("成功读取目录 inode 信息");
-
         if !inode.is_directory() {
-            println!("错误：路径 '{}' 不是目录", path);
             return Err(Ext4Error::InvalidDirectory(format!(
-                "'{}' is not a directory",
-                path
+                "Inode {} is not a directory",
+                inode_num
             )));
         }
+        if inode.has_inline_data() {
+            let data = self.read_inline_data(inode_num, &inode)?;
+            return Directory::read_inline(inode, inode_num, &data);
+        }
+        self.verify_extent_tree(inode_num, &inode)?;
+        self.verify_directory_blocks(inode_num, &inode)?;
 
-        // Check if directory is empty (unless force flag is used)
-        if !force {
-            println!("检查目录是否为空");
-            let directory = self.read_directory(inode_num)?;
-            // Skip "." and ".." entries when checking if directory is empty
-            let real_entries = directory
-                .entries
-                .iter()
-                .filter(|entry| entry.name != "." && entry.name != "..")
-                .count();
+        let mut reader = self.reader()?;
+        Directory::read(&mut reader, inode, self.superblock.block_size())
+    }
 
-            println!("目录中实际条目数（不包括 . 和 ..）: {}", real_entries);
-            if real_entries > 0 {
-                println!("错误：目录不为空且未使用强制删除标志");
-                return Err(Ext4Error::InvalidOperation(format!(
-                    "Directory '{}' is not empty. Use force flag to remove anyway.",
-                    path
-                )));
-            }
+    /// Verify the checksum tails of a directory's leaf and index blocks.
+    fn verify_directory_blocks(&self, inode_num: u32, inode: &Inode) -> Result<(), Ext4Error> {
+        if self.csum_seed.is_none() {
+            return Ok(());
         }
 
-        // Get the parent directory
-        println!("获取父目录路径");
-        let parent_path = match path.rfind('/') {
-            Some(pos) => {
-                if pos == 0 {
-                    "/"
-                } else {
-                    &path[..pos]
-                }
-            }
-            None => "/",
+        let block_size = self.superblock.block_size();
+        for i in 0..inode.get_size().div_ceil(block_size as u64) as u32 {
+            self.read_dir_block(inode_num, inode, i)?;
+        }
+        Ok(())
+    }
+
+    /// Read a logical block of a directory, verifying its dirent or dx checksum tail if it
+    /// has one.
+    ///
+    /// Returns `None` for holes.
+    fn read_dir_block(
+        &self,
+        inode_num: u32,
+        inode: &Inode,
+        logical: u32,
+    ) -> Result<Option<Vec<u8>>, Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let mut reader = self.reader()?;
+        let block_num = match inode.map_block(&mut reader, logical, block_size)? {
+            Some(block_num) => block_num,
+            None => return Ok(None),
         };
-        println!("父目录路径: {}", parent_path);
+        let mut block_data = vec![0u8; block_size as usize];
+        reader.seek(SeekFrom::Start(block_num * block_size as u64))?;
+        reader.read_exact(&mut block_data)?;
 
-        println!("获取目录名");
-        let dirname = match path.rfind('/') {
-            Some(pos) => &path[pos + 1..],
-            None => path,
+        let seed = match self.csum_seed {
+            Some(seed) => checksum::inode_seed(seed, inode_num, inode.generation),
+            None => return Ok(Some(block_data)),
         };
-        println!("目录名: {}", dirname);
 
-        println!("查找父目录的 inode 号");
-        let parent_inode_num = self.find_by_path(parent_path)?;
-        println!("父目录 inode 号: {}", parent_inode_num);
+        // Index blocks carry a dx tail after their entries and may keep a stale dirent tail
+        // from when they were leaves; blocks without a tail were written without checksums
+        let index_block =
+            self.is_indexed(inode) && (logical == 0 || htree::is_interior_node(&block_data));
+        let checksums = if index_block {
+            htree::count_offset(&block_data).and_then(|count_offset| {
+                checksum::dx_block_checksums(seed, &block_data, count_offset)
+            })
+        } else if checksum::has_dirent_tail(&block_data) {
+            Some(checksum::dirent_block_checksums(seed, &block_data))
+        } else {
+            None
+        };
+        if let Some((stored, computed)) = checksums {
+            self.checksum_policy.check(
+                &format!("directory block {} of inode {}", block_num, inode_num),
+                stored,
+                computed,
+            )?;
+        }
+        Ok(Some(block_data))
+    }
 
-        // 1. Remove the directory entry from the parent directory
-        println!("从父目录中移除目录项");
-        self.remove_directory_entry(parent_inode_num, dirname)?;
-        println!("成功从父目录移除目录项");
+    /// Read a logical block of a directory and parse its records.
+    ///
+    /// Returns the physical block, its contents and its records, or `None` for holes.
+    fn read_dir_records(
+        &self,
+        inode_num: u32,
+        inode: &Inode,
+        logical: u32,
+    ) -> Result<Option<(u64, Vec<u8>, DirectoryBlock)>, Ext4Error> {
+        let block_data = match self.read_dir_block(inode_num, inode, logical)? {
+            Some(block_data) => block_data,
+            None => return Ok(None),
+        };
+        let block_num = self.dir_block_num(inode, logical)?;
 
-        // 2. Update the parent inode's link count
-        println!("更新父目录的链接计数");
-        let mut parent_inode = self.read_inode(parent_inode_num)?;
-        parent_inode.links_count -= 1;
-        self.write_inode(parent_inode_num, &parent_inode)?;
-        println!("成功更新父目录链接计数: {}", parent_inode.links_count);
+        // 校验和尾部不属于可用空间
+        let usable = Self::dir_block_usable(&block_data);
+        let records = DirectoryBlock::parse(&block_data[..usable])?;
+        Ok(Some((block_num, block_data, records)))
+    }
 
-        // 3. Free the blocks used by the directory
-        println!("开始释放目录使用的数据块");
-        let mut blocks_freed = 0;
-        for i in 0..12 {
-            if inode.block[i] != 0 {
-                println!("释放数据块: {}", inode.block[i]);
-                self.free_block(inode.block[i])?;
-                blocks_freed += 1;
+    /// Look up a name in a directory, returning the inode it links to.
+    ///
+    /// Indexed directories are searched through their hash tree, so only the blocks on the
+    /// way to the name are read; others are scanned a block at a time.
+    pub fn lookup(&mut self, dir_inode_num: u32, name: &str) -> Result<Option<u32>, Ext4Error> {
+        let inode = self.read_inode(dir_inode_num)?;
+        if !inode.is_directory() {
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "Inode {} is not a directory",
+                dir_inode_num
+            )));
+        }
+        if inode.has_inline_data() {
+            if name == "." {
+                return Ok(Some(dir_inode_num));
+            }
+            let data = self.read_inline_data(dir_inode_num, &inode)?;
+            return Ok(InlineDirectory::parse(&data)?.find(name.as_bytes()));
+        }
+        self.verify_extent_tree(dir_inode_num, &inode)?;
+
+        // Like the kernel, fall back to a linear search when the index root is unusable;
+        // "." and ".." sit in the index root itself, which the first block search finds
+        if self.is_indexed(&inode) && name != "." && name != ".." {
+            match self.dx_lookup(dir_inode_num, &inode, name) {
+                Err(Ext4Error::InvalidDirectory(message)) => log::warn!(
+                    "Searching directory {} without its index: {}",
+                    dir_inode_num,
+                    message
+                ),
+                result => return result.map(|found| found.map(|(_, inode_num)| inode_num)),
             }
         }
-        println!("成功释放 {} 个数据块", blocks_freed);
 
-        // 4. Mark the inode as free
-        println!("标记 inode {} 为空闲", inode_num);
-        self.free_inode(inode_num)?;
-        println!("成功释放 inode");
+        let block_size = self.superblock.block_size();
+        for i in 0..inode.get_size().div_ceil(block_size as u64) as u32 {
+            if let Some(block_data) = self.read_dir_block(dir_inode_num, &inode, i)? {
+                if let Some(found) = DirectoryBlock::parse(&block_data)?.find(name.as_bytes()) {
+                    return Ok(Some(found));
+                }
+            }
+        }
+        Ok(None)
+    }
 
-        // 5. Update superblock and block group descriptors
-        println!("更新超级块和块组描述符");
-        self.superblock.free_blocks_count += blocks_freed;
-        self.superblock.free_inodes_count += 1;
-        println!(
-            "更新后的空闲块数: {}, 空闲inode数: {}",
-            self.superblock.free_blocks_count, self.superblock.free_inodes_count
-        );
-        self.write_superblock()?;
-        println!("成功更新超级块");
+    /// Check if a directory is indexed by a hash tree.
+    fn is_indexed(&self, inode: &Inode) -> bool {
+        self.superblock.has_compat(CompatFeature::DirIndex) && inode.flags & EXT4_INDEX_FL != 0
+    }
 
-        // 确保所有更改都写入磁盘
-        println!("正在同步文件系统更改到磁盘...");
-        self.sync_fs_metadata()?;
-        println!("文件系统更改已成功同步到磁盘");
+    /// Get the number of levels a directory index may have, counting the root.
+    fn dx_max_levels(&self) -> usize {
+        if self.superblock.has_incompat(IncompatFeature::LargeDir) {
+            3
+        } else {
+            2
+        }
+    }
 
-        println!("目录 '{}' 删除完成", path);
-        Ok(())
+    /// Follow the hash tree of an indexed directory down to the leaf that may hold a name.
+    ///
+    /// Returns the hash version, the hash of the name and the index nodes from the root down.
+    fn dx_probe(
+        &self,
+        inode_num: u32,
+        inode: &Inode,
+        name: &str,
+    ) -> Result<(HashVersion, u32, Vec<DxFrame>), Ext4Error> {
+        let root_block = self
+            .read_dir_block(inode_num, inode, 0)?
+            .ok_or_else(|| Ext4Error::InvalidDirectory("Index root is a hole".to_string()))?;
+        let root = DxRoot::parse(&root_block, self.dx_max_levels() as u8)?;
+        let unsigned = self.superblock.flags & EXT2_FLAGS_UNSIGNED_HASH != 0;
+        let version = HashVersion::from_root(root.hash_version, unsigned).ok_or_else(|| {
+            Ext4Error::InvalidDirectory(format!("Unknown hash version {}", root.hash_version))
+        })?;
+        let (hash, _) = htree::dx_hash(name.as_bytes(), version, &self.superblock.hash_seed);
+
+        let levels = root.indirect_levels as usize + 1;
+        let mut frames = vec![DxFrame {
+            logical: 0,
+            at: root.node.find(hash),
+            node: root.node,
+        }];
+        while frames.len() < levels {
+            let logical = frames[frames.len() - 1].child();
+            let node = self.read_dx_node(inode_num, inode, logical)?;
+            frames.push(DxFrame {
+                logical,
+                at: node.find(hash),
+                node,
+            });
+        }
+        Ok((version, hash, frames))
     }
 
-    /// Create a new directory in the filesystem.
-    pub fn create_directory(&mut self, parent_path: &str, dirname: &str) -> Result<(), Ext4Error> {
-        println!(
-            "开始创建目录: parent_path={}, dirname={}",
-            parent_path, dirname
-        );
+    /// Search an indexed directory, following the hash tree to the leaf blocks that can
+    /// hold the name.
+    ///
+    /// Returns the logical block holding the entry and the inode it links to.
+    fn dx_lookup(
+        &self,
+        inode_num: u32,
+        inode: &Inode,
+        name: &str,
+    ) -> Result<Option<(u32, u32)>, Ext4Error> {
+        let (_, hash, mut frames) = self.dx_probe(inode_num, inode, name)?;
+        let levels = frames.len();
+        loop {
+            let leaf = frames[levels - 1].child();
+            if let Some(block_data) = self.read_dir_block(inode_num, inode, leaf)? {
+                if let Some(found) = DirectoryBlock::parse(&block_data)?.find(name.as_bytes()) {
+                    return Ok(Some((leaf, found)));
+                }
+            }
 
-        // Find the parent directory inode
-        let parent_inode_num = self.find_by_path(parent_path)?;
-        println!("找到父目录 inode 号: {}", parent_inode_num);
+            // Names sharing a hash can spill into the next leaf, whose hash then has the low
+            // bit set
+            while let Some(frame) = frames.last() {
+                if frame.at + 1 < frame.node.entries.len() {
+                    break;
+                }
+                frames.pop();
+            }
+            let frame = match frames.last_mut() {
+                Some(frame) => frame,
+                None => return Ok(None),
+            };
+            frame.at += 1;
+            if frame.node.entries[frame.at].hash & !1 != hash {
+                return Ok(None);
+            }
+            while frames.len() < levels {
+                let logical = frames[frames.len() - 1].child();
+                let node = self.read_dx_node(inode_num, inode, logical)?;
+                frames.push(DxFrame {
+                    logical,
+                    node,
+                    at: 0,
+                });
+            }
+        }
+    }
 
-        let parent_inode = self.read_inode(parent_inode_num)?;
-        println!("读取父目录 inode 信息成功");
+    /// Read an interior node of a directory index.
+    fn read_dx_node(
+        &self,
+        inode_num: u32,
+        inode: &Inode,
+        logical: u32,
+    ) -> Result<DxNode, Ext4Error> {
+        let block_data = self
+            .read_dir_block(inode_num, inode, logical)?
+            .ok_or_else(|| {
+                Ext4Error::InvalidDirectory(format!("Index node {} is a hole", logical))
+            })?;
+        DxNode::parse_interior(&block_data)
+    }
 
-        if !parent_inode.is_directory() {
-            println!("错误: 父路径不是目录");
-            return Err(Ext4Error::InvalidDirectory(format!(
-                "'{}' is not a directory",
-                parent_path
+    /// Open a file from the filesystem.
+    pub fn open_file(&mut self, inode_num: u32) -> Result<File, Ext4Error> {
+        let inode = self.read_inode(inode_num)?;
+        if !inode.is_file() {
+            return Err(Ext4Error::InvalidFile(format!(
+                "Inode {} is not a regular file",
+                inode_num
             )));
         }
+        self.verify_extent_tree(inode_num, &inode)?;
 
-        // 检查目录是否已存在
-        println!("检查目录 '{}' 是否已存在", dirname);
-        let mut parent_directory = match self.read_directory(parent_inode_num) {
-            Ok(dir) => {
-                println!("成功读取父目录内容");
-                dir
-            }
-            Err(Ext4Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
-                println!("父目录为空，创建新的空目录结构");
+        let mut file = File::new(inode);
+        if file.inode.has_inline_data() {
+            file.inline_data = Some(self.read_inline_data(inode_num, &file.inode)?);
+        }
+        Ok(file)
+    }
+
+    /// Read data from a file.
+    pub fn read_file(
+        &mut self,
+        inode_num: u32,
+        buffer: &mut [u8],
+        position: u64,
+    ) -> Result<usize, Ext4Error> {
+        let mut file = self.open_file(inode_num)?;
+        file.seek(position)?;
+
+        let mut reader = self.reader()?;
+        file.read(&mut reader, buffer, self.superblock.block_size())
+    }
+
+    /// Read the target of a symbolic link, without following it.
+    pub fn readlink(&mut self, path: &str) -> Result<String, Ext4Error> {
+        let inode_num = self.resolve_path(path, false)?;
+        let inode = self.read_inode(inode_num)?;
+        if !inode.is_symlink() {
+            return Err(Ext4Error::InvalidFile(format!(
+                "'{}' is not a symbolic link",
+                path
+            )));
+        }
+        self.read_symlink(inode_num, &inode)
+    }
+
+    /// Read the target of a symbolic link inode, from `i_block` for a fast symlink or else
+    /// from inline data or its first block.
+    fn read_symlink(&self, inode_num: u32, inode: &Inode) -> Result<String, Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let len = inode.get_size() as usize;
+        let target = if inode.has_inline_data() {
+            self.read_inline_data(inode_num, inode)?
+        } else if inode.is_fast_symlink(block_size) {
+            if len > INODE_BLOCK_SIZE {
+                return Err(Ext4Error::InvalidInode(format!(
+                    "Fast symlink {} is {} bytes long",
+                    inode_num, len
+                )));
+            }
+            inode.block_bytes()[..len].to_vec()
+        } else {
+            if len >= block_size as usize {
+                return Err(Ext4Error::InvalidInode(format!(
+                    "Symlink {} is {} bytes long",
+                    inode_num, len
+                )));
+            }
+            let mut reader = self.reader()?;
+            let block_num = match inode.map_block(&mut reader, 0, block_size)? {
+                Some(block_num) => block_num,
+                None => {
+                    return Err(Ext4Error::InvalidInode(format!(
+                        "Symlink {} has no target block",
+                        inode_num
+                    )))
+                }
+            };
+            let mut block_data = vec![0u8; len];
+            reader.seek(SeekFrom::Start(block_num * block_size as u64))?;
+            reader.read_exact(&mut block_data)?;
+            block_data
+        };
+
+        String::from_utf8(target).map_err(|_| {
+            Ext4Error::InvalidInode(format!("Target of symlink {} is not UTF-8", inode_num))
+        })
+    }
+
+    /// Get the root directory of the filesystem.
+    pub fn root_directory(&mut self) -> Result<Directory, Ext4Error> {
+        // The root directory is always inode 2 in ext4
+        self.read_directory(2)
+    }
+
+    /// Find a file or directory by path, following symbolic links.
+    pub fn find_by_path(&mut self, path: &str) -> Result<u32, Ext4Error> {
+        self.resolve_path(path, true)
+    }
+
+    /// Find a file or directory by path.
+    ///
+    /// Symbolic links met on the way are followed, relative targets from the directory
+    /// holding the link; the last component is only followed if `follow_last` is set, so a
+    /// link itself can be found. Like the kernel, giving up after following 40 links.
+    pub fn resolve_path(&mut self, path: &str, follow_last: bool) -> Result<u32, Ext4Error> {
+        // Components still to be looked up, the next one last
+        let mut pending: Vec<String> = path.split('/').rev().map(String::from).collect();
+        let mut current_inode = 2; // Start from the root directory
+        let mut links_followed = 0;
+
+        while let Some(component) = pending.pop() {
+            if component.is_empty() || component == "." {
+                continue;
+            }
+
+            let inode_num = match self.lookup(current_inode, &component)? {
+                Some(inode_num) => inode_num,
+                None => {
+                    return Err(Ext4Error::InvalidFile(format!(
+                        "Path component not found: {}",
+                        component
+                    )));
+                }
+            };
+
+            // A trailing slash leaves an empty component behind, which also follows the link
+            let inode = self.read_inode(inode_num)?;
+            if inode.is_symlink() && (follow_last || !pending.is_empty()) {
+                links_followed += 1;
+                if links_followed > MAX_SYMLINK_FOLLOWS {
+                    return Err(Ext4Error::SymlinkLoop(path.to_string()));
+                }
+                let target = self.read_symlink(inode_num, &inode)?;
+                if target.starts_with('/') {
+                    current_inode = 2;
+                }
+                pending.extend(target.split('/').rev().map(String::from));
+                continue;
+            }
+
+            current_inode = inode_num;
+        }
+
+        Ok(current_inode)
+    }
+
+    /// Write a file to the filesystem.
+    pub fn write_file(
+        &mut self,
+        parent_path: &str,
+        filename: &str,
+        data: &[u8],
+    ) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.write_file_inner(parent_path, filename, data))
+    }
+
+    fn write_file_inner(
+        &mut self,
+        parent_path: &str,
+        filename: &str,
+        data: &[u8],
+    ) -> Result<(), Ext4Error> {
+        // Find the parent directory inode
+        let parent_inode_num = self.find_by_path(parent_path)?;
+        let parent_inode = self.read_inode(parent_inode_num)?;
+
+        if !parent_inode.is_directory() {
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "'{}' is not a directory",
+                parent_path
+            )));
+        }
+
+        // Get current time
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+
+        // Check if file already exists
+        let existing_entry = self.lookup(parent_inode_num, filename)?;
+        let (inode_num, mut inode) = match existing_entry {
+            Some(inode_num) => {
+                // File exists, read its inode
+                let mut inode = self.read_inode(inode_num)?;
+
+                if !inode.is_file() {
+                    return Err(Ext4Error::InvalidFile(format!(
+                        "'{}' exists but is not a regular file",
+                        filename
+                    )));
+                }
+
+                // Free the existing blocks, or drop the existing inline data
+                self.free_inode_blocks(&inode)?;
+                if inode.has_inline_data() {
+                    self.remove_inline_xattr(inode_num, &inode)?;
+                }
+
+                // Like truncating it, keep everything but the data: its links, owner,
+                // mode and attribute block stay as they are
+                inode.flags &= !(EXT4_EXTENTS_FL | EXT4_INLINE_DATA_FL | EXT4_HUGE_FILE_FL);
+                inode.set_block_bytes(&[0u8; INODE_BLOCK_SIZE]);
+                let xattr = (inode.get_file_acl() != 0) as u64;
+                let block_size = self.superblock.block_size();
+                self.set_inode_blocks(&mut inode, xattr * (block_size / 512) as u64)?;
+                (inode_num, inode)
+            }
+            None => {
+                // File doesn't exist, allocate a new inode
+                let inode = Inode {
+                    mode: 0x81A4, // Regular file with 0644 permissions
+                    links_count: 1,
+                    atime: now,
+                    crtime: now,
+                    ..self.default_inode()
+                };
+                (self.allocate_inode(false)?, inode)
+            }
+        };
+
+        // Create or update the inode
+        self.set_file_size(&mut inode, data.len() as u64);
+        inode.ctime = now;
+        inode.mtime = now;
+
+        // Like the kernel, small files live in the inode itself and empty ones get no
+        // inline data until written to
+        if !data.is_empty() && data.len() <= self.max_inline_size(inode_num, &inode)? {
+            self.write_inline_data(inode_num, &mut inode, data)?;
+        } else {
+            self.write_file_blocks(inode_num, &mut inode, data)?;
+        }
+
+        // If this is a new file, add an entry to the parent directory
+        if existing_entry.is_none() {
+            self.add_directory_entry(parent_inode_num, filename, inode_num, 1)?;
+            // 1 = regular file
+        }
+
+        // Update superblock
+        self.write_superblock()?;
+
+        Ok(())
+    }
+
+    /// Write the data of a file to newly allocated blocks, mapped through an extent tree
+    /// or block map, then write its inode.
+    fn write_file_blocks(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        data: &[u8],
+    ) -> Result<(), Ext4Error> {
+        // Calculate how many blocks we need
+        let block_size = self.superblock.block_size();
+        let blocks_needed = data.len().div_ceil(block_size as usize) as u64;
+
+        let mut blocks_allocated = 0;
+        if self.superblock.has_incompat(IncompatFeature::Extents) {
+            if blocks_needed > u32::MAX as u64 {
+                return Err(Ext4Error::InvalidOperation(
+                    "File is too large for an extent tree".to_string(),
+                ));
+            }
+
+            // Allocate contiguous runs and describe them with an extent tree
+            inode.flags |= EXT4_EXTENTS_FL;
+            let extents = self.allocate_extents(blocks_needed as u32)?;
+            for extent in &extents {
+                let start = extent.block as usize * block_size as usize;
+                let end = std::cmp::min(
+                    start + extent.length() as usize * block_size as usize,
+                    data.len(),
+                );
+                self.write_data_blocks(extent.start, &data[start..end])?;
+            }
+            blocks_allocated += blocks_needed;
+            blocks_allocated += self.write_extent_tree(inode_num, inode, &extents)? as u64;
+        } else {
+            if blocks_needed > block_map::max_blocks(block_size) {
+                return Err(Ext4Error::InvalidOperation(
+                    "File is too large for an indirect block map".to_string(),
+                ));
+            }
+
+            // Allocate data blocks and write data, then map them through the inode
+            let mut blocks = Vec::with_capacity(blocks_needed as usize);
+            while (blocks.len() as u64) < blocks_needed {
+                let wanted = std::cmp::min(blocks_needed - blocks.len() as u64, u32::MAX as u64);
+                let (start, len) = self.allocate_blocks(wanted as u32)?;
+                let offset = blocks.len() * block_size as usize;
+                let end = std::cmp::min(offset + len as usize * block_size as usize, data.len());
+                self.write_data_blocks(start, &data[offset..end])?;
+                let start = self.mappable_block(start, len)?;
+                blocks.extend(start..start + len);
+            }
+            blocks_allocated += blocks_needed;
+            blocks_allocated += self.write_block_map(inode, &blocks)? as u64;
+        }
+
+        // Update inode blocks count (in 512-byte units), with any attribute block
+        blocks_allocated += (inode.get_file_acl() != 0) as u64;
+        self.set_inode_blocks(inode, blocks_allocated * (block_size / 512) as u64)?;
+
+        // Write the inode to disk
+        self.write_inode(inode_num, inode)
+    }
+
+    /// Write data into a regular file at a byte offset, like `pwrite(2)`.
+    ///
+    /// Only the blocks the write touches are allocated, and whatever it skips over past the
+    /// end of the file is left as a hole. Returns the number of bytes written.
+    pub fn write_at(
+        &mut self,
+        inode_num: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, Ext4Error> {
+        self.transaction(|fs| fs.write_at_inner(inode_num, offset, data))
+    }
+
+    fn write_at_inner(
+        &mut self,
+        inode_num: u32,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<usize, Ext4Error> {
+        let mut inode = self.read_inode(inode_num)?;
+        if !inode.is_file() {
+            return Err(Ext4Error::InvalidFile(format!(
+                "Inode {} is not a regular file",
+                inode_num
+            )));
+        }
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let end = offset.saturating_add(data.len() as u64);
+        self.check_file_size(&inode, end)?;
+        let size = inode.get_size();
+
+        // Inline data grows in place while it fits, and is moved out to a block otherwise
+        if inode.has_inline_data() && end <= self.max_inline_size(inode_num, &inode)? as u64 {
+            let mut contents = self.read_inline_data(inode_num, &inode)?;
+            contents.resize(std::cmp::max(size, end) as usize, 0);
+            contents[offset as usize..end as usize].copy_from_slice(data);
+            self.write_inline_data(inode_num, &mut inode, &contents)?;
+        } else {
+            if inode.has_inline_data() {
+                self.expand_inline_data(inode_num, &mut inode)?;
+            }
+            self.write_blocks_at(inode_num, &mut inode, offset, data)?;
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        if end > size {
+            self.set_file_size(&mut inode, end);
+        }
+        inode.mtime = now;
+        inode.ctime = now;
+        self.write_inode(inode_num, &inode)?;
+        self.write_superblock()?;
+        Ok(data.len())
+    }
+
+    /// Write data at a byte offset into the blocks of a file, mapping the blocks it covers.
+    ///
+    /// Blocks the data only partly covers keep the rest of what they held.
+    fn write_blocks_at(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), Ext4Error> {
+        let block_size = self.superblock.block_size() as u64;
+        let end = offset + data.len() as u64;
+        let (first, last) = (offset / block_size, end.div_ceil(block_size));
+
+        // Lay the data out over whole blocks, starting from the old contents of the edges
+        let mut buf = vec![0u8; ((last - first) * block_size) as usize];
+        let mut edges = Vec::new();
+        if !offset.is_multiple_of(block_size) {
+            edges.push(first);
+        }
+        if !end.is_multiple_of(block_size) {
+            edges.push(last - 1);
+        }
+        let mut reader = self.reader()?;
+        for logical in edges {
+            let found = inode.map_block(&mut reader, logical as u32, block_size as u32)?;
+            if let Some(block_num) = found {
+                let at = ((logical - first) * block_size) as usize;
+                reader.seek(SeekFrom::Start(block_num * block_size))?;
+                reader.read_exact(&mut buf[at..at + block_size as usize])?;
+            }
+        }
+        let head = (offset - first * block_size) as usize;
+        buf[head..head + data.len()].copy_from_slice(data);
+
+        // Write each run of consecutive blocks at once
+        let mapped = self.map_file_blocks(inode_num, inode, first as u32, last as u32)?;
+        let mut i = 0;
+        while i < mapped.len() {
+            let mut run = 1;
+            while i + run < mapped.len() && mapped[i + run] == mapped[i] + run as u64 {
+                run += 1;
+            }
+            let at = i * block_size as usize;
+            self.write_data_blocks(mapped[i], &buf[at..at + run * block_size as usize])?;
+            i += run;
+        }
+        Ok(())
+    }
+
+    /// Change the size of a regular file, like `truncate(2)`.
+    ///
+    /// Shrinking frees the blocks past the new end and zeroes the rest of the last block;
+    /// growing leaves a hole.
+    pub fn truncate(&mut self, path: &str, new_size: u64) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.truncate_inner(path, new_size))
+    }
+
+    fn truncate_inner(&mut self, path: &str, new_size: u64) -> Result<(), Ext4Error> {
+        let (inode_num, mut inode) = self.find_regular_file(path)?;
+        self.check_file_size(&inode, new_size)?;
+        let block_size = self.superblock.block_size() as u64;
+        let old_size = inode.get_size();
+
+        // Like the kernel, inline data is cut or zero-extended in place only while it fits
+        // the space it already takes, and moved out to a block otherwise
+        if inode.has_inline_data() {
+            let xattrs = self.read_inode_xattrs(inode_num, &inode)?;
+            let value = xattrs.get(EXT4_XATTR_INDEX_SYSTEM, EXT4_XATTR_SYSTEM_DATA);
+            let inline_size = INODE_BLOCK_SIZE + value.map_or(0, <[u8]>::len);
+            if new_size <= inline_size as u64 {
+                let mut data = self.read_inline_data(inode_num, &inode)?;
+                data.resize(new_size as usize, 0);
+                self.write_inline_data(inode_num, &mut inode, &data)?;
+            } else {
+                self.expand_inline_data(inode_num, &mut inode)?;
+            }
+        }
+
+        if !inode.has_inline_data() && new_size != old_size {
+            // The part of the last block past the smaller end must read as zeros
+            let tail = std::cmp::min(old_size, new_size);
+            self.zero_partial_blocks(&inode, tail, tail.next_multiple_of(block_size))?;
+            if new_size < old_size {
+                let from = new_size.div_ceil(block_size);
+                self.unmap_file_blocks(inode_num, &mut inode, from, u64::MAX)?;
+            }
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        self.set_file_size(&mut inode, new_size);
+        inode.mtime = now;
+        inode.ctime = now;
+        self.write_inode(inode_num, &inode)?;
+        self.write_superblock()
+    }
+
+    /// Allocate, punch or zero a byte range of a regular file, like `fallocate(2)`.
+    ///
+    /// Preallocating and zeroing need an extent-mapped file. Inline data is moved out to a
+    /// block first.
+    pub fn fallocate(
+        &mut self,
+        path: &str,
+        mode: FallocateMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.fallocate_inner(path, mode, offset, len))
+    }
+
+    fn fallocate_inner(
+        &mut self,
+        path: &str,
+        mode: FallocateMode,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Ext4Error> {
+        let (inode_num, mut inode) = self.find_regular_file(path)?;
+        let end = match offset.checked_add(len) {
+            Some(end) if len != 0 => end,
+            _ => {
+                return Err(Ext4Error::InvalidOperation(format!(
+                    "Invalid range of {} bytes at offset {}",
+                    len, offset
+                )))
+            }
+        };
+        let block_size = self.superblock.block_size() as u64;
+        let size = inode.get_size();
+
+        if inode.has_inline_data() {
+            self.expand_inline_data(inode_num, &mut inode)?;
+        }
+
+        match mode {
+            FallocateMode::PunchHole => {
+                // Like the kernel, nothing past the block holding the end of the file
+                if offset >= size {
+                    return Ok(());
+                }
+                let end = std::cmp::min(end, size.next_multiple_of(block_size));
+                self.zero_partial_blocks(&inode, offset, end)?;
+                let (from, to) = (offset.div_ceil(block_size), end / block_size);
+                self.unmap_file_blocks(inode_num, &mut inode, from, to)?;
+            }
+            FallocateMode::Allocate { keep_size } | FallocateMode::ZeroRange { keep_size } => {
+                if !inode.uses_extents() {
+                    return Err(Ext4Error::UnsupportedFeature(
+                        "preallocation in block-mapped files".to_string(),
+                    ));
+                }
+                self.check_file_size(&inode, end)?;
+
+                let (mut extents, tree_blocks) = self.read_extent_map(&inode)?;
+                let zero = matches!(mode, FallocateMode::ZeroRange { .. });
+                if zero {
+                    let (from, to) = (offset.div_ceil(block_size), end / block_size);
+                    if from < to {
+                        let (from, len) = (from as u32, (to - from) as u32);
+                        extent::set_unwritten(&mut extents, from, len, true);
+                    }
+                }
+                let (from, to) = (offset / block_size, end.div_ceil(block_size));
+                self.fill_holes(&mut extents, from as u32, to as u32, true)?;
+                self.rewrite_extent_tree(inode_num, &mut inode, &extents, tree_blocks)?;
+                if zero {
+                    self.zero_partial_blocks(&inode, offset, end)?;
+                }
+
+                if !keep_size && end > size {
+                    self.set_file_size(&mut inode, end);
+                }
+            }
+        }
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        inode.mtime = now;
+        inode.ctime = now;
+        self.write_inode(inode_num, &inode)?;
+        self.write_superblock()
+    }
+
+    /// Find a regular file by path, following symbolic links.
+    fn find_regular_file(&mut self, path: &str) -> Result<(u32, Inode), Ext4Error> {
+        let inode_num = self.find_by_path(path)?;
+        let inode = self.read_inode(inode_num)?;
+        if !inode.is_file() {
+            return Err(Ext4Error::InvalidFile(format!(
+                "'{}' is not a regular file",
+                path
+            )));
+        }
+        Ok((inode_num, inode))
+    }
+
+    /// Check that a file's blocks can be mapped far enough to hold `size` bytes.
+    ///
+    /// Inline data counts as whatever mapping it would be moved out to.
+    fn check_file_size(&self, inode: &Inode, size: u64) -> Result<(), Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let extents = inode.uses_extents()
+            || (inode.has_inline_data() && self.superblock.has_incompat(IncompatFeature::Extents));
+        let max_blocks = if extents {
+            u32::MAX as u64
+        } else {
+            block_map::max_blocks(block_size)
+        };
+        if size.div_ceil(block_size as u64) > max_blocks {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "File size {} is too large",
+                size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Set the size of a file, turning on `large_file` once it passes 2 GiB like the kernel.
+    fn set_file_size(&mut self, inode: &mut Inode, size: u64) {
+        inode.set_size(size);
+        if size > i32::MAX as u64 {
+            self.superblock.feature_ro_compat |= RoCompatFeature::LargeFile as u32;
+        }
+    }
+
+    /// Zero the parts of the blocks at either end of bytes `start..end` of a file that the
+    /// range only partly covers.
+    ///
+    /// Holes and unwritten blocks already read as zeros and are left alone.
+    fn zero_partial_blocks(
+        &mut self,
+        inode: &Inode,
+        start: u64,
+        end: u64,
+    ) -> Result<(), Ext4Error> {
+        if start >= end {
+            return Ok(());
+        }
+        let block_size = self.superblock.block_size() as u64;
+        let (first, last) = (start / block_size, (end - 1) / block_size);
+        let ranges = if first == last {
+            vec![(start, end)]
+        } else {
+            vec![(start, (first + 1) * block_size), (last * block_size, end)]
+        };
+
+        let mut data = vec![0u8; block_size as usize];
+        for (from, to) in ranges {
+            if to - from == block_size {
+                continue;
+            }
+            let logical = (from / block_size) as u32;
+            let mut reader = self.reader()?;
+            let block_num = match inode.map_block(&mut reader, logical, block_size as u32)? {
+                Some(block_num) => block_num,
+                None => continue,
+            };
+            reader.seek(SeekFrom::Start(block_num * block_size))?;
+            reader.read_exact(&mut data)?;
+            let offset = (from % block_size) as usize;
+            data[offset..offset + (to - from) as usize].fill(0);
+            self.write_data_blocks(block_num, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Free the blocks mapping logical blocks `from..to` of a file, leaving a hole, and
+    /// update its block count.
+    fn unmap_file_blocks(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        from: u64,
+        to: u64,
+    ) -> Result<(), Ext4Error> {
+        if !inode.uses_extents() {
+            let freed = self.punch_block_map(inode, from, to)?;
+            let block_size = self.superblock.block_size();
+            let sectors = inode.get_sectors(block_size) - freed as u64 * (block_size / 512) as u64;
+            return self.set_inode_blocks(inode, sectors);
+        }
+
+        // The last logical block an extent can map is u32::MAX - 1
+        let to = std::cmp::min(to, u32::MAX as u64);
+        if from >= to {
+            return Ok(());
+        }
+        let (mut extents, tree_blocks) = self.read_extent_map(inode)?;
+        let removed = extent::punch_extents(&mut extents, from as u32, (to - from) as u32);
+        if removed.is_empty() {
+            return Ok(());
+        }
+        for piece in removed {
+            self.free_blocks(piece.start, piece.length())?;
+        }
+        self.rewrite_extent_tree(inode_num, inode, &extents, tree_blocks)
+    }
+
+    /// Allocate extents for the holes in logical blocks `from..to` of a sorted list of
+    /// extents, unwritten or written.
+    ///
+    /// Returns true if there were any holes.
+    fn fill_holes(
+        &mut self,
+        extents: &mut Vec<Extent>,
+        from: u32,
+        to: u32,
+        unwritten: bool,
+    ) -> Result<bool, Ext4Error> {
+        let (max_len, flag) = if unwritten {
+            let max_len = extent::EXT_UNWRITTEN_MAX_LEN as u32;
+            (max_len, extent::EXT_INIT_MAX_LEN)
+        } else {
+            (extent::EXT_INIT_MAX_LEN as u32, 0)
+        };
+        let holes = extent::find_holes(extents, from, to);
+        for hole in &holes {
+            let mut block = hole.start;
+            while block < hole.end {
+                let (start, len) =
+                    self.allocate_blocks(std::cmp::min(hole.end - block, max_len))?;
+                extent::insert_extent(
+                    extents,
+                    Extent {
+                        block,
+                        len: len as u16 + flag,
+                        start,
+                    },
+                );
+                block += len;
+            }
+        }
+        Ok(!holes.is_empty())
+    }
+
+    /// Map logical blocks `from..to` of a file to blocks that hold written data, allocating
+    /// blocks for the holes and marking unwritten extents written, and update its block count.
+    ///
+    /// Returns the block each logical block maps to.
+    fn map_file_blocks(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        from: u32,
+        to: u32,
+    ) -> Result<Vec<u64>, Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let mut mapped = Vec::with_capacity((to - from) as usize);
+
+        if !inode.uses_extents() {
+            for logical in from..to {
+                let mut reader = self.reader()?;
+                let block_num = match inode.map_block(&mut reader, logical, block_size)? {
+                    Some(block_num) => block_num,
+                    None => {
+                        let block_num = self.allocate_block()?;
+                        let block_num = self.mappable_block(block_num, 1)?;
+                        let meta_blocks = self.map_indirect_block(inode, logical, block_num)?;
+                        let sectors = (1 + meta_blocks as u64) * (block_size / 512) as u64;
+                        self.set_inode_blocks(inode, inode.get_sectors(block_size) + sectors)?;
+                        block_num as u64
+                    }
+                };
+                mapped.push(block_num);
+            }
+            return Ok(mapped);
+        }
+
+        let (mut extents, tree_blocks) = self.read_extent_map(inode)?;
+        let overlaps =
+            |e: &Extent| e.block < to && e.block as u64 + e.length() as u64 > from as u64;
+        let unwritten = extents.iter().any(|e| overlaps(e) && e.is_unwritten());
+        if unwritten {
+            extent::set_unwritten(&mut extents, from, to - from, false);
+        }
+        if self.fill_holes(&mut extents, from, to, false)? || unwritten {
+            self.rewrite_extent_tree(inode_num, inode, &extents, tree_blocks)?;
+        }
+
+        for e in extents.iter().filter(|e| overlaps(e)) {
+            let first = std::cmp::max(e.block, from);
+            let last = std::cmp::min(e.block as u64 + e.length() as u64, to as u64) as u32;
+            mapped.extend((first..last).map(|block| e.start + (block - e.block) as u64));
+        }
+        Ok(mapped)
+    }
+
+    /// Remove a file or symbolic link from the filesystem, like `unlink`.
+    pub fn remove_file(&mut self, path: &str) -> Result<(), Ext4Error> {
+        self.unlink(path)
+    }
+
+    /// Remove a name of a file or symbolic link.
+    ///
+    /// The inode loses a link, and is only deleted along with its blocks once the last
+    /// link to it is gone.
+    pub fn unlink(&mut self, path: &str) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.unlink_inner(path))
+    }
+
+    fn unlink_inner(&mut self, path: &str) -> Result<(), Ext4Error> {
+        // Find the file inode; a symlink is removed rather than followed
+        let inode_num = self.resolve_path(path, false)?;
+        let mut inode = self.read_inode(inode_num)?;
+
+        if inode.is_directory() {
+            return Err(Ext4Error::InvalidFile(format!("'{}' is a directory", path)));
+        }
+
+        // Remove the directory entry from the parent directory
+        let (parent_path, filename) = split_path(path);
+        let parent_inode_num = self.find_by_path(parent_path)?;
+        self.remove_directory_entry(parent_inode_num, filename)?;
+
+        // Drop the link, deleting the inode with the last one
+        inode.links_count = inode.links_count.saturating_sub(1);
+        if inode.links_count == 0 {
+            self.delete_inode(inode_num, &mut inode)?;
+        } else {
+            inode.ctime = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as u32;
+            self.write_inode(inode_num, &inode)?;
+        }
+
+        // Update superblock and block group descriptors
+        self.write_superblock()?;
+
+        Ok(())
+    }
+
+    /// Give an existing file or symbolic link another name at `newpath`.
+    ///
+    /// Like the kernel, a symlink at `existing` is linked rather than followed, directories
+    /// cannot be linked, and an inode takes at most `EXT4_LINK_MAX` links.
+    pub fn link(&mut self, existing: &str, newpath: &str) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.link_inner(existing, newpath))
+    }
+
+    fn link_inner(&mut self, existing: &str, newpath: &str) -> Result<(), Ext4Error> {
+        let inode_num = self.resolve_path(existing, false)?;
+        let mut inode = self.read_inode(inode_num)?;
+        if inode.is_directory() {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Cannot create a hard link to directory '{}'",
+                existing
+            )));
+        }
+        if inode.links_count >= EXT4_LINK_MAX {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Too many links to '{}'",
+                existing
+            )));
+        }
+
+        let (parent_path, name) = split_path(newpath);
+        if name.is_empty() {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Invalid link path '{}'",
+                newpath
+            )));
+        }
+        let parent_inode_num = self.find_by_path(parent_path)?;
+        if self.lookup(parent_inode_num, name)?.is_some() {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "'{}' already exists",
+                newpath
+            )));
+        }
+
+        let file_type = self.entry_file_type(&inode);
+        self.add_directory_entry(parent_inode_num, name, inode_num, file_type)?;
+
+        inode.links_count += 1;
+        inode.ctime = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        self.write_inode(inode_num, &inode)?;
+        self.write_superblock()?;
+
+        Ok(())
+    }
+
+    /// Rename a file, symbolic link or directory, replacing whatever `new` names.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), Ext4Error> {
+        self.rename_with_mode(old, new, RenameMode::Replace)
+    }
+
+    /// Rename a file, symbolic link or directory, with `mode` deciding what happens to an
+    /// existing entry at `new`.
+    ///
+    /// Like `rename(2)`, neither path is followed if it names a symlink, a directory may
+    /// only replace an empty directory and a non-directory only a non-directory, and a
+    /// directory cannot be moved into itself. Renaming a name onto another link to the same
+    /// inode does nothing.
+    pub fn rename_with_mode(
+        &mut self,
+        old: &str,
+        new: &str,
+        mode: RenameMode,
+    ) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.rename_inner(old, new, mode))
+    }
+
+    fn rename_inner(&mut self, old: &str, new: &str, mode: RenameMode) -> Result<(), Ext4Error> {
+        let (old_parent_path, old_name) = split_path(old);
+        let (new_parent_path, new_name) = split_path(new);
+        for name in [old_name, new_name] {
+            if name.is_empty() || name == "." || name == ".." {
+                return Err(Ext4Error::InvalidOperation(format!(
+                    "Cannot rename '{}' to '{}'",
+                    old, new
+                )));
+            }
+        }
+
+        let old_parent = self.find_by_path(old_parent_path)?;
+        let new_parent = self.find_by_path(new_parent_path)?;
+        let old_inode_num = self
+            .lookup(old_parent, old_name)?
+            .ok_or_else(|| Ext4Error::InvalidFile(format!("'{}' not found", old)))?;
+        let new_inode_num = self.lookup(new_parent, new_name)?;
+        match (mode, new_inode_num) {
+            (RenameMode::NoReplace, Some(_)) => {
+                return Err(Ext4Error::InvalidOperation(format!(
+                    "'{}' already exists",
+                    new
+                )));
+            }
+            (RenameMode::Exchange, None) => {
+                return Err(Ext4Error::InvalidFile(format!("'{}' not found", new)));
+            }
+            _ => {}
+        }
+        if new_inode_num == Some(old_inode_num) {
+            return Ok(());
+        }
+
+        let old_inode = self.read_inode(old_inode_num)?;
+        let new_inode = match new_inode_num {
+            Some(inode_num) => Some(self.read_inode(inode_num)?),
+            None => None,
+        };
+        let old_is_dir = old_inode.is_directory();
+        let new_is_dir = new_inode.as_ref().is_some_and(|inode| inode.is_directory());
+
+        if mode != RenameMode::Exchange {
+            if let (Some(new_inode_num), Some(new_inode)) = (new_inode_num, &new_inode) {
+                if old_is_dir && !new_is_dir {
+                    return Err(Ext4Error::InvalidDirectory(format!(
+                        "'{}' is not a directory",
+                        new
+                    )));
+                }
+                if !old_is_dir && new_is_dir {
+                    return Err(Ext4Error::InvalidOperation(format!(
+                        "'{}' is a directory",
+                        new
+                    )));
+                }
+                if new_is_dir && !self.is_empty_directory(new_inode_num, new_inode)? {
+                    return Err(Ext4Error::InvalidOperation(format!(
+                        "Directory '{}' is not empty",
+                        new
+                    )));
+                }
+            }
+        }
+
+        // A directory may not end up below itself
+        if old_is_dir && self.is_ancestor(old_inode_num, new_parent)? {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Cannot move '{}' into itself",
+                old
+            )));
+        }
+        if mode == RenameMode::Exchange && new_is_dir {
+            if let Some(new_inode_num) = new_inode_num {
+                if self.is_ancestor(new_inode_num, old_parent)? {
+                    return Err(Ext4Error::InvalidOperation(format!(
+                        "Cannot move '{}' into itself",
+                        new
+                    )));
+                }
+            }
+        }
+
+        // A directory moving to another parent needs a link there
+        let (old_moves_dir, new_moves_dir) = if old_parent == new_parent {
+            (false, false)
+        } else if mode == RenameMode::Exchange {
+            (old_is_dir && !new_is_dir, new_is_dir && !old_is_dir)
+        } else {
+            (old_is_dir, false)
+        };
+        for (moves_dir, parent, path) in [
+            (old_moves_dir, new_parent, new_parent_path),
+            (new_moves_dir, old_parent, old_parent_path),
+        ] {
+            if !moves_dir {
+                continue;
+            }
+            let parent_inode = self.read_inode(parent)?;
+            if self.dir_links_full(&parent_inode) {
+                return Err(Ext4Error::InvalidOperation(format!(
+                    "Too many subdirectories in '{}'",
+                    path
+                )));
+            }
+        }
+
+        // Point the new name at the inode before dropping the old name
+        let old_type = self.entry_file_type(&old_inode);
+        if let Some(new_inode_num) = new_inode_num {
+            self.remove_directory_entry(new_parent, new_name)?;
+            if mode == RenameMode::Exchange {
+                let new_type = new_inode
+                    .as_ref()
+                    .map_or(0, |inode| self.entry_file_type(inode));
+                self.remove_directory_entry(old_parent, old_name)?;
+                self.add_directory_entry(old_parent, old_name, new_inode_num, new_type)?;
+            }
+        }
+        self.add_directory_entry(new_parent, new_name, old_inode_num, old_type)?;
+        if mode != RenameMode::Exchange {
+            self.remove_directory_entry(old_parent, old_name)?;
+        }
+
+        // Moved directories get their new parent in ".."
+        if old_parent != new_parent {
+            if old_is_dir {
+                self.set_parent_entry(old_inode_num, &old_inode, new_parent)?;
+            }
+            if mode == RenameMode::Exchange && new_is_dir {
+                if let (Some(new_inode_num), Some(new_inode)) = (new_inode_num, &new_inode) {
+                    self.set_parent_entry(new_inode_num, new_inode, old_parent)?;
+                }
+            }
+        }
+
+        // Like the kernel, move the ".." links between the parents, and drop the replaced
+        // inode's link, all of them for a directory
+        if mode == RenameMode::Exchange {
+            if old_parent != new_parent && old_is_dir != new_is_dir {
+                let (from, to) = if old_is_dir {
+                    (old_parent, new_parent)
+                } else {
+                    (new_parent, old_parent)
+                };
+                self.move_dir_link(from, Some(to))?;
+            }
+        } else if old_is_dir {
+            let to = if new_inode_num.is_some() {
+                None
+            } else {
+                Some(new_parent)
+            };
+            self.move_dir_link(old_parent, to)?;
+        }
+
+        // Reread the inodes, as updating ".." may have changed an inline directory
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        if let Some(new_inode_num) = new_inode_num {
+            let mut new_inode = self.read_inode(new_inode_num)?;
+            if mode != RenameMode::Exchange {
+                new_inode.links_count = if new_is_dir {
+                    0
+                } else {
+                    new_inode.links_count.saturating_sub(1)
+                };
+            }
+            new_inode.ctime = now;
+            if new_inode.links_count == 0 {
+                self.delete_inode(new_inode_num, &mut new_inode)?;
+            } else {
+                self.write_inode(new_inode_num, &new_inode)?;
+            }
+        }
+        let mut old_inode = self.read_inode(old_inode_num)?;
+        old_inode.ctime = now;
+        self.write_inode(old_inode_num, &old_inode)?;
+
+        self.write_superblock()?;
+
+        Ok(())
+    }
+
+    /// Move the link a subdirectory's ".." gives its parent from one directory to another,
+    /// or drop it if the subdirectory goes away with no new parent.
+    fn move_dir_link(&mut self, from: u32, to: Option<u32>) -> Result<(), Ext4Error> {
+        let mut from_inode = self.read_inode(from)?;
+        Self::dec_dir_links(&mut from_inode);
+        self.write_inode(from, &from_inode)?;
+        if let Some(to) = to {
+            let mut to_inode = self.read_inode(to)?;
+            self.inc_dir_links(&mut to_inode);
+            self.write_inode(to, &to_inode)?;
+        }
+        Ok(())
+    }
+
+    /// Check if a directory holds nothing but "." and "..".
+    fn is_empty_directory(&mut self, inode_num: u32, inode: &Inode) -> Result<bool, Ext4Error> {
+        if !inode.is_directory() {
+            return Ok(false);
+        }
+        let directory = self.read_directory(inode_num)?;
+        Ok(directory
+            .entries
+            .iter()
+            .all(|entry| entry.name == "." || entry.name == ".."))
+    }
+
+    /// Check if `ancestor` is the directory `dir` or one of the directories above it.
+    fn is_ancestor(&mut self, ancestor: u32, dir: u32) -> Result<bool, Ext4Error> {
+        let mut current = dir;
+        let mut visited = HashSet::new();
+        loop {
+            if current == ancestor {
+                return Ok(true);
+            }
+            if current == 2 || !visited.insert(current) {
+                return Ok(false);
+            }
+            current = self.lookup(current, "..")?.ok_or_else(|| {
+                Ext4Error::InvalidDirectory(format!("Directory {} has no '..' entry", current))
+            })?;
+        }
+    }
+
+    /// Remove a directory from the filesystem.
+    pub fn remove_directory(&mut self, path: &str, force: bool) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.remove_directory_inner(path, force))
+    }
+
+    fn remove_directory_inner(&mut self, path: &str, force: bool) -> Result<(), Ext4Error> {
+        println!("开始删除目录: path={}, force={}", path, force);
+
+        // Find the directory inode
+        println!("查找目录的 inode 号: {}", path);
+        let inode_num = self.resolve_path(path, false)?;
+        println!("找到目录 inode 号: {}", inode_num);
+
+        let mut inode = self.read_inode(inode_num)?;
+        println!("成功读取目录 inode 信息");
+
+        if !inode.is_directory() {
+            println!("错误：路径 '{}' 不是目录", path);
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "'{}' is not a directory",
+                path
+            )));
+        }
+
+        // Check if directory is empty (unless force flag is used)
+        if !force {
+            println!("检查目录是否为空");
+            let directory = self.read_directory(inode_num)?;
+            // Skip "." and ".." entries when checking if directory is empty
+            let real_entries = directory
+                .entries
+                .iter()
+                .filter(|entry| entry.name != "." && entry.name != "..")
+                .count();
+
+            println!("目录中实际条目数（不包括 . 和 ..）: {}", real_entries);
+            if real_entries > 0 {
+                println!("错误：目录不为空且未使用强制删除标志");
+                return Err(Ext4Error::InvalidOperation(format!(
+                    "Directory '{}' is not empty. Use force flag to remove anyway.",
+                    path
+                )));
+            }
+        }
+
+        // Get the parent directory
+        println!("获取父目录路径");
+        let parent_path = match path.rfind('/') {
+            Some(pos) => {
+                if pos == 0 {
+                    "/"
+                } else {
+                    &path[..pos]
+                }
+            }
+            None => "/",
+        };
+        println!("父目录路径: {}", parent_path);
+
+        println!("获取目录名");
+        let dirname = match path.rfind('/') {
+            Some(pos) => &path[pos + 1..],
+            None => path,
+        };
+        println!("目录名: {}", dirname);
+
+        println!("查找父目录的 inode 号");
+        let parent_inode_num = self.find_by_path(parent_path)?;
+        println!("父目录 inode 号: {}", parent_inode_num);
+
+        // 1. Remove the directory entry from the parent directory
+        println!("从父目录中移除目录项");
+        self.remove_directory_entry(parent_inode_num, dirname)?;
+        println!("成功从父目录移除目录项");
+
+        // 2. Update the parent inode's link count
+        println!("更新父目录的链接计数");
+        let mut parent_inode = self.read_inode(parent_inode_num)?;
+        Self::dec_dir_links(&mut parent_inode);
+        self.write_inode(parent_inode_num, &parent_inode)?;
+        println!("成功更新父目录链接计数: {}", parent_inode.links_count);
+
+        // 3. Free the blocks used by the directory and mark the inode as free, taking its
+        // "." link along with the one from the parent
+        println!("标记 inode {} 为空闲", inode_num);
+        self.delete_inode(inode_num, &mut inode)?;
+        println!("成功释放 inode");
+
+        // 4. Update superblock and block group descriptors
+        println!("更新超级块和块组描述符");
+        println!(
+            "更新后的空闲块数: {}, 空闲inode数: {}",
+            self.superblock.free_blocks_count, self.superblock.free_inodes_count
+        );
+        self.write_superblock()?;
+        self.write_group_descriptors()?;
+        println!("成功更新超级块");
+
+        println!("目录 '{}' 删除完成", path);
+        Ok(())
+    }
+
+    /// Create a new directory in the filesystem.
+    pub fn create_directory(&mut self, parent_path: &str, dirname: &str) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.create_directory_inner(parent_path, dirname))
+    }
+
+    fn create_directory_inner(&mut self, parent_path: &str, dirname: &str) -> Result<(), Ext4Error> {
+        println!(
+            "开始创建目录: parent_path={}, dirname={}",
+            parent_path, dirname
+        );
+
+        // Find the parent directory inode
+        let parent_inode_num = self.find_by_path(parent_path)?;
+        println!("找到父目录 inode 号: {}", parent_inode_num);
+
+        let parent_inode = self.read_inode(parent_inode_num)?;
+        println!("读取父目录 inode 信息成功");
+
+        if !parent_inode.is_directory() {
+            println!("错误: 父路径不是目录");
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "'{}' is not a directory",
+                parent_path
+            )));
+        }
+        if self.dir_links_full(&parent_inode) {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Too many subdirectories in '{}'",
+                parent_path
+            )));
+        }
+
+        // 检查目录是否已存在
+        println!("检查目录 '{}' 是否已存在", dirname);
+        let parent_directory = match self.read_directory(parent_inode_num) {
+            Ok(dir) => {
+                println!("成功读取父目录内容");
+                dir
+            }
+            Err(Ext4Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                println!("父目录为空，创建新的空目录结构");
                 Directory::new()
             }
-            Err(e) => {
-                println!("读取父目录时发生错误: {:?}", e);
-                return Err(e);
+            Err(e) => {
+                println!("读取父目录时发生错误: {:?}", e);
+                return Err(e);
+            }
+        };
+
+        if parent_directory.find_entry(dirname).is_some() {
+            println!("错误: 目录 '{}' 已存在", dirname);
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Directory '{}' already exists",
+                dirname
+            )));
+        }
+
+        // 1. 分配新的 inode
+        println!("开始分配新的 inode");
+        let new_inode_num = self.allocate_inode(true)?;
+        println!("成功分配新的 inode: {}", new_inode_num);
+
+        // 2. 创建新的目录 inode
+        println!("创建新的目录 inode 结构");
+        let mut new_inode = Inode {
+            mode: 0x4180,    // 目录权限 0755
+            links_count: 2, // "." 和 ".." 链接
+            ..self.default_inode()
+        };
+
+        // 3. 分配目录数据块, unless the directory starts out inline like the kernel makes it
+        let block_num = if self.max_inline_size(new_inode_num, &new_inode)? > 0 {
+            None
+        } else {
+            println!("开始分配目录数据块");
+            let block_num = self.allocate_block()?;
+            println!("成功分配数据块: {}", block_num);
+            new_inode.block[0] = self.mappable_block(block_num, 1)?;
+            new_inode.blocks = self.superblock.block_size() / 512;
+            new_inode.size = self.superblock.block_size();
+            Some(block_num)
+        };
+
+        // 设置时间戳
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        new_inode.atime = now;
+        new_inode.ctime = now;
+        new_inode.mtime = now;
+        new_inode.crtime = now;
+        println!("设置 inode 时间戳: {}", now);
+
+        // 4. 写入 inode
+        println!("将新的 inode 写入磁盘");
+        self.write_inode(new_inode_num, &new_inode)?;
+        println!("inode 写入成功");
+
+        // 5. 写入目录项
+        println!("写入 '.' 和 '..' 目录项");
+        match block_num {
+            Some(block_num) => {
+                self.write_dot_entries(new_inode_num, &new_inode, block_num, parent_inode_num)?
+            }
+            None => {
+                let data = InlineDirectory::new(parent_inode_num).to_bytes();
+                self.write_inline_data(new_inode_num, &mut new_inode, &data)?
+            }
+        }
+        println!("目录项写入成功");
+
+        // 6. 添加目录项到父目录
+        println!("开始将新目录添加到父目录");
+        self.add_directory_entry(parent_inode_num, dirname, new_inode_num, 2)?;
+
+        // 7. 更新父目录, which adding the entry may have grown
+        println!("更新父目录的链接计数");
+        let mut updated_parent = self.read_inode(parent_inode_num)?;
+        self.inc_dir_links(&mut updated_parent);
+        self.write_inode(parent_inode_num, &updated_parent)?;
+        println!("父目录更新成功");
+
+        // 8. 更新超级块
+        println!("开始更新超级块计数器");
+        println!(
+            "更新后空闲块数: {}, 空闲inode数: {}",
+            self.superblock.free_blocks_count, self.superblock.free_inodes_count
+        );
+        self.write_superblock()?;
+        println!("超级块更新成功");
+
+        println!("目录结构更新完成");
+        println!("超级块状态：{:?}", self.superblock);
+        println!("目录 '{}' 创建完成", dirname);
+
+        parent_directory.print_details();
+
+        Ok(())
+    }
+
+    /// Create a symbolic link at `linkpath` pointing to `target`.
+    ///
+    /// Like the kernel, targets shorter than `i_block` are stored in it as a fast symlink,
+    /// and longer ones in a data block.
+    pub fn symlink(&mut self, target: &str, linkpath: &str) -> Result<(), Ext4Error> {
+        self.transaction(|fs| fs.symlink_inner(target, linkpath))
+    }
+
+    fn symlink_inner(&mut self, target: &str, linkpath: &str) -> Result<(), Ext4Error> {
+        let block_size = self.superblock.block_size();
+        if target.is_empty() || target.len() >= block_size as usize {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Symlink target must be 1 to {} bytes long",
+                block_size - 1
+            )));
+        }
+
+        let (parent_path, name) = split_path(linkpath);
+        if name.is_empty() {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "Invalid symlink path '{}'",
+                linkpath
+            )));
+        }
+
+        let parent_inode_num = self.find_by_path(parent_path)?;
+        if self.lookup(parent_inode_num, name)?.is_some() {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "'{}' already exists",
+                linkpath
+            )));
+        }
+
+        let inode_num = self.allocate_inode(false)?;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        let mut inode = Inode {
+            mode: 0xA1FF, // Symbolic link with 0777 permissions
+            links_count: 1,
+            size: target.len() as u32,
+            atime: now,
+            ctime: now,
+            mtime: now,
+            crtime: now,
+            ..self.default_inode()
+        };
+
+        // The target of a fast symlink is NUL padded and never mapped through extents
+        if target.len() < INODE_BLOCK_SIZE {
+            let mut block = [0u8; INODE_BLOCK_SIZE];
+            block[..target.len()].copy_from_slice(target.as_bytes());
+            inode.set_block_bytes(&block);
+            self.write_inode(inode_num, &inode)?;
+        } else {
+            self.write_file_blocks(inode_num, &mut inode, target.as_bytes())?;
+        }
+
+        self.add_directory_entry(parent_inode_num, name, inode_num, 7)?;
+        // 7 = symbolic link
+        self.write_superblock()?;
+
+        Ok(())
+    }
+
+    /// Allocate a new inode, counting it among the group's directories if `is_dir` is set.
+    fn allocate_inode(&mut self, is_dir: bool) -> Result<u32, Ext4Error> {
+        let inodes_per_group = self.superblock.inodes_per_group;
+
+        // Iterate through each block group to find a free inode
+        for group_idx in 0..self.block_groups.len() {
+            if self.block_groups[group_idx].free_inodes_count == 0 {
+                continue;
+            }
+
+            // Read the inode bitmap and search for a free inode (bit set to 0)
+            let mut bitmap = self.read_inode_bitmap(group_idx)?;
+            let inode_idx = match (0..inodes_per_group as usize)
+                .find(|&idx| (bitmap[idx / 8] & (1 << (idx % 8))) == 0)
+            {
+                Some(inode_idx) => inode_idx,
+                None => continue,
+            };
+
+            // Mark the inode as used (set bit to 1) and write the bitmap back
+            bitmap[inode_idx / 8] |= 1 << (inode_idx % 8);
+            self.write_inode_bitmap(group_idx, &bitmap)?;
+
+            // Calculate the global inode number
+            let inode_num = group_idx as u32 * inodes_per_group + inode_idx as u32 + 1;
+
+            // Clear the on-disk inode, including any stale extended attributes
+            let offset = self.inode_offset(inode_num)?;
+            let inode_size = self.superblock.inode_record_size() as usize;
+            self.write_metadata(offset, &vec![0u8; inode_size])?;
+
+            // Update the block group descriptor and superblock counters
+            let bg = &mut self.block_groups[group_idx];
+            bg.free_inodes_count -= 1;
+            if is_dir {
+                bg.used_dirs_count += 1;
+            }
+            let used = inodes_per_group - (inode_idx as u32 + 1);
+            if bg.itable_unused > used {
+                bg.itable_unused = used;
+            }
+            self.superblock.free_inodes_count -= 1;
+
+            return Ok(inode_num);
+        }
+
+        // No free inodes found
+        Err(Ext4Error::NoSpace("No free inodes available".to_string()))
+    }
+
+    /// Check if a directory has no room for another subdirectory, like the kernel's
+    /// `EXT4_DIR_LINK_MAX`.
+    ///
+    /// With dir_nlink, indexed directories stop counting links rather than run out of them.
+    fn dir_links_full(&self, dir: &Inode) -> bool {
+        dir.links_count >= EXT4_LINK_MAX
+            && !(self.superblock.has_ro_compat(RoCompatFeature::DirNlink) && self.is_indexed(dir))
+    }
+
+    /// Count a new subdirectory in a directory's links, like the kernel's `ext4_inc_count`.
+    ///
+    /// An indexed directory whose count would pass `EXT4_LINK_MAX` keeps a count of 1 from
+    /// then on, meaning its links are no longer counted.
+    fn inc_dir_links(&self, dir: &mut Inode) {
+        dir.links_count = dir.links_count.saturating_add(1);
+        if self.is_indexed(dir) && (dir.links_count > EXT4_LINK_MAX || dir.links_count == 2) {
+            dir.links_count = 1;
+        }
+    }
+
+    /// Drop a removed subdirectory from a directory's links, like the kernel's
+    /// `ext4_dec_count`; a directory whose links are no longer counted keeps its count of 1.
+    fn dec_dir_links(dir: &mut Inode) {
+        if dir.links_count > 2 {
+            dir.links_count -= 1;
+        }
+    }
+
+    /// Allocate a new block.
+    fn allocate_block(&mut self) -> Result<u64, Ext4Error> {
+        self.allocate_blocks(1).map(|(block_num, _)| block_num)
+    }
+
+    /// Allocate a run of up to `count` contiguous blocks.
+    ///
+    /// Returns the first block of the run and its length, which may be shorter than requested.
+    fn allocate_blocks(&mut self, count: u32) -> Result<(u64, u32), Ext4Error> {
+        let blocks_per_group = self.superblock.blocks_per_group as usize;
+
+        // Iterate through each block group to find a free block
+        for group_idx in 0..self.block_groups.len() {
+            if self.block_groups[group_idx].free_blocks_count == 0 {
+                continue;
+            }
+
+            // Read the block bitmap
+            let mut bitmap = self.read_block_bitmap(group_idx)?;
+
+            // Search for a free block (bit set to 0), then extend the run as far as possible
+            let is_free = |bitmap: &[u8], idx: usize| (bitmap[idx / 8] & (1 << (idx % 8))) == 0;
+            let limit = std::cmp::min(blocks_per_group, bitmap.len() * 8);
+            let first = match (0..limit).find(|&idx| is_free(&bitmap, idx)) {
+                Some(first) => first,
+                None => continue,
+            };
+            let mut len = 0;
+            while len < count as usize && first + len < limit && is_free(&bitmap, first + len) {
+                // Mark the block as used (set bit to 1)
+                bitmap[(first + len) / 8] |= 1 << ((first + len) % 8);
+                len += 1;
+            }
+
+            // Write the updated bitmap back to disk
+            self.write_block_bitmap(group_idx, &bitmap)?;
+
+            // Calculate the global block number
+            let block_num = group_idx as u64 * self.superblock.blocks_per_group as u64
+                + first as u64
+                + self.superblock.first_data_block as u64;
+
+            // Update the block group descriptor and superblock counters
+            self.block_groups[group_idx].free_blocks_count -= len as u32;
+            let free_blocks = self.superblock.free_blocks_count_64() - len as u64;
+            self.superblock.set_free_blocks_count_64(free_blocks);
+
+            return Ok((block_num, len as u32));
+        }
+
+        // No free blocks found
+        Err(Ext4Error::NoSpace("No free blocks available".to_string()))
+    }
+
+    /// Allocate `count` blocks as a list of extents covering logical blocks `0..count`.
+    fn allocate_extents(&mut self, count: u32) -> Result<Vec<Extent>, Ext4Error> {
+        let mut extents: Vec<Extent> = Vec::new();
+        let mut logical = 0;
+
+        while logical < count {
+            let (start, len) = self.allocate_blocks(std::cmp::min(
+                count - logical,
+                extent::EXT_INIT_MAX_LEN as u32,
+            ))?;
+
+            // Merge with the previous extent when the new run directly follows it
+            match extents.last_mut() {
+                Some(last)
+                    if last.start + last.length() as u64 == start
+                        && last.length() + len <= extent::EXT_INIT_MAX_LEN as u32 =>
+                {
+                    last.len += len as u16;
+                }
+                _ => extents.push(Extent {
+                    block: logical,
+                    len: len as u16,
+                    start,
+                }),
+            }
+            logical += len;
+        }
+
+        Ok(extents)
+    }
+
+    /// Store an extent tree for `extents` in the inode, spilling into tree blocks as needed.
+    ///
+    /// Returns the number of tree blocks allocated.
+    fn write_extent_tree(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        extents: &[Extent],
+    ) -> Result<u32, Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let per_block = extent::entries_per_block(block_size);
+        let mut level = ExtentNode::Leaf(extents.to_vec());
+        let mut depth = 0;
+        let mut tree_blocks = 0;
+
+        // Build the tree bottom-up until the top level fits in the inode
+        while level.len() > extent::EXT4_EXT_ROOT_ENTRIES as usize {
+            let mut indexes = Vec::new();
+            for node in level.chunks(per_block as usize) {
+                let block_num = self.allocate_block()?;
+                tree_blocks += 1;
+
+                let mut block_data = vec![0u8; block_size as usize];
+                node.write_to(&mut block_data, per_block, depth);
+                if let Some(seed) = self.csum_seed {
+                    let inode_seed = checksum::inode_seed(seed, inode_num, inode.generation);
+                    checksum::set_extent_block_checksum(inode_seed, &mut block_data);
+                }
+                self.write_metadata_block(block_num, &block_data)?;
+
+                indexes.push(ExtentIndex {
+                    block: node.first_block(),
+                    leaf: block_num,
+                });
+            }
+            level = ExtentNode::Index(indexes);
+            depth += 1;
+        }
+
+        let mut root = [0u8; 60];
+        level.write_to(&mut root, extent::EXT4_EXT_ROOT_ENTRIES, depth);
+        inode.set_block_bytes(&root);
+        inode.flags |= EXT4_EXTENTS_FL;
+
+        Ok(tree_blocks)
+    }
+
+    /// Set `i_blocks` of an inode from a number of 512-byte sectors, like the kernel's
+    /// `ext4_inode_blocks_set`.
+    ///
+    /// Counts past 32 bits need huge_file, and those past 48 bits are kept in filesystem
+    /// blocks under `EXT4_HUGE_FILE_FL`.
+    fn set_inode_blocks(&self, inode: &mut Inode, sectors: u64) -> Result<(), Ext4Error> {
+        let huge = sectors > 0xFFFF_FFFF_FFFF;
+        let count = if huge {
+            sectors / (self.superblock.block_size() / 512) as u64
+        } else {
+            sectors
+        };
+        if count > u32::MAX as u64 && !self.superblock.has_ro_compat(RoCompatFeature::HugeFile)
+            || count > 0xFFFF_FFFF_FFFF
+        {
+            return Err(Ext4Error::InvalidOperation(format!(
+                "{} sectors do not fit in i_blocks",
+                sectors
+            )));
+        }
+
+        if huge {
+            inode.flags |= EXT4_HUGE_FILE_FL;
+        } else {
+            inode.flags &= !EXT4_HUGE_FILE_FL;
+        }
+        inode.blocks = count as u32;
+        inode.blocks_high = (count >> 32) as u16;
+        Ok(())
+    }
+
+    /// Verify the checksums of the extent tree blocks below an inode.
+    fn verify_extent_tree(&self, inode_num: u32, inode: &Inode) -> Result<(), Ext4Error> {
+        let seed = match self.csum_seed {
+            Some(seed) if inode.uses_extents() => seed,
+            _ => return Ok(()),
+        };
+
+        let block_size = self.superblock.block_size();
+        let inode_seed = checksum::inode_seed(seed, inode_num, inode.generation);
+        let mut reader = self.reader()?;
+        let tree_blocks =
+            extent::collect_tree_blocks(&mut reader, &inode.block_bytes(), block_size)?;
+
+        let mut block_data = vec![0u8; block_size as usize];
+        for block_num in tree_blocks {
+            reader.seek(SeekFrom::Start(block_num * block_size as u64))?;
+            reader.read_exact(&mut block_data)?;
+            let (stored, computed) = checksum::extent_block_checksums(inode_seed, &block_data);
+            self.checksum_policy.check(
+                &format!("extent block {} of inode {}", block_num, inode_num),
+                stored,
+                computed,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Delete an inode whose last link is gone: free its blocks, stamp its deletion time
+    /// and mark it unused.
+    fn delete_inode(&mut self, inode_num: u32, inode: &mut Inode) -> Result<(), Ext4Error> {
+        if inode.get_blocks() != 0 {
+            self.free_inode_blocks(inode)?;
+            self.release_xattr_block(inode)?;
+        }
+        inode.links_count = 0;
+        inode.dtime = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as u32;
+        self.write_inode(inode_num, inode)?;
+        self.set_inode_in_use(inode_num, false, inode.is_directory())
+    }
+
+    /// Drop an inode's reference to its extended attribute block, freeing the block once no
+    /// inode shares it any more.
+    fn release_xattr_block(&mut self, inode: &mut Inode) -> Result<(), Ext4Error> {
+        let block_num = inode.get_file_acl();
+        if block_num == 0 {
+            return Ok(());
+        }
+
+        let block_size = self.superblock.block_size() as usize;
+        let mut block_data = vec![0u8; block_size];
+        let mut reader = self.reader()?;
+        reader.seek(SeekFrom::Start(block_num * block_size as u64))?;
+        reader.read_exact(&mut block_data)?;
+        if LittleEndian::read_u32(&block_data[0..4]) != EXT4_XATTR_MAGIC {
+            return Err(Ext4Error::InvalidInode(format!(
+                "Attribute block {} has a bad magic number",
+                block_num
+            )));
+        }
+
+        let refcount = LittleEndian::read_u32(&block_data[4..8]).saturating_sub(1);
+        if refcount == 0 {
+            self.free_block(block_num)?;
+        } else {
+            LittleEndian::write_u32(&mut block_data[4..8], refcount);
+            if let Some(seed) = self.csum_seed {
+                checksum::set_xattr_block_checksum(seed, block_num, &mut block_data);
+            }
+            self.write_metadata_block(block_num, &block_data)?;
+        }
+        inode.file_acl = 0;
+        inode.file_acl_high = 0;
+        Ok(())
+    }
+
+    /// Free every block owned by an inode, including extent tree blocks.
+    ///
+    /// Inline data and fast symlinks live in the inode itself and own no blocks.
+    fn free_inode_blocks(&mut self, inode: &Inode) -> Result<(), Ext4Error> {
+        if inode.has_inline_data() || inode.is_fast_symlink(self.superblock.block_size()) {
+            return Ok(());
+        }
+        if inode.uses_extents() {
+            let (extents, tree_blocks) = self.read_extent_map(inode)?;
+            for extent in extents {
+                self.free_blocks(extent.start, extent.length())?;
+            }
+            for block_num in tree_blocks {
+                self.free_block(block_num)?;
+            }
+        } else {
+            self.punch_block_map(&mut inode.clone(), 0, u64::MAX)?;
+        }
+
+        Ok(())
+    }
+
+    /// Map `blocks` as logical blocks `0..blocks.len()` of a block-mapped inode.
+    ///
+    /// Returns the number of indirect blocks allocated.
+    fn write_block_map(&mut self, inode: &mut Inode, blocks: &[u32]) -> Result<u32, Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let direct = std::cmp::min(blocks.len(), block_map::EXT4_NDIR_BLOCKS);
+        inode.block[..direct].copy_from_slice(&blocks[..direct]);
+
+        let mut remaining = &blocks[direct..];
+        let mut meta_blocks = 0;
+        for (slot, level) in [
+            (block_map::EXT4_IND_BLOCK, 1),
+            (block_map::EXT4_DIND_BLOCK, 2),
+            (block_map::EXT4_TIND_BLOCK, 3),
+        ] {
+            if remaining.is_empty() {
+                break;
+            }
+            let span = block_map::blocks_per_level(block_size, level);
+            let count = std::cmp::min(remaining.len() as u64, span) as usize;
+            let (block_num, used) = self.write_indirect(level, &remaining[..count])?;
+            inode.block[slot] = block_num;
+            meta_blocks += used;
+            remaining = &remaining[count..];
+        }
+
+        Ok(meta_blocks)
+    }
+
+    /// Allocate and fill an indirect block of the given level mapping `blocks`.
+    ///
+    /// Returns the indirect block and the number of indirect blocks allocated below and including it.
+    fn write_indirect(&mut self, level: u32, blocks: &[u32]) -> Result<(u32, u32), Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let block_num = self.allocate_block()?;
+        let block_num = self.mappable_block(block_num, 1)?;
+        let mut meta_blocks = 1;
+
+        let pointers = if level == 1 {
+            blocks.to_vec()
+        } else {
+            let span = block_map::blocks_per_level(block_size, level - 1) as usize;
+            let mut pointers = Vec::new();
+            for chunk in blocks.chunks(span) {
+                let (child, used) = self.write_indirect(level - 1, chunk)?;
+                pointers.push(child);
+                meta_blocks += used;
+            }
+            pointers
+        };
+
+        let data = block_map::write_pointers(&pointers, block_size);
+        self.write_metadata_block(block_num as u64, &data)?;
+        Ok((block_num, meta_blocks))
+    }
+
+    /// Free every block of a block-mapped inode that maps logical blocks `from..to`, along
+    /// with any indirect blocks left empty.
+    ///
+    /// Returns the number of blocks freed.
+    fn punch_block_map(&mut self, inode: &mut Inode, from: u64, to: u64) -> Result<u32, Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let mut to_free = Vec::new();
+
+        for logical in from..std::cmp::min(to, block_map::EXT4_NDIR_BLOCKS as u64) {
+            let slot = &mut inode.block[logical as usize];
+            if *slot != 0 {
+                to_free.push(*slot);
+                *slot = 0;
+            }
+        }
+
+        let mut base = block_map::EXT4_NDIR_BLOCKS as u64;
+        for (slot, level) in [
+            (block_map::EXT4_IND_BLOCK, 1),
+            (block_map::EXT4_DIND_BLOCK, 2),
+            (block_map::EXT4_TIND_BLOCK, 3),
+        ] {
+            let span = block_map::blocks_per_level(block_size, level);
+            if inode.block[slot] != 0 && base + span > from && base < to {
+                let emptied =
+                    self.punch_indirect(inode.block[slot], level, base, from..to, &mut to_free)?;
+                if emptied {
+                    inode.block[slot] = 0;
+                }
             }
-        };
+            base += span;
+        }
 
-        if parent_directory.find_entry(dirname).is_some() {
-            println!("错误: 目录 '{}' 已存在", dirname);
-            return Err(Ext4Error::InvalidOperation(format!(
-                "Directory '{}' already exists",
-                dirname
-            )));
+        // Free the collected blocks, coalescing contiguous runs
+        to_free.sort_unstable();
+        let freed = to_free.len() as u32;
+        let mut i = 0;
+        while i < to_free.len() {
+            let mut len = 1;
+            while i + len < to_free.len() && to_free[i + len] == to_free[i] + len as u32 {
+                len += 1;
+            }
+            self.free_blocks(to_free[i] as u64, len as u32)?;
+            i += len;
         }
 
-        let mut file_clone = self.file.try_clone()?;
+        Ok(freed)
+    }
 
-        // 1. 分配新的 inode
-        println!("开始分配新的 inode");
-        let new_inode_num = self.allocate_inode()?;
-        println!("成功分配新的 inode: {}", new_inode_num);
+    /// Collect the blocks below an indirect block that map logical blocks in `range`.
+    ///
+    /// `base` is the first logical block covered by the indirect block. Returns true if the
+    /// indirect block ends up empty, in which case it is collected as well.
+    fn punch_indirect(
+        &mut self,
+        block_num: u32,
+        level: u32,
+        base: u64,
+        range: std::ops::Range<u64>,
+        to_free: &mut Vec<u32>,
+    ) -> Result<bool, Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let span = block_map::blocks_per_level(block_size, level - 1);
+        let mut reader = self.reader()?;
+        let mut pointers = block_map::read_pointers(&mut reader, block_num, block_size)?;
+        let mut changed = false;
+
+        for (i, ptr) in pointers.iter_mut().enumerate() {
+            let child_base = base + i as u64 * span;
+            if *ptr == 0 || child_base + span <= range.start || child_base >= range.end {
+                continue;
+            }
 
-        // 2. 创建新的目录 inode
-        println!("创建新的目录 inode 结构");
-        let mut new_inode = Inode::default();
-        new_inode.mode = 0x4180; // 目录权限 0755
-        new_inode.links_count = 2; // "." 和 ".." 链接
+            let emptied = if level == 1 {
+                to_free.push(*ptr);
+                true
+            } else {
+                self.punch_indirect(*ptr, level - 1, child_base, range.clone(), to_free)?
+            };
+            if emptied {
+                *ptr = 0;
+                changed = true;
+            }
+        }
 
-        // 3. 分配目录数据块
-        println!("开始分配目录数据块");
-        let block_num = self.allocate_block()?;
-        println!("成功分配数据块: {}", block_num);
-        new_inode.block[0] = block_num;
-        new_inode.blocks = self.superblock.block_size() / 512;
-        new_inode.size = self.superblock.block_size();
+        if pointers.iter().all(|&ptr| ptr == 0) {
+            to_free.push(block_num);
+            return Ok(true);
+        }
+        if changed {
+            let data = block_map::write_pointers(&pointers, block_size);
+            self.write_metadata_block(block_num as u64, &data)?;
+        }
+        Ok(false)
+    }
 
-        // 设置时间戳
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs() as u32;
-        new_inode.atime = now;
-        new_inode.ctime = now;
-        new_inode.mtime = now;
-        println!("设置 inode 时间戳: {}", now);
+    /// Write data to consecutive blocks starting at `block_num`, zero-filling the last block.
+    ///
+    /// File data is written in place rather than through the journal, and lands before the
+    /// transaction that points at it commits.
+    fn write_data_blocks(&mut self, block_num: u64, data: &[u8]) -> Result<(), Ext4Error> {
+        let block_size = self.superblock.block_size() as usize;
 
-        // 4. 写入 inode
-        println!("将新的 inode 写入磁盘");
-        self.write_inode(new_inode_num, &new_inode)?;
-        println!("inode 写入成功");
+        // Any metadata the running transaction still holds for these blocks was freed
+        let mut overlay = self.overlay.lock();
+        for block in block_num..block_num + data.len().div_ceil(block_size) as u64 {
+            overlay.remove(&block);
+        }
+        drop(overlay);
 
-        // 5. 写入目录项
-        println!("写入 '.' 和 '..' 目录项");
-        // 创建新目录的 entries 结构
-        let mut new_directory = self.read_directory(new_inode_num)?;
-        println!("成功读取新创建的目录结构");
-        new_directory.entries.push(directory::DirectoryEntry {
-            inode: new_inode_num,
-            rec_len: 8 + 1,
-            name_len: 1,
-            file_type: 2,
-            name: ".".to_string(),
-        });
-        new_directory.entries.push(directory::DirectoryEntry {
-            inode: parent_inode_num,
-            rec_len: 8 + 2,
-            name_len: 2,
-            file_type: 2,
-            name: "..".to_string(),
-        });
-        // 持久化新目录的 entries
-        println!("持久化新目录的 entries 到磁盘");
-        new_directory.write(&mut file_clone, self.superblock.block_size())?;
-        println!("目录项写入成功");
+        let mut file_clone = self.file.try_clone()?;
+        file_clone.seek(SeekFrom::Start(block_num * block_size as u64))?;
+        file_clone.write_all(data)?;
 
-        // 6. 添加目录项到父目录
-        println!("开始将新目录添加到父目录");
-        // 添加新目录到父目录的 entries
-        parent_directory.entries.push(directory::DirectoryEntry {
-            inode: new_inode_num,
-            rec_len: (8 + dirname.len()) as u16,
-            name_len: dirname.len() as u8,
-            file_type: 2,
-            name: dirname.to_string(),
-        });
+        let tail = data.len() % block_size;
+        if tail != 0 {
+            file_clone.write_all(&vec![0u8; block_size - tail])?;
+        }
 
-        println!("持久化父目录的 entries 到磁盘");
-        parent_directory.write(&mut file_clone, self.superblock.block_size())?;
+        Ok(())
+    }
 
-        // 7. 更新父目录
-        println!("更新父目录的链接计数");
-        let mut updated_parent = parent_inode.clone();
-        updated_parent.links_count += 1;
-        self.write_inode(parent_inode_num, &updated_parent)?;
-        println!("父目录更新成功");
+    /// Free a block.
+    fn free_block(&mut self, block_num: u64) -> Result<(), Ext4Error> {
+        self.free_blocks(block_num, 1)
+    }
 
-        // 8. 更新超级块
-        println!("开始更新超级块计数器");
-        if self.superblock.free_blocks_count > 0 && self.superblock.free_inodes_count > 0 {
-            println!(
-                "当前空闲块数: {}, 空闲inode数: {}",
-                self.superblock.free_blocks_count, self.superblock.free_inodes_count
+    /// Free a run of contiguous blocks.
+    fn free_blocks(&mut self, block_num: u64, count: u32) -> Result<(), Ext4Error> {
+        if block_num < self.superblock.first_data_block as u64
+            || block_num + count as u64 > self.superblock.blocks_count_64()
+        {
+            return Err(Ext4Error::InvalidBlock(format!(
+                "Invalid block number: {}",
+                block_num
+            )));
+        }
+
+        let blocks_per_group = self.superblock.blocks_per_group as u64;
+        let mut freed = 0;
+        while freed < count {
+            let current = block_num + freed as u64;
+
+            // Calculate which block group this block belongs to
+            let relative = current - self.superblock.first_data_block as u64;
+            let group_idx = relative / blocks_per_group;
+            if group_idx as usize >= self.block_groups.len() {
+                return Err(Ext4Error::InvalidBlock(format!(
+                    "Invalid block group index: {}",
+                    group_idx
+                )));
+            }
+
+            // Calculate the range within the block group
+            let index_in_group = (relative % blocks_per_group) as u32;
+            let in_group = std::cmp::min(
+                count - freed,
+                self.superblock.blocks_per_group - index_in_group,
             );
 
-            self.superblock.free_blocks_count -= 1;
-            self.superblock.free_inodes_count -= 1;
+            // Read the block bitmap
+            let mut bitmap = self.read_block_bitmap(group_idx as usize)?;
+
+            for idx in index_in_group..index_in_group + in_group {
+                let byte_idx = (idx / 8) as usize;
+                let bit_idx = (idx % 8) as u8;
+
+                // Check if the block is already free
+                if (bitmap[byte_idx] & (1 << bit_idx)) == 0 {
+                    return Err(Ext4Error::InvalidOperation(format!(
+                        "Block {} is already free",
+                        current + (idx - index_in_group) as u64
+                    )));
+                }
+
+                // Mark the block as free (clear the bit)
+                bitmap[byte_idx] &= !(1 << bit_idx);
+            }
+
+            // Write the updated bitmap back to disk
+            self.write_block_bitmap(group_idx as usize, &bitmap)?;
+
+            // Update the block group descriptor and superblock counters
+            self.block_groups[group_idx as usize].free_blocks_count += in_group;
+            let free_blocks = self.superblock.free_blocks_count_64() + in_group as u64;
+            self.superblock.set_free_blocks_count_64(free_blocks);
+
+            freed += in_group;
+        }
+
+        Ok(())
+    }
+
+    /// Mark a run of blocks as used or free, whatever their current state.
+    ///
+    /// Unlike `free_blocks`, blocks already in the wanted state are left alone, which suits
+    /// replaying changes that may already be on disk.
+    fn set_blocks_in_use(
+        &mut self,
+        block_num: u64,
+        count: u32,
+        in_use: bool,
+    ) -> Result<(), Ext4Error> {
+        if block_num < self.superblock.first_data_block as u64
+            || block_num + count as u64 > self.superblock.blocks_count_64()
+        {
+            return Err(Ext4Error::InvalidBlock(format!(
+                "Invalid block number: {}",
+                block_num
+            )));
+        }
 
-            println!(
-                "更新后空闲块数: {}, 空闲inode数: {}",
-                self.superblock.free_blocks_count, self.superblock.free_inodes_count
+        let blocks_per_group = self.superblock.blocks_per_group as u64;
+        let mut done = 0;
+        while done < count {
+            let relative = block_num + done as u64 - self.superblock.first_data_block as u64;
+            let group_idx = (relative / blocks_per_group) as usize;
+            let index_in_group = (relative % blocks_per_group) as u32;
+            let in_group = std::cmp::min(
+                count - done,
+                self.superblock.blocks_per_group - index_in_group,
             );
 
-            self.write_superblock()?;
-            println!("超级块更新成功");
-        } else {
-            println!("错误：没有足够的空闲资源");
-            return Err(Ext4Error::NoSpace(format!(
-                "No enough free resources. Blocks: {}, Inodes: {}",
-                self.superblock.free_blocks_count, self.superblock.free_inodes_count
+            let mut bitmap = self.read_block_bitmap(group_idx)?;
+            let mut changed = 0;
+            for idx in index_in_group..index_in_group + in_group {
+                let mask = 1 << (idx % 8);
+                let byte = &mut bitmap[(idx / 8) as usize];
+                if (*byte & mask != 0) != in_use {
+                    *byte ^= mask;
+                    changed += 1;
+                }
+            }
+
+            if changed != 0 {
+                self.write_block_bitmap(group_idx, &bitmap)?;
+                let bg = &mut self.block_groups[group_idx];
+                let free_blocks = self.superblock.free_blocks_count_64();
+                if in_use {
+                    bg.free_blocks_count = bg.free_blocks_count.saturating_sub(changed);
+                    self.superblock
+                        .set_free_blocks_count_64(free_blocks.saturating_sub(changed as u64));
+                } else {
+                    bg.free_blocks_count += changed;
+                    self.superblock
+                        .set_free_blocks_count_64(free_blocks + changed as u64);
+                }
+            }
+
+            done += in_group;
+        }
+
+        Ok(())
+    }
+
+    /// Mark an inode as used or free in its group's bitmap, whatever its current state.
+    fn set_inode_in_use(
+        &mut self,
+        inode_num: u32,
+        in_use: bool,
+        is_dir: bool,
+    ) -> Result<(), Ext4Error> {
+        if inode_num == 0 || inode_num > self.superblock.inodes_count {
+            return Err(Ext4Error::InvalidInode(format!(
+                "Invalid inode number: {}",
+                inode_num
             )));
         }
 
-        println!("目录结构更新完成");
-        println!("超级块状态：{:?}", self.superblock);
-        println!("目录 '{}' 创建完成", dirname);
+        let inodes_per_group = self.superblock.inodes_per_group;
+        let group_idx = ((inode_num - 1) / inodes_per_group) as usize;
+        let index_in_group = (inode_num - 1) % inodes_per_group;
+        let mask = 1 << (index_in_group % 8);
 
-        parent_directory.print_details();
+        let mut bitmap = self.read_inode_bitmap(group_idx)?;
+        let byte = &mut bitmap[(index_in_group / 8) as usize];
+        if (*byte & mask != 0) == in_use {
+            return Ok(());
+        }
+        *byte ^= mask;
+        self.write_inode_bitmap(group_idx, &bitmap)?;
+
+        let bg = &mut self.block_groups[group_idx];
+        if in_use {
+            bg.free_inodes_count = bg.free_inodes_count.saturating_sub(1);
+            if is_dir {
+                bg.used_dirs_count += 1;
+            }
+            let used = inodes_per_group - (index_in_group + 1);
+            if bg.itable_unused > used {
+                bg.itable_unused = used;
+            }
+            self.superblock.free_inodes_count = self.superblock.free_inodes_count.saturating_sub(1);
+        } else {
+            bg.free_inodes_count += 1;
+            if is_dir {
+                bg.used_dirs_count = bg.used_dirs_count.saturating_sub(1);
+            }
+            self.superblock.free_inodes_count += 1;
+        }
+        Ok(())
+    }
+
+    /// Read the block bitmap of a group, building it if the group is uninitialized.
+    fn read_block_bitmap(&mut self, group_idx: usize) -> Result<Vec<u8>, Ext4Error> {
+        let bg = &self.block_groups[group_idx];
+        if bg.flags & EXT4_BG_BLOCK_UNINIT != 0 {
+            return Ok(self.init_block_bitmap(group_idx));
+        }
+
+        let block_size = self.superblock.block_size() as usize;
+        let mut bitmap = vec![0u8; block_size];
+        let mut reader = self.reader()?;
+        reader.seek(SeekFrom::Start(bg.block_bitmap * block_size as u64))?;
+        reader.read_exact(&mut bitmap)?;
+
+        if let Some(seed) = self.csum_seed {
+            let used = self.superblock.blocks_per_group as usize / 8;
+            let computed = checksum::bitmap_checksum(seed, &bitmap[..used]);
+            self.checksum_policy.check(
+                &format!("block bitmap of group {}", group_idx),
+                bg.block_bitmap_csum,
+                computed & self.bitmap_csum_mask(),
+            )?;
+        }
+        Ok(bitmap)
+    }
+
+    /// Write the block bitmap of a group, updating its checksum.
+    fn write_block_bitmap(&mut self, group_idx: usize, bitmap: &[u8]) -> Result<(), Ext4Error> {
+        let block_num = self.block_groups[group_idx].block_bitmap;
+        self.write_metadata_block(block_num, bitmap)?;
 
+        let mask = self.bitmap_csum_mask();
+        let used = self.superblock.blocks_per_group as usize / 8;
+        let bg = &mut self.block_groups[group_idx];
+        if let Some(seed) = self.csum_seed {
+            bg.block_bitmap_csum = checksum::bitmap_checksum(seed, &bitmap[..used]) & mask;
+        }
+        bg.flags &= !EXT4_BG_BLOCK_UNINIT;
         Ok(())
     }
 
-    /// Allocate a new inode.
-    fn allocate_inode(&mut self) -> Result<u32, Ext4Error> {
-        // Iterate through each block group to find a free inode
-        for (group_idx, block_group) in self.block_groups.iter().enumerate() {
-            let inode_bitmap_block = block_group.inode_bitmap;
-            let block_size = self.superblock.block_size();
+    /// Read the inode bitmap of a group, building it if the group is uninitialized.
+    fn read_inode_bitmap(&mut self, group_idx: usize) -> Result<Vec<u8>, Ext4Error> {
+        let block_size = self.superblock.block_size() as usize;
+        let inodes_per_group = self.superblock.inodes_per_group as usize;
+        let bg = &self.block_groups[group_idx];
+        let mut bitmap = vec![0u8; block_size];
+
+        if bg.flags & EXT4_BG_INODE_UNINIT != 0 {
+            // Every inode is free; only the padding past the last inode is marked in use
+            for idx in inodes_per_group..block_size * 8 {
+                bitmap[idx / 8] |= 1 << (idx % 8);
+            }
+            return Ok(bitmap);
+        }
 
-            // Read the inode bitmap
-            let mut file_clone = self.file.try_clone()?;
-            file_clone.seek(SeekFrom::Start((inode_bitmap_block * block_size) as u64))?;
-
-            let mut bitmap = vec![0u8; block_size as usize];
-            file_clone.read_exact(&mut bitmap)?;
-
-            // Search for a free inode (bit set to 0)
-            for byte_idx in 0..block_size as usize {
-                if bitmap[byte_idx] != 0xFF {
-                    // If not all bits are set
-                    for bit_idx in 0..8 {
-                        if (bitmap[byte_idx] & (1 << bit_idx)) == 0 {
-                            // Found a free inode
-                            let inode_idx = byte_idx * 8 + bit_idx;
-
-                            // Make sure it's within the valid range
-                            if inode_idx < self.superblock.inodes_per_group as usize {
-                                // Mark the inode as used (set bit to 1)
-                                bitmap[byte_idx] |= 1 << bit_idx;
-
-                                // Write the updated bitmap back to disk
-                                file_clone.seek(SeekFrom::Start(
-                                    (inode_bitmap_block * block_size) as u64,
-                                ))?;
-                                file_clone.write_all(&bitmap)?;
-
-                                // Calculate the global inode number
-                                let inode_num = group_idx as u32 * self.superblock.inodes_per_group
-                                    + inode_idx as u32
-                                    + 1;
-
-                                // Update the block group descriptor
-                                let mut bg = self.block_groups[group_idx].clone();
-                                bg.free_inodes_count -= 1;
-                                // We would update the block group descriptor on disk here
-                                self.block_groups[group_idx] = bg;
-
-                                return Ok(inode_num);
-                            }
-                        }
+        let mut reader = self.reader()?;
+        reader.seek(SeekFrom::Start(bg.inode_bitmap * block_size as u64))?;
+        reader.read_exact(&mut bitmap)?;
+
+        if let Some(seed) = self.csum_seed {
+            let computed = checksum::bitmap_checksum(seed, &bitmap[..inodes_per_group / 8]);
+            self.checksum_policy.check(
+                &format!("inode bitmap of group {}", group_idx),
+                bg.inode_bitmap_csum,
+                computed & self.bitmap_csum_mask(),
+            )?;
+        }
+        Ok(bitmap)
+    }
+
+    /// Write the inode bitmap of a group, updating its checksum.
+    fn write_inode_bitmap(&mut self, group_idx: usize, bitmap: &[u8]) -> Result<(), Ext4Error> {
+        let block_num = self.block_groups[group_idx].inode_bitmap;
+        self.write_metadata_block(block_num, bitmap)?;
+
+        let mask = self.bitmap_csum_mask();
+        let used = self.superblock.inodes_per_group as usize / 8;
+        let bg = &mut self.block_groups[group_idx];
+        if let Some(seed) = self.csum_seed {
+            bg.inode_bitmap_csum = checksum::bitmap_checksum(seed, &bitmap[..used]) & mask;
+        }
+        bg.flags &= !EXT4_BG_INODE_UNINIT;
+        Ok(())
+    }
+
+    /// Get the bits of a bitmap checksum that fit in the group descriptor.
+    fn bitmap_csum_mask(&self) -> u32 {
+        if self.superblock.group_desc_size() >= EXT4_MIN_DESC_SIZE_64BIT {
+            u32::MAX
+        } else {
+            0xFFFF
+        }
+    }
+
+    /// Build the block bitmap of a group whose bitmap was never initialized.
+    ///
+    /// Only the group's own metadata is in use: the superblock and descriptor table backups,
+    /// and any bitmaps and inode tables placed in the group.
+    fn init_block_bitmap(&self, group_idx: usize) -> Vec<u8> {
+        let sb = &self.superblock;
+        let block_size = sb.block_size() as usize;
+        let mut bitmap = vec![0u8; block_size];
+        let mut mark = |idx: u64| bitmap[idx as usize / 8] |= 1 << (idx % 8);
+
+        let first = sb.first_data_block as u64 + group_idx as u64 * sb.blocks_per_group as u64;
+        let in_group = std::cmp::min(sb.blocks_per_group as u64, sb.blocks_count_64() - first);
+
+        if sb.group_has_superblock(group_idx as u32) {
+            let desc_blocks = (self.block_groups.len() as u64 * sb.group_desc_size() as u64)
+                .div_ceil(block_size as u64);
+            for idx in 0..1 + desc_blocks + sb.reserved_gdt_blocks as u64 {
+                mark(idx);
+            }
+        }
+
+        let itable_blocks = (sb.inodes_per_group as u64 * sb.inode_record_size() as u64)
+            .div_ceil(block_size as u64);
+        for bg in &self.block_groups {
+            let metadata = [(bg.block_bitmap, 1), (bg.inode_bitmap, 1), (bg.inode_table, itable_blocks)];
+            for (start, len) in metadata {
+                for block in start..start + len {
+                    if block >= first && block < first + in_group {
+                        mark(block - first);
                     }
                 }
             }
         }
 
-        // No free inodes found
-        Err(Ext4Error::NoSpace("No free inodes available".to_string()))
+        // Blocks past the end of a short last group are never available
+        for idx in in_group..block_size as u64 * 8 {
+            mark(idx);
+        }
+
+        bitmap
     }
 
-    /// Allocate a new block.
-    fn allocate_block(&mut self) -> Result<u32, Ext4Error> {
-        // Iterate through each block group to find a free block
-        for (group_idx, block_group) in self.block_groups.iter().enumerate() {
-            let block_bitmap_block = block_group.block_bitmap;
-            let block_size = self.superblock.block_size();
+    /// Check that a newly allocated run fits in a 32-bit block map pointer.
+    ///
+    /// The run is released again if it lies beyond the reach of an indirect block map.
+    fn mappable_block(&mut self, block_num: u64, count: u32) -> Result<u32, Ext4Error> {
+        match u32::try_from(block_num + count as u64) {
+            Ok(_) => Ok(block_num as u32),
+            Err(_) => {
+                self.free_blocks(block_num, count)?;
+                Err(Ext4Error::InvalidBlock(format!(
+                    "Block {} cannot be addressed by an indirect block map",
+                    block_num
+                )))
+            }
+        }
+    }
 
-            // Read the block bitmap
-            let mut file_clone = self.file.try_clone()?;
-            file_clone.seek(SeekFrom::Start((block_bitmap_block * block_size) as u64))?;
-
-            let mut bitmap = vec![0u8; block_size as usize];
-            file_clone.read_exact(&mut bitmap)?;
-
-            // Search for a free block (bit set to 0)
-            for byte_idx in 0..block_size as usize {
-                if bitmap[byte_idx] != 0xFF {
-                    // If not all bits are set
-                    for bit_idx in 0..8 {
-                        if (bitmap[byte_idx] & (1 << bit_idx)) == 0 {
-                            // Found a free block
-                            let block_idx = byte_idx * 8 + bit_idx;
-
-                            // Make sure it's within the valid range
-                            if block_idx < self.superblock.blocks_per_group as usize {
-                                // Mark the block as used (set bit to 1)
-                                bitmap[byte_idx] |= 1 << bit_idx;
-
-                                // Write the updated bitmap back to disk
-                                file_clone.seek(SeekFrom::Start(
-                                    (block_bitmap_block * block_size) as u64,
-                                ))?;
-                                file_clone.write_all(&bitmap)?;
-
-                                // Calculate the global block number
-                                let block_num = group_idx as u32 * self.superblock.blocks_per_group
-                                    + block_idx as u32
-                                    + (if group_idx == 0 {
-                                        self.superblock.first_data_block
-                                    } else {
-                                        0
-                                    });
-
-                                // Update the block group descriptor
-                                let mut bg = self.block_groups[group_idx].clone();
-                                bg.free_blocks_count -= 1;
-                                // We would update the block group descriptor on disk here
-                                self.block_groups[group_idx] = bg;
-
-                                return Ok(block_num);
-                            }
-                        }
+    /// Add an entry to a directory.
+    fn add_directory_entry(
+        &mut self,
+        dir_inode_num: u32,
+        name: &str,
+        inode_num: u32,
+        file_type: u8,
+    ) -> Result<(), Ext4Error> {
+        println!(
+            "开始添加目录项: dir_inode={}, name={}, new_inode={}, file_type={}",
+            dir_inode_num, name, inode_num, file_type
+        );
+
+        // 读取目录的 inode
+        let mut dir_inode = self.read_inode(dir_inode_num)?;
+        let block_size = self.superblock.block_size() as usize;
+
+        // Inline directories keep the entry in the inode if they can, or else move out to a
+        // block first
+        if dir_inode.has_inline_data() {
+            let record = DirectoryRecord::new(inode_num, name.as_bytes(), file_type)?;
+            if self.add_inline_dir_entry(dir_inode_num, &mut dir_inode, record)? {
+                return Ok(());
+            }
+            self.expand_inline_data(dir_inode_num, &mut dir_inode)?;
+        }
+
+        // Indexed directories place the entry by the hash of its name
+        let mut dx_fallback = false;
+        if self.is_indexed(&dir_inode) {
+            match self.dx_probe(dir_inode_num, &dir_inode, name) {
+                Ok(probe) => {
+                    return self.dx_add_entry(
+                        dir_inode_num,
+                        &mut dir_inode,
+                        name,
+                        inode_num,
+                        file_type,
+                        probe,
+                    )
+                }
+                Err(Ext4Error::InvalidDirectory(message)) => {
+                    // Like the kernel, drop an index that cannot be used and carry on without
+                    // it, unless checksums are on: then the damage is real corruption
+                    if self.csum_seed.is_some() {
+                        return Err(Ext4Error::InvalidDirectory(format!(
+                            "Corrupt index in directory {}: {}",
+                            dir_inode_num, message
+                        )));
                     }
+                    log::warn!(
+                        "Clearing the unusable index of directory {}: {}",
+                        dir_inode_num,
+                        message
+                    );
+                    dir_inode.flags &= !EXT4_INDEX_FL;
+                    self.write_inode(dir_inode_num, &dir_inode)?;
+                    dx_fallback = true;
                 }
+                Err(e) => return Err(e),
             }
         }
 
-        // No free blocks found
-        Err(Ext4Error::NoSpace("No free blocks available".to_string()))
+        // 遍历目录的数据块, remembering the first block that only has room once compacted
+        let record = DirectoryRecord::new(inode_num, name.as_bytes(), file_type)?;
+        let blocks = dir_inode.get_size().div_ceil(block_size as u64) as u32;
+        let mut fragmented = None;
+        for i in 0..blocks {
+            let (block_num, mut block_data, mut records) =
+                match self.read_dir_records(dir_inode_num, &dir_inode, i)? {
+                    Some(block) => block,
+                    None => continue,
+                };
+            if records.insert(record.clone()) {
+                return self.write_dir_records(
+                    dir_inode_num,
+                    &dir_inode,
+                    block_num,
+                    &mut block_data,
+                    &records,
+                );
+            }
+            if fragmented.is_none() && records.free_space() >= record.rec_len {
+                fragmented = Some((block_num, block_data, records));
+            }
+        }
+
+        // Squeeze the slack out of a block before growing the directory
+        if let Some((block_num, mut block_data, mut records)) = fragmented {
+            records.insert_or_compact(record);
+            return self.write_dir_records(
+                dir_inode_num,
+                &dir_inode,
+                block_num,
+                &mut block_data,
+                &records,
+            );
+        }
+
+        // A directory outgrowing its first block gets an index
+        if blocks == 1 && !dx_fallback && self.superblock.has_compat(CompatFeature::DirIndex) {
+            return self.make_indexed_dir(
+                dir_inode_num,
+                &mut dir_inode,
+                name,
+                inode_num,
+                file_type,
+            );
+        }
+
+        // 需要分配新块
+        let (_, block_num) = self.append_dir_block(dir_inode_num, &mut dir_inode)?;
+        let (mut block_data, usable) = self.empty_dir_block();
+        let records = DirectoryBlock::pack(usable, [record]);
+        self.write_dir_records(
+            dir_inode_num,
+            &dir_inode,
+            block_num,
+            &mut block_data,
+            &records,
+        )
     }
 
-    /// Free an inode.
-    fn free_inode(&mut self, inode_num: u32) -> Result<(), Ext4Error> {
-        if inode_num == 0 || inode_num > self.superblock.inodes_count {
-            return Err(Ext4Error::InvalidInode(format!(
-                "Invalid inode number: {}",
-                inode_num
-            )));
+    /// Add an entry to an inline directory, growing its `system.data` attribute if that
+    /// makes room.
+    ///
+    /// Returns false, leaving the directory alone, if the entry does not fit in the inode.
+    fn add_inline_dir_entry(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &mut Inode,
+        record: DirectoryRecord,
+    ) -> Result<bool, Ext4Error> {
+        let data = self.read_inline_data(dir_inode_num, dir_inode)?;
+        let mut inline = InlineDirectory::parse(&data)?;
+        if !inline.insert(record.clone()) {
+            // Like the kernel, take all the room the inode has at once
+            let max_len = self
+                .max_inline_size(dir_inode_num, dir_inode)?
+                .saturating_sub(INODE_BLOCK_SIZE);
+            if max_len <= inline.xattr_len() {
+                return Ok(false);
+            }
+            inline.grow(max_len);
+            if !inline.insert(record) {
+                return Ok(false);
+            }
         }
 
-        // Calculate which block group this inode belongs to
-        let group_idx = (inode_num - 1) / self.superblock.inodes_per_group;
-        if group_idx as usize >= self.block_groups.len() {
-            return Err(Ext4Error::InvalidInode(format!(
-                "Invalid block group index: {}",
-                group_idx
-            )));
+        self.write_inline_data(dir_inode_num, dir_inode, &inline.to_bytes())?;
+        Ok(true)
+    }
+
+    /// Add an entry to an indexed directory, starting from the path `probe` found for it.
+    ///
+    /// When the leaf the name hashes to is full, it is split in two; the index node above
+    /// it is split first if it has no room for the new leaf, and the tree grows a level when
+    /// every node up to the root is full.
+    fn dx_add_entry(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &mut Inode,
+        name: &str,
+        inode_num: u32,
+        file_type: u8,
+        mut probe: (HashVersion, u32, Vec<DxFrame>),
+    ) -> Result<(), Ext4Error> {
+        let record = DirectoryRecord::new(inode_num, name.as_bytes(), file_type)?;
+        loop {
+            let (version, hash, mut frames) = probe;
+            let depth = frames.len() - 1;
+            let leaf = frames[depth].child();
+            let (leaf_num, mut leaf_data, mut records) = self
+                .read_dir_records(dir_inode_num, dir_inode, leaf)?
+                .ok_or_else(|| {
+                    Ext4Error::InvalidDirectory(format!("Directory block {} is a hole", leaf))
+                })?;
+
+            // Squeeze the slack out of the leaf rather than splitting it
+            if records.insert_or_compact(record.clone()) {
+                return self.write_dir_records(
+                    dir_inode_num,
+                    dir_inode,
+                    leaf_num,
+                    &mut leaf_data,
+                    &records,
+                );
+            }
+
+            // The new leaf needs a slot in the node above it; make one and look again
+            match frames.iter().rposition(|frame| !frame.node.is_full()) {
+                Some(free) if free == depth => {}
+                Some(free) => {
+                    self.dx_split_node(dir_inode_num, dir_inode, &mut frames, free + 1)?;
+                    probe = self.dx_probe(dir_inode_num, dir_inode, name)?;
+                    continue;
+                }
+                None if frames.len() < self.dx_max_levels() => {
+                    self.dx_add_level(dir_inode_num, dir_inode)?;
+                    probe = self.dx_probe(dir_inode_num, dir_inode, name)?;
+                    continue;
+                }
+                None => {
+                    return Err(Ext4Error::NoSpace("Directory index full".to_string()));
+                }
+            }
+
+            let (hash2, new_num, mut new_data, mut new_records) = self.dx_split_leaf(
+                dir_inode_num,
+                dir_inode,
+                version,
+                &mut frames[depth],
+                &mut records,
+                &record,
+            )?;
+            let target = if hash >= hash2 {
+                &mut new_records
+            } else {
+                &mut records
+            };
+            if !target.insert(record) {
+                return Err(Ext4Error::NoSpace(
+                    "No space left in split directory block".to_string(),
+                ));
+            }
+            self.write_dir_records(dir_inode_num, dir_inode, leaf_num, &mut leaf_data, &records)?;
+            return self.write_dir_records(
+                dir_inode_num,
+                dir_inode,
+                new_num,
+                &mut new_data,
+                &new_records,
+            );
         }
+    }
 
-        // Get the block group and inode bitmap block
-        let block_group = &self.block_groups[group_idx as usize];
-        let inode_bitmap_block = block_group.inode_bitmap;
-        let block_size = self.superblock.block_size();
+    /// Move the upper half of a full leaf, by hash, to a new block and index it after the
+    /// leaf in `frame`, keeping room for `new` in the half its hash falls in.
+    ///
+    /// Returns the lowest hash in the new block, its physical block, an empty buffer for it
+    /// and its records; both blocks still need to be written.
+    fn dx_split_leaf(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &mut Inode,
+        version: HashVersion,
+        frame: &mut DxFrame,
+        records: &mut DirectoryBlock,
+        new: &DirectoryRecord,
+    ) -> Result<(u32, u64, Vec<u8>, DirectoryBlock), Ext4Error> {
+        let block_size = self.superblock.block_size() as usize;
+        let mut map: Vec<_> = records
+            .entries()
+            .map(|record| {
+                let (hash, _) = htree::dx_hash(&record.name, version, &self.superblock.hash_seed);
+                (hash, record.clone())
+            })
+            .collect();
+        map.sort_by_key(|(hash, _)| *hash);
+        let count = map.len();
+        if count < 2 {
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "Cannot split directory block {} with {} entries",
+                frame.child(),
+                count
+            )));
+        }
 
-        // Calculate the index within the block group
-        let index_in_group = (inode_num - 1) % self.superblock.inodes_per_group;
-        let byte_idx = (index_in_group / 8) as usize;
-        let bit_idx = (index_in_group % 8) as u8;
+        // Like the kernel, move entries from the top while they fit in half a block, and
+        // split by count if that gets down to the first entry
+        let mut size = 0;
+        let mut moved = 0;
+        let mut stopped = false;
+        for (_, record) in map[1..].iter().rev() {
+            let len = Directory::entry_len(record.name.len());
+            if size + len / 2 > block_size / 2 {
+                stopped = true;
+                break;
+            }
+            size += len;
+            moved += 1;
+        }
+        let split = if stopped { count - moved } else { count / 2 };
+
+        // The kernel trusts the new entry to fit in whichever half its hash picks, but a
+        // split by count can leave most of the block in one half. Unlike it, move the split
+        // to the nearest point where the new entry fits, so adding it never fails.
+        let (hash, _) = htree::dx_hash(&new.name, version, &self.superblock.hash_seed);
+        let new_len = Directory::entry_len(new.name.len());
+        let usable = records.block_len();
+        // Bytes taken by the entries below each split point
+        let mut below = Vec::with_capacity(count + 1);
+        let mut total = 0;
+        below.push(total);
+        for (_, record) in &map {
+            total += Directory::entry_len(record.name.len());
+            below.push(total);
+        }
+        let fits = |split: usize| {
+            if hash >= map[split].0 {
+                below[count] - below[split] + new_len <= usable
+            } else {
+                below[split] + new_len <= usable
+            }
+        };
+        let split = (1..count)
+            .filter(|&candidate| fits(candidate))
+            .min_by_key(|&candidate| candidate.abs_diff(split))
+            .ok_or_else(|| {
+                Ext4Error::NoSpace("No space left in split directory block".to_string())
+            })?;
+        let hash2 = map[split].0;
+        let continued = (hash2 == map[split - 1].0) as u32;
+
+        let (new_logical, new_num) = self.append_dir_block(dir_inode_num, dir_inode)?;
+        let (new_data, new_usable) = self.empty_dir_block();
+        let upper: Vec<_> = map
+            .split_off(split)
+            .into_iter()
+            .map(|(_, record)| record)
+            .collect();
+        for record in &upper {
+            records.remove(&record.name);
+        }
+        records.compact();
+        let new_records = DirectoryBlock::pack(new_usable, upper);
+
+        // A hash continuing into the new block is marked by the low bit
+        frame.node.entries.insert(
+            frame.at + 1,
+            DxEntry {
+                hash: hash2 + continued,
+                block: new_logical,
+            },
+        );
+        self.write_dx_node(dir_inode_num, dir_inode, frame.logical, &frame.node)?;
 
-        // Read the inode bitmap
-        let mut file_clone = self.file.try_clone()?;
-        file_clone.seek(SeekFrom::Start((inode_bitmap_block * block_size) as u64))?;
+        Ok((hash2, new_num, new_data, new_records))
+    }
 
-        let mut bitmap = vec![0u8; block_size as usize];
-        file_clone.read_exact(&mut bitmap)?;
+    /// Move the upper half of the full index node at `frames[depth]` to a new block and
+    /// index it in the node above.
+    fn dx_split_node(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &mut Inode,
+        frames: &mut [DxFrame],
+        depth: usize,
+    ) -> Result<(), Ext4Error> {
+        let (parents, children) = frames.split_at_mut(depth);
+        let parent = &mut parents[depth - 1];
+        let child = &mut children[0];
+
+        let split = child.node.entries.len() / 2;
+        let upper = DxNode {
+            limit: child.node.limit,
+            entries: child.node.entries.split_off(split),
+        };
+        let (new_logical, _) = self.append_dir_block(dir_inode_num, dir_inode)?;
+        self.write_dx_node(dir_inode_num, dir_inode, new_logical, &upper)?;
+        self.write_dx_node(dir_inode_num, dir_inode, child.logical, &child.node)?;
+
+        parent.node.entries.insert(
+            parent.at + 1,
+            DxEntry {
+                hash: upper.entries[0].hash,
+                block: new_logical,
+            },
+        );
+        self.write_dx_node(dir_inode_num, dir_inode, parent.logical, &parent.node)
+    }
 
-        // Check if the inode is already free
-        if (bitmap[byte_idx] & (1 << bit_idx)) == 0 {
-            return Err(Ext4Error::InvalidOperation(format!(
-                "Inode {} is already free",
-                inode_num
-            )));
-        }
+    /// Grow the index of a directory by a level, moving the entries of the full root to a
+    /// new interior node.
+    fn dx_add_level(&mut self, dir_inode_num: u32, dir_inode: &mut Inode) -> Result<(), Ext4Error> {
+        let mut root_block = self
+            .read_dir_block(dir_inode_num, dir_inode, 0)?
+            .ok_or_else(|| Ext4Error::InvalidDirectory("Index root is a hole".to_string()))?;
+        let mut root = DxRoot::parse(&root_block, self.dx_max_levels() as u8)?;
+
+        let (new_logical, _) = self.append_dir_block(dir_inode_num, dir_inode)?;
+        let checksums = self.csum_seed.is_some();
+        let node = DxNode {
+            limit: htree::node_limit(root_block.len(), checksums),
+            entries: std::mem::take(&mut root.node.entries),
+        };
+        self.write_dx_node(dir_inode_num, dir_inode, new_logical, &node)?;
 
-        // Mark the inode as free (clear the bit)
-        bitmap[byte_idx] &= !(1 << bit_idx);
+        root.indirect_levels += 1;
+        root.node.entries.push(DxEntry {
+            hash: 0,
+            block: new_logical,
+        });
+        root.write_to(&mut root_block);
+        self.write_dx_block(dir_inode_num, dir_inode, 0, &mut root_block)
+    }
 
-        // Write the updated bitmap back to disk
-        file_clone.seek(SeekFrom::Start((inode_bitmap_block * block_size) as u64))?;
-        file_clone.write_all(&bitmap)?;
+    /// Turn a directory whose only block is full into an indexed one, then add the entry.
+    ///
+    /// The entries move to a new second block, which the root in the first block indexes
+    /// before it is split like any other full leaf.
+    fn make_indexed_dir(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &mut Inode,
+        name: &str,
+        inode_num: u32,
+        file_type: u8,
+    ) -> Result<(), Ext4Error> {
+        let block_size = self.superblock.block_size() as usize;
+        let (_, _, records) = self
+            .read_dir_records(dir_inode_num, dir_inode, 0)?
+            .ok_or_else(|| {
+                Ext4Error::InvalidDirectory("Directory block 0 is a hole".to_string())
+            })?;
+        let parent = records.find(b"..").ok_or_else(|| {
+            Ext4Error::InvalidDirectory(format!("Directory {} has no \"..\"", dir_inode_num))
+        })?;
+        let entries = records
+            .entries()
+            .filter(|record| record.name != b"." && record.name != b"..")
+            .cloned();
+
+        let (leaf, leaf_num) = self.append_dir_block(dir_inode_num, dir_inode)?;
+        let (mut leaf_data, leaf_usable) = self.empty_dir_block();
+        let leaf_records = DirectoryBlock::pack(leaf_usable, entries);
+        self.write_dir_records(
+            dir_inode_num,
+            dir_inode,
+            leaf_num,
+            &mut leaf_data,
+            &leaf_records,
+        )?;
+
+        // The root keeps "." and "..", with the index hidden in the slack of ".."
+        let mut root_block = vec![0u8; block_size];
+        let dots = [
+            DirectoryRecord::new(dir_inode_num, b".", 2)?,
+            DirectoryRecord::new(parent, b"..", 2)?,
+        ];
+        DirectoryBlock::pack(block_size, dots).write_to(&mut root_block);
+        let root = DxRoot {
+            hash_version: self.superblock.def_hash_version,
+            indirect_levels: 0,
+            node: DxNode {
+                limit: htree::root_limit(block_size, self.csum_seed.is_some()),
+                entries: vec![DxEntry {
+                    hash: 0,
+                    block: leaf,
+                }],
+            },
+        };
+        root.write_to(&mut root_block);
+        self.write_dx_block(dir_inode_num, dir_inode, 0, &mut root_block)?;
 
-        // Update the block group descriptor
-        let mut bg = self.block_groups[group_idx as usize].clone();
-        bg.free_inodes_count += 1;
-        self.block_groups[group_idx as usize] = bg;
+        dir_inode.flags |= EXT4_INDEX_FL;
+        self.write_inode(dir_inode_num, dir_inode)?;
 
-        // Update the block group descriptor on disk
-        // This would require writing the updated block group descriptor to disk
-        // For simplicity, we'll skip this step for now
+        let probe = self.dx_probe(dir_inode_num, dir_inode, name)?;
+        self.dx_add_entry(dir_inode_num, dir_inode, name, inode_num, file_type, probe)
+    }
 
-        Ok(())
+    /// Write an index node to its block in a directory.
+    ///
+    /// The root keeps the rest of its block; an interior node gets a fresh one.
+    fn write_dx_node(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &Inode,
+        logical: u32,
+        node: &DxNode,
+    ) -> Result<(), Ext4Error> {
+        let mut block_data = if logical == 0 {
+            let mut root_block = self
+                .read_dir_block(dir_inode_num, dir_inode, 0)?
+                .ok_or_else(|| Ext4Error::InvalidDirectory("Index root is a hole".to_string()))?;
+            node.write_to(&mut root_block, htree::DX_ROOT_ENTRIES_OFFSET);
+            root_block
+        } else {
+            let mut node_block = vec![0u8; self.superblock.block_size() as usize];
+            node.write_interior(&mut node_block);
+            node_block
+        };
+        self.write_dx_block(dir_inode_num, dir_inode, logical, &mut block_data)
     }
 
-    /// Free a block.
-    fn free_block(&mut self, block_num: u32) -> Result<(), Ext4Error> {
-        if block_num < self.superblock.first_data_block || block_num >= self.superblock.blocks_count
-        {
-            return Err(Ext4Error::InvalidBlock(format!(
-                "Invalid block number: {}",
-                block_num
-            )));
+    /// Write an index block of a directory, refreshing its checksum tail.
+    fn write_dx_block(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &Inode,
+        logical: u32,
+        block_data: &mut [u8],
+    ) -> Result<(), Ext4Error> {
+        if let Some(seed) = self.csum_seed {
+            let count_offset = if logical == 0 {
+                htree::DX_ROOT_ENTRIES_OFFSET
+            } else {
+                htree::DX_NODE_ENTRIES_OFFSET
+            };
+            let inode_seed = checksum::inode_seed(seed, dir_inode_num, dir_inode.generation);
+            checksum::set_dx_block_checksum(inode_seed, block_data, count_offset);
         }
+        let block_num = self.dir_block_num(dir_inode, logical)?;
+        self.write_metadata_block(block_num, block_data)
+    }
 
-        // Calculate which block group this block belongs to
-        let group_idx =
-            (block_num - self.superblock.first_data_block) / self.superblock.blocks_per_group;
-        if group_idx as usize >= self.block_groups.len() {
-            return Err(Ext4Error::InvalidBlock(format!(
-                "Invalid block group index: {}",
-                group_idx
-            )));
+    /// Get the physical block behind a logical block of a directory.
+    fn dir_block_num(&self, dir_inode: &Inode, logical: u32) -> Result<u64, Ext4Error> {
+        let mut reader = self.reader()?;
+        dir_inode
+            .map_block(&mut reader, logical, self.superblock.block_size())?
+            .ok_or_else(|| {
+                Ext4Error::InvalidDirectory(format!("Directory block {} is a hole", logical))
+            })
+    }
+
+    /// Get the length of a directory block without its checksum tail.
+    fn dir_block_usable(block_data: &[u8]) -> usize {
+        if checksum::has_dirent_tail(block_data) {
+            block_data.len() - checksum::DIRENT_TAIL_SIZE
+        } else {
+            block_data.len()
         }
+    }
 
-        // Get the block group and block bitmap block
-        let block_group = &self.block_groups[group_idx as usize];
-        let block_bitmap_block = block_group.block_bitmap;
+    /// Add a block to the end of a directory, mapping it through the directory's extent tree
+    /// or block map.
+    ///
+    /// Returns the logical and physical numbers of the new block, whose contents are left
+    /// to the caller.
+    fn append_dir_block(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &mut Inode,
+    ) -> Result<(u32, u64), Ext4Error> {
         let block_size = self.superblock.block_size();
+        let logical = dir_inode.get_size().div_ceil(block_size as u64) as u32;
+        let block_num = self.allocate_block()?;
 
-        // Calculate the index within the block group
-        let index_in_group =
-            (block_num - self.superblock.first_data_block) % self.superblock.blocks_per_group;
-        let byte_idx = (index_in_group / 8) as usize;
-        let bit_idx = (index_in_group % 8) as u8;
+        if dir_inode.uses_extents() {
+            let root = dir_inode.block_bytes();
+            let mut reader = self.reader()?;
+            let mut extents = extent::collect_extents(&mut reader, &root, block_size)?;
+            let tree_blocks = extent::collect_tree_blocks(&mut reader, &root, block_size)?;
+            extent::insert_extent(
+                &mut extents,
+                Extent {
+                    block: logical,
+                    len: 1,
+                    start: block_num,
+                },
+            );
 
-        // Read the block bitmap
-        let mut file_clone = self.file.try_clone()?;
-        file_clone.seek(SeekFrom::Start((block_bitmap_block * block_size) as u64))?;
+            // The tree is rebuilt from scratch, so the blocks of the old one go
+            for tree_block in tree_blocks {
+                self.free_block(tree_block)?;
+            }
+            self.write_extent_tree(dir_inode_num, dir_inode, &extents)?;
+            self.count_extent_blocks(dir_inode)?;
+        } else {
+            let block_num = self.mappable_block(block_num, 1)?;
+            let meta_blocks = self.map_indirect_block(dir_inode, logical, block_num)?;
+            let sectors = (1 + meta_blocks as u64) * (block_size / 512) as u64;
+            self.set_inode_blocks(dir_inode, dir_inode.get_sectors(block_size) + sectors)?;
+        }
 
-        let mut bitmap = vec![0u8; block_size as usize];
-        file_clone.read_exact(&mut bitmap)?;
+        dir_inode.size = (logical + 1) * block_size;
+        self.write_inode(dir_inode_num, dir_inode)?;
+        Ok((logical, block_num))
+    }
 
-        // Check if the block is already free
-        if (bitmap[byte_idx] & (1 << bit_idx)) == 0 {
-            return Err(Ext4Error::InvalidOperation(format!(
-                "Block {} is already free",
-                block_num
-            )));
+    /// Map a logical block of a block-mapped inode, allocating any missing indirect blocks on
+    /// the way.
+    ///
+    /// Returns the number of indirect blocks allocated.
+    fn map_indirect_block(
+        &mut self,
+        inode: &mut Inode,
+        logical: u32,
+        block_num: u32,
+    ) -> Result<u32, Ext4Error> {
+        let block_size = self.superblock.block_size();
+        let (slot, level, mut offset) =
+            block_map::locate(logical as u64, block_size).ok_or_else(|| {
+                Ext4Error::NoSpace("File is too large for an indirect block map".to_string())
+            })?;
+        if level == 0 {
+            inode.block[slot] = block_num;
+            return Ok(0);
         }
 
-        // Mark the block as free (clear the bit)
-        bitmap[byte_idx] &= !(1 << bit_idx);
+        let mut meta_blocks = 0;
+        if inode.block[slot] == 0 {
+            inode.block[slot] = self.allocate_indirect_block()?;
+            meta_blocks += 1;
+        }
+        let mut current = inode.block[slot];
+        for depth in (1..=level).rev() {
+            let span = block_map::blocks_per_level(block_size, depth - 1);
+            let index = (offset / span) as usize;
+            offset %= span;
+
+            let mut reader = self.reader()?;
+            let mut pointers = block_map::read_pointers(&mut reader, current, block_size)?;
+            if depth == 1 {
+                pointers[index] = block_num;
+            } else if pointers[index] == 0 {
+                pointers[index] = self.allocate_indirect_block()?;
+                meta_blocks += 1;
+            } else {
+                current = pointers[index];
+                continue;
+            }
+            let data = block_map::write_pointers(&pointers, block_size);
+            self.write_metadata_block(current as u64, &data)?;
+            current = pointers[index];
+        }
 
-        // Write the updated bitmap back to disk
-        file_clone.seek(SeekFrom::Start((block_bitmap_block * block_size) as u64))?;
-        file_clone.write_all(&bitmap)?;
+        Ok(meta_blocks)
+    }
 
-        // Update the block group descriptor
-        let mut bg = self.block_groups[group_idx as usize].clone();
-        bg.free_blocks_count += 1;
-        self.block_groups[group_idx as usize] = bg;
+    /// Allocate an empty indirect block.
+    fn allocate_indirect_block(&mut self) -> Result<u32, Ext4Error> {
+        let block_num = self.allocate_block()?;
+        let block_num = self.mappable_block(block_num, 1)?;
+        let data = vec![0u8; self.superblock.block_size() as usize];
+        self.write_metadata_block(block_num as u64, &data)?;
+        Ok(block_num)
+    }
 
-        // Update the block group descriptor on disk
-        // This would require writing the updated block group descriptor to disk
-        // For simplicity, we'll skip this step for now
+    /// Get a zeroed directory block, with a checksum tail if directories have them.
+    ///
+    /// Returns the block and the length of it left for records.
+    fn empty_dir_block(&self) -> (Vec<u8>, usize) {
+        let block_size = self.superblock.block_size() as usize;
+        let mut block_data = vec![0u8; block_size];
+        let mut usable = block_size;
+        if self.csum_seed.is_some() {
+            checksum::init_dirent_tail(&mut block_data);
+            usable -= checksum::DIRENT_TAIL_SIZE;
+        }
+        (block_data, usable)
+    }
 
-        Ok(())
+    /// Get the file type a directory entry for an inode records, or 0 if the filesystem
+    /// does not keep file types in entries.
+    fn entry_file_type(&self, inode: &Inode) -> u8 {
+        if self.superblock.has_incompat(IncompatFeature::Filetype) {
+            inode.file_type()
+        } else {
+            0
+        }
     }
 
-    /// Add an entry to a directory.
-    fn add_directory_entry(
+    /// Point the ".." entry of a directory at a new parent.
+    ///
+    /// ".." is the second entry of the first block, also when that block is an index root;
+    /// inline directories keep their parent at the start of `i_block`.
+    fn set_parent_entry(
         &mut self,
         dir_inode_num: u32,
-        name: &str,
-        inode_num: u32,
-        file_type: u8,
+        dir_inode: &Inode,
+        parent: u32,
     ) -> Result<(), Ext4Error> {
-        println!(
-            "开始添加目录项: dir_inode={}, name={}, new_inode={}, file_type={}",
-            dir_inode_num, name, inode_num, file_type
-        );
-
-        // 读取目录的 inode
-        let mut dir_inode = self.read_inode(dir_inode_num)?;
-        let block_size = self.superblock.block_size() as usize;
-
-        // 遍历目录的数据块
-        for i in 0..12 {
-            let block_num = dir_inode.block[i];
-            if block_num == 0 {
-                // 需要分配新块
-                let new_block = self.allocate_block()?;
-                dir_inode.block[i] = new_block;
-                dir_inode.size += block_size as u32;
-                dir_inode.blocks = ((i + 1) * block_size / 512) as u32;
-
-                // 写入新目录项
-                let entry_size = 8 + name.len(); // 头部(8字节) + 文件名长度
-                let mut file_clone = self.file.try_clone()?;
-                file_clone.seek(SeekFrom::Start(
-                    (new_block * self.superblock.block_size()) as u64,
-                ))?;
-
-                // 写入目录项
-                use byteorder::{LittleEndian, WriteBytesExt};
-                file_clone.write_u32::<LittleEndian>(inode_num)?;
-                file_clone.write_u16::<LittleEndian>(block_size as u16)?; // 使用整个块大小
-                file_clone.write_u8(name.len() as u8)?;
-                file_clone.write_u8(file_type)?;
-                file_clone.write_all(name.as_bytes())?;
-
-                // 填充剩余空间
-                let padding = vec![0u8; block_size - entry_size];
-                file_clone.write_all(&padding)?;
-
-                // 更新目录 inode
-                self.write_inode(dir_inode_num, &dir_inode)?;
-                return Ok(());
-            }
+        if dir_inode.has_inline_data() {
+            let mut dir_inode = dir_inode.clone();
+            let data = self.read_inline_data(dir_inode_num, &dir_inode)?;
+            let mut inline = InlineDirectory::parse(&data)?;
+            inline.parent = parent;
+            return self.write_inline_data(dir_inode_num, &mut dir_inode, &inline.to_bytes());
+        }
 
-            // 检查现有块中的空间
-            let mut file_clone = self.file.try_clone()?;
-            file_clone.seek(SeekFrom::Start(
-                (block_num * self.superblock.block_size()) as u64,
-            ))?;
+        let mut block_data = self
+            .read_dir_block(dir_inode_num, dir_inode, 0)?
+            .ok_or_else(|| {
+                Ext4Error::InvalidDirectory(format!(
+                    "First block of directory {} is a hole",
+                    dir_inode_num
+                ))
+            })?;
+        let dotdot = LittleEndian::read_u16(&block_data[4..6]) as usize;
+        if dotdot + 10 > block_data.len()
+            || block_data[dotdot + 6] != 2
+            || &block_data[dotdot + 8..dotdot + 10] != b".."
+        {
+            return Err(Ext4Error::InvalidDirectory(format!(
+                "Directory {} has no '..' entry",
+                dir_inode_num
+            )));
+        }
+        LittleEndian::write_u32(&mut block_data[dotdot..dotdot + 4], parent);
 
-            let mut block_data = vec![0u8; block_size];
-            file_clone.read_exact(&mut block_data)?;
+        if self.is_indexed(dir_inode) {
+            self.write_dx_block(dir_inode_num, dir_inode, 0, &mut block_data)
+        } else {
+            let block_num = self.dir_block_num(dir_inode, 0)?;
+            self.write_dir_block(dir_inode_num, dir_inode, block_num, &mut block_data)
+        }
+    }
 
-            // 查找空闲空间
-            let mut offset = 0;
-            while offset < block_size {
-                if offset + 8 > block_size {
-                    break;
-                }
+    /// Write the first block of a new directory, holding only "." and "..".
+    fn write_dot_entries(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &Inode,
+        block_num: u64,
+        parent: u32,
+    ) -> Result<(), Ext4Error> {
+        let (mut block_data, usable) = self.empty_dir_block();
+        let dots = [
+            DirectoryRecord::new(dir_inode_num, b".", 2)?,
+            DirectoryRecord::new(parent, b"..", 2)?,
+        ];
+        let records = DirectoryBlock::pack(usable, dots);
+        self.write_dir_records(
+            dir_inode_num,
+            dir_inode,
+            block_num,
+            &mut block_data,
+            &records,
+        )
+    }
 
-                let mut cursor = std::io::Cursor::new(&block_data[offset..]);
-                let entry_inode = cursor.read_u32::<LittleEndian>()?;
-                let rec_len = cursor.read_u16::<LittleEndian>()? as usize;
-
-                if entry_inode == 0 || offset + rec_len >= block_size {
-                    // 找到空闲空间
-                    let entry_size = 8 + name.len();
-                    if offset + entry_size <= block_size {
-                        let mut file_clone = self.file.try_clone()?;
-                        file_clone.seek(SeekFrom::Start(
-                            (block_num * self.superblock.block_size() + offset as u32) as u64,
-                        ))?;
-
-                        // 写入新目录项
-                        use byteorder::{LittleEndian, WriteBytesExt};
-                        file_clone.write_u32::<LittleEndian>(inode_num)?;
-                        file_clone.write_u16::<LittleEndian>((block_size - offset) as u16)?;
-                        file_clone.write_u8(name.len() as u8)?;
-                        file_clone.write_u8(file_type)?;
-                        file_clone.write_all(name.as_bytes())?;
-
-                        return Ok(());
-                    }
-                }
+    /// Write the records of a directory block over its contents, then the block itself.
+    fn write_dir_records(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &Inode,
+        block_num: u64,
+        block_data: &mut [u8],
+        records: &DirectoryBlock,
+    ) -> Result<(), Ext4Error> {
+        records.write_to(block_data);
+        self.write_dir_block(dir_inode_num, dir_inode, block_num, block_data)
+    }
 
-                offset += rec_len;
+    /// Write a directory block, refreshing its checksum tail if it has one.
+    fn write_dir_block(
+        &mut self,
+        dir_inode_num: u32,
+        dir_inode: &Inode,
+        block_num: u64,
+        block_data: &mut [u8],
+    ) -> Result<(), Ext4Error> {
+        if let Some(seed) = self.csum_seed {
+            if checksum::has_dirent_tail(block_data) {
+                let inode_seed = checksum::inode_seed(seed, dir_inode_num, dir_inode.generation);
+                checksum::set_dirent_block_checksum(inode_seed, block_data);
             }
         }
-
-        Err(Ext4Error::NoSpace(
-            "No space left in directory blocks".to_string(),
-        ))
+        self.write_metadata_block(block_num, block_data)
     }
 
     /// Remove an entry from a directory.
@@ -1130,7 +4329,7 @@ impl Ext4Filesystem {
         }
 
         // Read the directory inode
-        let dir_inode = self.read_inode(dir_inode_num)?;
+        let mut dir_inode = self.read_inode(dir_inode_num)?;
         if !dir_inode.is_directory() {
             return Err(Ext4Error::InvalidDirectory(format!(
                 "Inode {} is not a directory",
@@ -1138,105 +4337,54 @@ impl Ext4Filesystem {
             )));
         }
 
+        // Inline directories are rewritten whole
+        if dir_inode.has_inline_data() {
+            let data = self.read_inline_data(dir_inode_num, &dir_inode)?;
+            let mut inline = InlineDirectory::parse(&data)?;
+            if inline.remove(name.as_bytes()).is_none() {
+                return Err(Ext4Error::InvalidFile(format!(
+                    "Directory entry '{}' not found",
+                    name
+                )));
+            }
+            return self.write_inline_data(dir_inode_num, &mut dir_inode, &inline.to_bytes());
+        }
+
         // Read the directory data
         let block_size = self.superblock.block_size() as usize;
 
-        // Iterate through directory blocks to find the entry
-        for i in 0..12 {
-            // Only handling direct blocks for now
-            if dir_inode.block[i] == 0 {
-                continue; // Skip empty blocks
+        // Indexed directories know which leaf holds the name
+        let leaf = if self.is_indexed(&dir_inode) {
+            match self.dx_lookup(dir_inode_num, &dir_inode, name) {
+                Ok(found) => found.map(|(leaf, _)| leaf),
+                Err(Ext4Error::InvalidDirectory(_)) => None,
+                Err(e) => return Err(e),
             }
+        } else {
+            None
+        };
+        let blocks = match leaf {
+            Some(leaf) => leaf..leaf + 1,
+            None => 0..dir_inode.get_size().div_ceil(block_size as u64) as u32,
+        };
 
-            // Read existing block data
-            let block_num = dir_inode.block[i];
-            let mut file_clone = self.file.try_clone()?;
-            file_clone.seek(SeekFrom::Start(
-                (block_num * self.superblock.block_size()) as u64,
-            ))?;
-
-            let mut block_data = vec![0u8; block_size];
-            file_clone.read_exact(&mut block_data)?;
-
-            // Parse directory entries to find the one to remove
-            let mut offset = 0;
-            let mut prev_offset = 0;
-            let mut prev_rec_len = 0;
-
-            while offset < block_size {
-                // Read entry header
-                if offset + 8 > block_size {
-                    break;
-                }
-
-                use byteorder::{LittleEndian, ReadBytesExt};
-                let mut cursor = std::io::Cursor::new(&block_data[offset..]);
-
-                let entry_inode = cursor.read_u32::<LittleEndian>()?;
-                let rec_len = cursor.read_u16::<LittleEndian>()? as usize;
-                let name_len = cursor.read_u8()? as usize;
-                let _file_type = cursor.read_u8()?;
-
-                // Skip deleted entries
-                if entry_inode == 0 || rec_len == 0 {
-                    prev_offset = offset;
-                    prev_rec_len = rec_len;
-                    offset += rec_len;
-                    continue;
-                }
-
-                // Check if this is the entry we want to remove
-                if name_len == name.len() {
-                    let entry_name =
-                        String::from_utf8_lossy(&block_data[offset + 8..offset + 8 + name_len]);
-                    if entry_name == name {
-                        // Found the entry to remove
-
-                        // Strategy 1: Mark as deleted by setting inode to 0
-                        let mut file_clone = self.file.try_clone()?;
-                        file_clone.seek(SeekFrom::Start(
-                            (block_num * self.superblock.block_size() + offset as u32) as u64,
-                        ))?;
-
-                        use byteorder::{LittleEndian, WriteBytesExt};
-                        file_clone.write_u32::<LittleEndian>(0)?; // Set inode to 0 to mark as deleted
-
-                        // Strategy 2: If this is not the last entry in the block, merge with previous entry
-                        if offset + rec_len < block_size && prev_rec_len > 0 {
-                            // There's another entry after this one, so extend the previous entry
-                            let mut file_clone = self.file.try_clone()?;
-                            file_clone.seek(SeekFrom::Start(
-                                (block_num * self.superblock.block_size() + prev_offset as u32 + 4)
-                                    as u64,
-                            ))?;
-
-                            file_clone
-                                .write_u16::<LittleEndian>((prev_rec_len + rec_len) as u16)?;
-                        }
-
-                        // Strategy 3: If this is the last entry in the block, adjust the previous entry's rec_len
-                        if offset + rec_len >= block_size && prev_rec_len > 0 {
-                            let mut file_clone = self.file.try_clone()?;
-                            file_clone.seek(SeekFrom::Start(
-                                (block_num * self.superblock.block_size() + prev_offset as u32 + 4)
-                                    as u64,
-                            ))?;
-
-                            file_clone
-                                .write_u16::<LittleEndian>((block_size - prev_offset) as u16)?;
-                        }
-
-                        // If this is the only entry in the block, we could potentially free the block
-                        // but for simplicity, we'll just leave it marked as deleted
-
-                        return Ok(());
-                    }
-                }
-
-                // Move to the next entry
-                prev_offset = offset;
-                prev_rec_len = rec_len;
-                offset += rec_len;
+        // Iterate through directory blocks to find the entry
+        for i in blocks {
+            let (block_num, mut block_data, mut records) =
+                match self.read_dir_records(dir_inode_num, &dir_inode, i)? {
+                    Some(block) => block,
+                    None => continue, // Skip empty blocks
+                };
+
+            // The freed space goes to the entry before it
+            if records.remove(name.as_bytes()).is_some() {
+                return self.write_dir_records(
+                    dir_inode_num,
+                    &dir_inode,
+                    block_num,
+                    &mut block_data,
+                    &records,
+                );
             }
         }
 
@@ -1247,8 +4395,8 @@ impl Ext4Filesystem {
         )))
     }
 
-    /// Write an inode back to disk.
-    fn write_inode(&mut self, inode_num: u32, inode: &Inode) -> Result<(), Ext4Error> {
+    /// Get the byte offset of an inode in its inode table.
+    fn inode_offset(&self, inode_num: u32) -> Result<u64, Ext4Error> {
         if inode_num == 0 || inode_num > self.superblock.inodes_count {
             return Err(Ext4Error::InvalidInode(format!(
                 "Invalid inode number: {}",
@@ -1266,161 +4414,375 @@ impl Ext4Filesystem {
 
         let block_group = &self.block_groups[group_idx as usize];
         let index = (inode_num - 1) % self.superblock.inodes_per_group;
-        let offset = block_group.inode_table * self.superblock.block_size() + index * 256; // Assuming inode size is 256 bytes
+        Ok(block_group.inode_table * self.superblock.block_size() as u64
+            + index as u64 * self.superblock.inode_record_size() as u64)
+    }
 
-        let mut file_clone = self.file.try_clone()?;
-        file_clone.seek(SeekFrom::Start(offset as u64))?;
+    /// Build an empty in-memory inode sized for this filesystem.
+    fn default_inode(&self) -> Inode {
+        let inode_size = self.superblock.inode_record_size() as usize;
+        let extra_isize = if inode_size > EXT4_GOOD_OLD_INODE_SIZE {
+            let want = match self.superblock.want_extra_isize {
+                0 => EXT4_INODE_EXTRA_SIZE,
+                want => want,
+            };
+            std::cmp::min(want as usize, inode_size - EXT4_GOOD_OLD_INODE_SIZE) as u16
+        } else {
+            0
+        };
 
-        // For now, we'll just return an error since writing to disk is not fully implemented
-        // return Err(Ext4Error::InvalidOperation("Writing inodes to disk is not fully implemented yet".to_string()));
+        Inode {
+            extra_isize,
+            ..Default::default()
+        }
+    }
 
-        // The following would be the implementation for writing the inode:
-        use byteorder::{LittleEndian, WriteBytesExt};
+    /// Write an inode back to disk.
+    fn write_inode(&mut self, inode_num: u32, inode: &Inode) -> Result<(), Ext4Error> {
+        let offset = self.inode_offset(inode_num)?;
+        let data = inode.to_bytes(self.superblock.inode_record_size())?;
+
+        // Keep the bytes past the fields we know about, then refresh the checksum
+        let mut raw = self.read_inode_record(inode_num)?;
+        raw[..data.len()].copy_from_slice(&data);
+        if let Some(seed) = self.csum_seed {
+            checksum::set_inode_checksum(seed, inode_num, &mut raw);
+        }
 
-        file_clone.write_u16::<LittleEndian>(inode.mode)?;
-        file_clone.write_u16::<LittleEndian>(inode.uid)?;
-        file_clone.write_u32::<LittleEndian>(inode.size)?;
-        file_clone.write_u32::<LittleEndian>(inode.atime)?;
-        file_clone.write_u32::<LittleEndian>(inode.ctime)?;
-        file_clone.write_u32::<LittleEndian>(inode.mtime)?;
-        file_clone.write_u32::<LittleEndian>(inode.dtime)?;
-        file_clone.write_u16::<LittleEndian>(inode.gid)?;
-        file_clone.write_u16::<LittleEndian>(inode.links_count)?;
-        file_clone.write_u32::<LittleEndian>(inode.blocks)?;
-        file_clone.write_u32::<LittleEndian>(inode.flags)?;
-        file_clone.write_u32::<LittleEndian>(inode.osd1)?;
+        self.write_metadata(offset, &raw)
+    }
 
-        for i in 0..15 {
-            file_clone.write_u32::<LittleEndian>(inode.block[i])?;
-        }
+    /// Read the full on-disk record of an inode.
+    fn read_inode_record(&self, inode_num: u32) -> Result<Vec<u8>, Ext4Error> {
+        let offset = self.inode_offset(inode_num)?;
+        let mut raw = vec![0u8; self.superblock.inode_record_size() as usize];
 
-        file_clone.write_u32::<LittleEndian>(inode.generation)?;
-        file_clone.write_u32::<LittleEndian>(inode.file_acl)?;
-        file_clone.write_u32::<LittleEndian>(inode.dir_acl)?;
-        file_clone.write_u32::<LittleEndian>(inode.faddr)?;
-        file_clone.write_all(&inode.osd2)?;
+        let mut reader = self.reader()?;
+        reader.seek(SeekFrom::Start(offset))?;
+        reader.read_exact(&mut raw)?;
+        Ok(raw)
+    }
 
-        Ok(())
+    /// Get the part of an inode record past its extra fields, where extended attributes
+    /// may be stored, or `None` if there is no room for them.
+    fn ibody_range(&self, inode: &Inode) -> Option<std::ops::Range<usize>> {
+        let inode_size = self.superblock.inode_record_size() as usize;
+        let start = EXT4_GOOD_OLD_INODE_SIZE + inode.extra_isize as usize;
+        if inode_size <= EXT4_GOOD_OLD_INODE_SIZE || start + 4 > inode_size {
+            None
+        } else {
+            Some(start..inode_size)
+        }
     }
 
-    /// Write the "." and ".." directory entries to a newly allocated directory block.
-    fn write_directory_entries(
+    /// Read the extended attributes stored inside an inode.
+    fn read_inode_xattrs(&self, inode_num: u32, inode: &Inode) -> Result<InodeXattrs, Ext4Error> {
+        match self.ibody_range(inode) {
+            Some(range) => InodeXattrs::parse(&self.read_inode_record(inode_num)?[range]),
+            None => Ok(InodeXattrs::default()),
+        }
+    }
+
+    /// Replace the extended attributes stored inside an inode, keeping the rest of its record.
+    fn write_inode_xattrs(
         &mut self,
-        block_num: u32,
-        dir_inode_num: u32,
-        parent_inode_num: u32,
+        inode_num: u32,
+        inode: &Inode,
+        xattrs: &InodeXattrs,
     ) -> Result<(), Ext4Error> {
-        let block_size = self.superblock.block_size();
-        let offset = block_num * block_size;
+        let range = self.ibody_range(inode).ok_or_else(|| {
+            Ext4Error::NoSpace(format!(
+                "Inode {} has no room for extended attributes",
+                inode_num
+            ))
+        })?;
+        let mut raw = self.read_inode_record(inode_num)?;
+        xattrs.write_to(&mut raw[range])?;
+        if let Some(seed) = self.csum_seed {
+            checksum::set_inode_checksum(seed, inode_num, &mut raw);
+        }
+        let offset = self.inode_offset(inode_num)?;
+        self.write_metadata(offset, &raw)
+    }
 
-        let mut file_clone = self.file.try_clone()?;
-        file_clone.seek(SeekFrom::Start(offset as u64))?;
-
-        // Write "." entry (points to this directory)
-        // inode (4 bytes)
-        file_clone.write_u32::<LittleEndian>(dir_inode_num)?;
-        // rec_len (2 bytes) - 12 bytes for this entry (8 bytes header + 1 byte name + 3 bytes padding)
-        file_clone.write_u16::<LittleEndian>(12)?;
-        // name_len (1 byte)
-        file_clone.write_u8(1)?;
-        // file_type (1 byte) - 2 is directory
-        file_clone.write_u8(2)?;
-        // name (1 byte + padding)
-        file_clone.write_all(b".")?;
-        // padding to 4-byte alignment
-        file_clone.write_all(&[0, 0, 0])?;
-
-        // Write ".." entry (points to parent directory)
-        // inode (4 bytes)
-        file_clone.write_u32::<LittleEndian>(parent_inode_num)?;
-        // rec_len (2 bytes) - remaining space in the block
-        file_clone.write_u16::<LittleEndian>((block_size - 12) as u16)?;
-        // name_len (1 byte)
-        file_clone.write_u8(2)?;
-        // file_type (1 byte) - 2 is directory
-        file_clone.write_u8(2)?;
-        // name (2 bytes + padding)
-        file_clone.write_all(b"..")?;
-        // padding to 4-byte alignment
-        file_clone.write_all(&[0, 0])?;
-
-        // Fill the rest of the block with zeros
-        let remaining = block_size as usize - 24; // 12 bytes for "." + 12 bytes for ".."
-        if remaining > 0 {
-            let zeros = vec![0u8; remaining];
-            file_clone.write_all(&zeros)?;
+    /// Read the inline data of an inode: `i_block` followed by the value of `system.data`.
+    ///
+    /// A file's data is cut to its size; a directory keeps its whole inline area.
+    fn read_inline_data(&self, inode_num: u32, inode: &Inode) -> Result<Vec<u8>, Ext4Error> {
+        let xattrs = self.read_inode_xattrs(inode_num, inode)?;
+        let value = xattrs
+            .get(EXT4_XATTR_INDEX_SYSTEM, EXT4_XATTR_SYSTEM_DATA)
+            .ok_or_else(|| {
+                Ext4Error::InvalidInode(format!(
+                    "Inode {} has inline data but no system.data attribute",
+                    inode_num
+                ))
+            })?;
+
+        let mut data = inode.block_bytes().to_vec();
+        data.extend_from_slice(value);
+        if !inode.is_directory() {
+            data.truncate(inode.get_size() as usize);
         }
+        Ok(data)
+    }
+
+    /// Get the most data an inode could keep inline, with its other extended attributes
+    /// left where they are; 0 if the filesystem does not use inline data.
+    fn max_inline_size(&self, inode_num: u32, inode: &Inode) -> Result<usize, Ext4Error> {
+        if !self.superblock.has_incompat(IncompatFeature::InlineData) {
+            return Ok(0);
+        }
+        let range = match self.ibody_range(inode) {
+            Some(range) => range,
+            None => return Ok(0),
+        };
+        let xattrs = self.read_inode_xattrs(inode_num, inode)?;
+        Ok(INODE_BLOCK_SIZE
+            + xattrs.max_value_len(range.len(), EXT4_XATTR_INDEX_SYSTEM, EXT4_XATTR_SYSTEM_DATA))
+    }
+
+    /// Store data inline in an inode, the first 60 bytes in `i_block` and the rest in the
+    /// `system.data` attribute, and set the inode's size to its length.
+    fn write_inline_data(
+        &mut self,
+        inode_num: u32,
+        inode: &mut Inode,
+        data: &[u8],
+    ) -> Result<(), Ext4Error> {
+        let split = std::cmp::min(data.len(), INODE_BLOCK_SIZE);
+        let mut block = [0u8; INODE_BLOCK_SIZE];
+        block[..split].copy_from_slice(&data[..split]);
+        inode.set_block_bytes(&block);
+        inode.flags = (inode.flags & !EXT4_EXTENTS_FL) | EXT4_INLINE_DATA_FL;
+        inode.size = data.len() as u32;
+        self.write_inode(inode_num, inode)?;
+
+        let mut xattrs = self.read_inode_xattrs(inode_num, inode)?;
+        xattrs.set(
+            EXT4_XATTR_INDEX_SYSTEM,
+            EXT4_XATTR_SYSTEM_DATA,
+            data[split..].to_vec(),
+        );
+        self.write_inode_xattrs(inode_num, inode, &xattrs)
+    }
 
+    /// Drop the `system.data` attribute of an inode whose data no longer lives inline.
+    fn remove_inline_xattr(&mut self, inode_num: u32, inode: &Inode) -> Result<(), Ext4Error> {
+        let mut xattrs = self.read_inode_xattrs(inode_num, inode)?;
+        if xattrs.remove(EXT4_XATTR_INDEX_SYSTEM, EXT4_XATTR_SYSTEM_DATA) {
+            self.write_inode_xattrs(inode_num, inode, &xattrs)?;
+        }
         Ok(())
     }
 
+    /// Move the inline data of an inode out to a newly allocated block.
+    ///
+    /// A directory's entries are laid out as a regular directory block, with "." and ".."
+    /// and a checksum tail.
+    fn expand_inline_data(&mut self, inode_num: u32, inode: &mut Inode) -> Result<(), Ext4Error> {
+        let data = self.read_inline_data(inode_num, inode)?;
+        self.remove_inline_xattr(inode_num, inode)?;
+
+        // Map the block like a freshly written file would be
+        let block_size = self.superblock.block_size();
+        let block_num = self.allocate_block()?;
+        inode.flags &= !EXT4_INLINE_DATA_FL;
+        inode.set_block_bytes(&[0u8; INODE_BLOCK_SIZE]);
+        let meta_blocks = if self.superblock.has_incompat(IncompatFeature::Extents) {
+            let extent = Extent {
+                block: 0,
+                len: 1,
+                start: block_num,
+            };
+            self.write_extent_tree(inode_num, inode, &[extent])?
+        } else {
+            let block = self.mappable_block(block_num, 1)?;
+            self.write_block_map(inode, &[block])?
+        };
+        let xattr = (inode.get_file_acl() != 0) as u64;
+        let sectors = (1 + meta_blocks as u64 + xattr) * (block_size / 512) as u64;
+        self.set_inode_blocks(inode, sectors)?;
+
+        if inode.is_directory() {
+            let inline = InlineDirectory::parse(&data)?;
+            let dots = [
+                DirectoryRecord::new(inode_num, b".", 2)?,
+                DirectoryRecord::new(inline.parent, b"..", 2)?,
+            ];
+            let (mut block_data, usable) = self.empty_dir_block();
+            let records =
+                DirectoryBlock::pack(usable, dots.into_iter().chain(inline.entries().cloned()));
+            inode.size = block_size;
+            self.write_inode(inode_num, inode)?;
+            self.write_dir_records(inode_num, inode, block_num, &mut block_data, &records)
+        } else {
+            self.write_data_blocks(block_num, &data)?;
+            self.write_inode(inode_num, inode)
+        }
+    }
+
     /// Write the superblock back to disk.
     fn write_superblock(&mut self) -> Result<(), Ext4Error> {
         println!("开始写入超级块");
-        let mut file_clone = self.file.try_clone()?;
 
         // 写入主超级块（位于偏移量 1024 字节处）
-        println!("写入主超级块到偏移量 1024");
-        file_clone.seek(SeekFrom::Start(1024))?;
+        println!("写入主超级块到偏移量 {}", SUPERBLOCK_OFFSET);
+        let mut superblock = self.superblock.clone();
+        superblock.block_group_nr = 0;
+        if self.csum_seed.is_some() {
+            superblock.checksum = superblock.compute_checksum()?;
+        }
+        self.write_metadata(SUPERBLOCK_OFFSET, &superblock.to_bytes()?)?;
+
+        // 事务中的备份超级块等到提交并写回之后再写
+        if !self.in_transaction() {
+            self.write_backup_superblocks()?;
+        }
 
-        // 写入超级块字段
-        self.write_superblock_data(&mut file_clone)?;
+        println!("超级块写入完成");
+        Ok(())
+    }
 
-        // 写入备份超级块
-        if self.superblock.rev_level >= 1 {
-            println!("开始写入备份超级块");
-            // 备份超级块位于块组 0、1 和 3、5、7 的幂
-            let backup_groups = [1u32, 3, 5, 7];
+    /// Write the superblock to the backup groups.
+    ///
+    /// Backups are not journaled, so they are only written once the primary superblock
+    /// they copy is on disk, and never on a read-only mount.
+    fn write_backup_superblocks(&mut self) -> Result<(), Ext4Error> {
+        if self.read_only.is_some() {
+            return Ok(());
+        }
 
-            for &bg_idx in backup_groups.iter() {
-                if bg_idx as usize >= self.block_groups.len() {
-                    println!("块组 {} 超出范围，停止写入备份", bg_idx);
-                    break;
-                }
+        // 写入备份超级块：备份位于每个备份块组的第一个块
+        println!("开始写入备份超级块");
+        let mut file_clone = self.file.try_clone()?;
+        let mut superblock = self.superblock.clone();
+        let block_size = self.superblock.block_size() as u64;
+        for bg_idx in 1..self.block_groups.len() as u32 {
+            if !self.superblock.group_has_superblock(bg_idx) {
+                continue;
+            }
 
-                let offset =
-                    bg_idx * self.superblock.blocks_per_group * self.superblock.block_size() + 1024;
-                println!("写入备份超级块到块组 {}, 偏移量 {}", bg_idx, offset);
+            let block = self.superblock.first_data_block as u64
+                + bg_idx as u64 * self.superblock.blocks_per_group as u64;
+            let offset = block * block_size;
+            println!("写入备份超级块到块组 {}, 偏移量 {}", bg_idx, offset);
 
-                file_clone.seek(SeekFrom::Start(offset as u64))?;
-                self.write_superblock_data(&mut file_clone)?;
+            superblock.block_group_nr = bg_idx as u16;
+            if self.csum_seed.is_some() {
+                superblock.checksum = superblock.compute_checksum()?;
             }
+            file_clone.seek(SeekFrom::Start(offset))?;
+            superblock.write(&mut file_clone)?;
         }
+        Ok(())
+    }
 
-        println!("超级块写入完成");
+    /// Write metadata at a byte offset of the image.
+    ///
+    /// Inside a transaction on a journaled filesystem the update is made to the in-memory
+    /// copies of the blocks it touches, which are logged when the transaction commits.
+    /// On a read-only mount it only ever reaches those copies. Otherwise it goes straight
+    /// to disk.
+    fn write_metadata(&mut self, offset: u64, data: &[u8]) -> Result<(), Ext4Error> {
+        let in_memory = self.read_only.is_some();
+        if !in_memory && !self.in_transaction() {
+            let mut file_clone = self.file.try_clone()?;
+            file_clone.seek(SeekFrom::Start(offset))?;
+            file_clone.write_all(data)?;
+            return Ok(());
+        }
+
+        let block_size = self.superblock.block_size() as u64;
+        let mut position = offset;
+        let mut remaining = data;
+        while !remaining.is_empty() {
+            let block_num = position / block_size;
+            let in_block = (position % block_size) as usize;
+            let len = std::cmp::min(remaining.len(), block_size as usize - in_block);
+
+            if in_memory {
+                self.cache_block(block_num)?;
+            } else {
+                self.join_transaction(block_num)?;
+            }
+            if let Some(block) = self.overlay.lock().get_mut(&block_num) {
+                block[in_block..in_block + len].copy_from_slice(&remaining[..len]);
+            }
+
+            position += len as u64;
+            remaining = &remaining[len..];
+        }
         Ok(())
     }
 
-    /// 写入超级块数据的辅助函数
-    fn write_superblock_data(&self, file: &mut StdFile) -> Result<(), Ext4Error> {
-        use byteorder::{LittleEndian, WriteBytesExt};
-
-        file.write_u32::<LittleEndian>(self.superblock.inodes_count)?;
-        file.write_u32::<LittleEndian>(self.superblock.blocks_count)?;
-        file.write_u32::<LittleEndian>(self.superblock.r_blocks_count)?;
-        file.write_u32::<LittleEndian>(self.superblock.free_blocks_count)?;
-        file.write_u32::<LittleEndian>(self.superblock.free_inodes_count)?;
-        file.write_u32::<LittleEndian>(self.superblock.first_data_block)?;
-        file.write_u32::<LittleEndian>(self.superblock.log_block_size)?;
-        file.write_u32::<LittleEndian>(self.superblock.log_block_size)?;
-        file.write_u32::<LittleEndian>(self.superblock.blocks_per_group)?;
-        file.write_u32::<LittleEndian>(self.superblock.frags_per_group)?;
-        file.write_u32::<LittleEndian>(self.superblock.inodes_per_group)?;
-        file.write_u32::<LittleEndian>(self.superblock.mtime)?;
-        file.write_u32::<LittleEndian>(self.superblock.wtime)?;
-        file.write_u16::<LittleEndian>(self.superblock.mnt_count)?;
-        file.write_u16::<LittleEndian>(self.superblock.max_mnt_count)?;
-        file.write_u16::<LittleEndian>(self.superblock.magic)?;
-        file.write_u16::<LittleEndian>(self.superblock.state)?;
-        file.write_u16::<LittleEndian>(self.superblock.errors)?;
-        file.write_u16::<LittleEndian>(self.superblock.minor_rev_level)?;
-        file.write_u32::<LittleEndian>(self.superblock.lastcheck)?;
-        file.write_u32::<LittleEndian>(self.superblock.checkinterval)?;
-        file.write_u32::<LittleEndian>(self.superblock.creator_os)?;
-        file.write_u32::<LittleEndian>(self.superblock.rev_level)?;
-        file.write_u16::<LittleEndian>(self.superblock.def_resuid)?;
-        file.write_u16::<LittleEndian>(self.superblock.def_resgid)?;
+    /// Write a whole metadata block.
+    fn write_metadata_block(&mut self, block_num: u64, data: &[u8]) -> Result<(), Ext4Error> {
+        self.write_metadata(block_num * self.superblock.block_size() as u64, data)
+    }
+
+    /// Add a block to the running transaction, with its current contents.
+    ///
+    /// Every transaction holds the primary superblock and group descriptor blocks, which it
+    /// rewrites when it commits. A transaction that would outgrow the journal fails with
+    /// `NoSpace`, since committing part of an operation could leave it half done.
+    fn join_transaction(&mut self, block_num: u64) -> Result<(), Ext4Error> {
+        if self.overlay.lock().contains_key(&block_num) {
+            return Ok(());
+        }
+
+        if self.overlay.lock().is_empty() {
+            let block_size = self.superblock.block_size() as u64;
+            let first_desc_block = self.superblock.first_data_block as u64 + 1;
+            let desc_blocks = (self.block_groups.len() as u64
+                * self.superblock.group_desc_size() as u64)
+                .div_ceil(block_size);
+            self.cache_block(SUPERBLOCK_OFFSET / block_size)?;
+            for block in first_desc_block..first_desc_block + desc_blocks {
+                self.cache_block(block)?;
+            }
+            if self.overlay.lock().contains_key(&block_num) {
+                return Ok(());
+            }
+        }
+
+        let limit = self
+            .journal
+            .as_ref()
+            .map_or(usize::MAX, |journal| journal.max_transaction_blocks());
+        if self.overlay.lock().len() >= limit {
+            return Err(Ext4Error::NoSpace(format!(
+                "Transaction needs more than the {} blocks the journal can hold",
+                limit
+            )));
+        }
+
+        self.cache_block(block_num)
+    }
+
+    /// Read a block from disk into the in-memory copies, unless it is already there.
+    fn cache_block(&mut self, block_num: u64) -> Result<(), Ext4Error> {
+        if self.overlay.lock().contains_key(&block_num) {
+            return Ok(());
+        }
 
+        let block_size = self.superblock.block_size() as u64;
+        let mut data = vec![0u8; block_size as usize];
+        let mut file_clone = self.file.try_clone()?;
+        file_clone.seek(SeekFrom::Start(block_num * block_size))?;
+        file_clone.read_exact(&mut data)?;
+        self.overlay.lock().insert(block_num, data);
         Ok(())
     }
 }
+
+/// Split a path into its parent directory and last component.
+fn split_path(path: &str) -> (&str, &str) {
+    match path.rfind('/') {
+        Some(pos) => {
+            let parent = if pos == 0 { "/" } else { &path[..pos] };
+            (parent, &path[pos + 1..])
+        }
+        None => ("/", path),
+    }
+}
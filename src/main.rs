@@ -1,4 +1,6 @@
-use rust_ext4_impl::Ext4Filesystem;
+use rust_ext4_impl::{
+    format_uuid, Ext4Filesystem, FallocateMode, JournalLocation, JournalRecovery, MountOptions,
+};
 use std::env;
 use std::fs::File;
 use std::io::{self, Read, Write};
@@ -12,14 +14,33 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         eprintln!("  ls [path]                - List directory contents");
         eprintln!("  cat <path>               - Display file contents");
         eprintln!("  write <path> <local_file> - Write file to image");
+        eprintln!("  append <path> <local_file> - Append local file to a file in the image");
         eprintln!("  mkdir <path>             - Create a new directory");
-        eprintln!("  rm <path>                - Remove file or directory");
+        eprintln!("  rm <path>                - Remove file, symlink or directory");
+        eprintln!("  mv <path> <new_path>     - Rename or move a file or directory");
+        eprintln!("  ln <path> <new_path>     - Create a hard link");
+        eprintln!("  symlink <target> <path>  - Create a symbolic link");
+        eprintln!("  readlink <path>          - Display the target of a symbolic link");
+        eprintln!("  truncate <path> <size>   - Shrink or grow a file to <size> bytes");
+        eprintln!("  fallocate <path> <off> <len> [-k] [-p|-z] - Allocate, punch or zero bytes");
         eprintln!("  info                     - Display filesystem information");
+        eprintln!("  journal [-b <block>]     - List journal transactions, dumping copies of <block>");
         return Ok(());
     }
 
     let image_path = &args[1];
-    let mut fs = Ext4Filesystem::mount(image_path)?;
+
+    // Inspecting the journal must not replay it
+    let recovery = if args.get(2).map(String::as_str) == Some("journal") {
+        JournalRecovery::Skip
+    } else {
+        JournalRecovery::Replay
+    };
+    let options = MountOptions {
+        recovery,
+        ..Default::default()
+    };
+    let mut fs = Ext4Filesystem::mount_with_options(image_path, options)?;
 
     if args.len() < 3 {
         // Default to 'info' command
@@ -52,6 +73,14 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             write_file(&mut fs, target_path, local_file_path)?;
             fs.sync()?;
         }
+        "append" => {
+            if args.len() < 5 {
+                eprintln!("Error: 'append' command requires target path and local file path");
+                return Ok(());
+            }
+            append_file(&mut fs, &args[3], &args[4])?;
+            fs.sync()?;
+        }
         "mkdir" => {
             if args.len() < 4 {
                 eprintln!("Error: 'mkdir' command requires a directory path");
@@ -71,9 +100,98 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             remove_path(&mut fs, path, force)?;
             fs.sync()?;
         }
+        "mv" => {
+            if args.len() < 5 {
+                eprintln!("Error: 'mv' command requires an existing path and a new path");
+                return Ok(());
+            }
+            fs.rename(&args[3], &args[4])?;
+            fs.sync()?;
+        }
+        "ln" => {
+            if args.len() < 5 {
+                eprintln!("Error: 'ln' command requires an existing path and a new path");
+                return Ok(());
+            }
+            fs.link(&args[3], &args[4])?;
+            fs.sync()?;
+        }
+        "symlink" => {
+            if args.len() < 5 {
+                eprintln!("Error: 'symlink' command requires a target and a link path");
+                return Ok(());
+            }
+            fs.symlink(&args[3], &args[4])?;
+            fs.sync()?;
+        }
+        "readlink" => {
+            if args.len() < 4 {
+                eprintln!("Error: 'readlink' command requires a path");
+                return Ok(());
+            }
+            println!("{}", fs.readlink(&args[3])?);
+        }
         "info" => {
             print_filesystem_info(&fs);
         }
+        "truncate" => {
+            let size = match args.get(4).and_then(|size| size.parse::<u64>().ok()) {
+                Some(size) => size,
+                None => {
+                    eprintln!("Error: 'truncate' command requires a path and a size in bytes");
+                    return Ok(());
+                }
+            };
+            fs.truncate(&args[3], size)?;
+            fs.sync()?;
+        }
+        "fallocate" => {
+            let offset = args.get(4).and_then(|offset| offset.parse::<u64>().ok());
+            let len = args.get(5).and_then(|len| len.parse::<u64>().ok());
+            let (offset, len) = match offset.zip(len) {
+                Some(range) => range,
+                None => {
+                    eprintln!("Error: 'fallocate' command requires a path, an offset and a length");
+                    return Ok(());
+                }
+            };
+            let options = &args[6..];
+            let known = ["-k", "-p", "-z"];
+            if let Some(option) = options.iter().find(|o| !known.contains(&o.as_str())) {
+                eprintln!("Unknown option: {}", option);
+                return Ok(());
+            }
+            let has = |option: &str| options.iter().any(|o| o == option);
+            let keep_size = has("-k");
+            let mode = match (has("-p"), has("-z")) {
+                (false, false) => FallocateMode::Allocate { keep_size },
+                (true, false) => FallocateMode::PunchHole,
+                (false, true) => FallocateMode::ZeroRange { keep_size },
+                (true, true) => {
+                    eprintln!("Error: '-p' and '-z' cannot be combined");
+                    return Ok(());
+                }
+            };
+            fs.fallocate(&args[3], mode, offset, len)?;
+            fs.sync()?;
+        }
+        "journal" => {
+            let dump_block = match args.get(3).map(String::as_str) {
+                Some("-b") => match args.get(4).and_then(|block| block.parse::<u64>().ok()) {
+                    Some(block) => Some(block),
+                    None => {
+                        eprintln!("Error: '-b' requires a block number");
+                        return Ok(());
+                    }
+                },
+                Some(option) => {
+                    eprintln!("Unknown option: {}", option);
+                    return Ok(());
+                }
+                None => None,
+            };
+            dump_journal(&fs, dump_block)?;
+        }
         _ => {
             eprintln!("Unknown command: {}", command);
         }
@@ -87,18 +205,156 @@ fn print_filesystem_info(fs: &Ext4Filesystem) {
 
     println!("Ext4 Filesystem Information:");
     println!("---------------------------");
+    println!("Volume name:       {}", sb.label());
+    println!("UUID:              {}", sb.uuid_string());
+    println!("Features:          {}", sb.feature_names().join(" "));
+    println!("Mount mode:        {}", if fs.is_read_only() { "read-only" } else { "read-write" });
     println!("Inodes count:      {}", sb.inodes_count);
-    println!("Blocks count:      {}", sb.blocks_count);
-    println!("Free blocks count: {}", sb.free_blocks_count);
+    println!("Blocks count:      {}", sb.blocks_count_64());
+    println!("Free blocks count: {}", sb.free_blocks_count_64());
     println!("Free inodes count: {}", sb.free_inodes_count);
     println!("Block size:        {} bytes", sb.block_size());
-    println!("Inode size:        256 bytes");
+    println!("Inode size:        {} bytes", sb.inode_record_size());
     println!("Blocks per group:  {}", sb.blocks_per_group);
     println!("Inodes per group:  {}", sb.inodes_per_group);
     println!("Block groups:      {}", sb.block_groups_count());
+    if let Some(journal) = fs.journal() {
+        let jsb = &journal.superblock;
+        let location = match &journal.location {
+            JournalLocation::Inode(inode_num) => format!("inode {}", inode_num),
+            JournalLocation::External(uuid) => format!("external device {}", format_uuid(uuid)),
+        };
+        println!("Journal:           {}", location);
+        println!("Journal version:   {}", jsb.block_type - 2);
+        println!("Journal features:  {}", jsb.feature_names().join(" "));
+        println!("Journal size:      {} blocks", jsb.maxlen);
+        println!("Journal sequence:  {}", jsb.sequence_id);
+        println!("Journal start:     {}", jsb.start);
+    }
     println!("---------------------------");
 }
 
+/// List the transactions in the journal, like `debugfs logdump`
+fn dump_journal(
+    fs: &Ext4Filesystem,
+    dump_block: Option<u64>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let journal = match fs.journal() {
+        Some(journal) => journal,
+        None => return Err("the filesystem has no journal".into()),
+    };
+    let jsb = &journal.superblock;
+
+    if !jsb.needs_recovery() {
+        println!("Journal is empty, next transaction {}", jsb.sequence_id);
+        return Ok(());
+    }
+    println!(
+        "Journal starts at block {}, transaction {}",
+        jsb.start, jsb.sequence_id
+    );
+
+    let transactions = fs.journal_transactions()?;
+    for transaction in &transactions {
+        if transaction.committed {
+            println!(
+                "Transaction {} at block {}, committed {}",
+                transaction.sequence,
+                transaction.start_block,
+                format_timestamp(transaction.commit_sec, transaction.commit_nsec)
+            );
+        } else {
+            println!(
+                "Transaction {} at block {}, not committed",
+                transaction.sequence, transaction.start_block
+            );
+        }
+
+        for logged in &transaction.blocks {
+            let checksum = match logged.checksum_valid {
+                Some(true) => ", checksum ok",
+                Some(false) => ", checksum BAD",
+                None => "",
+            };
+            println!(
+                "  FS block {} logged at journal block {} (flags {:#x}{})",
+                logged.home_block, logged.log_block, logged.flags, checksum
+            );
+
+            if dump_block == Some(logged.home_block) {
+                let data = fs.read_logged_block(logged)?;
+                print_hex_dump(&data);
+            }
+        }
+
+        for block in &transaction.revoked {
+            let marker = if dump_block == Some(*block) { " <--" } else { "" };
+            println!("  FS block {} revoked{}", block, marker);
+        }
+    }
+
+    let end = match transactions.last() {
+        Some(transaction) if transaction.committed => transaction.sequence.wrapping_add(1),
+        Some(transaction) => transaction.sequence,
+        None => jsb.sequence_id,
+    };
+    println!("End of journal, next transaction {}", end);
+    Ok(())
+}
+
+/// Print a block as a hex dump, collapsing runs of identical lines like `od`
+fn print_hex_dump(data: &[u8]) {
+    let mut previous: Option<&[u8]> = None;
+    let mut skipping = false;
+    for (i, line) in data.chunks(16).enumerate() {
+        if previous == Some(line) {
+            if !skipping {
+                println!("    *");
+                skipping = true;
+            }
+            continue;
+        }
+        previous = Some(line);
+        skipping = false;
+
+        let hex: Vec<String> = line.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let text: String = line
+            .iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        println!("    {:06x}  {:<47}  {}", i * 16, hex.join(" "), text);
+    }
+    println!("    {:06x}", data.len());
+}
+
+/// Format seconds and nanoseconds since the epoch as a UTC date and time
+fn format_timestamp(sec: u64, nsec: u32) -> String {
+    let days = (sec / 86400) as i64;
+    let secs_of_day = sec % 86400;
+
+    // Convert days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:09} UTC",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        nsec
+    )
+}
+
 fn get_file_type_str(file_type: u8) -> &'static str {
     match file_type {
         0 => "未知",
@@ -212,6 +468,28 @@ fn cat_file(fs: &mut Ext4Filesystem, path: &str) -> Result<(), Box<dyn std::erro
     Ok(())
 }
 
+/// Append a local file to a file in the ext4 image, a chunk at a time
+fn append_file(
+    fs: &mut Ext4Filesystem,
+    target_path: &str,
+    local_file_path: &str,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let inode_num = fs.find_by_path(target_path)?;
+    let mut offset = fs.read_inode(inode_num)?.get_size();
+
+    let mut local_file = File::open(local_file_path)?;
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let len = local_file.read(&mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        offset += fs.write_at(inode_num, offset, &buffer[..len])? as u64;
+    }
+
+    Ok(())
+}
+
 /// Write a local file to the ext4 image
 fn write_file(
     fs: &mut Ext4Filesystem,
@@ -264,14 +542,18 @@ fn remove_path(
     path: &str,
     force: bool,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    // Find the inode for the path
-    let inode_num = fs.find_by_path(path)?;
+    // Find the inode for the path, without following a symlink at the end
+    let inode_num = fs.resolve_path(path, false)?;
     let inode = fs.read_inode(inode_num)?;
 
     if inode.is_file() {
         println!("Removing file: '{}'", path);
         fs.remove_file(path)?;
         println!("File removed successfully");
+    } else if inode.is_symlink() {
+        println!("Removing symlink: '{}'", path);
+        fs.remove_file(path)?;
+        println!("Symlink removed successfully");
     } else if inode.is_directory() {
         println!("Removing directory: '{}'", path);
 
@@ -285,11 +567,6 @@ fn remove_path(
     Ok(())
 }
 
-// Keep the original remove_file function for backward compatibility
-fn remove_file(fs: &mut Ext4Filesystem, path: &str) -> Result<(), Box<dyn std::error::Error>> {
-    remove_path(fs, path, false)
-}
-
 /// Create a new directory in the ext4 image
 fn create_directory(fs: &mut Ext4Filesystem, path: &str) -> Result<(), Box<dyn std::error::Error>> {
     // Parse the path to get parent directory and new directory name
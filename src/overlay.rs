@@ -0,0 +1,95 @@
+//! Reading a device through blocks held in memory in place of their on-disk contents.
+
+use std::collections::HashMap;
+use std::fs::File as StdFile;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Block contents held in memory, by filesystem block number.
+///
+/// These are either blocks replayed from the journal for a read-only mount, or metadata
+/// blocks modified by the running transaction that have not been written home yet.
+#[derive(Debug, Clone, Default)]
+pub struct BlockOverlay {
+    blocks: Arc<Mutex<HashMap<u64, Vec<u8>>>>,
+}
+
+impl BlockOverlay {
+    /// Create an overlay holding `blocks`.
+    pub fn new(blocks: HashMap<u64, Vec<u8>>) -> Self {
+        BlockOverlay {
+            blocks: Arc::new(Mutex::new(blocks)),
+        }
+    }
+
+    /// Lock the overlay for access to its blocks.
+    pub fn lock(&self) -> MutexGuard<'_, HashMap<u64, Vec<u8>>> {
+        // The map is never left half-updated, so a poisoned lock is still usable
+        self.blocks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// A reader over the filesystem image that sees the blocks of an overlay in place of the
+/// stale copies on disk.
+#[derive(Debug)]
+pub struct OverlayReader {
+    /// The underlying image.
+    file: StdFile,
+    /// Blocks taking precedence over the disk contents.
+    overlay: BlockOverlay,
+    /// The filesystem block size.
+    block_size: u64,
+    /// The current position.
+    position: u64,
+}
+
+impl OverlayReader {
+    /// Create a reader over `file`, with the blocks of `overlay` taking precedence.
+    pub fn new(file: StdFile, overlay: BlockOverlay, block_size: u32) -> Self {
+        OverlayReader {
+            file,
+            overlay,
+            block_size: block_size as u64,
+            position: 0,
+        }
+    }
+}
+
+impl Read for OverlayReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Never cross a block boundary in one call, so each block comes from a single source
+        let block = self.position / self.block_size;
+        let in_block = (self.position % self.block_size) as usize;
+        let len = std::cmp::min(buf.len(), self.block_size as usize - in_block);
+
+        let cached = match self.overlay.lock().get(&block) {
+            Some(data) => {
+                buf[..len].copy_from_slice(&data[in_block..in_block + len]);
+                true
+            }
+            None => false,
+        };
+        let read = if cached {
+            len
+        } else {
+            self.file.seek(SeekFrom::Start(self.position))?;
+            self.file.read(&mut buf[..len])?
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for OverlayReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(_) => self.file.seek(pos)?,
+            SeekFrom::Current(delta) => self
+                .position
+                .checked_add_signed(delta)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?,
+        };
+        Ok(self.position)
+    }
+}
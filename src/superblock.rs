@@ -1,12 +1,253 @@
 //! The superblock of an ext4 filesystem.
 
-use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{Read, Seek, SeekFrom};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::io::{Read, Seek, SeekFrom, Write};
+use crate::block_group::{EXT4_MIN_DESC_SIZE, EXT4_MIN_DESC_SIZE_64BIT};
+use crate::checksum;
 use crate::error::Ext4Error;
 
 /// The magic number of an ext4 filesystem.
 const EXT4_MAGIC: u16 = 0xEF53;
 
+/// The size of the on-disk superblock in bytes.
+pub const SUPERBLOCK_SIZE: usize = 1024;
+
+/// The byte offset of the primary superblock.
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+
+/// The size of an inode in revision 0 filesystems, and the smallest inode size.
+pub const EXT4_GOOD_OLD_INODE_SIZE: u16 = 128;
+
+/// Directory hashes treat names as unsigned chars (the filesystem was made where `char` is unsigned).
+pub const EXT2_FLAGS_UNSIGNED_HASH: u32 = 0x0002;
+
+/// Compatible features: an implementation that does not know them may still read and write.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompatFeature {
+    /// Directory preallocation.
+    DirPrealloc = 0x1,
+    /// AFS server inodes exist.
+    ImagicInodes = 0x2,
+    /// The filesystem has a journal.
+    HasJournal = 0x4,
+    /// Extended attributes are supported.
+    ExtAttr = 0x8,
+    /// Reserved GDT blocks for filesystem expansion.
+    ResizeInode = 0x10,
+    /// Directories use hashed b-tree indexes.
+    DirIndex = 0x20,
+    /// Lazy block group initialization (legacy).
+    LazyBg = 0x40,
+    /// Exclude bitmap (unused).
+    ExcludeBitmap = 0x100,
+    /// Backup superblocks only in the groups listed in `backup_bgs`.
+    SparseSuper2 = 0x200,
+    /// Fast commits are enabled in the journal.
+    FastCommit = 0x400,
+    /// Inode numbers stay stable across shrinking.
+    StableInodes = 0x800,
+    /// Orphan file is present.
+    OrphanFile = 0x1000,
+}
+
+/// Incompatible features: an implementation that does not know them must not mount.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IncompatFeature {
+    /// Compression (never implemented).
+    Compression = 0x1,
+    /// Directory entries record the file type.
+    Filetype = 0x2,
+    /// The journal needs recovery.
+    Recover = 0x4,
+    /// The filesystem is an external journal device.
+    JournalDev = 0x8,
+    /// Meta block groups.
+    MetaBg = 0x10,
+    /// Files use extents.
+    Extents = 0x40,
+    /// Block numbers are 64 bits wide.
+    SixtyFourBit = 0x80,
+    /// Multiple mount protection.
+    Mmp = 0x100,
+    /// Flexible block groups.
+    FlexBg = 0x200,
+    /// Large extended attribute values live in inodes.
+    EaInode = 0x400,
+    /// Data in directory entries.
+    DirData = 0x1000,
+    /// Metadata checksum seed is stored in the superblock.
+    CsumSeed = 0x2000,
+    /// Directories may exceed 2GB and use a three-level htree.
+    LargeDir = 0x4000,
+    /// Small files and directories store data inside the inode.
+    InlineData = 0x8000,
+    /// Encrypted inodes are present.
+    Encrypt = 0x10000,
+    /// Directories may be case-insensitive.
+    Casefold = 0x20000,
+}
+
+/// Read-only compatible features: an implementation that does not know them may only read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoCompatFeature {
+    /// Backup superblocks and group descriptors only in some groups.
+    SparseSuper = 0x1,
+    /// Files may be larger than 2GB.
+    LargeFile = 0x2,
+    /// Directories are stored as b-trees (never used).
+    BtreeDir = 0x4,
+    /// Block counts may be in filesystem blocks rather than 512-byte sectors.
+    HugeFile = 0x8,
+    /// Group descriptors have checksums.
+    GdtCsum = 0x10,
+    /// Directories may have more than 65000 subdirectories.
+    DirNlink = 0x20,
+    /// Inodes have room for extended fields.
+    ExtraIsize = 0x40,
+    /// The filesystem has a snapshot.
+    HasSnapshot = 0x80,
+    /// Disk quotas.
+    Quota = 0x100,
+    /// Allocation is done in clusters.
+    Bigalloc = 0x200,
+    /// Metadata is protected by checksums.
+    MetadataCsum = 0x400,
+    /// Replicas (never implemented).
+    Replica = 0x800,
+    /// The filesystem may only be mounted read-only.
+    ReadOnly = 0x1000,
+    /// Project quotas.
+    Project = 0x2000,
+    /// Verity inodes may be present.
+    Verity = 0x8000,
+    /// The orphan file may be non-empty.
+    OrphanPresent = 0x10000,
+}
+
+impl CompatFeature {
+    /// Every known compatible feature.
+    pub const ALL: [CompatFeature; 12] = [
+        CompatFeature::DirPrealloc,
+        CompatFeature::ImagicInodes,
+        CompatFeature::HasJournal,
+        CompatFeature::ExtAttr,
+        CompatFeature::ResizeInode,
+        CompatFeature::DirIndex,
+        CompatFeature::LazyBg,
+        CompatFeature::ExcludeBitmap,
+        CompatFeature::SparseSuper2,
+        CompatFeature::FastCommit,
+        CompatFeature::StableInodes,
+        CompatFeature::OrphanFile,
+    ];
+
+    /// Get the feature name used by e2fsprogs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CompatFeature::DirPrealloc => "dir_prealloc",
+            CompatFeature::ImagicInodes => "imagic_inodes",
+            CompatFeature::HasJournal => "has_journal",
+            CompatFeature::ExtAttr => "ext_attr",
+            CompatFeature::ResizeInode => "resize_inode",
+            CompatFeature::DirIndex => "dir_index",
+            CompatFeature::LazyBg => "lazy_bg",
+            CompatFeature::ExcludeBitmap => "snapshot_bitmap",
+            CompatFeature::SparseSuper2 => "sparse_super2",
+            CompatFeature::FastCommit => "fast_commit",
+            CompatFeature::StableInodes => "stable_inodes",
+            CompatFeature::OrphanFile => "orphan_file",
+        }
+    }
+}
+
+impl IncompatFeature {
+    /// Every known incompatible feature.
+    pub const ALL: [IncompatFeature; 16] = [
+        IncompatFeature::Compression,
+        IncompatFeature::Filetype,
+        IncompatFeature::Recover,
+        IncompatFeature::JournalDev,
+        IncompatFeature::MetaBg,
+        IncompatFeature::Extents,
+        IncompatFeature::SixtyFourBit,
+        IncompatFeature::Mmp,
+        IncompatFeature::FlexBg,
+        IncompatFeature::EaInode,
+        IncompatFeature::DirData,
+        IncompatFeature::CsumSeed,
+        IncompatFeature::LargeDir,
+        IncompatFeature::InlineData,
+        IncompatFeature::Encrypt,
+        IncompatFeature::Casefold,
+    ];
+
+    /// Get the feature name used by e2fsprogs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            IncompatFeature::Compression => "compression",
+            IncompatFeature::Filetype => "filetype",
+            IncompatFeature::Recover => "needs_recovery",
+            IncompatFeature::JournalDev => "journal_dev",
+            IncompatFeature::MetaBg => "meta_bg",
+            IncompatFeature::Extents => "extent",
+            IncompatFeature::SixtyFourBit => "64bit",
+            IncompatFeature::Mmp => "mmp",
+            IncompatFeature::FlexBg => "flex_bg",
+            IncompatFeature::EaInode => "ea_inode",
+            IncompatFeature::DirData => "dirdata",
+            IncompatFeature::CsumSeed => "metadata_csum_seed",
+            IncompatFeature::LargeDir => "large_dir",
+            IncompatFeature::InlineData => "inline_data",
+            IncompatFeature::Encrypt => "encrypt",
+            IncompatFeature::Casefold => "casefold",
+        }
+    }
+}
+
+impl RoCompatFeature {
+    /// Every known read-only compatible feature.
+    pub const ALL: [RoCompatFeature; 16] = [
+        RoCompatFeature::SparseSuper,
+        RoCompatFeature::LargeFile,
+        RoCompatFeature::BtreeDir,
+        RoCompatFeature::HugeFile,
+        RoCompatFeature::GdtCsum,
+        RoCompatFeature::DirNlink,
+        RoCompatFeature::ExtraIsize,
+        RoCompatFeature::HasSnapshot,
+        RoCompatFeature::Quota,
+        RoCompatFeature::Bigalloc,
+        RoCompatFeature::MetadataCsum,
+        RoCompatFeature::Replica,
+        RoCompatFeature::ReadOnly,
+        RoCompatFeature::Project,
+        RoCompatFeature::Verity,
+        RoCompatFeature::OrphanPresent,
+    ];
+
+    /// Get the feature name used by e2fsprogs.
+    pub fn name(&self) -> &'static str {
+        match self {
+            RoCompatFeature::SparseSuper => "sparse_super",
+            RoCompatFeature::LargeFile => "large_file",
+            RoCompatFeature::BtreeDir => "btree_dir",
+            RoCompatFeature::HugeFile => "huge_file",
+            RoCompatFeature::GdtCsum => "uninit_bg",
+            RoCompatFeature::DirNlink => "dir_nlink",
+            RoCompatFeature::ExtraIsize => "extra_isize",
+            RoCompatFeature::HasSnapshot => "snapshot",
+            RoCompatFeature::Quota => "quota",
+            RoCompatFeature::Bigalloc => "bigalloc",
+            RoCompatFeature::MetadataCsum => "metadata_csum",
+            RoCompatFeature::Replica => "replica",
+            RoCompatFeature::ReadOnly => "read-only",
+            RoCompatFeature::Project => "project",
+            RoCompatFeature::Verity => "verity",
+            RoCompatFeature::OrphanPresent => "orphan_present",
+        }
+    }
+}
+
 /// The superblock of an ext4 filesystem.
 #[derive(Debug, Clone)]
 pub struct Superblock {
@@ -60,14 +301,231 @@ pub struct Superblock {
     pub def_resuid: u16,
     /// Default gid for reserved blocks.
     pub def_resgid: u16,
-    // ... more fields would be added here for a complete implementation
+    /// First non-reserved inode.
+    pub first_ino: u32,
+    /// Size of each inode structure in bytes.
+    pub inode_size: u16,
+    /// Block group number of this superblock.
+    pub block_group_nr: u16,
+    /// Compatible feature set flags.
+    pub feature_compat: u32,
+    /// Incompatible feature set flags.
+    pub feature_incompat: u32,
+    /// Read-only compatible feature set flags.
+    pub feature_ro_compat: u32,
+    /// 128-bit UUID of the volume.
+    pub uuid: [u8; 16],
+    /// Volume label.
+    pub volume_name: [u8; 16],
+    /// Directory where the filesystem was last mounted.
+    pub last_mounted: [u8; 64],
+    /// Compression algorithm usage bitmap.
+    pub algorithm_usage_bitmap: u32,
+    /// Number of blocks to preallocate for files.
+    pub prealloc_blocks: u8,
+    /// Number of blocks to preallocate for directories.
+    pub prealloc_dir_blocks: u8,
+    /// Number of reserved GDT entries for future filesystem expansion.
+    pub reserved_gdt_blocks: u16,
+    /// UUID of the journal superblock.
+    pub journal_uuid: [u8; 16],
+    /// Inode number of the journal file.
+    pub journal_inum: u32,
+    /// Device number of the journal file (external journals).
+    pub journal_dev: u32,
+    /// Start of the list of orphaned inodes to delete.
+    pub last_orphan: u32,
+    /// HTREE hash seed.
+    pub hash_seed: [u32; 4],
+    /// Default hash algorithm for directory hashes.
+    pub def_hash_version: u8,
+    /// Whether `jnl_blocks` holds a backup of the journal inode's `i_block`.
+    pub jnl_backup_type: u8,
+    /// Size of group descriptors in bytes (64bit mode).
+    pub desc_size: u16,
+    /// Default mount options.
+    pub default_mount_opts: u32,
+    /// First metablock block group.
+    pub first_meta_bg: u32,
+    /// When the filesystem was created (in UNIX time).
+    pub mkfs_time: u32,
+    /// Backup copy of the journal inode's `i_block` and size.
+    pub jnl_blocks: [u32; 17],
+    /// High 32 bits of the block count.
+    pub blocks_count_hi: u32,
+    /// High 32 bits of the reserved block count.
+    pub r_blocks_count_hi: u32,
+    /// High 32 bits of the free block count.
+    pub free_blocks_count_hi: u32,
+    /// All inodes have at least this many extra bytes.
+    pub min_extra_isize: u16,
+    /// New inodes should reserve this many extra bytes.
+    pub want_extra_isize: u16,
+    /// Miscellaneous flags (e.g. signed directory hashes).
+    pub flags: u32,
+    /// RAID stride.
+    pub raid_stride: u16,
+    /// Seconds to wait in multi-mount prevention checking.
+    pub mmp_interval: u16,
+    /// Block for multi-mount protection data.
+    pub mmp_block: u64,
+    /// RAID stripe width.
+    pub raid_stripe_width: u32,
+    /// Size of a flexible block group is 2^log_groups_per_flex.
+    pub log_groups_per_flex: u8,
+    /// Metadata checksum algorithm type (1 = crc32c).
+    pub checksum_type: u8,
+    /// Encryption version level.
+    pub encryption_level: u8,
+    /// Padding.
+    pub reserved_pad: u8,
+    /// Number of KiB written to this filesystem over its lifetime.
+    pub kbytes_written: u64,
+    /// Inode number of the active snapshot.
+    pub snapshot_inum: u32,
+    /// Sequential ID of the active snapshot.
+    pub snapshot_id: u32,
+    /// Number of blocks reserved for the active snapshot's future use.
+    pub snapshot_r_blocks_count: u64,
+    /// Inode number of the head of the on-disk snapshot list.
+    pub snapshot_list: u32,
+    /// Number of errors seen.
+    pub error_count: u32,
+    /// First time an error happened (in UNIX time).
+    pub first_error_time: u32,
+    /// Inode involved in the first error.
+    pub first_error_ino: u32,
+    /// Block involved in the first error.
+    pub first_error_block: u64,
+    /// Name of the function where the first error happened.
+    pub first_error_func: [u8; 32],
+    /// Line number where the first error happened.
+    pub first_error_line: u32,
+    /// Most recent time an error happened (in UNIX time).
+    pub last_error_time: u32,
+    /// Inode involved in the most recent error.
+    pub last_error_ino: u32,
+    /// Line number where the most recent error happened.
+    pub last_error_line: u32,
+    /// Block involved in the most recent error.
+    pub last_error_block: u64,
+    /// Name of the function where the most recent error happened.
+    pub last_error_func: [u8; 32],
+    /// Mount options as a C string.
+    pub mount_opts: [u8; 64],
+    /// Inode number of the user quota file.
+    pub usr_quota_inum: u32,
+    /// Inode number of the group quota file.
+    pub grp_quota_inum: u32,
+    /// Overhead blocks/clusters in the filesystem.
+    pub overhead_clusters: u32,
+    /// Block groups containing superblock backups (sparse_super2).
+    pub backup_bgs: [u32; 2],
+    /// Encryption algorithms in use.
+    pub encrypt_algos: [u8; 4],
+    /// Salt for the string2key algorithm for encryption.
+    pub encrypt_pw_salt: [u8; 16],
+    /// Inode number of lost+found.
+    pub lpf_ino: u32,
+    /// Inode number of the project quota file.
+    pub prj_quota_inum: u32,
+    /// Checksum seed used for metadata_csum calculations.
+    pub checksum_seed: u32,
+    /// Upper 8 bits of `wtime`.
+    pub wtime_hi: u8,
+    /// Upper 8 bits of `mtime`.
+    pub mtime_hi: u8,
+    /// Upper 8 bits of `mkfs_time`.
+    pub mkfs_time_hi: u8,
+    /// Upper 8 bits of `lastcheck`.
+    pub lastcheck_hi: u8,
+    /// Upper 8 bits of `first_error_time`.
+    pub first_error_time_hi: u8,
+    /// Upper 8 bits of `last_error_time`.
+    pub last_error_time_hi: u8,
+    /// Error code of the first error.
+    pub first_error_errcode: u8,
+    /// Error code of the most recent error.
+    pub last_error_errcode: u8,
+    /// Filename charset encoding.
+    pub encoding: u16,
+    /// Filename charset encoding flags.
+    pub encoding_flags: u16,
+    /// Inode number of the orphan file.
+    pub orphan_file_inum: u32,
+    /// Padding to the end of the block.
+    pub reserved: [u32; 94],
+    /// Superblock checksum.
+    pub checksum: u32,
+}
+
+/// Incompatible features this crate knows how to handle.
+pub const EXT4_FEATURE_INCOMPAT_SUPP: u32 = IncompatFeature::Filetype as u32
+    | IncompatFeature::Recover as u32
+    | IncompatFeature::Extents as u32
+    | IncompatFeature::SixtyFourBit as u32
+    | IncompatFeature::FlexBg as u32
+    | IncompatFeature::CsumSeed as u32
+    | IncompatFeature::InlineData as u32;
+
+/// Read-only compatible features this crate can safely write.
+pub const EXT4_FEATURE_RO_COMPAT_SUPP: u32 = RoCompatFeature::SparseSuper as u32
+    | RoCompatFeature::LargeFile as u32
+    | RoCompatFeature::HugeFile as u32
+    | RoCompatFeature::DirNlink as u32
+    | RoCompatFeature::ExtraIsize as u32
+    | RoCompatFeature::MetadataCsum as u32;
+
+/// Name every set bit of a feature mask, using the e2fsprogs `FEATURE_<kind><bit>` form for unknown bits.
+fn feature_bit_names(
+    bits: u32,
+    kind: char,
+    name_of: impl Fn(u32) -> Option<&'static str>,
+) -> Vec<String> {
+    (0..32)
+        .map(|bit| 1u32 << bit)
+        .filter(|mask| bits & mask != 0)
+        .map(|mask| match name_of(mask) {
+            Some(name) => name.to_string(),
+            None => format!("FEATURE_{}{}", kind, mask.trailing_zeros()),
+        })
+        .collect()
+}
+
+/// Format a 16-byte UUID in its canonical textual form.
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let hex: Vec<String> = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )
+}
+
+/// Decode a NUL-padded on-disk string.
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).to_string()
 }
 
 impl Superblock {
     /// Read a superblock from a reader.
     pub fn read<R: Read + Seek>(reader: &mut R) -> Result<Self, Ext4Error> {
         // The superblock starts at offset 1024 bytes
-        reader.seek(SeekFrom::Start(1024))?;
+        reader.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+
+        let mut raw = [0u8; SUPERBLOCK_SIZE];
+        reader.read_exact(&mut raw)?;
+        Self::parse(&raw)
+    }
+
+    /// Parse a superblock from its 1024 on-disk bytes.
+    pub fn parse(raw: &[u8; SUPERBLOCK_SIZE]) -> Result<Self, Ext4Error> {
+        let mut reader = std::io::Cursor::new(&raw[..]);
+        let reader = &mut reader;
 
         let inodes_count = reader.read_u32::<LittleEndian>()?;
         let blocks_count = reader.read_u32::<LittleEndian>()?;
@@ -94,6 +552,97 @@ impl Superblock {
         let rev_level = reader.read_u32::<LittleEndian>()?;
         let def_resuid = reader.read_u16::<LittleEndian>()?;
         let def_resgid = reader.read_u16::<LittleEndian>()?;
+        let first_ino = reader.read_u32::<LittleEndian>()?;
+        let inode_size = reader.read_u16::<LittleEndian>()?;
+        let block_group_nr = reader.read_u16::<LittleEndian>()?;
+        let feature_compat = reader.read_u32::<LittleEndian>()?;
+        let feature_incompat = reader.read_u32::<LittleEndian>()?;
+        let feature_ro_compat = reader.read_u32::<LittleEndian>()?;
+        let mut uuid = [0u8; 16];
+        reader.read_exact(&mut uuid)?;
+        let mut volume_name = [0u8; 16];
+        reader.read_exact(&mut volume_name)?;
+        let mut last_mounted = [0u8; 64];
+        reader.read_exact(&mut last_mounted)?;
+        let algorithm_usage_bitmap = reader.read_u32::<LittleEndian>()?;
+        let prealloc_blocks = reader.read_u8()?;
+        let prealloc_dir_blocks = reader.read_u8()?;
+        let reserved_gdt_blocks = reader.read_u16::<LittleEndian>()?;
+        let mut journal_uuid = [0u8; 16];
+        reader.read_exact(&mut journal_uuid)?;
+        let journal_inum = reader.read_u32::<LittleEndian>()?;
+        let journal_dev = reader.read_u32::<LittleEndian>()?;
+        let last_orphan = reader.read_u32::<LittleEndian>()?;
+        let mut hash_seed = [0u32; 4];
+        reader.read_u32_into::<LittleEndian>(&mut hash_seed)?;
+        let def_hash_version = reader.read_u8()?;
+        let jnl_backup_type = reader.read_u8()?;
+        let desc_size = reader.read_u16::<LittleEndian>()?;
+        let default_mount_opts = reader.read_u32::<LittleEndian>()?;
+        let first_meta_bg = reader.read_u32::<LittleEndian>()?;
+        let mkfs_time = reader.read_u32::<LittleEndian>()?;
+        let mut jnl_blocks = [0u32; 17];
+        reader.read_u32_into::<LittleEndian>(&mut jnl_blocks)?;
+        let blocks_count_hi = reader.read_u32::<LittleEndian>()?;
+        let r_blocks_count_hi = reader.read_u32::<LittleEndian>()?;
+        let free_blocks_count_hi = reader.read_u32::<LittleEndian>()?;
+        let min_extra_isize = reader.read_u16::<LittleEndian>()?;
+        let want_extra_isize = reader.read_u16::<LittleEndian>()?;
+        let flags = reader.read_u32::<LittleEndian>()?;
+        let raid_stride = reader.read_u16::<LittleEndian>()?;
+        let mmp_interval = reader.read_u16::<LittleEndian>()?;
+        let mmp_block = reader.read_u64::<LittleEndian>()?;
+        let raid_stripe_width = reader.read_u32::<LittleEndian>()?;
+        let log_groups_per_flex = reader.read_u8()?;
+        let checksum_type = reader.read_u8()?;
+        let encryption_level = reader.read_u8()?;
+        let reserved_pad = reader.read_u8()?;
+        let kbytes_written = reader.read_u64::<LittleEndian>()?;
+        let snapshot_inum = reader.read_u32::<LittleEndian>()?;
+        let snapshot_id = reader.read_u32::<LittleEndian>()?;
+        let snapshot_r_blocks_count = reader.read_u64::<LittleEndian>()?;
+        let snapshot_list = reader.read_u32::<LittleEndian>()?;
+        let error_count = reader.read_u32::<LittleEndian>()?;
+        let first_error_time = reader.read_u32::<LittleEndian>()?;
+        let first_error_ino = reader.read_u32::<LittleEndian>()?;
+        let first_error_block = reader.read_u64::<LittleEndian>()?;
+        let mut first_error_func = [0u8; 32];
+        reader.read_exact(&mut first_error_func)?;
+        let first_error_line = reader.read_u32::<LittleEndian>()?;
+        let last_error_time = reader.read_u32::<LittleEndian>()?;
+        let last_error_ino = reader.read_u32::<LittleEndian>()?;
+        let last_error_line = reader.read_u32::<LittleEndian>()?;
+        let last_error_block = reader.read_u64::<LittleEndian>()?;
+        let mut last_error_func = [0u8; 32];
+        reader.read_exact(&mut last_error_func)?;
+        let mut mount_opts = [0u8; 64];
+        reader.read_exact(&mut mount_opts)?;
+        let usr_quota_inum = reader.read_u32::<LittleEndian>()?;
+        let grp_quota_inum = reader.read_u32::<LittleEndian>()?;
+        let overhead_clusters = reader.read_u32::<LittleEndian>()?;
+        let mut backup_bgs = [0u32; 2];
+        reader.read_u32_into::<LittleEndian>(&mut backup_bgs)?;
+        let mut encrypt_algos = [0u8; 4];
+        reader.read_exact(&mut encrypt_algos)?;
+        let mut encrypt_pw_salt = [0u8; 16];
+        reader.read_exact(&mut encrypt_pw_salt)?;
+        let lpf_ino = reader.read_u32::<LittleEndian>()?;
+        let prj_quota_inum = reader.read_u32::<LittleEndian>()?;
+        let checksum_seed = reader.read_u32::<LittleEndian>()?;
+        let wtime_hi = reader.read_u8()?;
+        let mtime_hi = reader.read_u8()?;
+        let mkfs_time_hi = reader.read_u8()?;
+        let lastcheck_hi = reader.read_u8()?;
+        let first_error_time_hi = reader.read_u8()?;
+        let last_error_time_hi = reader.read_u8()?;
+        let first_error_errcode = reader.read_u8()?;
+        let last_error_errcode = reader.read_u8()?;
+        let encoding = reader.read_u16::<LittleEndian>()?;
+        let encoding_flags = reader.read_u16::<LittleEndian>()?;
+        let orphan_file_inum = reader.read_u32::<LittleEndian>()?;
+        let mut reserved = [0u32; 94];
+        reader.read_u32_into::<LittleEndian>(&mut reserved)?;
+        let checksum = reader.read_u32::<LittleEndian>()?;
 
         // Check the magic number
         if magic != EXT4_MAGIC {
@@ -103,6 +652,31 @@ impl Superblock {
             )));
         }
 
+        // Check the inode size of dynamic revision filesystems
+        let block_size = 1024u64.checked_shl(log_block_size).unwrap_or(0);
+        if rev_level >= 1
+            && (inode_size < EXT4_GOOD_OLD_INODE_SIZE
+                || !inode_size.is_power_of_two()
+                || inode_size as u64 > block_size)
+        {
+            return Err(Ext4Error::InvalidSuperblock(format!(
+                "Invalid inode size: {}",
+                inode_size
+            )));
+        }
+
+        // Check the group descriptor size of 64bit filesystems
+        if (feature_incompat & IncompatFeature::SixtyFourBit as u32) != 0
+            && (desc_size < EXT4_MIN_DESC_SIZE_64BIT
+                || !desc_size.is_power_of_two()
+                || desc_size as usize > SUPERBLOCK_SIZE)
+        {
+            return Err(Ext4Error::InvalidSuperblock(format!(
+                "Invalid group descriptor size: {}",
+                desc_size
+            )));
+        }
+
         Ok(Superblock {
             inodes_count,
             blocks_count,
@@ -129,9 +703,375 @@ impl Superblock {
             rev_level,
             def_resuid,
             def_resgid,
+            first_ino,
+            inode_size,
+            block_group_nr,
+            feature_compat,
+            feature_incompat,
+            feature_ro_compat,
+            uuid,
+            volume_name,
+            last_mounted,
+            algorithm_usage_bitmap,
+            prealloc_blocks,
+            prealloc_dir_blocks,
+            reserved_gdt_blocks,
+            journal_uuid,
+            journal_inum,
+            journal_dev,
+            last_orphan,
+            hash_seed,
+            def_hash_version,
+            jnl_backup_type,
+            desc_size,
+            default_mount_opts,
+            first_meta_bg,
+            mkfs_time,
+            jnl_blocks,
+            blocks_count_hi,
+            r_blocks_count_hi,
+            free_blocks_count_hi,
+            min_extra_isize,
+            want_extra_isize,
+            flags,
+            raid_stride,
+            mmp_interval,
+            mmp_block,
+            raid_stripe_width,
+            log_groups_per_flex,
+            checksum_type,
+            encryption_level,
+            reserved_pad,
+            kbytes_written,
+            snapshot_inum,
+            snapshot_id,
+            snapshot_r_blocks_count,
+            snapshot_list,
+            error_count,
+            first_error_time,
+            first_error_ino,
+            first_error_block,
+            first_error_func,
+            first_error_line,
+            last_error_time,
+            last_error_ino,
+            last_error_line,
+            last_error_block,
+            last_error_func,
+            mount_opts,
+            usr_quota_inum,
+            grp_quota_inum,
+            overhead_clusters,
+            backup_bgs,
+            encrypt_algos,
+            encrypt_pw_salt,
+            lpf_ino,
+            prj_quota_inum,
+            checksum_seed,
+            wtime_hi,
+            mtime_hi,
+            mkfs_time_hi,
+            lastcheck_hi,
+            first_error_time_hi,
+            last_error_time_hi,
+            first_error_errcode,
+            last_error_errcode,
+            encoding,
+            encoding_flags,
+            orphan_file_inum,
+            reserved,
+            checksum,
         })
     }
 
+    /// Serialize the superblock into its 1024 on-disk bytes.
+    pub fn to_bytes(&self) -> Result<[u8; SUPERBLOCK_SIZE], Ext4Error> {
+        let mut raw = [0u8; SUPERBLOCK_SIZE];
+        let mut writer = std::io::Cursor::new(&mut raw[..]);
+        let writer = &mut writer;
+
+        writer.write_u32::<LittleEndian>(self.inodes_count)?;
+        writer.write_u32::<LittleEndian>(self.blocks_count)?;
+        writer.write_u32::<LittleEndian>(self.r_blocks_count)?;
+        writer.write_u32::<LittleEndian>(self.free_blocks_count)?;
+        writer.write_u32::<LittleEndian>(self.free_inodes_count)?;
+        writer.write_u32::<LittleEndian>(self.first_data_block)?;
+        writer.write_u32::<LittleEndian>(self.log_block_size)?;
+        writer.write_i32::<LittleEndian>(self.log_frag_size)?;
+        writer.write_u32::<LittleEndian>(self.blocks_per_group)?;
+        writer.write_u32::<LittleEndian>(self.frags_per_group)?;
+        writer.write_u32::<LittleEndian>(self.inodes_per_group)?;
+        writer.write_u32::<LittleEndian>(self.mtime)?;
+        writer.write_u32::<LittleEndian>(self.wtime)?;
+        writer.write_u16::<LittleEndian>(self.mnt_count)?;
+        writer.write_u16::<LittleEndian>(self.max_mnt_count)?;
+        writer.write_u16::<LittleEndian>(self.magic)?;
+        writer.write_u16::<LittleEndian>(self.state)?;
+        writer.write_u16::<LittleEndian>(self.errors)?;
+        writer.write_u16::<LittleEndian>(self.minor_rev_level)?;
+        writer.write_u32::<LittleEndian>(self.lastcheck)?;
+        writer.write_u32::<LittleEndian>(self.checkinterval)?;
+        writer.write_u32::<LittleEndian>(self.creator_os)?;
+        writer.write_u32::<LittleEndian>(self.rev_level)?;
+        writer.write_u16::<LittleEndian>(self.def_resuid)?;
+        writer.write_u16::<LittleEndian>(self.def_resgid)?;
+        writer.write_u32::<LittleEndian>(self.first_ino)?;
+        writer.write_u16::<LittleEndian>(self.inode_size)?;
+        writer.write_u16::<LittleEndian>(self.block_group_nr)?;
+        writer.write_u32::<LittleEndian>(self.feature_compat)?;
+        writer.write_u32::<LittleEndian>(self.feature_incompat)?;
+        writer.write_u32::<LittleEndian>(self.feature_ro_compat)?;
+        writer.write_all(&self.uuid)?;
+        writer.write_all(&self.volume_name)?;
+        writer.write_all(&self.last_mounted)?;
+        writer.write_u32::<LittleEndian>(self.algorithm_usage_bitmap)?;
+        writer.write_u8(self.prealloc_blocks)?;
+        writer.write_u8(self.prealloc_dir_blocks)?;
+        writer.write_u16::<LittleEndian>(self.reserved_gdt_blocks)?;
+        writer.write_all(&self.journal_uuid)?;
+        writer.write_u32::<LittleEndian>(self.journal_inum)?;
+        writer.write_u32::<LittleEndian>(self.journal_dev)?;
+        writer.write_u32::<LittleEndian>(self.last_orphan)?;
+        for &seed in &self.hash_seed {
+            writer.write_u32::<LittleEndian>(seed)?;
+        }
+        writer.write_u8(self.def_hash_version)?;
+        writer.write_u8(self.jnl_backup_type)?;
+        writer.write_u16::<LittleEndian>(self.desc_size)?;
+        writer.write_u32::<LittleEndian>(self.default_mount_opts)?;
+        writer.write_u32::<LittleEndian>(self.first_meta_bg)?;
+        writer.write_u32::<LittleEndian>(self.mkfs_time)?;
+        for &block in &self.jnl_blocks {
+            writer.write_u32::<LittleEndian>(block)?;
+        }
+        writer.write_u32::<LittleEndian>(self.blocks_count_hi)?;
+        writer.write_u32::<LittleEndian>(self.r_blocks_count_hi)?;
+        writer.write_u32::<LittleEndian>(self.free_blocks_count_hi)?;
+        writer.write_u16::<LittleEndian>(self.min_extra_isize)?;
+        writer.write_u16::<LittleEndian>(self.want_extra_isize)?;
+        writer.write_u32::<LittleEndian>(self.flags)?;
+        writer.write_u16::<LittleEndian>(self.raid_stride)?;
+        writer.write_u16::<LittleEndian>(self.mmp_interval)?;
+        writer.write_u64::<LittleEndian>(self.mmp_block)?;
+        writer.write_u32::<LittleEndian>(self.raid_stripe_width)?;
+        writer.write_u8(self.log_groups_per_flex)?;
+        writer.write_u8(self.checksum_type)?;
+        writer.write_u8(self.encryption_level)?;
+        writer.write_u8(self.reserved_pad)?;
+        writer.write_u64::<LittleEndian>(self.kbytes_written)?;
+        writer.write_u32::<LittleEndian>(self.snapshot_inum)?;
+        writer.write_u32::<LittleEndian>(self.snapshot_id)?;
+        writer.write_u64::<LittleEndian>(self.snapshot_r_blocks_count)?;
+        writer.write_u32::<LittleEndian>(self.snapshot_list)?;
+        writer.write_u32::<LittleEndian>(self.error_count)?;
+        writer.write_u32::<LittleEndian>(self.first_error_time)?;
+        writer.write_u32::<LittleEndian>(self.first_error_ino)?;
+        writer.write_u64::<LittleEndian>(self.first_error_block)?;
+        writer.write_all(&self.first_error_func)?;
+        writer.write_u32::<LittleEndian>(self.first_error_line)?;
+        writer.write_u32::<LittleEndian>(self.last_error_time)?;
+        writer.write_u32::<LittleEndian>(self.last_error_ino)?;
+        writer.write_u32::<LittleEndian>(self.last_error_line)?;
+        writer.write_u64::<LittleEndian>(self.last_error_block)?;
+        writer.write_all(&self.last_error_func)?;
+        writer.write_all(&self.mount_opts)?;
+        writer.write_u32::<LittleEndian>(self.usr_quota_inum)?;
+        writer.write_u32::<LittleEndian>(self.grp_quota_inum)?;
+        writer.write_u32::<LittleEndian>(self.overhead_clusters)?;
+        for &group in &self.backup_bgs {
+            writer.write_u32::<LittleEndian>(group)?;
+        }
+        writer.write_all(&self.encrypt_algos)?;
+        writer.write_all(&self.encrypt_pw_salt)?;
+        writer.write_u32::<LittleEndian>(self.lpf_ino)?;
+        writer.write_u32::<LittleEndian>(self.prj_quota_inum)?;
+        writer.write_u32::<LittleEndian>(self.checksum_seed)?;
+        writer.write_u8(self.wtime_hi)?;
+        writer.write_u8(self.mtime_hi)?;
+        writer.write_u8(self.mkfs_time_hi)?;
+        writer.write_u8(self.lastcheck_hi)?;
+        writer.write_u8(self.first_error_time_hi)?;
+        writer.write_u8(self.last_error_time_hi)?;
+        writer.write_u8(self.first_error_errcode)?;
+        writer.write_u8(self.last_error_errcode)?;
+        writer.write_u16::<LittleEndian>(self.encoding)?;
+        writer.write_u16::<LittleEndian>(self.encoding_flags)?;
+        writer.write_u32::<LittleEndian>(self.orphan_file_inum)?;
+        for &word in &self.reserved {
+            writer.write_u32::<LittleEndian>(word)?;
+        }
+        writer.write_u32::<LittleEndian>(self.checksum)?;
+
+        Ok(raw)
+    }
+
+    /// Write the superblock to a writer at its current position.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<(), Ext4Error> {
+        writer.write_all(&self.to_bytes()?)?;
+        Ok(())
+    }
+
+    /// Compute the checksum of the superblock as it would be written.
+    pub fn compute_checksum(&self) -> Result<u32, Ext4Error> {
+        Ok(checksum::superblock_checksum(&self.to_bytes()?))
+    }
+
+    /// Get the seed for metadata checksums, if the filesystem has them.
+    pub fn csum_seed(&self) -> Option<u32> {
+        if !self.has_ro_compat(RoCompatFeature::MetadataCsum) {
+            None
+        } else if self.has_incompat(IncompatFeature::CsumSeed) {
+            Some(self.checksum_seed)
+        } else {
+            Some(checksum::uuid_seed(&self.uuid))
+        }
+    }
+
+    /// Check if a compatible feature is enabled.
+    pub fn has_compat(&self, feature: CompatFeature) -> bool {
+        (self.feature_compat & feature as u32) != 0
+    }
+
+    /// Check if an incompatible feature is enabled.
+    pub fn has_incompat(&self, feature: IncompatFeature) -> bool {
+        (self.feature_incompat & feature as u32) != 0
+    }
+
+    /// Check if a read-only compatible feature is enabled.
+    pub fn has_ro_compat(&self, feature: RoCompatFeature) -> bool {
+        (self.feature_ro_compat & feature as u32) != 0
+    }
+
+    /// Get the enabled compatible features.
+    pub fn compat_features(&self) -> Vec<CompatFeature> {
+        CompatFeature::ALL.into_iter().filter(|&f| self.has_compat(f)).collect()
+    }
+
+    /// Get the enabled incompatible features.
+    pub fn incompat_features(&self) -> Vec<IncompatFeature> {
+        IncompatFeature::ALL.into_iter().filter(|&f| self.has_incompat(f)).collect()
+    }
+
+    /// Get the enabled read-only compatible features.
+    pub fn ro_compat_features(&self) -> Vec<RoCompatFeature> {
+        RoCompatFeature::ALL.into_iter().filter(|&f| self.has_ro_compat(f)).collect()
+    }
+
+    /// Get the names of all enabled features, including unknown bits.
+    pub fn feature_names(&self) -> Vec<String> {
+        let mut names = feature_bit_names(self.feature_compat, 'C', |bit| {
+            CompatFeature::ALL.iter().find(|&&f| f as u32 == bit).map(|f| f.name())
+        });
+        names.extend(self.incompat_names(self.feature_incompat));
+        names.extend(self.ro_compat_names(self.feature_ro_compat));
+        names
+    }
+
+    /// Get the names of enabled incompatible features this crate cannot handle.
+    ///
+    /// A filesystem using any of them must not be mounted at all.
+    pub fn unsupported_incompat_features(&self) -> Vec<String> {
+        self.incompat_names(self.feature_incompat & !EXT4_FEATURE_INCOMPAT_SUPP)
+    }
+
+    /// Get the names of enabled read-only compatible features this crate cannot write.
+    ///
+    /// A filesystem using any of them may only be mounted read-only.
+    pub fn unsupported_ro_compat_features(&self) -> Vec<String> {
+        self.ro_compat_names(self.feature_ro_compat & !EXT4_FEATURE_RO_COMPAT_SUPP)
+    }
+
+    fn incompat_names(&self, bits: u32) -> Vec<String> {
+        feature_bit_names(bits, 'I', |bit| {
+            IncompatFeature::ALL.iter().find(|&&f| f as u32 == bit).map(|f| f.name())
+        })
+    }
+
+    fn ro_compat_names(&self, bits: u32) -> Vec<String> {
+        feature_bit_names(bits, 'R', |bit| {
+            RoCompatFeature::ALL.iter().find(|&&f| f as u32 == bit).map(|f| f.name())
+        })
+    }
+
+    /// Get the volume label.
+    pub fn label(&self) -> String {
+        c_string(&self.volume_name)
+    }
+
+    /// Get the directory where the filesystem was last mounted.
+    pub fn last_mounted_path(&self) -> String {
+        c_string(&self.last_mounted)
+    }
+
+    /// Get the volume UUID in its canonical textual form.
+    pub fn uuid_string(&self) -> String {
+        format_uuid(&self.uuid)
+    }
+
+    /// Get the total number of blocks, including the high 32 bits on 64bit filesystems.
+    pub fn blocks_count_64(&self) -> u64 {
+        self.combine_hi(self.blocks_count, self.blocks_count_hi)
+    }
+
+    /// Get the number of reserved blocks, including the high 32 bits on 64bit filesystems.
+    pub fn r_blocks_count_64(&self) -> u64 {
+        self.combine_hi(self.r_blocks_count, self.r_blocks_count_hi)
+    }
+
+    /// Get the number of free blocks, including the high 32 bits on 64bit filesystems.
+    pub fn free_blocks_count_64(&self) -> u64 {
+        self.combine_hi(self.free_blocks_count, self.free_blocks_count_hi)
+    }
+
+    /// Set the number of free blocks, splitting it into the low and high words.
+    pub fn set_free_blocks_count_64(&mut self, count: u64) {
+        self.free_blocks_count = count as u32;
+        if self.has_incompat(IncompatFeature::SixtyFourBit) {
+            self.free_blocks_count_hi = (count >> 32) as u32;
+        }
+    }
+
+    fn combine_hi(&self, lo: u32, hi: u32) -> u64 {
+        if self.has_incompat(IncompatFeature::SixtyFourBit) {
+            ((hi as u64) << 32) | lo as u64
+        } else {
+            lo as u64
+        }
+    }
+
+    /// Check if a block group holds a backup of the superblock and group descriptors.
+    pub fn group_has_superblock(&self, group: u32) -> bool {
+        if group == 0 {
+            return true;
+        }
+        if self.has_compat(CompatFeature::SparseSuper2) {
+            return self.backup_bgs.contains(&group);
+        }
+        if group == 1 || !self.has_ro_compat(RoCompatFeature::SparseSuper) {
+            return true;
+        }
+        if group.is_multiple_of(2) {
+            return false;
+        }
+
+        [3u32, 5, 7].iter().any(|&base| {
+            let mut power = base;
+            while power < group {
+                power *= base;
+            }
+            power == group
+        })
+    }
+
+    /// Get the size of an on-disk inode in bytes.
+    pub fn inode_record_size(&self) -> u32 {
+        if self.rev_level == 0 {
+            EXT4_GOOD_OLD_INODE_SIZE as u32
+        } else {
+            self.inode_size as u32
+        }
+    }
+
     /// Get the block size in bytes.
     pub fn block_size(&self) -> u32 {
         1024 << self.log_block_size
@@ -148,6 +1088,17 @@ impl Superblock {
 
     /// Get the number of block groups.
     pub fn block_groups_count(&self) -> u32 {
-        (self.blocks_count + self.blocks_per_group - 1) / self.blocks_per_group
+        self.blocks_count_64()
+            .saturating_sub(self.first_data_block as u64)
+            .div_ceil(self.blocks_per_group as u64) as u32
     }
-}
\ No newline at end of file
+
+    /// Get the size of a block group descriptor in bytes.
+    pub fn group_desc_size(&self) -> u16 {
+        if self.has_incompat(IncompatFeature::SixtyFourBit) {
+            self.desc_size
+        } else {
+            EXT4_MIN_DESC_SIZE
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! Group descriptors of 32 and 64 bytes.
+
+use std::process::Command;
+
+use super::{pattern, read_path, Image};
+use crate::BlockGroup;
+
+/// Get the block bitmap, inode bitmap and inode table of each group as `dumpe2fs` reports.
+fn dumpe2fs_locations(image: &Image) -> Vec<[u64; 3]> {
+    let output = Command::new("dumpe2fs").arg(image.path()).output().unwrap();
+    let mut groups = Vec::new();
+    let mut current = [0u64; 3];
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        let line = line.trim();
+        let first_number = |prefix: &str| {
+            let rest = &line[prefix.len()..];
+            rest[..rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len())]
+                .parse::<u64>()
+                .unwrap()
+        };
+        if line.starts_with("Block bitmap at ") {
+            current[0] = first_number("Block bitmap at ");
+        } else if line.starts_with("Inode bitmap at ") {
+            current[1] = first_number("Inode bitmap at ");
+        } else if line.starts_with("Inode table at ") {
+            current[2] = first_number("Inode table at ");
+            groups.push(current);
+        }
+    }
+    groups
+}
+
+fn check_descriptors(feature: &str, desc_size: u16) {
+    let image = Image::mkfs_with("32M", &["-b", "1024", "-O", feature], |_| {});
+    let mut fs = image.mount();
+    assert_eq!(fs.superblock().desc_size, desc_size);
+
+    let expected = dumpe2fs_locations(&image);
+    assert_eq!(expected.len(), fs.block_groups().len());
+    for (group, locations) in fs.block_groups().iter().zip(&expected) {
+        let found = [group.block_bitmap, group.inode_bitmap, group.inode_table];
+        assert_eq!(&found, locations);
+    }
+
+    // Enough data to spill into later groups, whose descriptors then get updated
+    let data = pattern(12 << 20, 3);
+    fs.write_file("/", "big", &data).unwrap();
+    fs.create_directory("/", "dir").unwrap();
+    drop(fs);
+
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/big"), data);
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn handles_32_byte_descriptors() {
+    check_descriptors("^64bit", 0);
+}
+
+#[test]
+fn handles_64_byte_descriptors() {
+    check_descriptors("64bit", 64);
+}
+
+#[test]
+fn keeps_high_halves_of_64_byte_descriptors() {
+    let group = BlockGroup {
+        block_bitmap: 0x1234_0000_0010,
+        inode_bitmap: 0x1234_0000_0020,
+        inode_table: 0x1234_0000_0030,
+        free_blocks_count: 0x0001_0002,
+        free_inodes_count: 0x0003_0004,
+        used_dirs_count: 0x0005_0006,
+        itable_unused: 0x0007_0008,
+        ..BlockGroup::parse(&[0u8; 64])
+    };
+    let parsed = BlockGroup::parse(&group.to_bytes(64));
+    assert_eq!(parsed.block_bitmap, group.block_bitmap);
+    assert_eq!(parsed.inode_bitmap, group.inode_bitmap);
+    assert_eq!(parsed.inode_table, group.inode_table);
+    assert_eq!(parsed.free_blocks_count, group.free_blocks_count);
+    assert_eq!(parsed.free_inodes_count, group.free_inodes_count);
+    assert_eq!(parsed.used_dirs_count, group.used_dirs_count);
+    assert_eq!(parsed.itable_unused, group.itable_unused);
+
+    // A 32-byte descriptor only has room for the low halves
+    let parsed = BlockGroup::parse(&group.to_bytes(32));
+    assert_eq!(parsed.block_bitmap, 0x10);
+    assert_eq!(parsed.free_blocks_count, 2);
+}
@@ -0,0 +1,65 @@
+//! Reading and writing files mapped through indirect blocks.
+
+use std::fs;
+use std::io::{Seek, SeekFrom, Write};
+
+use super::{pattern, read_path, Image};
+
+/// Offsets of data in the direct, indirect, double and triple indirect ranges of a file
+/// with 1 KiB blocks.
+const OFFSETS: [u64; 4] = [0, 20 << 10, 300 << 10, 70 << 20];
+
+#[test]
+fn reads_file_through_every_level() {
+    let chunks: Vec<Vec<u8>> = (0..4).map(|i| pattern(3000, i)).collect();
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-O", "^extents,^64bit"], |source| {
+        let mut file = fs::File::create(source.join("sparse")).unwrap();
+        for (offset, chunk) in OFFSETS.iter().zip(&chunks) {
+            file.seek(SeekFrom::Start(*offset)).unwrap();
+            file.write_all(chunk).unwrap();
+        }
+    });
+
+    let mut fs = image.mount();
+    let inode_num = fs.find_by_path("/sparse").unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert!(!inode.uses_extents());
+    assert!(inode.block[14] != 0);
+
+    let data = read_path(&mut fs, "/sparse");
+    assert_eq!(data.len() as u64, OFFSETS[3] + 3000);
+    for (offset, chunk) in OFFSETS.iter().zip(&chunks) {
+        let offset = *offset as usize;
+        assert_eq!(&data[offset..offset + 3000], &chunk[..]);
+    }
+    assert!(data[3000..OFFSETS[1] as usize]
+        .iter()
+        .all(|&byte| byte == 0));
+    image.fsck();
+}
+
+#[test]
+fn writes_file_through_double_indirect_blocks() {
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-O", "^extents,^64bit"], |_| {});
+    let mut fs = image.mount();
+    let data = pattern(400 << 10, 7);
+    fs.write_file("/", "big", &data).unwrap();
+    let inode_num = fs.find_by_path("/big").unwrap();
+    fs.write_at(inode_num, OFFSETS[3], b"tail").unwrap();
+    drop(fs);
+
+    let mut fs = image.mount();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert!(inode.block[13] != 0 && inode.block[14] != 0);
+    let read = read_path(&mut fs, "/big");
+    assert_eq!(&read[..data.len()], &data[..]);
+    assert_eq!(&read[OFFSETS[3] as usize..], b"tail");
+    drop(fs);
+    image.fsck();
+
+    // Removing the file releases every level of the map
+    let mut fs = image.mount();
+    fs.remove_file("/big").unwrap();
+    drop(fs);
+    image.fsck();
+}
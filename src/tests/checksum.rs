@@ -0,0 +1,136 @@
+//! Verifying and maintaining metadata checksums.
+
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+
+use super::{pattern, read_path, Image};
+use crate::inode::EXT4_INDEX_FL;
+use crate::{checksum, htree, ChecksumPolicy, Ext4Error, Ext4Filesystem, MountOptions};
+
+#[test]
+fn keeps_checksums_valid_across_writes() {
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-O", "metadata_csum"], |_| {});
+    let mut fs = image.mount();
+    fs.create_directory("/", "dir").unwrap();
+    for i in 0..80 {
+        fs.write_file("/dir", &format!("file-{}", i), &pattern(i * 300, i as u32))
+            .unwrap();
+    }
+    for i in (0..80).step_by(3) {
+        fs.unlink(&format!("/dir/file-{}", i)).unwrap();
+    }
+    let big = pattern(600 << 10, 5);
+    fs.write_file("/", "big", &big).unwrap();
+    drop(fs);
+
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/big"), big);
+    assert_eq!(read_path(&mut fs, "/dir/file-4"), pattern(1200, 4));
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn detects_corrupt_inodes() {
+    let image = Image::mkfs_with("8M", &["-O", "metadata_csum"], |_| {});
+    let mut fs = image.mount();
+    fs.write_file("/", "file", b"contents").unwrap();
+    let inode_num = fs.find_by_path("/file").unwrap();
+    let offset = fs.inode_offset(inode_num).unwrap();
+    drop(fs);
+
+    // Flip a byte of the modification time
+    let mut file = OpenOptions::new().write(true).open(image.path()).unwrap();
+    file.seek(SeekFrom::Start(offset + 0x10)).unwrap();
+    file.write_all(&[0x5A]).unwrap();
+    drop(file);
+
+    let mut fs = image.mount();
+    assert!(matches!(
+        fs.read_inode(inode_num),
+        Err(Ext4Error::ChecksumMismatch(_))
+    ));
+    let options = MountOptions {
+        checksum_policy: ChecksumPolicy::Warn,
+        ..Default::default()
+    };
+    let mut fs = Ext4Filesystem::mount_with_options(image.path(), options).unwrap();
+    assert_eq!(read_path(&mut fs, "/file"), b"contents");
+}
+
+#[test]
+fn keeps_directory_tails() {
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-O", "metadata_csum"], |_| {});
+    let mut fs = image.mount();
+    fs.create_directory("/", "dir").unwrap();
+    fs.write_file("/", "target", b"target").unwrap();
+    for i in 0..300 {
+        fs.link(
+            "/target",
+            &format!("/dir/entry-{:03}-with-a-longer-name", i),
+        )
+        .unwrap();
+    }
+    for i in (0..300).step_by(4) {
+        fs.unlink(&format!("/dir/entry-{:03}-with-a-longer-name", i))
+            .unwrap();
+    }
+    drop(fs);
+
+    // Leaves end with a dirent tail and index blocks with a dx tail, all of which are
+    // verified as the blocks are read
+    let mut fs = image.mount();
+    let dir = fs.find_by_path("/dir").unwrap();
+    let inode = fs.read_inode(dir).unwrap();
+    assert_ne!(inode.flags & EXT4_INDEX_FL, 0);
+    let blocks = inode.get_size().div_ceil(1024) as u32;
+    for logical in 1..blocks {
+        let block_data = fs.read_dir_block(dir, &inode, logical).unwrap().unwrap();
+        if !htree::is_interior_node(&block_data) {
+            assert!(checksum::has_dirent_tail(&block_data));
+        }
+    }
+    assert!(fs.read_directory(dir).is_ok());
+    let leaf = fs.dir_block_num(&inode, blocks - 1).unwrap();
+    drop(fs);
+    image.fsck();
+
+    // Flip a byte of a name in the last leaf
+    let mut file = OpenOptions::new().write(true).open(image.path()).unwrap();
+    file.seek(SeekFrom::Start(leaf * 1024 + 20)).unwrap();
+    file.write_all(&[0x5A]).unwrap();
+    drop(file);
+
+    let mut fs = image.mount();
+    assert!(matches!(
+        fs.read_directory(dir),
+        Err(Ext4Error::ChecksumMismatch(_))
+    ));
+}
+
+#[test]
+fn adds_tails_to_converted_inline_directories() {
+    let image = Image::mkfs_with("16M", &["-O", "metadata_csum,inline_data"], |_| {});
+    let mut fs = image.mount();
+    fs.create_directory("/", "dir").unwrap();
+    fs.write_file("/", "target", b"target").unwrap();
+    let dir = fs.find_by_path("/dir").unwrap();
+    fs.link("/target", "/dir/first").unwrap();
+    assert!(fs.read_inode(dir).unwrap().has_inline_data());
+
+    // Enough names to push the directory out of its inode into a block
+    for i in 0..40 {
+        fs.link("/target", &format!("/dir/entry-{:02}", i)).unwrap();
+    }
+    let inode = fs.read_inode(dir).unwrap();
+    assert!(!inode.has_inline_data());
+    let block_data = fs.read_dir_block(dir, &inode, 0).unwrap().unwrap();
+    assert!(checksum::has_dirent_tail(&block_data));
+    drop(fs);
+    image.fsck();
+
+    let mut fs = image.mount();
+    let entries = fs.read_directory(dir).unwrap().entries;
+    assert_eq!(entries.len(), 2 + 41);
+    assert!(entries.iter().any(|entry| entry.name == "first"));
+}
@@ -0,0 +1,196 @@
+//! Editing directory blocks, growing directories and reusing the space in their blocks.
+
+use crate::directory::{DirectoryBlock, DirectoryRecord};
+
+use super::{Image, Rng};
+
+/// Name of the `i`th entry, padded so that `len` bytes of name go into each entry.
+fn entry_name(i: usize, len: usize) -> String {
+    format!("{:05}{}", i, "n".repeat(len - 5))
+}
+
+fn grows_past_twelve_blocks(features: &str) {
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-O", features], |source| {
+        std::fs::create_dir(source.join("dir")).unwrap();
+        std::fs::write(source.join("target"), b"target").unwrap();
+    });
+
+    let mut fs = image.mount();
+    for i in 0..400 {
+        fs.link("/target", &format!("/dir/{}", entry_name(i, 40)))
+            .unwrap();
+    }
+    drop(fs);
+
+    let mut fs = image.mount();
+    let dir = fs.find_by_path("/dir").unwrap();
+    let size = fs.read_inode(dir).unwrap().get_size();
+    assert!(size > 12 * 1024, "directory is only {} bytes", size);
+    let target = fs.find_by_path("/target").unwrap();
+    for i in 0..400 {
+        let path = format!("/dir/{}", entry_name(i, 40));
+        assert_eq!(fs.find_by_path(&path).unwrap(), target);
+    }
+    drop(fs);
+    image.fsck();
+
+    // Every other entry leaves a gap too small for a longer name on its own, so the
+    // blocks are compacted instead of the directory growing
+    let mut fs = image.mount();
+    for i in (0..400).step_by(2) {
+        fs.unlink(&format!("/dir/{}", entry_name(i, 40))).unwrap();
+    }
+    for i in 0..150 {
+        fs.link("/target", &format!("/dir/{}", entry_name(1000 + i, 48)))
+            .unwrap();
+    }
+    assert_eq!(fs.read_inode(dir).unwrap().get_size(), size);
+    for i in 0..150 {
+        let path = format!("/dir/{}", entry_name(1000 + i, 48));
+        assert_eq!(fs.find_by_path(&path).unwrap(), target);
+    }
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn grows_extent_mapped_directory() {
+    grows_past_twelve_blocks("^dir_index");
+}
+
+#[test]
+fn grows_block_mapped_directory() {
+    grows_past_twelve_blocks("^dir_index,^extents,^64bit");
+}
+
+/// Apply random inserts, removals and compactions to a block, checking each change
+/// against a list of the names it should hold and that the block reads back the same.
+fn edit_block_randomly(len: usize, seed: u32) {
+    let mut rng = Rng::new(seed);
+    let mut block = DirectoryBlock::new(len);
+    let mut names: Vec<Vec<u8>> = Vec::new();
+    for i in 0..2000u32 {
+        match rng.next() % 8 {
+            0..=4 => {
+                let name_len = 1 + (rng.next() % 255) as usize;
+                let mut name = format!("{}-", i).into_bytes();
+                name.resize(name_len.max(name.len()), b'n');
+                let record = DirectoryRecord::new(i + 1, &name, 1).unwrap();
+                let fits = block.free_space() >= record.rec_len;
+                assert_eq!(block.insert_or_compact(record), fits);
+                if fits {
+                    names.push(name);
+                }
+            }
+            5 | 6 if !names.is_empty() => {
+                let name = names.swap_remove((rng.next() % names.len() as u64) as usize);
+                assert!(block.remove(&name).is_some());
+            }
+            _ => block.compact(),
+        }
+
+        let mut data = vec![0u8; len];
+        block.write_to(&mut data);
+        let parsed = DirectoryBlock::parse(&data).unwrap();
+        let mut found: Vec<_> = parsed.entries().map(|record| record.name.clone()).collect();
+        let mut expected = names.clone();
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+        assert_eq!(parsed.free_space(), block.free_space());
+    }
+}
+
+#[test]
+fn edits_blocks_randomly() {
+    for (seed, len) in [1024, 4096 - 12, 65536 - 12, 65536].into_iter().enumerate() {
+        edit_block_randomly(len, seed as u32);
+    }
+}
+
+/// Link and unlink random names in a directory, checking it against the names it should
+/// hold, then check the image.
+fn edit_directory_randomly(features: &str, seed: u32) {
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-O", features], |source| {
+        std::fs::create_dir(source.join("dir")).unwrap();
+        std::fs::write(source.join("target"), b"target").unwrap();
+    });
+
+    let mut fs = image.mount();
+    let mut rng = Rng::new(seed);
+    let mut names = Vec::new();
+    for i in 0..600 {
+        if rng.next().is_multiple_of(3) && !names.is_empty() {
+            let name: String = names.swap_remove((rng.next() % names.len() as u64) as usize);
+            fs.unlink(&format!("/dir/{}", name)).unwrap();
+        } else {
+            let name_len = 6 + (rng.next() % 250) as usize;
+            let name = format!("{:05}{}", i, "n".repeat(name_len - 5));
+            fs.link("/target", &format!("/dir/{}", name)).unwrap();
+            names.push(name);
+        }
+    }
+    drop(fs);
+
+    let mut fs = image.mount();
+    let dir = fs.find_by_path("/dir").unwrap();
+    let mut found: Vec<_> = fs
+        .read_directory(dir)
+        .unwrap()
+        .entries
+        .into_iter()
+        .map(|entry| entry.name)
+        .filter(|name| name != "." && name != "..")
+        .collect();
+    found.sort();
+    names.sort();
+    assert_eq!(found, names);
+    let target = fs.find_by_path("/target").unwrap();
+    for name in &names {
+        assert_eq!(fs.lookup(dir, name).unwrap(), Some(target));
+    }
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn edits_directories_randomly() {
+    for seed in 0..3 {
+        edit_directory_randomly("^dir_index", seed);
+        edit_directory_randomly("dir_index", seed);
+    }
+}
+
+#[test]
+fn handles_64k_blocks() {
+    let image = Image::mkfs_with("64M", &["-b", "65536", "-O", "^metadata_csum"], |source| {
+        std::fs::write(source.join("target"), b"target").unwrap();
+    });
+
+    // The second block of lost+found is one empty record spanning the whole block
+    let mut fs = image.mount();
+    let lost = fs.find_by_path("/lost+found").unwrap();
+    assert_eq!(fs.read_inode(lost).unwrap().get_size(), 2 * 65536);
+    assert_eq!(fs.lookup(lost, "missing").unwrap(), None);
+
+    for i in 0..400 {
+        fs.link("/target", &format!("/lost+found/{}", entry_name(i, 200)))
+            .unwrap();
+    }
+    drop(fs);
+
+    // The names spill into the second block, and removing them all leaves it spanned by
+    // one empty record again
+    let mut fs = image.mount();
+    assert_eq!(fs.read_inode(lost).unwrap().get_size(), 2 * 65536);
+    let target = fs.find_by_path("/target").unwrap();
+    for i in 0..400 {
+        assert_eq!(fs.lookup(lost, &entry_name(i, 200)).unwrap(), Some(target));
+    }
+    for i in 0..400 {
+        fs.unlink(&format!("/lost+found/{}", entry_name(i, 200)))
+            .unwrap();
+    }
+    drop(fs);
+    image.fsck();
+}
@@ -0,0 +1,88 @@
+//! Reading files through extent trees of different shapes.
+
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+
+use super::{pattern, read_path, Image};
+use crate::extent::{ExtentHeader, EXT_INIT_MAX_LEN};
+
+/// Get the depth of the extent tree of a file.
+fn tree_depth(image: &Image, path: &str) -> u16 {
+    let mut fs = image.mount();
+    let inode_num = fs.find_by_path(path).unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert!(inode.uses_extents());
+    ExtentHeader::parse(&inode.block_bytes()).unwrap().depth
+}
+
+#[test]
+fn reads_file_from_root_extents() {
+    let data = pattern(20_000, 1);
+    let image = Image::mkfs_with("8M", &["-b", "1024"], |source| {
+        fs::write(source.join("file"), &data).unwrap();
+    });
+    assert_eq!(tree_depth(&image, "/file"), 0);
+
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/file"), data);
+    image.fsck();
+}
+
+#[test]
+fn reads_file_through_index_nodes() {
+    // Islands of data between holes need more extents than fit in the inode
+    let island = 4096;
+    let stride = 65536;
+    let mut data = vec![0u8; stride * 9 + island];
+    for i in 0..10 {
+        data[i * stride..i * stride + island].copy_from_slice(&pattern(island, i as u32 + 1));
+    }
+    let image = Image::mkfs_with("8M", &["-b", "1024"], |source| {
+        let mut file = fs::File::create(source.join("sparse")).unwrap();
+        for i in 0..10 {
+            file.seek(SeekFrom::Start((i * stride) as u64)).unwrap();
+            file.write_all(&data[i * stride..i * stride + island])
+                .unwrap();
+        }
+    });
+    assert!(tree_depth(&image, "/sparse") >= 1);
+
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/sparse"), data);
+    image.fsck();
+}
+
+#[test]
+fn reads_unwritten_extents_as_zeros() {
+    let data = pattern(8192, 2);
+    let image = Image::mkfs_with("8M", &["-b", "1024"], |source| {
+        fs::write(source.join("prealloc"), &data).unwrap();
+    });
+    image.debugfs(&["fallocate /prealloc 8 11", "sif /prealloc size 12288"]);
+
+    // Fill the preallocated blocks with junk, which must never be read back
+    let mut fs = image.mount();
+    let inode_num = fs.find_by_path("/prealloc").unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    let (extents, _) = fs.read_extent_map(&inode).unwrap();
+    let unwritten: Vec<_> = extents
+        .iter()
+        .filter(|extent| extent.is_unwritten())
+        .collect();
+    assert!(!unwritten.is_empty());
+    assert!(unwritten.iter().all(|extent| extent.len > EXT_INIT_MAX_LEN));
+    drop(fs);
+    let mut file = OpenOptions::new().write(true).open(image.path()).unwrap();
+    for extent in &unwritten {
+        file.seek(SeekFrom::Start(extent.start * 1024)).unwrap();
+        file.write_all(&vec![0xAA; extent.length() as usize * 1024])
+            .unwrap();
+    }
+    drop(file);
+
+    let mut fs = image.mount();
+    let mut expected = data;
+    expected.resize(12288, 0);
+    assert_eq!(read_path(&mut fs, "/prealloc"), expected);
+    image.fsck();
+}
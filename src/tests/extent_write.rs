@@ -0,0 +1,88 @@
+//! Writing files through extent trees and keeping their sizes and block counts.
+
+use super::{pattern, read_path, Image};
+use crate::extent::ExtentHeader;
+use crate::inode::EXT4_HUGE_FILE_FL;
+use crate::Inode;
+
+#[test]
+fn writes_file_into_index_nodes() {
+    let image = Image::mkfs_with("8M", &["-b", "1024"], |_| {});
+    let mut fs = image.mount();
+
+    // Scatter the free space so the file needs more extents than fit in the inode
+    for i in 0..40 {
+        fs.write_file("/", &format!("f{}", i), &pattern(2048, i))
+            .unwrap();
+    }
+    for i in (0..40).step_by(2) {
+        fs.remove_file(&format!("/f{}", i)).unwrap();
+    }
+    let data = pattern(300_000, 99);
+    fs.write_file("/", "big", &data).unwrap();
+    drop(fs);
+
+    let mut fs = image.mount();
+    let inode_num = fs.find_by_path("/big").unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert!(ExtentHeader::parse(&inode.block_bytes()).unwrap().depth >= 1);
+    assert_eq!(inode.get_size(), data.len() as u64);
+    assert_eq!(read_path(&mut fs, "/big"), data);
+    image.fsck();
+}
+
+#[test]
+fn overwrites_file_with_other_sizes() {
+    let image = Image::mkfs_with("8M", &["-b", "1024"], |_| {});
+    let mut fs = image.mount();
+    for (i, len) in [70_000, 0, 1, 5_000, 1024].into_iter().enumerate() {
+        let data = pattern(len, i as u32);
+        fs.write_file("/", "file", &data).unwrap();
+        assert_eq!(read_path(&mut fs, "/file"), data);
+    }
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn keeps_block_counts_past_32_bits() {
+    let image = Image::mkfs_with("8M", &["-b", "4096", "-O", "huge_file"], |_| {});
+    let fs = image.mount();
+    let mut inode = Inode::default();
+
+    fs.set_inode_blocks(&mut inode, 1 << 33).unwrap();
+    assert_eq!((inode.blocks, inode.blocks_high), (0, 2));
+    assert_eq!(inode.flags & EXT4_HUGE_FILE_FL, 0);
+    assert_eq!(inode.get_sectors(4096), 1 << 33);
+
+    fs.set_inode_blocks(&mut inode, 1 << 50).unwrap();
+    assert_ne!(inode.flags & EXT4_HUGE_FILE_FL, 0);
+    assert_eq!(inode.get_sectors(4096), 1 << 50);
+
+    assert!(fs.set_inode_blocks(&mut inode, 1 << 60).is_err());
+    fs.set_inode_blocks(&mut inode, 8).unwrap();
+    assert_eq!((inode.blocks, inode.blocks_high, inode.flags), (8, 0, 0));
+
+    let image = Image::mkfs_with("8M", &["-O", "^huge_file"], |_| {});
+    let fs = image.mount();
+    assert!(fs.set_inode_blocks(&mut inode, 1 << 33).is_err());
+}
+
+#[test]
+fn stores_sizes_past_32_bits() {
+    let image = Image::mkfs_with("8M", &[], |_| {});
+    let mut fs = image.mount();
+    fs.write_file("/", "file", b"data").unwrap();
+    let inode_num = fs.find_by_path("/file").unwrap();
+    let mut inode = fs.read_inode(inode_num).unwrap();
+    fs.set_file_size(&mut inode, (5 << 30) + 3);
+    assert_eq!((inode.size, inode.dir_acl), ((1 << 30) + 3, 1));
+    assert_eq!(inode.get_size(), (5 << 30) + 3);
+
+    fs.truncate("/file", (5 << 30) + 3).unwrap();
+    drop(fs);
+    let mut fs = image.mount();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert_eq!(inode.get_size(), (5 << 30) + 3);
+    image.fsck();
+}
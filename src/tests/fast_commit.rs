@@ -0,0 +1,169 @@
+//! Replaying fast commits written after the last full commit.
+
+use std::fs;
+use std::process::Command;
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+
+use super::{pattern, read_path, Image};
+use crate::checksum;
+use crate::inode::{EXT4_EXTENTS_FL, INODE_BLOCK_SIZE};
+use crate::journal::{
+    EXT4_FC_TAG_ADD_RANGE, EXT4_FC_TAG_CREAT, EXT4_FC_TAG_HEAD, EXT4_FC_TAG_INODE,
+    EXT4_FC_TAG_TAIL, EXT4_FC_TAG_UNLINK,
+};
+use crate::{
+    Ext4Filesystem, ExtentNode, IncompatFeature, Inode, JournalIncompatFeature, JournalSuperblock,
+};
+
+const BLOCK_SIZE: usize = 4096;
+
+/// Free inode and block the fast commit hands to the new file.
+const NEW_INODE: u32 = 20;
+const NEW_BLOCK: u64 = 3000;
+
+/// Append a tag to a fast-commit block.
+fn push_tag(block: &mut Vec<u8>, tag: u16, value: &[u8]) {
+    let mut header = [0u8; 4];
+    LittleEndian::write_u16(&mut header[0..2], tag);
+    LittleEndian::write_u16(&mut header[2..4], value.len() as u16);
+    block.extend_from_slice(&header);
+    block.extend_from_slice(value);
+}
+
+/// Build the value of a dentry tag.
+fn dentry(parent: u32, inode: u32, name: &str) -> Vec<u8> {
+    let mut value = vec![0u8; 8];
+    LittleEndian::write_u32(&mut value[0..4], parent);
+    LittleEndian::write_u32(&mut value[4..8], inode);
+    value.extend_from_slice(name.as_bytes());
+    value
+}
+
+/// Build a fast commit for transaction `tid` that creates a two-block file and unlinks
+/// another, ending it with a tail that pads out the block like the kernel does.
+fn fast_commit_block(tid: u32, unlinked: u32) -> Vec<u8> {
+    let mut block = Vec::new();
+    let mut value = vec![0u8; 8];
+    LittleEndian::write_u32(&mut value[4..8], tid);
+    push_tag(&mut block, EXT4_FC_TAG_HEAD, &value);
+
+    // Like the kernel, a created inode is logged before its blocks and its entry
+    let mut inode = Inode {
+        mode: 0x81A4,
+        links_count: 1,
+        size: 5000,
+        flags: EXT4_EXTENTS_FL,
+        extra_isize: 32,
+        ..Default::default()
+    };
+    let mut root = [0u8; INODE_BLOCK_SIZE];
+    ExtentNode::Leaf(Vec::new()).write_to(&mut root, 4, 0);
+    inode.set_block_bytes(&root);
+    let mut value = vec![0u8; 4];
+    LittleEndian::write_u32(&mut value, NEW_INODE);
+    value.extend_from_slice(&inode.to_bytes(256).unwrap());
+    push_tag(&mut block, EXT4_FC_TAG_INODE, &value);
+
+    let mut value = vec![0u8; 16];
+    LittleEndian::write_u32(&mut value[0..4], NEW_INODE);
+    LittleEndian::write_u32(&mut value[4..8], 0);
+    LittleEndian::write_u16(&mut value[8..10], 2);
+    LittleEndian::write_u32(&mut value[12..16], NEW_BLOCK as u32);
+    push_tag(&mut block, EXT4_FC_TAG_ADD_RANGE, &value);
+    push_tag(&mut block, EXT4_FC_TAG_CREAT, &dentry(2, NEW_INODE, "new"));
+    push_tag(&mut block, EXT4_FC_TAG_UNLINK, &dentry(2, unlinked, "old"));
+
+    // The checksum runs from the head through the tail's transaction ID
+    let tail_len = BLOCK_SIZE - block.len() - 4;
+    let mut value = vec![0u8; tail_len];
+    LittleEndian::write_u32(&mut value[0..4], tid);
+    push_tag(&mut block, EXT4_FC_TAG_TAIL, &value);
+    let crc_offset = BLOCK_SIZE - tail_len + 4;
+    let crc = checksum::crc32c(0, &block[..crc_offset]);
+    LittleEndian::write_u32(&mut block[crc_offset..crc_offset + 4], crc);
+    block
+}
+
+/// Format an image with a fast-commit area holding a fast commit that has not been
+/// replayed, as after a crash, returning the new file's contents.
+fn crashed_image() -> (Image, Vec<u8>) {
+    let image = Image::mkfs_with("16M", &["-b", "4096", "-O", "fast_commit"], |source| {
+        fs::write(source.join("old"), pattern(9000, 1)).unwrap();
+    });
+    let mut fs = image.mount();
+    let old = fs.find_by_path("/old").unwrap();
+    assert_eq!(fs.read_inode(NEW_INODE).unwrap().links_count, 0);
+    let journal_inode = fs.read_inode(8).unwrap();
+    let (extents, _) = fs.read_extent_map(&journal_inode).unwrap();
+    drop(fs);
+    let journal_offset = |logical: u32| {
+        let extent = extents
+            .iter()
+            .find(|extent| extent.contains(logical))
+            .unwrap();
+        (extent.start + (logical - extent.block) as u64) as usize * BLOCK_SIZE
+    };
+    let journal_start = journal_offset(0);
+
+    let mut raw = fs::read(image.path()).unwrap();
+
+    // File data goes to its blocks before the fast commit that maps them
+    let data = pattern(5000, 2);
+    let offset = NEW_BLOCK as usize * BLOCK_SIZE;
+    raw[offset..offset + data.len()].copy_from_slice(&data);
+
+    // The journal is in use with no full commit after the last checkpoint
+    let jsb = &mut raw[journal_start..journal_start + 1024];
+    let sequence = BigEndian::read_u32(&jsb[24..28]);
+    let first = BigEndian::read_u32(&jsb[20..24]);
+    BigEndian::write_u32(&mut jsb[28..32], first);
+    let incompat = BigEndian::read_u32(&jsb[40..44]) | JournalIncompatFeature::FastCommit as u32;
+    BigEndian::write_u32(&mut jsb[40..44], incompat);
+    let superblock = JournalSuperblock::parse(jsb).unwrap();
+    let checksum = JournalSuperblock::compute_checksum(jsb);
+    BigEndian::write_u32(&mut jsb[0xFC..0x100], checksum);
+
+    let fc_offset = journal_offset(superblock.log_end() + 1);
+    raw[fc_offset..fc_offset + BLOCK_SIZE].copy_from_slice(&fast_commit_block(sequence, old));
+
+    let sb: [u8; 1024] = raw[1024..2048].try_into().unwrap();
+    let mut sb = crate::Superblock::parse(&sb).unwrap();
+    sb.feature_incompat |= IncompatFeature::Recover as u32;
+    sb.checksum = sb.compute_checksum().unwrap();
+    raw[1024..2048].copy_from_slice(&sb.to_bytes().unwrap());
+    fs::write(image.path(), &raw).unwrap();
+    (image, data)
+}
+
+fn check_replayed(fs: &mut Ext4Filesystem, data: &[u8]) {
+    assert_eq!(fs.find_by_path("/new").unwrap(), NEW_INODE);
+    assert_eq!(read_path(fs, "/new"), data);
+    assert!(fs.find_by_path("/old").is_err());
+}
+
+#[test]
+fn replays_fast_commits() {
+    let (image, data) = crashed_image();
+    let mut fs = image.mount();
+    assert!(!fs.superblock().has_incompat(IncompatFeature::Recover));
+    check_replayed(&mut fs, &data);
+    drop(fs);
+    image.fsck();
+
+    let mut fs = image.mount();
+    check_replayed(&mut fs, &data);
+}
+
+#[test]
+fn replays_fast_commits_like_e2fsck() {
+    let (image, data) = crashed_image();
+    let output = Command::new("e2fsck")
+        .arg("-fy")
+        .arg(image.path())
+        .output()
+        .unwrap();
+    assert!(matches!(output.status.code(), Some(0) | Some(1)));
+    image.fsck();
+    check_replayed(&mut image.mount(), &data);
+}
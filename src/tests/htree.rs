@@ -0,0 +1,94 @@
+//! Looking up and maintaining hash-tree indexed directories.
+
+use std::fs;
+
+use super::Image;
+use crate::inode::EXT4_INDEX_FL;
+
+/// Get the name of the `i`th entry of a test directory, long enough to need many leaves.
+fn entry_name(i: u32) -> String {
+    format!("entry-{:05}-padding-the-name-out-to-forty", i)
+}
+
+#[test]
+fn looks_up_names_through_two_level_index() {
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-N", "8192"], |source| {
+        let dir = source.join("dir");
+        fs::create_dir(&dir).unwrap();
+        for i in 0..4000 {
+            fs::write(dir.join(entry_name(i)), b"").unwrap();
+        }
+    });
+    image.index_directories();
+
+    let mut fs = image.mount();
+    let dir = fs.find_by_path("/dir").unwrap();
+    let inode = fs.read_inode(dir).unwrap();
+    assert_ne!(inode.flags & EXT4_INDEX_FL, 0);
+    let (_, _, frames) = fs.dx_probe(dir, &inode, &entry_name(0)).unwrap();
+    assert_eq!(frames.len(), 2);
+
+    for i in 0..4000 {
+        let name = entry_name(i);
+        let (_, found) = fs.dx_lookup(dir, &inode, &name).unwrap().unwrap();
+        assert_eq!(fs.lookup(dir, &name).unwrap(), Some(found));
+        assert!(fs.read_inode(found).unwrap().is_file());
+    }
+    assert_eq!(fs.lookup(dir, "missing").unwrap(), None);
+    assert_eq!(fs.lookup(dir, &entry_name(4000)).unwrap(), None);
+    assert_eq!(fs.lookup(dir, "..").unwrap(), Some(2));
+    assert_eq!(fs.lookup(dir, ".").unwrap(), Some(dir));
+    drop(fs);
+    image.fsck();
+}
+
+/// Add names to an empty directory of a 1 KiB-block image until its index has two levels,
+/// checking every name can be found again.
+fn grow_to_two_levels(name: impl Fn(u32) -> String) {
+    let image = Image::mkfs_with("32M", &["-b", "1024", "-N", "4096"], |source| {
+        fs::create_dir(source.join("dir")).unwrap();
+    });
+    let mut fs = image.mount();
+    let dir = fs.find_by_path("/dir").unwrap();
+    fs.write_file("/", "target", b"").unwrap();
+    let target = fs.find_by_path("/target").unwrap();
+
+    let mut added = 0;
+    loop {
+        fs.link("/target", &format!("/dir/{}", name(added)))
+            .unwrap();
+        added += 1;
+        let inode = fs.read_inode(dir).unwrap();
+        if inode.flags & EXT4_INDEX_FL != 0 {
+            let (_, _, frames) = fs.dx_probe(dir, &inode, &name(0)).unwrap();
+            if frames.len() == 2 {
+                break;
+            }
+        }
+        assert!(added < 3000, "index never grew a second level");
+    }
+
+    // A few more go through the new level
+    for i in added..added + 20 {
+        fs.link("/target", &format!("/dir/{}", name(i))).unwrap();
+    }
+    for i in 0..added + 20 {
+        assert_eq!(fs.lookup(dir, &name(i)).unwrap(), Some(target));
+    }
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn splits_leaves_of_long_names() {
+    grow_to_two_levels(|i| format!("{:05}{}", i, "x".repeat(250)));
+}
+
+#[test]
+fn splits_leaves_of_mixed_names() {
+    grow_to_two_levels(|i| match i % 3 {
+        0 => format!("{:05}{}", i, "y".repeat(250)),
+        1 => format!("{}", i),
+        _ => format!("{:05}{}", i, "z".repeat(120)),
+    });
+}
@@ -0,0 +1,102 @@
+//! Small files and directories stored inline in their inodes.
+
+use std::fs;
+
+use super::{pattern, read_path, Image};
+
+#[test]
+fn reads_inline_files_and_directories() {
+    let image = Image::mkfs_with("8M", &["-O", "inline_data"], |source| {
+        fs::write(source.join("tiny"), b"fits in i_block").unwrap();
+        fs::write(source.join("small"), pattern(100, 1)).unwrap();
+        fs::create_dir(source.join("dir")).unwrap();
+        fs::write(source.join("dir/inner"), b"inner").unwrap();
+    });
+
+    let mut fs = image.mount();
+    for path in ["/tiny", "/small", "/dir"] {
+        let inode_num = fs.find_by_path(path).unwrap();
+        assert!(
+            fs.read_inode(inode_num).unwrap().has_inline_data(),
+            "{}",
+            path
+        );
+    }
+    assert_eq!(read_path(&mut fs, "/tiny"), b"fits in i_block");
+    assert_eq!(read_path(&mut fs, "/small"), pattern(100, 1));
+    assert_eq!(read_path(&mut fs, "/dir/inner"), b"inner");
+    let dir = fs.find_by_path("/dir").unwrap();
+    let names: Vec<_> = fs
+        .read_directory(dir)
+        .unwrap()
+        .entries
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, [".", "..", "inner"]);
+}
+
+#[test]
+fn writes_and_converts_inline_files() {
+    let image = Image::mkfs_with("8M", &["-O", "inline_data"], |_| {});
+    let mut fs = image.mount();
+    fs.write_file("/", "tiny", b"tiny").unwrap();
+    fs.write_file("/", "small", &pattern(120, 2)).unwrap();
+    for path in ["/tiny", "/small"] {
+        let inode_num = fs.find_by_path(path).unwrap();
+        let inode = fs.read_inode(inode_num).unwrap();
+        assert!(inode.has_inline_data(), "{}", path);
+        assert_eq!(inode.blocks, 0);
+    }
+    drop(fs);
+    image.fsck();
+
+    // Growing past the room in the inode moves the data to a block
+    let mut fs = image.mount();
+    let small = fs.find_by_path("/small").unwrap();
+    let tail = pattern(3000, 3);
+    fs.write_at(small, 120, &tail).unwrap();
+    assert!(!fs.read_inode(small).unwrap().has_inline_data());
+    drop(fs);
+
+    let mut fs = image.mount();
+    let mut expected = pattern(120, 2);
+    expected.extend_from_slice(&tail);
+    assert_eq!(read_path(&mut fs, "/small"), expected);
+    assert_eq!(read_path(&mut fs, "/tiny"), b"tiny");
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn grows_inline_directories() {
+    let image = Image::mkfs_with("8M", &["-O", "inline_data"], |_| {});
+    let mut fs = image.mount();
+    fs.create_directory("/", "dir").unwrap();
+    let dir = fs.find_by_path("/dir").unwrap();
+    for i in 0..3 {
+        fs.write_file("/dir", &format!("file-{}", i), b"data")
+            .unwrap();
+    }
+    assert!(fs.read_inode(dir).unwrap().has_inline_data());
+    drop(fs);
+    image.fsck();
+
+    // Past the inode and the system.data attribute, the entries move to a block
+    let mut fs = image.mount();
+    for i in 3..60 {
+        fs.write_file("/dir", &format!("file-{}", i), b"data")
+            .unwrap();
+    }
+    assert!(!fs.read_inode(dir).unwrap().has_inline_data());
+    fs.unlink("/dir/file-0").unwrap();
+    drop(fs);
+
+    let mut fs = image.mount();
+    for i in 1..60 {
+        assert_eq!(read_path(&mut fs, &format!("/dir/file-{}", i)), b"data");
+    }
+    assert!(fs.find_by_path("/dir/file-0").is_err());
+    drop(fs);
+    image.fsck();
+}
@@ -0,0 +1,104 @@
+//! Reading and writing inodes of every size.
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+
+use super::{pattern, read_path, Image};
+use crate::Ext4Filesystem;
+
+/// Format an image with inodes of `inode_size` bytes holding enough files to fill several
+/// blocks of the inode table, then check each file's size and mode.
+fn check_inode_size(inode_size: u32) {
+    let image = Image::mkfs_with(
+        "8M",
+        &["-b", "1024", "-I", &inode_size.to_string()],
+        |source| {
+            for i in 0..40 {
+                let path = source.join(format!("f{}", i));
+                fs::write(&path, pattern(i as usize * 100, i)).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o600 + i)).unwrap();
+            }
+        },
+    );
+
+    let mut fs = image.mount();
+    assert_eq!(fs.superblock().inode_size as u32, inode_size);
+    let per_block = 1024 / inode_size;
+    for i in 0..40 {
+        let path = format!("/f{}", i);
+        let inode_num = fs.find_by_path(&path).unwrap();
+        let inode = fs.read_inode(inode_num).unwrap();
+        assert_eq!(inode.get_size(), i as u64 * 100);
+        assert_eq!(inode.mode as u32, 0o100000 | (0o600 + i));
+        assert_eq!(read_path(&mut fs, &path), pattern(i as usize * 100, i));
+        if i == 39 {
+            assert!(
+                inode_num > per_block,
+                "inode {} is in the first block",
+                inode_num
+            );
+        }
+    }
+
+    // Rewriting inodes past the first block of the table keeps their neighbours intact
+    fs.write_file("/", "f20", b"rewritten").unwrap();
+    fs.write_file("/", "new", b"new").unwrap();
+    drop(fs);
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/f20"), b"rewritten");
+    assert_eq!(read_path(&mut fs, "/f21"), pattern(2100, 21));
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn reads_128_byte_inodes() {
+    check_inode_size(128);
+}
+
+#[test]
+fn reads_256_byte_inodes() {
+    check_inode_size(256);
+}
+
+#[test]
+fn reads_512_byte_inodes() {
+    check_inode_size(512);
+}
+
+#[test]
+fn keeps_extra_fields() {
+    let image = Image::mkfs_with("8M", &[], |source| {
+        fs::write(source.join("file"), b"contents").unwrap();
+    });
+    image.debugfs(&[
+        "sif /file uid 70000",
+        "sif /file gid 80000",
+        "sif /file crtime 20200101120000",
+        "sif /file crtime_extra 8",
+        "sif /file mtime_extra 0x1234",
+        "sif /file version_hi 7",
+        "sif /file projid 42",
+    ]);
+
+    let check = |fs: &mut Ext4Filesystem| {
+        let inode_num = fs.find_by_path("/file").unwrap();
+        let inode = fs.read_inode(inode_num).unwrap();
+        assert_eq!(inode.extra_isize, 32);
+        assert_eq!((inode.get_uid(), inode.get_gid()), (70000, 80000));
+        assert_eq!((inode.crtime, inode.crtime_extra), (1577880000, 8));
+        assert_eq!(inode.mtime_extra, 0x1234);
+        assert_eq!((inode.version_hi, inode.projid), (7, 42));
+        inode
+    };
+    let mut fs = image.mount();
+    check(&mut fs);
+
+    // Rewriting the inode keeps every extra field
+    fs.link("/file", "/other").unwrap();
+    drop(fs);
+    let mut fs = image.mount();
+    assert_eq!(check(&mut fs).links_count, 2);
+    drop(fs);
+    image.fsck();
+}
@@ -0,0 +1,341 @@
+//! Reading, writing and replaying the JBD2 journal.
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::process::Command;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::{pattern, read_path, Image};
+use crate::superblock::SUPERBLOCK_SIZE;
+use crate::{
+    Ext4Error, Ext4Filesystem, IncompatFeature, JournalIncompatFeature, JournalLocation,
+    JournalRecovery, JournalSuperblock, MountOptions, Superblock,
+};
+
+#[test]
+fn parses_internal_journal_superblock() {
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-J", "size=2"], |_| {});
+    let fs = image.mount();
+    let journal = fs.journal().unwrap();
+    assert!(matches!(journal.location, JournalLocation::Inode(8)));
+
+    let superblock = &journal.superblock;
+    assert_eq!(superblock.magic, 0xC03B3998);
+    assert_eq!(superblock.blocksize, 1024);
+    assert_eq!(superblock.maxlen, 2048);
+    assert_eq!(superblock.first, 1);
+    assert_eq!(superblock.sequence_id, 1);
+    assert_eq!(superblock.start, 0);
+    assert!(!superblock.needs_recovery());
+    assert!(fs.journal_transactions().unwrap().is_empty());
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn parses_fast_commit_journal_superblock() {
+    let image = Image::mkfs_with("16M", &["-b", "4096", "-O", "fast_commit"], |_| {});
+    let mut fs = image.mount();
+    let superblock = &fs.journal().unwrap().superblock;
+    // The kernel only turns the journal feature on once it writes a fast commit
+    assert!(!superblock.has_incompat(JournalIncompatFeature::FastCommit));
+    assert_eq!((superblock.maxlen, superblock.num_fc_blocks), (1040, 16));
+
+    let data = pattern(10_000, 1);
+    fs.write_file("/", "file", &data).unwrap();
+    drop(fs);
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/file"), data);
+    drop(fs);
+    image.fsck();
+}
+
+/// Get the physical block holding a logical block of a file.
+fn file_block(fs: &mut Ext4Filesystem, path: &str, logical: u32) -> u64 {
+    let inode_num = fs.find_by_path(path).unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    let mut reader = fs.reader().unwrap();
+    let block_size = fs.superblock().block_size();
+    inode
+        .map_block(&mut reader, logical, block_size)
+        .unwrap()
+        .unwrap()
+}
+
+/// Read a block straight from the image file.
+fn raw_block(image: &Image, block_num: u64) -> Vec<u8> {
+    let mut data = vec![0u8; 1024];
+    let mut file = fs::File::open(image.path()).unwrap();
+    file.seek(SeekFrom::Start(block_num * 1024)).unwrap();
+    file.read_exact(&mut data).unwrap();
+    data
+}
+
+/// Format a 1 KiB-block image holding a two-block file, then log new contents for both
+/// of its blocks in a transaction left in the journal, and revoke the second block in a
+/// later one.
+fn dirty_image(features: &str) -> (Image, Vec<u8>, [u64; 2]) {
+    let old = pattern(2048, 1);
+    let image = Image::mkfs_with("8M", &["-b", "1024", "-O", features], |source| {
+        fs::write(source.join("file"), &old).unwrap();
+    });
+    let mut fs = image.mount();
+    let blocks = [
+        file_block(&mut fs, "/file", 0),
+        file_block(&mut fs, "/file", 1),
+    ];
+    drop(fs);
+
+    let new = pattern(2048, 2);
+    let block_file = image.scratch_path("blocks");
+    fs::write(&block_file, &new).unwrap();
+    let block_file = block_file.to_str().unwrap();
+    image.debugfs(&[
+        "jo",
+        &format!("jw -b {},{} {}", blocks[0], blocks[1], block_file),
+        &format!("jw -r {}", blocks[1]),
+        "jc",
+    ]);
+    let mut expected = new;
+    expected[1024..].copy_from_slice(&old[1024..]);
+    (image, expected, blocks)
+}
+
+#[test]
+fn replays_dirty_journal() {
+    for features in ["^metadata_csum", "metadata_csum"] {
+        let (image, expected, _) = dirty_image(features);
+        let mut fs = image.mount();
+        assert!(!fs.superblock().has_incompat(IncompatFeature::Recover));
+        assert_eq!(read_path(&mut fs, "/file"), expected);
+        drop(fs);
+
+        let mut fs = image.mount();
+        assert_eq!(read_path(&mut fs, "/file"), expected);
+        drop(fs);
+        image.fsck();
+    }
+}
+
+#[test]
+fn replays_into_memory_without_touching_image() {
+    let (image, expected, blocks) = dirty_image("metadata_csum");
+    let before = raw_block(&image, blocks[0]);
+    let options = MountOptions {
+        recovery: JournalRecovery::InMemory,
+        ..Default::default()
+    };
+    let mut fs = Ext4Filesystem::mount_with_options(image.path(), options).unwrap();
+    assert!(fs.is_read_only());
+    assert_eq!(read_path(&mut fs, "/file"), expected);
+    drop(fs);
+
+    assert_eq!(raw_block(&image, blocks[0]), before);
+    let fs = image.mount();
+    assert!(!fs.superblock().has_incompat(IncompatFeature::Recover));
+    drop(fs);
+    image.fsck();
+}
+
+/// Set or clear needs_recovery in the superblock of a raw image.
+fn set_needs_recovery(image: &mut [u8]) {
+    let raw: [u8; SUPERBLOCK_SIZE] = image[1024..2048].try_into().unwrap();
+    let mut superblock = Superblock::parse(&raw).unwrap();
+    superblock.feature_incompat |= IncompatFeature::Recover as u32;
+    superblock.checksum = superblock.compute_checksum().unwrap();
+    image[1024..2048].copy_from_slice(&superblock.to_bytes().unwrap());
+}
+
+/// Build the image a crash would leave after the last transaction written to `image` was
+/// committed to the journal but before any of it was checkpointed: its file data is on disk
+/// but the metadata it logged is still as in `before`.
+fn crash_image(image: &Image, before: &[u8]) -> Vec<u8> {
+    let mut crashed = fs::read(image.path()).unwrap();
+    let mut fs = image.mount();
+    let journal_inode = fs.read_inode(8).unwrap();
+    let (extents, _) = fs.read_extent_map(&journal_inode).unwrap();
+    drop(fs);
+    let sb_offset = extents[0].start as usize * 1024;
+
+    // Point the journal back at the transaction, which the commit marked clean
+    let journal_sb = &mut crashed[sb_offset..sb_offset + 1024];
+    let sequence = BigEndian::read_u32(&journal_sb[24..28]) - 1;
+    let first = BigEndian::read_u32(&journal_sb[20..24]);
+    BigEndian::write_u32(&mut journal_sb[24..28], sequence);
+    BigEndian::write_u32(&mut journal_sb[28..32], first);
+    let checksum = JournalSuperblock::compute_checksum(journal_sb);
+    BigEndian::write_u32(&mut journal_sb[0xFC..0x100], checksum);
+    set_needs_recovery(&mut crashed);
+    fs::write(image.path(), &crashed).unwrap();
+
+    // Undo the checkpoint of every block the transaction logged
+    let options = MountOptions {
+        recovery: JournalRecovery::Skip,
+        ..Default::default()
+    };
+    let fs = Ext4Filesystem::mount_with_options(image.path(), options).unwrap();
+    let transactions = fs.journal_transactions().unwrap();
+    assert_eq!(transactions.len(), 1);
+    for logged in &transactions[0].blocks {
+        let offset = logged.home_block as usize * 1024;
+        crashed[offset..offset + 1024].copy_from_slice(&before[offset..offset + 1024]);
+    }
+    set_needs_recovery(&mut crashed);
+    crashed
+}
+
+#[test]
+fn recovers_committed_transaction_after_crash() {
+    let old = pattern(3000, 1);
+    let image = Image::mkfs_with("8M", &["-b", "1024", "-O", "metadata_csum"], |source| {
+        fs::write(source.join("old"), &old).unwrap();
+        fs::create_dir(source.join("sub")).unwrap();
+    });
+    let before = fs::read(image.path()).unwrap();
+
+    let new = pattern(5000, 2);
+    let mut fs = image.mount();
+    fs.transaction(|fs| {
+        fs.write_file("/sub", "new", &new)?;
+        fs.create_directory("/", "dir")?;
+        fs.unlink("/old")
+    })
+    .unwrap();
+    drop(fs);
+    image.fsck();
+
+    let crashed = crash_image(&image, &before);
+    let check = |fs: &mut Ext4Filesystem| {
+        assert_eq!(read_path(fs, "/sub/new"), new);
+        assert!(fs.find_by_path("/dir").is_ok());
+        assert!(fs.find_by_path("/old").is_err());
+    };
+
+    // Replayed by this crate
+    fs::write(image.path(), &crashed).unwrap();
+    let mut fs = image.mount();
+    check(&mut fs);
+    drop(fs);
+    image.fsck();
+
+    // Replayed by e2fsck, like the kernel would
+    fs::write(image.path(), &crashed).unwrap();
+    let status = Command::new("e2fsck")
+        .arg("-fy")
+        .arg(image.path())
+        .output()
+        .unwrap()
+        .status;
+    assert!(matches!(status.code(), Some(0) | Some(1)));
+    image.fsck();
+    check(&mut image.mount());
+}
+
+#[test]
+fn discards_failed_transaction() {
+    let image = Image::mkfs_with("8M", &["-O", "metadata_csum"], |source| {
+        fs::write(source.join("old"), b"old").unwrap();
+    });
+    let mut fs = image.mount();
+    let result: Result<(), Ext4Error> = fs.transaction(|fs| {
+        fs.write_file("/", "new", b"new")?;
+        fs.create_directory("/", "dir")?;
+        fs.unlink("/old")?;
+        Err(Ext4Error::InvalidOperation("give up".to_string()))
+    });
+    assert!(result.is_err());
+    assert!(fs.find_by_path("/new").is_err());
+    assert_eq!(read_path(&mut fs, "/old"), b"old");
+    drop(fs);
+
+    let mut fs = image.mount();
+    assert!(fs.find_by_path("/dir").is_err());
+    assert_eq!(read_path(&mut fs, "/old"), b"old");
+    drop(fs);
+    image.fsck();
+}
+
+/// Shrink the journal of an image to `maxlen` blocks, like a small external journal.
+fn shrink_journal(image: &Image, maxlen: u32) {
+    let mut raw = fs::read(image.path()).unwrap();
+    let mut fs = image.mount();
+    let journal_inode = fs.read_inode(8).unwrap();
+    let (extents, _) = fs.read_extent_map(&journal_inode).unwrap();
+    drop(fs);
+
+    let sb_offset = extents[0].start as usize * 1024;
+    let journal_sb = &mut raw[sb_offset..sb_offset + 1024];
+    BigEndian::write_u32(&mut journal_sb[16..20], maxlen);
+    let checksum = JournalSuperblock::compute_checksum(journal_sb);
+    BigEndian::write_u32(&mut journal_sb[0xFC..0x100], checksum);
+    fs::write(image.path(), &raw).unwrap();
+}
+
+#[test]
+fn refuses_transaction_larger_than_journal() {
+    let image = Image::mkfs_with("8M", &["-b", "1024", "-O", "metadata_csum"], |source| {
+        for i in 0..200 {
+            fs::write(source.join(format!("f{}", i)), b"").unwrap();
+        }
+    });
+    shrink_journal(&image, 32);
+
+    // Each file has its inode in a different table block than most others
+    let mut fs = image.mount();
+    let result = fs.transaction(|fs| {
+        for i in 0..200 {
+            fs.write_file("/", &format!("f{}", i), b"data")?;
+        }
+        Ok(())
+    });
+    assert!(matches!(result, Err(Ext4Error::NoSpace(_))));
+    assert_eq!(read_path(&mut fs, "/f0"), b"");
+    drop(fs);
+    image.fsck();
+
+    // Nothing of the failed transaction reached the image, and smaller ones still fit
+    let mut fs = image.mount();
+    for i in 0..200 {
+        assert_eq!(read_path(&mut fs, &format!("/f{}", i)), b"");
+    }
+    fs.write_file("/", "f0", b"data").unwrap();
+    drop(fs);
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/f0"), b"data");
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn lists_logged_blocks_and_revokes() {
+    let (image, expected, blocks) = dirty_image("metadata_csum");
+    let options = MountOptions {
+        recovery: JournalRecovery::Skip,
+        ..Default::default()
+    };
+    let fs = Ext4Filesystem::mount_with_options(image.path(), options).unwrap();
+    let transactions = fs.journal_transactions().unwrap();
+    assert_eq!(transactions.len(), 2);
+
+    let logged = &transactions[0];
+    assert_eq!((logged.sequence, logged.committed), (1, true));
+    let homes: Vec<u64> = logged.blocks.iter().map(|block| block.home_block).collect();
+    assert_eq!(homes, blocks);
+    assert_eq!(
+        fs.read_logged_block(&logged.blocks[0]).unwrap(),
+        &expected[..1024]
+    );
+
+    let revoking = &transactions[1];
+    assert_eq!((revoking.sequence, revoking.committed), (2, true));
+    assert!(revoking.blocks.is_empty());
+    assert_eq!(revoking.revoked, [blocks[1]]);
+    drop(fs);
+
+    // Inspecting the journal leaves it to be replayed
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/file"), expected);
+    drop(fs);
+    image.fsck();
+}
@@ -0,0 +1,101 @@
+//! Hard links, and what an inode keeps while names come and go.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use super::{pattern, read_path, Image};
+use crate::checksum;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Set a user attribute too big for the inode on a file, so it goes to an attribute block.
+fn set_big_xattr(image: &Image, path: &str) {
+    let command = format!("ea_set {} user.big {}", path, "v".repeat(500));
+    image.debugfs(&[&command]);
+}
+
+#[test]
+fn overwrite_keeps_links_and_owner() {
+    let image = Image::mkfs_with("8M", &[], |_| {});
+    let mut fs = image.mount();
+    fs.write_file("/", "first", &pattern(5000, 1)).unwrap();
+    fs.link("/first", "/second").unwrap();
+    drop(fs);
+    image.debugfs(&["sif /first mode 0100600", "sif /first uid 1234"]);
+    set_big_xattr(&image, "/first");
+
+    let mut fs = image.mount();
+    let inode_num = fs.find_by_path("/first").unwrap();
+    let acl = fs.read_inode(inode_num).unwrap().get_file_acl();
+    assert_ne!(acl, 0);
+    fs.write_file("/", "first", &pattern(3000, 2)).unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert_eq!(inode.links_count, 2);
+    assert_eq!(inode.mode, 0o100600);
+    assert_eq!(inode.uid, 1234);
+    assert_eq!(inode.get_file_acl(), acl);
+    drop(fs);
+    image.fsck();
+
+    // The other name still reaches the new data once the first is gone
+    let mut fs = image.mount();
+    fs.unlink("/first").unwrap();
+    drop(fs);
+    image.fsck();
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/second"), pattern(3000, 2));
+    fs.unlink("/second").unwrap();
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn releases_attribute_blocks() {
+    let image = Image::mkfs_with("8M", &["-O", "metadata_csum"], |_| {});
+    let mut fs = image.mount();
+    fs.write_file("/", "alone", b"alone").unwrap();
+    fs.write_file("/", "first", b"first").unwrap();
+    fs.write_file("/", "second", b"second").unwrap();
+    drop(fs);
+    set_big_xattr(&image, "/alone");
+    set_big_xattr(&image, "/first");
+
+    // Share the attribute block of the first file with the second
+    let mut fs = image.mount();
+    let first = fs.find_by_path("/first").unwrap();
+    let inode = fs.read_inode(first).unwrap();
+    let acl = inode.get_file_acl();
+    let seed = fs.csum_seed.unwrap();
+    drop(fs);
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(image.path())
+        .unwrap();
+    let mut block = vec![0u8; 1024];
+    file.seek(SeekFrom::Start(acl * 1024)).unwrap();
+    file.read_exact(&mut block).unwrap();
+    LittleEndian::write_u32(&mut block[4..8], 2);
+    checksum::set_xattr_block_checksum(seed, acl, &mut block);
+    file.seek(SeekFrom::Start(acl * 1024)).unwrap();
+    file.write_all(&block).unwrap();
+    drop(file);
+    image.debugfs(&[
+        &format!("sif /second file_acl {}", acl),
+        &format!("sif /second blocks {}", inode.blocks),
+    ]);
+    image.fsck();
+
+    let mut fs = image.mount();
+    let free = fs.superblock().free_blocks_count_64();
+    fs.unlink("/alone").unwrap();
+    fs.unlink("/first").unwrap();
+    assert_eq!(fs.superblock().free_blocks_count_64(), free + 3);
+    drop(fs);
+    image.fsck();
+
+    let mut fs = image.mount();
+    fs.unlink("/second").unwrap();
+    assert_eq!(fs.superblock().free_blocks_count_64(), free + 5);
+    drop(fs);
+    image.fsck();
+}
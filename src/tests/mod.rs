@@ -0,0 +1,177 @@
+//! Tests run against images built with the e2fsprogs tools.
+
+mod block_group;
+mod block_map;
+mod checksum;
+mod directory;
+mod extent_read;
+mod extent_write;
+mod fast_commit;
+mod htree;
+mod inline_data;
+mod inode;
+mod journal;
+mod link;
+mod rename;
+mod superblock;
+mod symlink;
+mod truncate;
+mod write_at;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::Ext4Filesystem;
+
+/// Numbers the scratch directories of the tests running in this process.
+static NEXT_IMAGE: AtomicU32 = AtomicU32::new(0);
+
+/// A filesystem image in a scratch directory removed when the image is dropped.
+pub struct Image {
+    /// The scratch directory.
+    dir: PathBuf,
+    /// The image file inside it.
+    path: PathBuf,
+}
+
+impl Image {
+    /// Create an empty scratch directory for an image.
+    fn scratch() -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "ext4-test-{}-{}",
+            std::process::id(),
+            NEXT_IMAGE.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("image");
+        Image { dir, path }
+    }
+
+    /// Format an image of `size` with `mkfs.ext4` and the given options, populated from a directory that `populate` fills in.
+    pub fn mkfs_with(size: &str, options: &[&str], populate: impl FnOnce(&Path)) -> Self {
+        let image = Self::scratch();
+        let source = image.dir.join("source");
+        fs::create_dir(&source).unwrap();
+        populate(&source);
+        image.format(size, options, Some(&source));
+        image
+    }
+
+    fn format(&self, size: &str, options: &[&str], source: Option<&Path>) {
+        let mut command = Command::new("mkfs.ext4");
+        command
+            .args(["-q", "-F", "-E", "root_owner=0:0"])
+            .args(options);
+        if let Some(source) = source {
+            command.arg("-d").arg(source);
+        }
+        let output = command.arg(&self.path).arg(size).output().unwrap();
+        assert!(
+            output.status.success(),
+            "mkfs.ext4 failed: {}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// Get the path of the image file.
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+
+    /// Get a path in the scratch directory, for files a test keeps next to the image.
+    pub fn scratch_path(&self, name: &str) -> PathBuf {
+        self.dir.join(name)
+    }
+
+    /// Mount the image.
+    pub fn mount(&self) -> Ext4Filesystem {
+        Ext4Filesystem::mount(self.path()).unwrap()
+    }
+
+    /// Check the image with `e2fsck -fn`, failing the test on any problem.
+    pub fn fsck(&self) {
+        let output = Command::new("e2fsck")
+            .arg("-fn")
+            .arg(&self.path)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "e2fsck found problems:\n{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+
+    /// Index every directory big enough for it with `e2fsck -fyD`.
+    pub fn index_directories(&self) {
+        let output = Command::new("e2fsck")
+            .arg("-fyD")
+            .arg(&self.path)
+            .output()
+            .unwrap();
+        assert!(
+            matches!(output.status.code(), Some(0) | Some(1)),
+            "e2fsck -fyD failed:\n{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+
+    /// Run `debugfs` commands against the image, with write access, returning their output.
+    pub fn debugfs(&self, commands: &[&str]) -> String {
+        let script = self.dir.join("debugfs.cmds");
+        fs::write(&script, commands.join("\n") + "\n").unwrap();
+        let output = Command::new("debugfs")
+            .arg("-w")
+            .arg("-f")
+            .arg(&script)
+            .arg(&self.path)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "debugfs failed");
+        String::from_utf8_lossy(&output.stdout).into_owned()
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Read a whole file by path.
+pub fn read_path(fs: &mut Ext4Filesystem, path: &str) -> Vec<u8> {
+    let inode_num = fs.find_by_path(path).unwrap();
+    let size = fs.read_inode(inode_num).unwrap().get_size() as usize;
+    let mut data = vec![0u8; size];
+    let read = fs.read_file(inode_num, &mut data, 0).unwrap();
+    assert_eq!(read, size);
+    data
+}
+
+/// Generate `len` bytes that differ from block to block.
+pub fn pattern(len: usize, seed: u32) -> Vec<u8> {
+    let mut rng = Rng::new(seed);
+    (0..len).map(|_| rng.next() as u8).collect()
+}
+
+/// A small xorshift generator, so tests are repeatable without extra dependencies.
+pub struct Rng(u64);
+
+impl Rng {
+    /// Create a generator from a seed.
+    pub fn new(seed: u32) -> Self {
+        Rng(0x9E37_79B9_7F4A_7C15 ^ seed as u64)
+    }
+
+    /// Get the next number.
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
@@ -0,0 +1,74 @@
+//! Renaming files and directories within and across directories.
+
+use std::fs;
+
+use super::{read_path, Image};
+use crate::{Ext4Error, RenameMode};
+
+/// Build an image with two directories holding a file each, and a subdirectory in the first.
+fn populated_image() -> Image {
+    Image::mkfs_with("8M", &[], |source| {
+        fs::create_dir_all(source.join("a/sub")).unwrap();
+        fs::create_dir(source.join("b")).unwrap();
+        fs::write(source.join("a/file"), b"a").unwrap();
+        fs::write(source.join("b/file"), b"b").unwrap();
+        fs::write(source.join("a/sub/inner"), b"inner").unwrap();
+    })
+}
+
+#[test]
+fn moves_and_replaces_files() {
+    let image = populated_image();
+    let mut fs = image.mount();
+    fs.rename("/a/file", "/a/renamed").unwrap();
+    fs.rename("/a/renamed", "/b/file").unwrap();
+    assert!(fs.find_by_path("/a/renamed").is_err());
+    assert_eq!(read_path(&mut fs, "/b/file"), b"a");
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn moves_directories_between_parents() {
+    let image = populated_image();
+    let mut fs = image.mount();
+    let a = fs.find_by_path("/a").unwrap();
+    let b = fs.find_by_path("/b").unwrap();
+    let a_links = fs.read_inode(a).unwrap().links_count;
+    let b_links = fs.read_inode(b).unwrap().links_count;
+    fs.rename("/a/sub", "/b/sub").unwrap();
+    assert!(matches!(
+        fs.rename("/b", "/b/sub/b"),
+        Err(Ext4Error::InvalidOperation(_))
+    ));
+    drop(fs);
+    image.fsck();
+
+    let mut fs = image.mount();
+    assert_eq!(fs.read_inode(a).unwrap().links_count, a_links - 1);
+    assert_eq!(fs.read_inode(b).unwrap().links_count, b_links + 1);
+    assert_eq!(fs.find_by_path("/b/sub/..").unwrap(), b);
+    assert_eq!(read_path(&mut fs, "/b/sub/inner"), b"inner");
+}
+
+#[test]
+fn honours_no_replace_and_exchange() {
+    let image = populated_image();
+    let mut fs = image.mount();
+    assert!(fs
+        .rename_with_mode("/a/file", "/b/file", RenameMode::NoReplace)
+        .is_err());
+    fs.rename_with_mode("/a/file", "/b/file", RenameMode::Exchange)
+        .unwrap();
+    assert_eq!(read_path(&mut fs, "/a/file"), b"b");
+    assert_eq!(read_path(&mut fs, "/b/file"), b"a");
+
+    // Exchanging a directory with a file moves the directory's ".." to its new parent
+    fs.rename_with_mode("/a/sub", "/b/file", RenameMode::Exchange)
+        .unwrap();
+    let b = fs.find_by_path("/b").unwrap();
+    assert_eq!(fs.find_by_path("/b/file/..").unwrap(), b);
+    assert_eq!(read_path(&mut fs, "/a/sub"), b"a");
+    drop(fs);
+    image.fsck();
+}
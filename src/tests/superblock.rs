@@ -0,0 +1,84 @@
+//! Parsing the superblock and honoring its feature flags.
+
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+
+use super::{read_path, Image};
+use crate::superblock::{SUPERBLOCK_OFFSET, SUPERBLOCK_SIZE};
+use crate::{
+    CompatFeature, Ext4Error, Ext4Filesystem, IncompatFeature, RoCompatFeature, Superblock,
+};
+
+#[test]
+fn parses_label_uuid_and_features() {
+    let uuid = "0123abcd-4567-89ef-0123-456789abcdef";
+    let image = Image::mkfs_with(
+        "32M",
+        &[
+            "-b",
+            "4096",
+            "-L",
+            "test-volume",
+            "-U",
+            uuid,
+            "-O",
+            "metadata_csum",
+        ],
+        |_| {},
+    );
+
+    let mut raw = [0u8; SUPERBLOCK_SIZE];
+    let mut file = fs::File::open(image.path()).unwrap();
+    file.seek(SeekFrom::Start(SUPERBLOCK_OFFSET)).unwrap();
+    file.read_exact(&mut raw).unwrap();
+    let superblock = Superblock::parse(&raw).unwrap();
+
+    assert_eq!(superblock.label(), "test-volume");
+    assert_eq!(superblock.uuid_string(), uuid);
+    assert_eq!(superblock.block_size(), 4096);
+    assert_eq!(superblock.blocks_count_64(), 8192);
+    assert!(superblock.has_compat(CompatFeature::HasJournal));
+    assert!(superblock.has_incompat(IncompatFeature::Extents));
+    assert!(superblock.has_ro_compat(RoCompatFeature::MetadataCsum));
+    assert!(superblock
+        .feature_names()
+        .iter()
+        .any(|name| name == "metadata_csum"));
+    assert_eq!(superblock.compute_checksum().unwrap(), superblock.checksum);
+    assert_eq!(superblock.to_bytes().unwrap()[..], raw[..]);
+
+    // Writing the superblock back keeps everything e2fsck checks
+    let mut fs = image.mount();
+    assert_eq!(fs.superblock().label(), "test-volume");
+    fs.write_file("/", "file", b"data").unwrap();
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn refuses_unsupported_incompat_features() {
+    let image = Image::mkfs_with("8M", &["-O", "encrypt"], |_| {});
+    match Ext4Filesystem::mount(image.path()) {
+        Err(Ext4Error::UnsupportedFeature(message)) => assert!(message.contains("encrypt")),
+        Err(error) => panic!("unexpected error: {}", error),
+        Ok(_) => panic!("mounted a filesystem with encrypt"),
+    }
+    image.fsck();
+}
+
+#[test]
+fn mounts_unsupported_ro_compat_features_read_only() {
+    let image = Image::mkfs_with("8M", &["-O", "verity"], |source| {
+        fs::write(source.join("file"), b"contents").unwrap();
+    });
+    let mut fs = image.mount();
+    assert!(fs.is_read_only());
+    assert_eq!(read_path(&mut fs, "/file"), b"contents");
+    assert!(matches!(
+        fs.write_file("/", "new", b"data"),
+        Err(Ext4Error::ReadOnly(_))
+    ));
+    assert!(matches!(fs.unlink("/file"), Err(Ext4Error::ReadOnly(_))));
+    drop(fs);
+    image.fsck();
+}
@@ -0,0 +1,70 @@
+//! Creating symbolic links and following them when resolving paths.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+
+use super::{read_path, Image};
+use crate::Ext4Error;
+
+#[test]
+fn reads_links_made_by_mkfs() {
+    let long_target = format!("dir/{}", "long/".repeat(30)) + "file";
+    let image = Image::mkfs_with("8M", &[], |source| {
+        fs::create_dir(source.join("dir")).unwrap();
+        fs::write(source.join("dir/file"), b"contents").unwrap();
+        symlink("dir/file", source.join("fast")).unwrap();
+        symlink(&long_target, source.join("slow")).unwrap();
+    });
+
+    let mut fs = image.mount();
+    assert_eq!(fs.readlink("/fast").unwrap(), "dir/file");
+    assert_eq!(fs.readlink("/slow").unwrap(), long_target);
+    assert_eq!(read_path(&mut fs, "/fast"), b"contents");
+    let fast = fs.resolve_path("/fast", false).unwrap();
+    assert!(fs.read_inode(fast).unwrap().is_symlink());
+}
+
+#[test]
+fn creates_fast_and_slow_links() {
+    let long_target = "/dir/".to_string() + &"x".repeat(200);
+    let image = Image::mkfs_with("8M", &[], |source| {
+        fs::create_dir(source.join("dir")).unwrap();
+        fs::write(source.join("dir/file"), b"contents").unwrap();
+    });
+
+    let mut fs = image.mount();
+    fs.symlink("dir/file", "/relative").unwrap();
+    fs.symlink("/dir", "/absolute").unwrap();
+    fs.symlink("../dir/file", "/dir/up").unwrap();
+    fs.symlink(&long_target, "/long").unwrap();
+    fs.symlink("/loop-b", "/loop-a").unwrap();
+    fs.symlink("/loop-a", "/loop-b").unwrap();
+    drop(fs);
+    image.fsck();
+
+    let mut fs = image.mount();
+    let block_size = fs.superblock().block_size();
+    let relative = fs.resolve_path("/relative", false).unwrap();
+    assert!(fs.read_inode(relative).unwrap().is_fast_symlink(block_size));
+    let long = fs.resolve_path("/long", false).unwrap();
+    assert!(!fs.read_inode(long).unwrap().is_fast_symlink(block_size));
+    assert_eq!(fs.readlink("/long").unwrap(), long_target);
+
+    assert_eq!(read_path(&mut fs, "/relative"), b"contents");
+    assert_eq!(read_path(&mut fs, "/absolute/file"), b"contents");
+    assert_eq!(read_path(&mut fs, "/absolute/up"), b"contents");
+    assert!(matches!(
+        fs.find_by_path("/loop-a"),
+        Err(Ext4Error::SymlinkLoop(_))
+    ));
+    assert!(fs.resolve_path("/loop-a", false).is_ok());
+
+    // Removing the links leaves what they point to alone
+    fs.unlink("/long").unwrap();
+    fs.unlink("/relative").unwrap();
+    drop(fs);
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/dir/file"), b"contents");
+    drop(fs);
+    image.fsck();
+}
@@ -0,0 +1,84 @@
+//! Truncating files and preallocating, punching and zeroing ranges of them.
+
+use super::{pattern, read_path, Image};
+use crate::FallocateMode;
+
+fn shrinks_and_grows(features: &str) {
+    let image = Image::mkfs_with("16M", &["-b", "1024", "-O", features], |_| {});
+    let data = pattern(300 << 10, 1);
+    let mut fs = image.mount();
+    fs.write_file("/", "file", &data).unwrap();
+    let inode_num = fs.find_by_path("/file").unwrap();
+    let blocks = fs.read_inode(inode_num).unwrap().get_blocks();
+    fs.truncate("/file", 10000).unwrap();
+    assert!(fs.read_inode(inode_num).unwrap().get_blocks() < blocks);
+    drop(fs);
+    image.fsck();
+
+    // Growing again leaves a hole, and the old data past the cut reads as zeros
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/file"), &data[..10000]);
+    fs.truncate("/file", 200 << 10).unwrap();
+    let read = read_path(&mut fs, "/file");
+    assert_eq!(read.len(), 200 << 10);
+    assert_eq!(&read[..10000], &data[..10000]);
+    assert!(read[10000..].iter().all(|&byte| byte == 0));
+    fs.truncate("/file", 0).unwrap();
+    assert_eq!(fs.read_inode(inode_num).unwrap().get_blocks(), 0);
+    drop(fs);
+    image.fsck();
+}
+
+#[test]
+fn truncates_extent_mapped_files() {
+    shrinks_and_grows("extents");
+}
+
+#[test]
+fn truncates_block_mapped_files() {
+    shrinks_and_grows("^extents,^64bit");
+}
+
+#[test]
+fn preallocates_punches_and_zeroes_ranges() {
+    let image = Image::mkfs_with("16M", &["-b", "1024"], |_| {});
+    let data = pattern(64 << 10, 2);
+    let mut fs = image.mount();
+    fs.write_file("/", "file", &data).unwrap();
+    let inode_num = fs.find_by_path("/file").unwrap();
+    let blocks = fs.read_inode(inode_num).unwrap().get_blocks();
+
+    // Preallocating past the end keeps the size and reads as nothing new
+    let keep_size = FallocateMode::Allocate { keep_size: true };
+    fs.fallocate("/file", keep_size, 64 << 10, 32 << 10)
+        .unwrap();
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert_eq!(inode.get_size(), 64 << 10);
+    assert_eq!(inode.get_blocks(), blocks + 64);
+    let extend = FallocateMode::Allocate { keep_size: false };
+    fs.fallocate("/file", extend, 96 << 10, 4 << 10).unwrap();
+    assert_eq!(fs.read_inode(inode_num).unwrap().get_size(), 100 << 10);
+    drop(fs);
+    image.fsck();
+
+    let mut fs = image.mount();
+    let mut expected = data.clone();
+    expected.resize(100 << 10, 0);
+    assert_eq!(read_path(&mut fs, "/file"), expected);
+
+    // Punching a hole frees the whole blocks in it and zeroes the partial ones
+    fs.fallocate("/file", FallocateMode::PunchHole, 1500, 8000)
+        .unwrap();
+    expected[1500..9500].fill(0);
+    assert_eq!(read_path(&mut fs, "/file"), expected);
+    let zero = FallocateMode::ZeroRange { keep_size: false };
+    fs.fallocate("/file", zero, 30000, 20000).unwrap();
+    expected[30000..50000].fill(0);
+    assert_eq!(read_path(&mut fs, "/file"), expected);
+    drop(fs);
+    image.fsck();
+
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/file"), expected);
+    assert!(fs.read_inode(inode_num).unwrap().get_blocks() < blocks + 72);
+}
@@ -0,0 +1,52 @@
+//! Positional writes into existing files.
+
+use super::{pattern, read_path, Image};
+
+#[test]
+fn writes_into_holes_and_past_the_end() {
+    let image = Image::mkfs_with("16M", &["-b", "1024"], |_| {});
+    let mut fs = image.mount();
+    fs.write_file("/", "log", b"").unwrap();
+    drop(fs);
+    image.debugfs(&["sif /log mtime 1", "sif /log ctime 1"]);
+    let mut fs = image.mount();
+    let inode_num = fs.find_by_path("/log").unwrap();
+
+    // Appending a record at a time only allocates the blocks the records land in
+    let mut expected = Vec::new();
+    for i in 0..50 {
+        let record = pattern(700, i);
+        let written = fs
+            .write_at(inode_num, expected.len() as u64, &record)
+            .unwrap();
+        assert_eq!(written, record.len());
+        expected.extend_from_slice(&record);
+    }
+    let inode = fs.read_inode(inode_num).unwrap();
+    assert_eq!(inode.get_size(), expected.len() as u64);
+    assert_eq!(inode.get_blocks(), 2 * expected.len().div_ceil(1024) as u64);
+    assert!(inode.mtime > 1 && inode.ctime > 1);
+    drop(fs);
+    image.fsck();
+
+    // A write far past the end leaves a hole, which a later write partly fills
+    let mut fs = image.mount();
+    let far = 1 << 20;
+    fs.write_at(inode_num, far, b"far").unwrap();
+    let blocks = fs.read_inode(inode_num).unwrap().get_blocks();
+    fs.write_at(inode_num, 500 << 10, &pattern(3000, 99))
+        .unwrap();
+    assert_eq!(fs.read_inode(inode_num).unwrap().get_blocks(), blocks + 6);
+    fs.write_at(inode_num, 100, b"overwritten").unwrap();
+    drop(fs);
+
+    expected[100..111].copy_from_slice(b"overwritten");
+    expected.resize(500 << 10, 0);
+    expected.extend_from_slice(&pattern(3000, 99));
+    expected.resize(far as usize, 0);
+    expected.extend_from_slice(b"far");
+    let mut fs = image.mount();
+    assert_eq!(read_path(&mut fs, "/log"), expected);
+    drop(fs);
+    image.fsck();
+}
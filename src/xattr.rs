@@ -0,0 +1,203 @@
+//! In-inode extended attributes of ext4 filesystem.
+
+use crate::error::Ext4Error;
+use byteorder::{ByteOrder, LittleEndian};
+
+/// Magic number at the start of the extended attribute area inside an inode, and of an
+/// attribute block.
+pub const EXT4_XATTR_MAGIC: u32 = 0xEA02_0000;
+
+/// Name index of the "system." namespace, which holds inline data.
+pub const EXT4_XATTR_INDEX_SYSTEM: u8 = 7;
+
+/// Name of the attribute holding inline data past `i_block`, in the system namespace.
+pub const EXT4_XATTR_SYSTEM_DATA: &[u8] = b"data";
+
+/// Size of an attribute entry before its name.
+const XATTR_ENTRY_SIZE: usize = 16;
+
+/// Round a length up to the 4-byte alignment of attribute entries and values.
+fn xattr_pad(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Get the space an entry with a name of this length takes, without its value.
+fn xattr_entry_len(name_len: usize) -> usize {
+    xattr_pad(XATTR_ENTRY_SIZE + name_len)
+}
+
+/// An extended attribute stored inside an inode.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XattrEntry {
+    /// Namespace of the name, such as `EXT4_XATTR_INDEX_SYSTEM`.
+    pub name_index: u8,
+    /// Name without its namespace prefix.
+    pub name: Vec<u8>,
+    /// Hash of the name and value, or 0 if not computed.
+    pub hash: u32,
+    /// Value.
+    pub value: Vec<u8>,
+}
+
+/// The extended attributes stored in the space after the extra fields of an inode.
+#[derive(Debug, Clone, Default)]
+pub struct InodeXattrs {
+    /// The attributes in on-disk order.
+    pub entries: Vec<XattrEntry>,
+}
+
+impl InodeXattrs {
+    /// Parse the attribute area of an inode, which starts right after `i_extra_isize` bytes
+    /// of extra fields.
+    ///
+    /// An area without the magic number holds no attributes.
+    pub fn parse(ibody: &[u8]) -> Result<Self, Ext4Error> {
+        if ibody.len() < 4 || LittleEndian::read_u32(&ibody[0..4]) != EXT4_XATTR_MAGIC {
+            return Ok(InodeXattrs::default());
+        }
+
+        // Value offsets count from the first entry
+        let area = &ibody[4..];
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        while offset + 4 <= area.len() && LittleEndian::read_u32(&area[offset..offset + 4]) != 0 {
+            if offset + XATTR_ENTRY_SIZE > area.len() {
+                return Err(Ext4Error::InvalidInode(
+                    "Extended attribute entry overruns the inode".to_string(),
+                ));
+            }
+            let entry = &area[offset..];
+            let name_len = entry[0] as usize;
+            let value_offs = LittleEndian::read_u16(&entry[2..4]) as usize;
+            let value_inum = LittleEndian::read_u32(&entry[4..8]);
+            let value_size = LittleEndian::read_u32(&entry[8..12]) as usize;
+            if value_inum != 0 {
+                return Err(Ext4Error::UnsupportedFeature(
+                    "extended attribute values in separate inodes".to_string(),
+                ));
+            }
+            if offset + xattr_entry_len(name_len) > area.len()
+                || value_offs + value_size > area.len()
+            {
+                return Err(Ext4Error::InvalidInode(
+                    "Extended attribute entry overruns the inode".to_string(),
+                ));
+            }
+
+            entries.push(XattrEntry {
+                name_index: entry[1],
+                name: entry[XATTR_ENTRY_SIZE..XATTR_ENTRY_SIZE + name_len].to_vec(),
+                hash: LittleEndian::read_u32(&entry[12..16]),
+                value: area[value_offs..value_offs + value_size].to_vec(),
+            });
+            offset += xattr_entry_len(name_len);
+        }
+        Ok(InodeXattrs { entries })
+    }
+
+    /// Get the value of an attribute.
+    pub fn get(&self, name_index: u8, name: &[u8]) -> Option<&[u8]> {
+        self.entries
+            .iter()
+            .find(|entry| entry.name_index == name_index && entry.name == name)
+            .map(|entry| entry.value.as_slice())
+    }
+
+    /// Set the value of an attribute, adding it after the others if it is new.
+    pub fn set(&mut self, name_index: u8, name: &[u8], value: Vec<u8>) {
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.name_index == name_index && entry.name == name)
+        {
+            Some(entry) => {
+                entry.value = value;
+                entry.hash = 0;
+            }
+            None => self.entries.push(XattrEntry {
+                name_index,
+                name: name.to_vec(),
+                hash: 0,
+                value,
+            }),
+        }
+    }
+
+    /// Remove an attribute, returning whether it was there.
+    pub fn remove(&mut self, name_index: u8, name: &[u8]) -> bool {
+        let len = self.entries.len();
+        self.entries
+            .retain(|entry| entry.name_index != name_index || entry.name != name);
+        self.entries.len() != len
+    }
+
+    /// Get the longest value an attribute could have in an area of `ibody_len` bytes, with
+    /// the other attributes left as they are.
+    pub fn max_value_len(&self, ibody_len: usize, name_index: u8, name: &[u8]) -> usize {
+        let others: usize = self
+            .entries
+            .iter()
+            .filter(|entry| entry.name_index != name_index || entry.name != name)
+            .map(|entry| xattr_entry_len(entry.name.len()) + xattr_pad(entry.value.len()))
+            .sum();
+
+        // The magic number, this entry and the terminating zero word
+        let used = 4 + others + xattr_entry_len(name.len()) + 4;
+        ibody_len.saturating_sub(used) & !3
+    }
+
+    /// Serialize the attributes into the attribute area of an inode.
+    ///
+    /// Entries are laid out from the start and values packed from the end, like the kernel
+    /// does. Fails without touching the area if they do not fit.
+    pub fn write_to(&self, ibody: &mut [u8]) -> Result<(), Ext4Error> {
+        if self.entries.is_empty() {
+            ibody.fill(0);
+            return Ok(());
+        }
+
+        let area_len = ibody.len().saturating_sub(4);
+        let entries_len: usize = self
+            .entries
+            .iter()
+            .map(|entry| xattr_entry_len(entry.name.len()))
+            .sum::<usize>()
+            + 4;
+        let values_len: usize = self
+            .entries
+            .iter()
+            .map(|entry| xattr_pad(entry.value.len()))
+            .sum();
+        if entries_len + values_len > area_len {
+            return Err(Ext4Error::NoSpace(
+                "No space left for extended attributes in the inode".to_string(),
+            ));
+        }
+
+        ibody.fill(0);
+        LittleEndian::write_u32(&mut ibody[0..4], EXT4_XATTR_MAGIC);
+        let area = &mut ibody[4..];
+        let mut offset = 0;
+        let mut value_end = area_len;
+        for entry in &self.entries {
+            let value_offs = if entry.value.is_empty() {
+                0
+            } else {
+                value_end -= xattr_pad(entry.value.len());
+                area[value_end..value_end + entry.value.len()].copy_from_slice(&entry.value);
+                value_end
+            };
+
+            let slot = &mut area[offset..offset + xattr_entry_len(entry.name.len())];
+            slot[0] = entry.name.len() as u8;
+            slot[1] = entry.name_index;
+            LittleEndian::write_u16(&mut slot[2..4], value_offs as u16);
+            LittleEndian::write_u32(&mut slot[8..12], entry.value.len() as u32);
+            LittleEndian::write_u32(&mut slot[12..16], entry.hash);
+            slot[XATTR_ENTRY_SIZE..XATTR_ENTRY_SIZE + entry.name.len()]
+                .copy_from_slice(&entry.name);
+            offset += slot.len();
+        }
+        Ok(())
+    }
+}